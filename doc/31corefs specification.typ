@@ -22,6 +22,7 @@ Supported features:
 - Sparse file
 - Subvolume and snapshot
 - POSIX ACLs
+- Transparent compression
 - Case-sentitive
 
 = Definitions
@@ -32,6 +33,8 @@ Supported features:
 = Super block
 The super block is the first block of the physical device, it records metadata describing the filesystem.
 
+With `FEATURE_COMPAT_BACKUP_SUPERBLOCK`, a copy of the super block is kept at block `total_blocks - 1`, which is left out of block groups. It is written whenever the super block is, and can replace the super block if the first block is damaged.
+
 *Definition*
 ```c
 struct super_block {
@@ -45,6 +48,13 @@ struct super_block {
     uint64_t default_subvol;
     uint64_t subvol_mgr;
     uint64_t creation_time;
+    uint64_t subvols;
+    uint64_t feature_compat;
+    uint64_t feature_incompat;
+    uint64_t feature_ro_compat;
+    uint64_t block_map_size;
+    uint64_t metadata_reserve;
+    uint64_t dir_hash;
 };
 ```
 
@@ -56,11 +66,33 @@ struct super_block {
     [magic_header], [Pre-defined as `[0x31, 0xc0, 0x8e, 0xf5]`.],
     [version], [`0x01` for version 1.],
     [uuid], [Recommend to use UUIDv4.],
-    [label], [A regular C string that ends with `NULL` character which can be ASCII or UTF-8 charset.]
+    [label], [A regular C string that ends with `NULL` character which can be ASCII or UTF-8 charset.],
+    [default_subvol], [ID of default subvolume, `0xffffffffffffffff` if no default subvolume is set.],
+    [subvols], [Count of allocated subvolumes, removed subvolumes kept for snapshots are not counted.],
+    [feature_incompat], [Features that must be supported to access the filesystem.],
+    [block_map_size], [Count of bitmap blocks in each block group, `1` by default. `FEATURE_INCOMPAT_BLOCK_MAP_SIZE` must be set if it is not `1`.],
+    [metadata_reserve], [Count of free blocks which file data must not be allocated from, so that metadata can still be allocated when file data fills the filesystem. Content of directories is allocated as metadata.],
+    [dir_hash], [Hash of names in indexed directories, `0` for FNV-1a and `1` for SipHash-2-4 keyed with `uuid`. `FEATURE_INCOMPAT_DIR_HASH` must be set if it is not `0`.]
+)
+
+*Compatible features*
+
+#table(columns: 2,
+    [FEATURE_COMPAT_HEAT_MAP], [`0x1`],
+    [FEATURE_COMPAT_BACKUP_SUPERBLOCK], [`0x2`],
+)
+
+*Incompatible features*
+
+#table(columns: 2,
+    [FEATURE_INCOMPAT_BTREE_DIR], [`0x1`],
+    [FEATURE_INCOMPAT_COMPRESSION], [`0x2`],
+    [FEATURE_INCOMPAT_BLOCK_MAP_SIZE], [`0x4`],
+    [FEATURE_INCOMPAT_DIR_HASH], [`0x8`],
 )
 
 = Block group
-The whole filesystem is divided into several block groups, each block group is an independent block allocator. A block group includes a bitmap block and $8 times "BLOCK_SIZE"$ data blocks. The meta block is the first block of a block group, it records allocation status of the block groups. And the bitmap is the second block of a block group and it is uesd to tracking allocation of the data blocks.
+The whole filesystem is divided into several block groups, each block group is an independent block allocator. A block group includes `block_map_size` bitmap blocks and $8 times "BLOCK_SIZE" times "block_map_size"$ data blocks. The meta block is the first block of a block group, it records allocation status of the block groups. And the bitmap blocks follow the meta block and they are uesd to tracking allocation of the data blocks.
 
 *Definition*
 ```c
@@ -68,9 +100,12 @@ struct block_group_meta {
     uint64_t id;
     uint64_t free_blocks;
     uint64_t next_group;
+    uint64_t writes;
 };
 ```
 
+`writes` counts writes of data blocks in the group with `FEATURE_COMPAT_HEAT_MAP`, it is left `0` otherwise.
+
 = B-Tree
 == B-Tree entry
 
@@ -147,6 +182,9 @@ struct inode {
     uint16_t hlinks;
     uint64_t size;
     uint64_t btree_root;
+    uint64_t xattr;
+    uint32_t dev_major;
+    uint32_t dev_minor;
 };
 ```
 
@@ -162,7 +200,10 @@ struct inode {
     [mtime], [Last modify time (unit: nano sec)],
     [hlinks], [Count of hard links],
     [size], [File size],
-    [btree_root], [Root B-Tree node block of content management]
+    [btree_root], [Root B-Tree node block of content management],
+    [xattr], [First block of extended attributes, 0 if there is no extended attribute],
+    [dev_major], [Major device number of a character or block device],
+    [dev_minor], [Minor device number of a character or block device]
 )
 
 *Empty inode*
@@ -183,6 +224,7 @@ An empty Inode always has `acl` valued `0xffff`.
 - `ACL_SYMBOLLINK`: `0x4`
 - `ACL_CHAR`: `0x8`
 - `ACL_BLOCK`: `0x10`
+- `ACL_WHITEOUT`: `0x20`, a name deleted in an overlay, it hides the file of the same name in lower layers
 
 *Permission*
 
@@ -226,9 +268,26 @@ struct subvolume_entry {
     uint64_t snaps;
     uint64_t parent_subvol;
     uint8_t state;
+    uint8_t type;
+    uint8_t flags;
+    uint8_t reserved;
+    uint32_t max_inodes;
+    uint64_t quota;
+    uint8_t name[24];
 };
 ```
 
+`max_inodes` is the max count of inodes allocated in the subvolume, `0` if unlimited.
+
+`quota` is the max count of blocks used by the subvolume, `0` if unlimited.
+
+`name` is a unique name of the subvolume padded with `NULL` characters, all `NULL` if the subvolume is not named.
+
+Subvolume flags used by `flags` field:
+#table(columns: 2,
+    [SUBVOLUME_FLAG_RO], [`0x01`, the subvolume is read-only, snapshots are created with it set]
+)
+
 Subvolume statement used by `state` field:
 #table(columns: 2,
     [SUBVOLUME_STATE_ALLOCATED], [`0x01`],
@@ -272,6 +331,46 @@ struct igroup_bitmap {
 
 Subvolume mark an allocated block on the subvolume bitmap after allocated with the global allocator, and unmark an block when release it. This subvolume bitmap will be used when destroying a subvolume.
 
+= Directory
+A directory is stored as the content of its inode, which is a list of directory entries.
+
+*Definition*
+```c
+struct dir_entry {
+    uint64_t inode;
+    uint8_t name_len;
+    uint8_t name[name_len];
+};
+```
+
+Without `FEATURE_INCOMPAT_BTREE_DIR`, removed entries are kept in place with `inode` valued `0xffffffffffffffff`. When removed entries take more than half of the entries, the remaining entries are packed before entries are added. A removed last entry is truncated.
+
+== Indexed directory
+With `FEATURE_INCOMPAT_BTREE_DIR`, directory entries are stored in buckets mapped by the B-Tree of the directory inode in place of its content, and the size of the directory is the size of its buckets. A bucket is a block of packed entries.
+
+*Definition*
+```c
+struct dir_bucket {
+    uint64_t size;
+    struct dir_entry entries[];
+};
+```
+
+The hash of a name is the hash selected by `dir_hash` shifted right by 8 bits. The key of a bucket is the least hash of names it holds shifted left by 8 bits, and a bucket holds the names whose hashes are from its key up to the key of the next bucket. Entries inside a bucket are not ordered.
+
+Names sharing a hash are kept in one bucket. If they do not fit in a block, they fill buckets keyed from the hash with the lowest 8 bits counted up from `0` to at most `0xfe`, and the last of them may hold greater hashes.
+
+When an entry does not fit in its bucket, the entries of the bucket are sorted by hash and split into buckets, which keep names of one hash together unless they take more than a bucket. A bucket left without entries is released, buckets are not merged otherwise. When the last entry is removed, the B-Tree is released.
+
+= Compression
+With `FEATURE_INCOMPAT_COMPRESSION`, data of a regular file which has extended attribute `system.compression` is compressed with zlib. Data blocks are compressed in clusters of 16 blocks, cluster $c$ covers blocks from $16 times c$ to $16 times c + 15$.
+
+A compressed cluster taking $k$ blocks is stored in the blocks mapped by keys from $16 times c$ to $16 times c + k - 1$, and the value of the first key is
+```c
+value = block | (compressed_len << 40) | (1 << 63);
+```
+Clusters which do not take fewer blocks when compressed are stored raw, blocks of a raw cluster are mapped as usual. Compressed and raw clusters can be mixed in a file.
+
 = Linked content table
 *Definition*
 ```c
@@ -0,0 +1,454 @@
+use crate::block::BLOCK_SIZE;
+use crate::{Filesystem, Subvolume};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Error, ErrorKind, Result as IOResult};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/**
+ * A device with asynchronous block IO, e.g. a network block device or io_uring
+ *
+ * Futures returned by the methods aren't required to be `Send`.
+ */
+#[allow(async_fn_in_trait)]
+pub trait AsyncDevice {
+    /** Read the block into `buf`, which is `BLOCK_SIZE` bytes */
+    async fn read_block(&mut self, block_count: u64, buf: &mut [u8]) -> IOResult<()>;
+    /** Write `buf`, which is `BLOCK_SIZE` bytes, to the block */
+    async fn write_block(&mut self, block_count: u64, buf: &[u8]) -> IOResult<()>;
+    /** Make written blocks durable */
+    async fn flush(&mut self) -> IOResult<()>;
+}
+
+impl<T> AsyncDevice for &mut T
+where
+    T: AsyncDevice + ?Sized,
+{
+    async fn read_block(&mut self, block_count: u64, buf: &mut [u8]) -> IOResult<()> {
+        (**self).read_block(block_count, buf).await
+    }
+    async fn write_block(&mut self, block_count: u64, buf: &[u8]) -> IOResult<()> {
+        (**self).write_block(block_count, buf).await
+    }
+    async fn flush(&mut self) -> IOResult<()> {
+        (**self).flush().await
+    }
+}
+
+/** Count of blocks fetched from a missed block after the first miss of an operation */
+const READAHEAD_MIN: u64 = 8;
+/** Limit of blocks fetched from a missed block, the count is doubled on each miss */
+const READAHEAD_MAX: u64 = 1024;
+
+/**
+ * A synchronous device staging blocks of an asynchronous device for an operation
+ *
+ * The operation reads blocks fetched so far, and fails with `ErrorKind::WouldBlock` at blocks
+ * not fetched, which are then fetched with the blocks following them before the operation runs
+ * again. Blocks written are kept until the operation completes without missing a block, so a
+ * failed attempt writes nothing. Each block written records the count of flushes before it, so
+ * that blocks are written back with flushes in between, as write barriers of the operation
+ * expect.
+ */
+#[derive(Debug, Default)]
+struct StagedDevice {
+    /** Blocks fetched from the device */
+    clean: HashMap<u64, Box<[u8; BLOCK_SIZE]>>,
+    /** Blocks written by the operation, with count of flushes before the last write */
+    dirty: BTreeMap<u64, (Box<[u8; BLOCK_SIZE]>, u64)>,
+    flushes: u64,
+    /** Blocks the operation needed but not fetched */
+    missing: BTreeSet<u64>,
+    position: u64,
+}
+
+impl StagedDevice {
+    fn block(&mut self, block_count: u64) -> IOResult<&[u8; BLOCK_SIZE]> {
+        if let Some((block, _)) = self.dirty.get(&block_count) {
+            return Ok(block);
+        }
+        match self.clean.get(&block_count) {
+            Some(block) => Ok(block),
+            None => {
+                self.missing.insert(block_count);
+                Err(Error::new(
+                    ErrorKind::WouldBlock,
+                    format!("Block {} isn't fetched yet.", block_count),
+                ))
+            }
+        }
+    }
+    /** Forget blocks written by a previous attempt */
+    fn reset(&mut self) {
+        self.dirty.clear();
+        self.flushes = 0;
+        self.missing.clear();
+        self.position = 0;
+    }
+    /** Fetch a block and up to `readahead - 1` blocks following it
+     *
+     * Blocks following it are fetched until one can't be read, e.g. beyond the end of the
+     * device, so only failing to fetch the block itself is an error.
+     */
+    async fn fetch<A>(&mut self, device: &mut A, block_count: u64, readahead: u64) -> IOResult<()>
+    where
+        A: AsyncDevice,
+    {
+        for count in block_count..block_count + readahead {
+            if self.clean.contains_key(&count) {
+                continue;
+            }
+            let mut block = Box::new([0; BLOCK_SIZE]);
+            match device.read_block(count, &mut *block).await {
+                Ok(()) => {
+                    self.clean.insert(count, block);
+                }
+                Err(err) if count == block_count => return Err(err),
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+    /** Write blocks back in order of flushes, and flush if the operation did */
+    async fn commit<A>(&mut self, device: &mut A) -> IOResult<()>
+    where
+        A: AsyncDevice,
+    {
+        let mut barriers: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        for (block_count, (_, flushes)) in &self.dirty {
+            barriers.entry(*flushes).or_default().push(*block_count);
+        }
+
+        let mut unflushed = false;
+        for (flushes, blocks) in barriers {
+            if unflushed && flushes > 0 {
+                device.flush().await?;
+            }
+            for block_count in blocks {
+                let (block, _) = self.dirty.remove(&block_count).unwrap();
+                device.write_block(block_count, &*block).await?;
+                self.clean.insert(block_count, block);
+            }
+            unflushed = flushes < self.flushes;
+        }
+        if unflushed {
+            device.flush().await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for StagedDevice {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let block_count = self.position / BLOCK_SIZE as u64;
+            let offset = (self.position % BLOCK_SIZE as u64) as usize;
+            let len = std::cmp::min(buf.len() - done, BLOCK_SIZE - offset);
+
+            let block = self.block(block_count)?;
+            buf[done..done + len].copy_from_slice(&block[offset..offset + len]);
+            done += len;
+            self.position += len as u64;
+        }
+        Ok(done)
+    }
+}
+
+impl Write for StagedDevice {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let block_count = self.position / BLOCK_SIZE as u64;
+            let offset = (self.position % BLOCK_SIZE as u64) as usize;
+            let len = std::cmp::min(buf.len() - done, BLOCK_SIZE - offset);
+
+            /* a partially written block needs its content */
+            let mut block = if len == BLOCK_SIZE {
+                Box::new([0; BLOCK_SIZE])
+            } else {
+                Box::new(*self.block(block_count)?)
+            };
+            block[offset..offset + len].copy_from_slice(&buf[done..done + len]);
+            self.dirty.insert(block_count, (block, self.flushes));
+            done += len;
+            self.position += len as u64;
+        }
+        Ok(done)
+    }
+    fn flush(&mut self) -> IOResult<()> {
+        self.flushes += 1;
+        Ok(())
+    }
+}
+
+impl Seek for StagedDevice {
+    fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(_) => None,
+        };
+        self.position = position
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid seek position."))?;
+
+        Ok(self.position)
+    }
+}
+
+/**
+ * Run a synchronous operation over an asynchronous device
+ *
+ * The operation runs again each time it misses blocks, so it must start from the same state
+ * every time. Blocks following a missed one are fetched with it, twice as many on each attempt,
+ * so reading `n` consecutive blocks takes about `log2(n)` attempts rather than `n`. Blocks it
+ * wrote are written back once it completes, and dropped if it fails.
+ */
+async fn run<A, T, F>(device: &mut A, mut op: F) -> IOResult<T>
+where
+    A: AsyncDevice,
+    F: FnMut(&mut StagedDevice) -> IOResult<T>,
+{
+    let mut staged = StagedDevice::default();
+    let mut readahead = READAHEAD_MIN;
+    loop {
+        staged.reset();
+        let result = op(&mut staged);
+
+        if staged.missing.is_empty() {
+            if result.is_ok() {
+                staged.commit(device).await?;
+            }
+            return result;
+        }
+        for block_count in std::mem::take(&mut staged.missing) {
+            staged.fetch(device, block_count, readahead).await?;
+        }
+        readahead = std::cmp::min(readahead * 2, READAHEAD_MAX);
+    }
+}
+
+/**
+ * Asynchronous variants of the hot path, built with the `async` feature
+ *
+ * Each runs the synchronous code over blocks fetched from the device, so the on-disk format is
+ * shared. States of the filesystem and the subvolume are cloned for each attempt and only kept
+ * from the attempt that completes successfully, a failed operation changes nothing.
+ */
+impl Filesystem {
+    pub async fn load_async<A>(device: &mut A) -> IOResult<Self>
+    where
+        A: AsyncDevice,
+    {
+        run(device, Self::load).await
+    }
+    pub async fn get_default_subvolume_async<A>(&self, device: &mut A) -> IOResult<Subvolume>
+    where
+        A: AsyncDevice,
+    {
+        run(device, |staged| self.get_default_subvolume(staged)).await
+    }
+    pub async fn read_async<A, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut A,
+        path: P,
+    ) -> IOResult<Vec<u8>>
+    where
+        A: AsyncDevice,
+        P: AsRef<Path>,
+    {
+        self.run_async(subvol, device, |fs, subvol, staged| {
+            fs.read(subvol, staged, path.as_ref())
+        })
+        .await
+    }
+    pub async fn write_async<A, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut A,
+        path: P,
+        data: &[u8],
+    ) -> IOResult<()>
+    where
+        A: AsyncDevice,
+        P: AsRef<Path>,
+    {
+        self.run_async(subvol, device, |fs, subvol, staged| {
+            fs.write(subvol, staged, path.as_ref(), data)
+        })
+        .await
+    }
+    /** Synchronize meta data of the filesystem and the subvolume */
+    pub async fn sync_meta_data_async<A>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut A,
+    ) -> IOResult<()>
+    where
+        A: AsyncDevice,
+    {
+        self.run_async(subvol, device, |fs, subvol, staged| {
+            subvol.sync_meta_data(fs, staged)?;
+            fs.sync_meta_data(staged)
+        })
+        .await
+    }
+    async fn run_async<A, T, F>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut A,
+        mut op: F,
+    ) -> IOResult<T>
+    where
+        A: AsyncDevice,
+        F: FnMut(&mut Self, &mut Subvolume, &mut StagedDevice) -> IOResult<T>,
+    {
+        let (fs, new_subvol, value) = run(device, |staged| {
+            let mut fs = self.clone();
+            let mut subvol = subvol.clone();
+            let value = op(&mut fs, &mut subvol, staged)?;
+            Ok((fs, subvol, value))
+        })
+        .await?;
+
+        *self = fs;
+        *subvol = new_subvol;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryDevice;
+
+    /** An in-memory device yielding to the runtime on each IO */
+    struct AsyncMemoryDevice {
+        inner: MemoryDevice,
+        reads: usize,
+        writes: usize,
+        flushes: usize,
+    }
+
+    impl AsyncDevice for AsyncMemoryDevice {
+        async fn read_block(&mut self, block_count: u64, buf: &mut [u8]) -> IOResult<()> {
+            tokio::task::yield_now().await;
+            self.reads += 1;
+            self.inner
+                .seek(SeekFrom::Start(block_count * BLOCK_SIZE as u64))?;
+            self.inner.read_exact(buf)
+        }
+        async fn write_block(&mut self, block_count: u64, buf: &[u8]) -> IOResult<()> {
+            tokio::task::yield_now().await;
+            self.writes += 1;
+            self.inner
+                .seek(SeekFrom::Start(block_count * BLOCK_SIZE as u64))?;
+            self.inner.write_all(buf)
+        }
+        async fn flush(&mut self) -> IOResult<()> {
+            tokio::task::yield_now().await;
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn read_write() {
+        let mut inner = MemoryDevice::with_size(2000);
+        let mut fs = Filesystem::create(&mut inner, 2000).unwrap();
+        fs.sync_meta_data(&mut inner).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut inner).unwrap();
+        fs.write(&mut subvol, &mut inner, "/sync", b"written synchronously")
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut inner).unwrap();
+        fs.sync_meta_data(&mut inner).unwrap();
+
+        let mut device = AsyncMemoryDevice {
+            inner,
+            reads: 0,
+            writes: 0,
+            flushes: 0,
+        };
+        let mut fs = Filesystem::load_async(&mut device).await.unwrap();
+        let mut subvol = fs.get_default_subvolume_async(&mut device).await.unwrap();
+        assert_eq!(
+            fs.read_async(&mut subvol, &mut device, "/sync")
+                .await
+                .unwrap(),
+            b"written synchronously"
+        );
+
+        let data = (0..20000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs.write_async(&mut subvol, &mut device, "/async", &data)
+            .await
+            .unwrap();
+        assert!(device.writes > 0);
+        assert_eq!(
+            fs.read_async(&mut subvol, &mut device, "/async")
+                .await
+                .unwrap(),
+            data
+        );
+        assert_eq!(
+            fs.read_async(&mut subvol, &mut device, "/missing")
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        fs.sync_meta_data_async(&mut subvol, &mut device)
+            .await
+            .unwrap();
+        assert!(device.flushes > 0);
+
+        /* a failed operation writes nothing and keeps the state */
+        let (writes, used_blocks) = (device.writes, fs.sb.used_blocks);
+        assert_eq!(
+            fs.write_async(&mut subvol, &mut device, "/missing/f", b"data")
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!((device.writes, fs.sb.used_blocks), (writes, used_blocks));
+
+        /* the synchronous path sees what was written asynchronously */
+        let mut inner = device.inner;
+        let mut fs = Filesystem::load(&mut inner).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut inner).unwrap();
+        assert_eq!(fs.read(&mut subvol, &mut inner, "/async").unwrap(), data);
+        fs.check_used_blocks(&mut inner).unwrap();
+    }
+
+    #[tokio::test]
+    async fn readahead() {
+        let mut inner = MemoryDevice::with_size(2000);
+        let mut fs = Filesystem::create(&mut inner, 2000).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut inner).unwrap();
+        let data = vec![1; 500 * BLOCK_SIZE];
+        fs.write(&mut subvol, &mut inner, "/f", &data).unwrap();
+        subvol.sync_meta_data(&mut fs, &mut inner).unwrap();
+        fs.sync_meta_data(&mut inner).unwrap();
+
+        let mut device = AsyncMemoryDevice {
+            inner,
+            reads: 0,
+            writes: 0,
+            flushes: 0,
+        };
+        let mut attempts = 0;
+        let read = run(&mut device, |staged| {
+            attempts += 1;
+            fs.read(&mut subvol, staged, "/f")
+        })
+        .await
+        .unwrap();
+        assert_eq!(read, data);
+        /* rather than an attempt for each block */
+        assert!(attempts < 30, "{attempts}");
+        assert!(device.reads < 2000, "{}", device.reads);
+    }
+}
@@ -1,16 +1,22 @@
 use crate::inode::{INode, INODE_PER_GROUP, INODE_SIZE};
 use crate::subvol::Subvolume;
-use crate::Filesystem;
+use crate::{Filesystem, FsError};
 
 use std::fmt::Debug;
-use std::io::Result as IOResult;
+use std::io::{Error, Result as IOResult};
 use std::io::{Read, Seek, SeekFrom, Write};
 
 pub const BLOCK_SIZE: usize = 4096;
 
-const BLOCK_MAP_SIZE: usize = 1;
+/** Default count of bitmap blocks in a block group */
+pub const DEFAULT_BLOCK_MAP_SIZE: u64 = 1;
 const LABEL_MAX_LEN: usize = 256;
 
+/** Build the error returned for a malformed block */
+pub(crate) fn invalid_block(msg: &str) -> Error {
+    FsError::CorruptedMetadata(msg.to_string()).into()
+}
+
 /** Copy out a mutiple referenced data block */
 pub fn block_copy_out<D>(
     fs: &mut Filesystem,
@@ -22,11 +28,21 @@ where
     D: Read + Write + Seek,
 {
     let block = load_block(device, count)?;
-    let new_block = subvol.new_block(fs, device)?;
+    let new_block = subvol.new_data_block(fs, device)?;
     save_block(device, new_block, block)?;
+    fs.record_copy_out();
     Ok(new_block)
 }
 
+/** Store data of consecutive blocks from `block_count`, its length is a multiple of `BLOCK_SIZE` */
+pub(crate) fn save_blocks<D>(device: &mut D, block_count: u64, data: &[u8]) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+{
+    device.seek(SeekFrom::Start(block_count * BLOCK_SIZE as u64))?;
+    device.write_all(data)
+}
+
 pub(crate) fn load_block<D>(device: &mut D, block_count: u64) -> IOResult<[u8; BLOCK_SIZE]>
 where
     D: Read + Write + Seek,
@@ -56,6 +72,10 @@ where
 pub trait Block: Default + Debug {
     /** Load from bytes */
     fn load(bytes: [u8; BLOCK_SIZE]) -> Self;
+    /** Load from bytes, return an error if the block is malformed */
+    fn try_load(bytes: [u8; BLOCK_SIZE]) -> IOResult<Self> {
+        Ok(Self::load(bytes))
+    }
     /** Dump to bytes */
     fn dump(&self) -> [u8; BLOCK_SIZE];
     /** Load from device */
@@ -63,7 +83,7 @@ pub trait Block: Default + Debug {
     where
         D: Read + Write + Seek,
     {
-        Ok(Self::load(load_block(device, block_count)?))
+        Self::try_load(load_block(device, block_count)?)
     }
     /** Synchronize to device */
     fn sync<D>(&mut self, device: &mut D, block_count: u64) -> IOResult<()>
@@ -80,6 +100,7 @@ pub trait Block: Default + Debug {
         D: Write + Read + Seek,
     {
         let block_count = fs.new_block()?;
+        crate::subvol::SubvolumeManager::verify_allocation(fs, device, block_count)?;
         Self::default().sync(device, block_count)?;
         Ok(block_count)
     }
@@ -115,6 +136,14 @@ pub trait Block: Default + Debug {
  * |309  |317|Subvolume block|
  * |317  |325|Default subvolume|
  * |325  |333|Filesystem created time|
+ * |333  |341|Count of subvolumes|
+ * |341  |349|Compatible features|
+ * |349  |357|Incompatible features|
+ * |357  |365|Read-only compatible features|
+ * |365  |373|Bitmap blocks per group|
+ * |373  |381|Blocks reserved for metadata|
+ * |381  |389|Hash of names in directory indexes|
+ * |389  |397|State, 0 if meta data was synchronized after the last change|
 */
 pub struct SuperBlock {
     pub groups: u64,
@@ -126,6 +155,19 @@ pub struct SuperBlock {
     pub default_subvol: u64,
     pub subvol_mgr: u64,
     pub creation_time: u64,
+    /** Count of allocated subvolumes, excluding removed ones kept for their snapshots */
+    pub subvols: u64,
+    pub feature_compat: u64,
+    pub feature_incompat: u64,
+    pub feature_ro_compat: u64,
+    /** Count of bitmap blocks in each block group */
+    pub block_map_size: u64,
+    /** Count of free blocks which only metadata can be allocated from */
+    pub metadata_reserve: u64,
+    /** Id of the `DirHash` of directory indexes */
+    pub dir_hash: u64,
+    /** `FS_STATE_CLEAN` or `FS_STATE_DIRTY` */
+    pub state: u64,
 }
 
 impl Default for SuperBlock {
@@ -140,6 +182,14 @@ impl Default for SuperBlock {
             subvol_mgr: 0,
             default_subvol: 0,
             creation_time: 0,
+            subvols: 0,
+            feature_compat: 0,
+            feature_incompat: 0,
+            feature_ro_compat: 0,
+            block_map_size: DEFAULT_BLOCK_MAP_SIZE,
+            metadata_reserve: 0,
+            dir_hash: 0,
+            state: 0,
         }
     }
 }
@@ -156,6 +206,17 @@ impl Block for SuperBlock {
             subvol_mgr: u64::from_be_bytes(bytes[309..317].try_into().unwrap()),
             default_subvol: u64::from_be_bytes(bytes[317..325].try_into().unwrap()),
             creation_time: u64::from_be_bytes(bytes[325..333].try_into().unwrap()),
+            subvols: u64::from_be_bytes(bytes[333..341].try_into().unwrap()),
+            feature_compat: u64::from_be_bytes(bytes[341..349].try_into().unwrap()),
+            feature_incompat: u64::from_be_bytes(bytes[349..357].try_into().unwrap()),
+            feature_ro_compat: u64::from_be_bytes(bytes[357..365].try_into().unwrap()),
+            block_map_size: match u64::from_be_bytes(bytes[365..373].try_into().unwrap()) {
+                0 => DEFAULT_BLOCK_MAP_SIZE,
+                size => size,
+            },
+            metadata_reserve: u64::from_be_bytes(bytes[373..381].try_into().unwrap()),
+            dir_hash: u64::from_be_bytes(bytes[381..389].try_into().unwrap()),
+            state: u64::from_be_bytes(bytes[389..397].try_into().unwrap()),
         }
     }
     fn dump(&self) -> [u8; BLOCK_SIZE] {
@@ -172,6 +233,14 @@ impl Block for SuperBlock {
         bytes[309..317].copy_from_slice(&self.subvol_mgr.to_be_bytes());
         bytes[317..325].copy_from_slice(&self.default_subvol.to_be_bytes());
         bytes[325..333].copy_from_slice(&self.creation_time.to_be_bytes());
+        bytes[333..341].copy_from_slice(&self.subvols.to_be_bytes());
+        bytes[341..349].copy_from_slice(&self.feature_compat.to_be_bytes());
+        bytes[349..357].copy_from_slice(&self.feature_incompat.to_be_bytes());
+        bytes[357..365].copy_from_slice(&self.feature_ro_compat.to_be_bytes());
+        bytes[365..373].copy_from_slice(&self.block_map_size.to_be_bytes());
+        bytes[373..381].copy_from_slice(&self.metadata_reserve.to_be_bytes());
+        bytes[381..389].copy_from_slice(&self.dir_hash.to_be_bytes());
+        bytes[389..397].copy_from_slice(&self.state.to_be_bytes());
 
         bytes
     }
@@ -196,6 +265,14 @@ impl SuperBlock {
 
         String::from_utf8_lossy(&self.label[..null_idx]).to_string()
     }
+    /** Check if a compatible feature is enabled */
+    pub fn has_feature_compat(&self, feature: u64) -> bool {
+        self.feature_compat & feature != 0
+    }
+    /** Check if an incompatible feature is enabled */
+    pub fn has_feature_incompat(&self, feature: u64) -> bool {
+        self.feature_incompat & feature != 0
+    }
     pub(crate) fn is_valid(bytes: &[u8]) -> bool {
         /* check magic header */
         for (i, byte) in crate::FS_MAGIC_HEADER.iter().enumerate() {
@@ -209,11 +286,26 @@ impl SuperBlock {
     }
 }
 
+/** Usage counters of a filesystem at one moment, see `Filesystem::superblock_snapshot` */
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SuperBlockView {
+    pub groups: u64,
+    pub total_blocks: u64,
+    pub used_blocks: u64,
+    pub real_used_blocks: u64,
+    /** Free blocks, including blocks reserved for metadata */
+    pub free_blocks: u64,
+    pub metadata_reserve: u64,
+    pub subvols: u64,
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct BlockGroupMeta {
     pub id: u64,
     pub free_blocks: u64,
     pub next_group: u64,
+    /** Count of data block writes, only updated with `FEATURE_COMPAT_HEAT_MAP` */
+    pub writes: u64,
 }
 
 impl Block for BlockGroupMeta {
@@ -222,6 +314,7 @@ impl Block for BlockGroupMeta {
             id: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
             free_blocks: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
             next_group: u64::from_be_bytes(bytes[16..24].try_into().unwrap()),
+            writes: u64::from_be_bytes(bytes[24..32].try_into().unwrap()),
         }
     }
     fn dump(&self) -> [u8; BLOCK_SIZE] {
@@ -229,33 +322,65 @@ impl Block for BlockGroupMeta {
         block[..8].copy_from_slice(&self.id.to_be_bytes());
         block[8..16].copy_from_slice(&self.free_blocks.to_be_bytes());
         block[16..24].copy_from_slice(&self.next_group.to_be_bytes());
+        block[24..32].copy_from_slice(&self.writes.to_be_bytes());
 
         block
     }
 }
 
-#[derive(Default, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct BlockGroup {
     pub meta_data: BlockGroupMeta,
     pub start_block: u64,
-    pub block_map: BitmapBlock,
+    /** Count of bitmap blocks, each of which tracks `8 * BLOCK_SIZE` data blocks */
+    pub block_map_size: u64,
+    pub block_map: Vec<BitmapBlock>,
+}
+
+impl Default for BlockGroup {
+    fn default() -> Self {
+        Self {
+            meta_data: BlockGroupMeta::default(),
+            start_block: 0,
+            block_map_size: DEFAULT_BLOCK_MAP_SIZE,
+            block_map: vec![BitmapBlock::default(); DEFAULT_BLOCK_MAP_SIZE as usize],
+        }
+    }
 }
 
 impl BlockGroup {
-    pub fn create(group_start: u64, totol_blocks: u64) -> Self {
+    /** Create a group starting at `group_start`, with `totol_blocks` blocks left on the device
+     *
+     * The group takes all blocks left if they fit in it. Otherwise it is full, unless too few
+     * blocks would be left for the next group, in which case the group leaves the minimal size
+     * of a group to it.
+     */
+    pub fn create(group_start: u64, totol_blocks: u64, block_map_size: u64) -> Self {
+        const META_BLOCK: u64 = 1;
         let mut group = BlockGroup {
             start_block: group_start,
+            block_map_size,
+            block_map: vec![BitmapBlock::default(); block_map_size as usize],
             ..Default::default()
         };
 
-        if totol_blocks <= group.blocks() {
-            group.meta_data.next_group = 0;
-            const META_BLOCK: u64 = 1;
-            group.meta_data.free_blocks = totol_blocks - META_BLOCK - BLOCK_MAP_SIZE as u64;
+        /* meta block, bitmap blocks and at least one data block */
+        let minimal_size = META_BLOCK + block_map_size + 1;
+        let size = if totol_blocks <= group.blocks() {
+            totol_blocks
+        } else if totol_blocks - group.blocks() < minimal_size {
+            totol_blocks - minimal_size
         } else {
-            group.meta_data.next_group = group_start + group.blocks();
-            group.meta_data.free_blocks = 8 * BLOCK_SIZE as u64;
-        }
+            group.blocks()
+        };
+
+        group.meta_data.next_group = if size < totol_blocks {
+            group_start + size
+        } else {
+            0
+        };
+        /* blocks past the capacity are never allocated, as free blocks are taken from the lowest */
+        group.meta_data.free_blocks = size - META_BLOCK - block_map_size;
 
         group
     }
@@ -264,53 +389,189 @@ impl BlockGroup {
         D: Read + Write + Seek,
     {
         self.meta_data = BlockGroupMeta::load_block(device, self.start_block)?;
-        self.block_map = BitmapBlock::load_block(device, self.start_block + 1)?;
+        self.block_map = (0..self.block_map_size)
+            .map(|i| BitmapBlock::load_block(device, self.start_block + 1 + i))
+            .collect::<IOResult<_>>()?;
 
         Ok(())
     }
     /** Allocate a data block */
     pub fn new_block(&mut self) -> Option<u64> {
         if self.meta_data.free_blocks > 0 {
-            if let Some(off) = self.block_map.find_unused() {
-                self.block_map.set_used(off);
-                self.meta_data.free_blocks -= 1;
-                return Some(off);
+            for (i, bitmap) in self.block_map.iter_mut().enumerate() {
+                if let Some(off) = bitmap.find_unused() {
+                    bitmap.set_used(off);
+                    self.meta_data.free_blocks -= 1;
+                    return Some(i as u64 * 8 * BLOCK_SIZE as u64 + off);
+                }
             }
         }
         None
     }
+    /** Find the first run of `count` free data blocks, blocks are not marked as allocated */
+    pub fn find_free_run(&self, count: u64) -> Option<u64> {
+        if count == 0 || self.meta_data.free_blocks < count {
+            return None;
+        }
+
+        let end = self.data_capacity();
+        let mut from = 0;
+        while let Some((start, len)) = self.next_free_run(from, end, count) {
+            if len == count {
+                return Some(start);
+            }
+            from = start + len;
+        }
+        None
+    }
+    /** Allocate the first run of `count` free data blocks, or the longest run if none is as long
+     *
+     * Return the relative block starting the run and its length.
+     */
+    pub fn allocate_run(&mut self, count: u64) -> Option<(u64, u64)> {
+        if count == 0 || self.meta_data.free_blocks == 0 {
+            return None;
+        }
+
+        let end = self.data_capacity();
+        let mut longest: Option<(u64, u64)> = None;
+        let mut from = 0;
+        while let Some((start, len)) = self.next_free_run(from, end, count) {
+            if longest.is_none_or(|(_, longest_len)| len > longest_len) {
+                longest = Some((start, len));
+            }
+            if len == count {
+                break;
+            }
+            from = start + len;
+        }
+
+        let (start, len) = longest?;
+        for block in start..start + len {
+            self.set_used(block);
+        }
+        self.meta_data.free_blocks -= len;
+        Some((start, len))
+    }
+    /** Get every run of free data blocks, as relative block starting the run and its length */
+    pub fn free_runs(&self) -> Vec<(u64, u64)> {
+        let end = self.data_capacity();
+        let mut runs = Vec::new();
+        let mut from = 0;
+        while let Some((start, len)) = self.next_free_run(from, end, u64::MAX) {
+            runs.push((start, len));
+            from = start + len;
+        }
+        runs
+    }
+    /** Find the next run of free data blocks from `from` to `end`, at most `max` blocks long */
+    fn next_free_run(&self, mut from: u64, end: u64, max: u64) -> Option<(u64, u64)> {
+        let bits = 8 * BLOCK_SIZE as u64;
+        /* skip used blocks, a byte at a time where possible */
+        while from < end && self.get_used(from) {
+            from += 1;
+            while from.is_multiple_of(8)
+                && from + 8 <= end
+                && self.block_map[(from / bits) as usize].bytes[(from % bits / 8) as usize] == 0xff
+            {
+                from += 8;
+            }
+        }
+        if from >= end {
+            return None;
+        }
+
+        let mut len = 1;
+        while len < max && from + len < end && !self.get_used(from + len) {
+            len += 1;
+        }
+        Some((from, len))
+    }
+    /** Count of data blocks of the group within the device
+     *
+     * Blocks past the device are never allocated, so they are the free blocks beyond the count
+     * of used and free blocks.
+     */
+    pub(crate) fn data_capacity(&self) -> u64 {
+        let used: u64 = self
+            .block_map
+            .iter()
+            .flat_map(|bitmap| bitmap.bytes.iter())
+            .map(|byte| byte.count_ones() as u64)
+            .sum();
+        used + self.meta_data.free_blocks
+    }
     /** Clone a data block */
     pub fn clone_block(&mut self, count: u64) {
-        self.block_map.get_used(count);
+        self.get_used(count);
     }
     /** Release a data block */
     pub fn release_block(&mut self, count: u64) {
-        self.block_map.set_unused(count);
+        self.set_unused(count);
         self.meta_data.free_blocks += 1;
     }
+    /** Check if a data block is allocated */
+    pub fn get_used(&self, count: u64) -> bool {
+        let bits = 8 * BLOCK_SIZE as u64;
+        self.block_map[(count / bits) as usize].get_used(count % bits)
+    }
+    /** Mark a data block as allocated */
+    pub fn set_used(&mut self, count: u64) {
+        let bits = 8 * BLOCK_SIZE as u64;
+        self.block_map[(count / bits) as usize].set_used(count % bits);
+    }
+    /** Mark a data block as free */
+    pub fn set_unused(&mut self, count: u64) {
+        let bits = 8 * BLOCK_SIZE as u64;
+        self.block_map[(count / bits) as usize].set_unused(count % bits);
+    }
     pub fn sync<D>(&mut self, device: &mut D) -> IOResult<()>
     where
         D: Read + Write + Seek,
     {
         self.meta_data.sync(device, self.start_block)?;
-        self.block_map.sync(device, self.start_block + 1)?;
+        for (i, bitmap) in self.block_map.iter_mut().enumerate() {
+            bitmap.sync(device, self.start_block + 1 + i as u64)?;
+        }
 
         Ok(())
     }
+    /** Iterate absolute numbers of meta data and allocated data blocks */
+    pub(crate) fn used_blocks(&self) -> impl Iterator<Item = u64> + '_ {
+        const META_BLOCK: u64 = 1;
+        let meta = self.start_block..self.start_block + META_BLOCK + self.block_map_size;
+        let data = (0..8 * BLOCK_SIZE as u64 * self.block_map_size)
+            .filter(|count| self.get_used(*count))
+            .map(|count| self.to_absolute_block(count));
+        meta.chain(data)
+    }
+    /** Get blocks of the group, including its meta data and bitmap blocks */
+    pub(crate) fn block_range(&self) -> std::ops::Range<u64> {
+        self.start_block..self.end_block()
+    }
+    /** Get the block following the group, which the last group may extend beyond the device */
+    pub(crate) fn end_block(&self) -> u64 {
+        if self.meta_data.next_group != 0 {
+            self.meta_data.next_group
+        } else {
+            self.start_block + self.blocks()
+        }
+    }
     #[inline]
     pub(crate) fn blocks(&self) -> u64 {
         const META_BLOCK: u64 = 1;
-        META_BLOCK + BLOCK_MAP_SIZE as u64 + 8 * BLOCK_SIZE as u64
+        META_BLOCK + self.block_map_size + 8 * BLOCK_SIZE as u64 * self.block_map_size
     }
     #[inline]
     pub(crate) fn to_relative_block(&self, absolute_block: u64) -> u64 {
         const META_BLOCK: u64 = 1;
-        absolute_block - self.start_block - META_BLOCK - BLOCK_MAP_SIZE as u64
+        absolute_block - self.start_block - META_BLOCK - self.block_map_size
     }
     #[inline]
     pub(crate) fn to_absolute_block(&self, relative_block: u64) -> u64 {
         const META_BLOCK: u64 = 1;
-        self.start_block + META_BLOCK + BLOCK_MAP_SIZE as u64 + relative_block
+        debug_assert!(relative_block < 8 * BLOCK_SIZE as u64 * self.block_map_size);
+        self.start_block + META_BLOCK + self.block_map_size + relative_block
     }
 }
 
@@ -503,3 +764,81 @@ impl Block for LinkedContentTable {
         block
     }
 }
+
+impl LinkedContentTable {
+    /** Read content of a chain of tables */
+    pub(crate) fn read_chain<D>(device: &mut D, mut count: u64) -> IOResult<Vec<u8>>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut data = Vec::new();
+        while count != 0 {
+            let lct = Self::load_block(device, count)?;
+            data.extend_from_slice(&lct.content);
+            count = lct.next;
+        }
+
+        Ok(data)
+    }
+    /** Store data into a new chain of tables and return its first block, or 0 for empty data */
+    pub(crate) fn write_chain<D>(
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        data: &[u8],
+    ) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut next = 0;
+        for chunk in data.chunks(BLOCK_SIZE - 8).rev() {
+            let mut lct = Self {
+                next,
+                ..Default::default()
+            };
+            lct.content[..chunk.len()].copy_from_slice(chunk);
+
+            next = subvol.new_block(fs, device)?;
+            lct.sync(device, next)?;
+        }
+
+        Ok(next)
+    }
+    /** Copy a chain of tables and return the first block of the copy */
+    pub(crate) fn copy_chain<D>(
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        count: u64,
+    ) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        let data = Self::read_chain(device, count)?;
+        Self::write_chain(fs, subvol, device, &data)
+    }
+    /** Release all blocks of a chain of tables
+     *
+     * Chains are shared rather than copied when an inode group is cloned, so blocks which the
+     * subvolume doesn't own are kept for the subvolume sharing them.
+     */
+    pub(crate) fn release_chain<D>(
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        mut count: u64,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        while count != 0 {
+            let next = Self::load_block(device, count)?.next;
+            if subvol.owns_block(device, count)? {
+                subvol.release_block(fs, device, count)?;
+            }
+            count = next;
+        }
+
+        Ok(())
+    }
+}
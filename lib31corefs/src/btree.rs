@@ -1,4 +1,4 @@
-use crate::block::{Block, BLOCK_SIZE};
+use crate::block::{invalid_block, Block, BLOCK_SIZE};
 use crate::subvol::Subvolume;
 use crate::Filesystem;
 
@@ -11,6 +11,9 @@ const ENTRY_LEAF_SIZE: usize = 3 * 8;
 const ENTRY_INTERNAL_SIZE: usize = 2 * 8;
 const ENTRY_START: usize = 16;
 
+/** Reference counts above it can not be reached by any filesystem, so they are corrupted */
+const PLAUSIBLE_MAX_RC: u64 = u32::MAX as u64;
+
 const BTREE_NODE_TYPE_INTERNAL: u8 = 0xf0;
 const BTREE_NODE_TYPE_LEAF: u8 = 0x0f;
 
@@ -115,6 +118,19 @@ impl Block for BtreeNode {
             Self::load_leaf(bytes)
         }
     }
+    fn try_load(bytes: [u8; BLOCK_SIZE]) -> IOResult<Self> {
+        let entries = u16::from_be_bytes(bytes[..2].try_into().unwrap()) as usize;
+        match bytes[3] {
+            BTREE_NODE_TYPE_INTERNAL if entries > MAX_INTERNAL_COUNT => Err(invalid_block(
+                "Count of B-Tree internal entries exceeds node capacity.",
+            )),
+            BTREE_NODE_TYPE_LEAF if entries > MAX_LEAF_COUNT => Err(invalid_block(
+                "Count of B-Tree leaf entries exceeds node capacity.",
+            )),
+            BTREE_NODE_TYPE_INTERNAL | BTREE_NODE_TYPE_LEAF => Ok(Self::load(bytes)),
+            _ => Err(invalid_block("Unknown B-Tree node type.")),
+        }
+    }
     fn dump(&self) -> [u8; BLOCK_SIZE] {
         let mut block = [0; BLOCK_SIZE];
 
@@ -148,7 +164,11 @@ impl BtreeNode {
         };
 
         let content = &bytes[ENTRY_START..];
-        let entries = u16::from_be_bytes(bytes[..2].try_into().unwrap()) as usize;
+        /* a corrupted count must not read beyond the block */
+        let entries = std::cmp::min(
+            u16::from_be_bytes(bytes[..2].try_into().unwrap()) as usize,
+            MAX_INTERNAL_COUNT,
+        );
 
         for i in 0..entries {
             let entry = BtreeEntry::load_internal(
@@ -166,7 +186,11 @@ impl BtreeNode {
         };
 
         let content = &bytes[ENTRY_START..];
-        let entries = u16::from_be_bytes(bytes[..2].try_into().unwrap()) as usize;
+        /* a corrupted count must not read beyond the block */
+        let entries = std::cmp::min(
+            u16::from_be_bytes(bytes[..2].try_into().unwrap()) as usize,
+            MAX_LEAF_COUNT,
+        );
 
         for i in 0..entries {
             let entry =
@@ -264,6 +288,48 @@ impl BtreeNode {
 
         Ok(())
     }
+    /** Find the child node which may contain the key
+     *
+     * Return the last entry whose key is not greater than the given key, or the first entry
+     * if the key is smaller than all of them.
+     */
+    fn child_index(&self, key: u64) -> usize {
+        let mut index = 0;
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.key <= key {
+                index = i;
+            } else {
+                break;
+            }
+        }
+        index
+    }
+    /** Load the child node pointed by the entry, cloning it if multiple referenced
+     *
+     * The entry is updated if the child node has been copied out.
+     */
+    fn load_child_cow<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        index: usize,
+    ) -> IOResult<(Self, bool)>
+    where
+        D: Write + Read + Seek,
+    {
+        let mut child_node = Self::load_block(device, self.entries[index].value)?;
+        child_node.block_count = self.entries[index].value;
+
+        child_node.cow_clone_node(fs, subvol, device)?;
+
+        if child_node.block_count != self.entries[index].value {
+            self.entries[index].value = child_node.block_count;
+            Ok((child_node, true))
+        } else {
+            Ok((child_node, false))
+        }
+    }
     /** Insert an id
      *
      * Return:
@@ -294,35 +360,183 @@ impl BtreeNode {
             }
             BtreeType::Internal => {
                 /* find child node to insert */
-                for i in 0..self.entries.len() {
-                    if i + 1 < self.entries.len()
-                        && offset > self.entries[i].key
-                        && offset < self.entries[i + 1].key
-                        || i == self.entries.len() - 1
-                    {
-                        let mut child_node = Self::load_block(device, self.entries[i].value)?;
-                        child_node.block_count = self.entries[i].value;
-
-                        child_node.cow_clone_node(fs, subvol, device)?;
-
-                        /* if parted into tow sub trees */
-                        if let Some((id, block)) =
-                            child_node.insert_internal(fs, subvol, device, offset, block)?
-                        {
-                            self.add(id, block);
+                let i = self.child_index(offset);
+                let (mut child_node, mut modified) = self.load_child_cow(fs, subvol, device, i)?;
 
-                            if self.entries.len() > MAX_INTERNAL_COUNT {
-                                return Ok(Some(self.part(fs, subvol, device)?));
-                            } else {
-                                self.sync(device, self.block_count)?;
-                            }
-                        }
+                /* keep the key of the first entry as the minimum key of the child node */
+                if offset < self.entries[i].key {
+                    self.entries[i].key = offset;
+                    modified = true;
+                }
+
+                /* if parted into tow sub trees */
+                if let Some((id, block)) =
+                    child_node.insert_internal(fs, subvol, device, offset, block)?
+                {
+                    self.add(id, block);
+
+                    if self.entries.len() > MAX_INTERNAL_COUNT {
+                        return Ok(Some(self.part(fs, subvol, device)?));
                     }
+                    modified = true;
+                }
+
+                if modified {
+                    self.sync(device, self.block_count)?;
                 }
             }
         }
         Ok(None)
     }
+    /** Insert `count` consecutive keys from `key`, mapped to consecutive values from `value`
+     *
+     * None of the keys may exist. Each node is descended once for all keys under it, rather
+     * than once per key, and nodes overflowing are parted into as many nodes as needed.
+     */
+    pub fn insert_run<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        key: u64,
+        value: u64,
+        count: u64,
+    ) -> IOResult<()>
+    where
+        D: Write + Read + Seek,
+    {
+        let entries = (0..count)
+            .map(|i| BtreeEntry::new(key + i, value + i))
+            .collect::<Vec<_>>();
+
+        self.cow_clone_node(fs, subvol, device)?;
+        let mut siblings = self.insert_run_internal(fs, subvol, device, &entries)?;
+
+        /* grow the tree until the root holds all nodes parted */
+        while !siblings.is_empty() {
+            let mut left = Self {
+                r#type: self.r#type,
+                block_count: subvol.new_block(fs, device)?,
+                entries: std::mem::take(&mut self.entries),
+                ..Default::default()
+            };
+            left.sync(device, left.block_count)?;
+
+            self.entries
+                .push(BtreeEntry::new(left.entries[0].key, left.block_count));
+            self.entries.extend(
+                siblings
+                    .into_iter()
+                    .map(|(id, block)| BtreeEntry::new(id, block)),
+            );
+            self.r#type = BtreeType::Internal;
+            siblings = self.part_overflow(fs, subvol, device)?;
+        }
+
+        Ok(())
+    }
+    /** Insert sorted entries under the node
+     *
+     * Return node IDs and block counts of the nodes parted from the node.
+     */
+    fn insert_run_internal<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        entries: &[BtreeEntry],
+    ) -> IOResult<Vec<(u64, u64)>>
+    where
+        D: Write + Read + Seek,
+    {
+        match self.r#type {
+            BtreeType::Leaf => {
+                self.entries.extend_from_slice(entries);
+                self.entries.sort_unstable_by_key(|entry| entry.key);
+            }
+            BtreeType::Internal => {
+                let mut parted = Vec::new();
+                let mut modified = false;
+
+                /* from the last child node, so that nodes parted don't move the others */
+                let mut end = entries.len();
+                while end > 0 {
+                    let i = self.child_index(entries[end - 1].key);
+                    let start =
+                        entries[..end].partition_point(|entry| self.child_index(entry.key) < i);
+                    let (mut child_node, cloned) = self.load_child_cow(fs, subvol, device, i)?;
+                    modified |= cloned;
+
+                    /* keep the key of the first entry as the minimum key of the child node */
+                    if entries[start].key < self.entries[i].key {
+                        self.entries[i].key = entries[start].key;
+                        modified = true;
+                    }
+
+                    parted.extend(child_node.insert_run_internal(
+                        fs,
+                        subvol,
+                        device,
+                        &entries[start..end],
+                    )?);
+                    end = start;
+                }
+
+                if parted.is_empty() {
+                    if modified {
+                        self.sync(device, self.block_count)?;
+                    }
+                    return Ok(Vec::new());
+                }
+                self.entries.extend(
+                    parted
+                        .into_iter()
+                        .map(|(id, block)| BtreeEntry::new(id, block)),
+                );
+                self.entries.sort_unstable_by_key(|entry| entry.key);
+            }
+        }
+
+        self.part_overflow(fs, subvol, device)
+    }
+    /** Part the node into nodes evenly filled if it has too many entries, and write it
+     *
+     * Return node IDs and block counts of the nodes parted from the node.
+     */
+    fn part_overflow<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<Vec<(u64, u64)>>
+    where
+        D: Write + Read + Seek,
+    {
+        let max = match self.r#type {
+            BtreeType::Internal => MAX_INTERNAL_COUNT,
+            BtreeType::Leaf => MAX_LEAF_COUNT,
+        };
+        let nodes = self.entries.len().div_ceil(max).max(1);
+        let per_node = self.entries.len().div_ceil(nodes);
+
+        let mut parted = Vec::new();
+        if nodes > 1 {
+            let rest = self.entries.split_off(per_node);
+            for chunk in rest.chunks(per_node) {
+                let mut node = Self {
+                    r#type: self.r#type,
+                    block_count: subvol.new_block(fs, device)?,
+                    entries: chunk.to_vec(),
+                    ..Default::default()
+                };
+                node.sync(device, node.block_count)?;
+                parted.push((node.entries[0].key, node.block_count));
+            }
+        }
+        self.sync(device, self.block_count)?;
+
+        Ok(parted)
+    }
     /** Modify an offset from B-Tree */
     pub fn modify<D>(
         &mut self,
@@ -332,11 +546,26 @@ impl BtreeNode {
         key: u64,
         value: u64,
     ) -> IOResult<()>
+    where
+        D: Write + Read + Seek,
+    {
+        self.modify_with_rc(fs, subvol, device, key, value, 0)
+    }
+    /** Modify an offset from B-Tree and set the reference count of its value */
+    pub fn modify_with_rc<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        key: u64,
+        value: u64,
+        rc: u64,
+    ) -> IOResult<()>
     where
         D: Write + Read + Seek,
     {
         self.cow_clone_node(fs, subvol, device)?;
-        self.modify_internal(fs, subvol, device, key, value)?;
+        self.modify_internal(fs, subvol, device, key, value, rc)?;
         Ok(())
     }
     fn modify_internal<D>(
@@ -346,6 +575,7 @@ impl BtreeNode {
         device: &mut D,
         key: u64,
         value: u64,
+        rc: u64,
     ) -> IOResult<()>
     where
         D: Write + Read + Seek,
@@ -356,26 +586,20 @@ impl BtreeNode {
                 for entry in &mut self.entries {
                     if entry.key == key {
                         entry.value = value;
-                        entry.rc = 0;
+                        entry.rc = rc;
                         self.sync(device, self.block_count)?;
                         break;
                     }
                 }
             }
             BtreeType::Internal => {
-                for i in 0..self.entries.len() {
-                    if i + 1 < self.entries.len()
-                        && key >= self.entries[i].key
-                        && key < self.entries[i + 1].key
-                        || i == self.entries.len() - 1
-                    {
-                        let mut child_node = Self::load_block(device, self.entries[i].value)?;
-                        child_node.block_count = self.entries[i].value;
-
-                        child_node.cow_clone_node(fs, subvol, device)?;
-
-                        child_node.modify_internal(fs, subvol, device, key, value)?;
-                    }
+                let i = self.child_index(key);
+                let (mut child_node, modified) = self.load_child_cow(fs, subvol, device, i)?;
+
+                child_node.modify_internal(fs, subvol, device, key, value, rc)?;
+
+                if modified {
+                    self.sync(device, self.block_count)?;
                 }
             }
         }
@@ -402,6 +626,7 @@ impl BtreeNode {
             for entry in &child.entries {
                 self.entries.push(*entry);
             }
+            self.r#type = child.r#type;
 
             child.cow_release_node(fs, subvol, device)?;
 
@@ -422,91 +647,75 @@ impl BtreeNode {
     {
         match self.r#type {
             BtreeType::Internal => {
-                for i in 0..self.entries.len() {
-                    if i + 1 < self.entries.len()
-                        && key >= self.entries[i].key
-                        && key < self.entries[i + 1].key
-                        || i == self.entries.len() - 1
-                    {
-                        let mut child_node = Self::load_block(device, self.entries[i].value)?;
-                        child_node.block_count = self.entries[i].value;
+                let i = self.child_index(key);
+                let (mut child_node, _) = self.load_child_cow(fs, subvol, device, i)?;
 
-                        child_node.cow_clone_node(fs, subvol, device)?;
+                child_node.remove_internal(fs, subvol, device, key)?;
 
-                        child_node.remove_internal(fs, subvol, device, key)?;
+                /* child nodes can be merged into previous or next node */
+                if child_node.r#type == BtreeType::Internal
+                    && child_node.entries.len() < MAX_INTERNAL_COUNT / 2
+                    || child_node.r#type == BtreeType::Leaf
+                        && child_node.entries.len() < MAX_LEAF_COUNT / 2
+                {
+                    if i > 0 {
+                        let (mut previous_node, _) =
+                            self.load_child_cow(fs, subvol, device, i - 1)?;
 
-                        /* child nodes can be merged into previous or next node */
+                        /* merge this child node into previous node */
                         if child_node.r#type == BtreeType::Internal
-                            && child_node.entries.len() < MAX_INTERNAL_COUNT / 2
+                            && previous_node.entries.len() + child_node.entries.len()
+                                <= MAX_INTERNAL_COUNT
                             || child_node.r#type == BtreeType::Leaf
-                                && child_node.entries.len() < MAX_LEAF_COUNT / 2
+                                && previous_node.entries.len() + child_node.entries.len()
+                                    <= MAX_LEAF_COUNT
                         {
-                            if i > 0 {
-                                let mut previous_node =
-                                    Self::load_block(device, self.entries[i - 1].value)?;
-                                previous_node.block_count = self.entries[i - 1].value;
-
-                                previous_node.cow_clone_node(fs, subvol, device)?;
-
-                                /* merge this child node into previous node */
-                                if child_node.r#type == BtreeType::Internal
-                                    && previous_node.entries.len() + child_node.entries.len()
-                                        <= MAX_INTERNAL_COUNT
-                                    || child_node.r#type == BtreeType::Leaf
-                                        && previous_node.entries.len() + child_node.entries.len()
-                                            <= MAX_LEAF_COUNT
-                                {
-                                    for child_entry in child_node.entries.iter() {
-                                        previous_node.entries.push(*child_entry);
-                                    }
-
-                                    child_node.cow_release_node(fs, subvol, device)?;
-                                    self.entries.remove(i);
-                                } else {
-                                    let id = previous_node.entries.last().unwrap().key;
-                                    child_node
-                                        .entries
-                                        .insert(0, previous_node.entries.pop().unwrap());
-                                    child_node.sync(device, child_node.block_count)?;
-                                    self.entries[i].key = id;
-                                }
-                                previous_node.sync(device, previous_node.block_count)?;
-                            } else if i < self.entries.len() - 1 {
-                                let mut next_node =
-                                    Self::load_block(device, self.entries[i + 1].value)?;
-                                next_node.block_count = self.entries[i + 1].value;
-
-                                next_node.cow_clone_node(fs, subvol, device)?;
-                                /* merge this child node into next node */
-                                if child_node.r#type == BtreeType::Internal
-                                    && next_node.entries.len() + child_node.entries.len()
-                                        <= MAX_INTERNAL_COUNT
-                                    || child_node.r#type == BtreeType::Leaf
-                                        && next_node.entries.len() + child_node.entries.len()
-                                            <= MAX_LEAF_COUNT
-                                {
-                                    for child_entry in child_node.entries.iter().rev() {
-                                        next_node.entries.insert(0, *child_entry);
-                                    }
-                                    self.entries[i + 1].key =
-                                        next_node.entries.first().unwrap().key;
-
-                                    child_node.cow_release_node(fs, subvol, device)?;
-
-                                    self.entries.remove(i);
-                                } else {
-                                    next_node.entries.remove(0);
-                                    child_node.entries.push(*next_node.entries.first().unwrap());
-                                    child_node.sync(device, child_node.block_count)?;
-                                    self.entries[i + 1].key =
-                                        next_node.entries.first().unwrap().key;
-                                }
-                                next_node.sync(device, next_node.block_count)?;
+                            for child_entry in child_node.entries.iter() {
+                                previous_node.entries.push(*child_entry);
                             }
+
+                            child_node.cow_release_node(fs, subvol, device)?;
+                            self.entries.remove(i);
+                        } else {
+                            let id = previous_node.entries.last().unwrap().key;
+                            child_node
+                                .entries
+                                .insert(0, previous_node.entries.pop().unwrap());
+                            child_node.sync(device, child_node.block_count)?;
+                            self.entries[i].key = id;
                         }
-                        self.sync(device, self.block_count)?;
+                        previous_node.sync(device, previous_node.block_count)?;
+                    } else if i < self.entries.len() - 1 {
+                        let (mut next_node, _) = self.load_child_cow(fs, subvol, device, i + 1)?;
+
+                        /* merge this child node into next node */
+                        if child_node.r#type == BtreeType::Internal
+                            && next_node.entries.len() + child_node.entries.len()
+                                <= MAX_INTERNAL_COUNT
+                            || child_node.r#type == BtreeType::Leaf
+                                && next_node.entries.len() + child_node.entries.len()
+                                    <= MAX_LEAF_COUNT
+                        {
+                            for child_entry in child_node.entries.iter().rev() {
+                                next_node.entries.insert(0, *child_entry);
+                            }
+                            if let Some(first) = next_node.entries.first() {
+                                self.entries[i + 1].key = first.key;
+                            }
+
+                            child_node.cow_release_node(fs, subvol, device)?;
+
+                            self.entries.remove(i);
+                        } else {
+                            let entry = next_node.entries.remove(0);
+                            child_node.entries.push(entry);
+                            child_node.sync(device, child_node.block_count)?;
+                            self.entries[i + 1].key = next_node.entries.first().unwrap().key;
+                        }
+                        next_node.sync(device, next_node.block_count)?;
                     }
                 }
+                self.sync(device, self.block_count)?;
             }
             BtreeType::Leaf => {
                 /* find and remove */
@@ -532,17 +741,12 @@ impl BtreeNode {
     {
         match self.r#type {
             BtreeType::Internal => {
-                for i in 0..self.entries.len() {
-                    if i + 1 < self.entries.len()
-                        && key >= self.entries[i].key
-                        && key < self.entries[i + 1].key
-                        || i == self.entries.len() - 1
-                    {
-                        let mut child = Self::load_block(device, self.entries[i].value)?;
-                        child.block_count = self.entries[i].value;
+                if !self.entries.is_empty() {
+                    let i = self.child_index(key);
+                    let mut child = Self::load_block(device, self.entries[i].value)?;
+                    child.block_count = self.entries[i].value;
 
-                        return child.lookup(device, key);
-                    }
+                    return child.lookup(device, key);
                 }
             }
             BtreeType::Leaf => {
@@ -558,74 +762,260 @@ impl BtreeNode {
             format!("No such key '{}'.", key),
         ))
     }
-    fn find_unused_internal<D>(&self, device: &mut D) -> IOResult<(Option<u64>, Option<u64>)>
+    /** Find the entry with the greatest key not greater than the given key */
+    pub fn floor<D>(&self, device: &mut D, key: u64) -> IOResult<Option<BtreeEntry>>
     where
         D: Write + Read + Seek,
     {
-        if self.r#type == BtreeType::Internal {
-            for i in 0..self.entries.len() {
-                let mut child = Self::load_block(device, self.entries[i].value)?;
-                child.block_count = self.entries[i].value;
-                let result = child.find_unused_internal(device)?;
-
-                if let Some(id) = result.0 {
-                    return Ok((Some(id), None));
-                } else if let Some(id) = result.1 {
-                    if i < self.entries.len() - 1 && id + 1 < self.entries[i + 1].key
-                        || i == self.entries.len() - 1
-                    {
-                        return Ok((Some(id + 1), None));
+        match self.r#type {
+            BtreeType::Internal => {
+                if !self.entries.is_empty() {
+                    /* separators are lower bounds, the child may only hold greater keys */
+                    for i in (0..=self.child_index(key)).rev() {
+                        let mut child = Self::load_block(device, self.entries[i].value)?;
+                        child.block_count = self.entries[i].value;
+
+                        if let Some(entry) = child.floor(device, key)? {
+                            return Ok(Some(entry));
+                        }
                     }
                 }
+                Ok(None)
             }
-        } else if self.entries.len() > 1 {
-            for i in 0..self.entries.len() - 1 {
-                if self.entries[i].key + 1 < self.entries[i + 1].key {
-                    return Ok((Some(self.entries[i].key + 1), None));
+            BtreeType::Leaf => Ok(self
+                .entries
+                .iter()
+                .take_while(|entry| entry.key <= key)
+                .last()
+                .copied()),
+        }
+    }
+    /** Get entries with keys in `start_key..end_key` in order of keys
+     *
+     * Only subtrees overlapping the range are visited, so reading a range of keys costs one
+     * descent plus the leaves holding it, rather than a descent for each key.
+     */
+    pub fn range<D>(
+        &self,
+        device: &mut D,
+        start_key: u64,
+        end_key: u64,
+    ) -> IOResult<Vec<BtreeEntry>>
+    where
+        D: Write + Read + Seek,
+    {
+        let mut entries = Vec::new();
+        self.range_internal(device, start_key, end_key, &mut entries)?;
+        Ok(entries)
+    }
+    fn range_internal<D>(
+        &self,
+        device: &mut D,
+        start_key: u64,
+        end_key: u64,
+        entries: &mut Vec<BtreeEntry>,
+    ) -> IOResult<()>
+    where
+        D: Write + Read + Seek,
+    {
+        match self.r#type {
+            BtreeType::Internal => {
+                for (i, entry) in self.entries.iter().enumerate() {
+                    /* the first child also holds keys less than its key, see `child_index` */
+                    let lower = if i == 0 { 0 } else { entry.key };
+                    let upper = self.entries.get(i + 1).map_or(u64::MAX, |next| next.key);
+                    if lower >= end_key {
+                        break;
+                    }
+                    if upper <= start_key {
+                        continue;
+                    }
+
+                    let mut child = Self::load_block(device, entry.value)?;
+                    child.block_count = entry.value;
+                    child.range_internal(device, start_key, end_key, entries)?;
                 }
             }
-            return Ok((None, Some(self.entries.last().unwrap().key + 1)));
-        } else if self.entries.len() == 1 {
-            return Ok((None, Some(self.entries.last().unwrap().key + 1)));
+            BtreeType::Leaf => entries.extend(
+                self.entries
+                    .iter()
+                    .filter(|entry| (start_key..end_key).contains(&entry.key)),
+            ),
         }
-        Ok((None, None))
+        Ok(())
     }
-    /** Find unused id */
-    pub fn find_unused<D>(&mut self, device: &mut D) -> IOResult<u64>
+    /** Scan keys in order, counting `next` up while it is used, return if a gap was found */
+    fn find_unused_internal<D>(&self, device: &mut D, next: &mut u64) -> IOResult<bool>
     where
         D: Write + Read + Seek,
     {
-        let result = self.find_unused_internal(device)?;
-
-        if let Some(id) = result.0 {
-            Ok(id)
-        } else if let Some(id) = result.1 {
-            Ok(id)
-        } else {
-            Ok(0)
+        match self.r#type {
+            BtreeType::Internal => {
+                for (i, entry) in self.entries.iter().enumerate() {
+                    /* keys of later children are not less than their separator, while the first
+                     * child may hold keys less than its separator */
+                    if i > 0 && entry.key > *next {
+                        return Ok(true);
+                    }
+                    let mut child = Self::load_block(device, entry.value)?;
+                    child.block_count = entry.value;
+                    if child.find_unused_internal(device, next)? {
+                        return Ok(true);
+                    }
+                }
+            }
+            BtreeType::Leaf => {
+                for entry in &self.entries {
+                    if entry.key > *next {
+                        return Ok(true);
+                    }
+                    *next = entry.key + 1;
+                }
+            }
         }
+        Ok(false)
+    }
+    /** Find the lowest unused id */
+    pub fn find_unused<D>(&mut self, device: &mut D) -> IOResult<u64>
+    where
+        D: Write + Read + Seek,
+    {
+        let mut next = 0;
+        self.find_unused_internal(device, &mut next)?;
+        Ok(next)
     }
-    /** Clone the full B-Tree */
-    pub fn clone_tree<D>(&mut self, device: &mut D) -> IOResult<()>
+    /** Clone the full B-Tree, return count of blocks referenced once more */
+    pub fn clone_tree<D>(&mut self, device: &mut D) -> IOResult<u64>
     where
         D: Write + Read + Seek,
     {
+        let mut count = 1;
         match self.r#type {
             BtreeType::Leaf => {
                 for entry in &mut self.entries {
                     entry.rc += 1;
                 }
+                count += self.entries.len() as u64;
             }
             BtreeType::Internal => {
                 for entry in &mut self.entries {
                     let mut child_node = Self::load_block(device, entry.value)?;
                     child_node.block_count = entry.value;
-                    child_node.clone_tree(device)?;
+                    count += child_node.clone_tree(device)?;
                 }
             }
         }
         self.rc += 1;
         self.sync(device, self.block_count)?;
+        Ok(count)
+    }
+    /** Visit every node in pre-order along with its depth, the root node has depth 0 */
+    pub fn walk<D, F>(&self, device: &mut D, mut visit: F) -> IOResult<()>
+    where
+        D: Write + Read + Seek,
+        F: FnMut(&BtreeNode, usize),
+    {
+        self.walk_internal(device, 0, &mut visit)
+    }
+    fn walk_internal<D, F>(&self, device: &mut D, depth: usize, visit: &mut F) -> IOResult<()>
+    where
+        D: Write + Read + Seek,
+        F: FnMut(&BtreeNode, usize),
+    {
+        visit(self, depth);
+
+        if self.r#type == BtreeType::Internal {
+            for entry in &self.entries {
+                let mut child_node = Self::load_block(device, entry.value)?;
+                child_node.block_count = entry.value;
+                child_node.walk_internal(device, depth + 1, visit)?;
+            }
+        }
+
+        Ok(())
+    }
+    /** Check the tree is well formed, returning `InvalidData` describing the first violation
+     *
+     * Keys must be strictly increasing within and across nodes, keys of a child must not be less
+     * than its separator key nor reach the next one, reference counts must be plausible and no block
+     * may be referenced twice within the tree. Separators are lower bounds rather than the first key
+     * of their child, which removing the first key of a child leaves in place.
+     */
+    pub fn verify<D>(&self, device: &mut D) -> IOResult<()>
+    where
+        D: Write + Read + Seek,
+    {
+        let mut blocks = std::collections::HashSet::from([self.block_count]);
+        self.verify_internal(device, 0..u64::MAX, true, &mut blocks)
+    }
+    fn verify_internal<D>(
+        &self,
+        device: &mut D,
+        keys: std::ops::Range<u64>,
+        is_root: bool,
+        blocks: &mut std::collections::HashSet<u64>,
+    ) -> IOResult<()>
+    where
+        D: Write + Read + Seek,
+    {
+        let node = self.block_count;
+        if self.rc > PLAUSIBLE_MAX_RC {
+            return Err(invalid_block(&format!(
+                "B-Tree node {node} has implausible reference count {}.",
+                self.rc
+            )));
+        }
+        if self.entries.is_empty() && (!is_root || self.r#type == BtreeType::Internal) {
+            return Err(invalid_block(&format!("B-Tree node {node} is empty.")));
+        }
+        for pair in self.entries.windows(2) {
+            if pair[0].key >= pair[1].key {
+                return Err(invalid_block(&format!(
+                    "Keys {} and {} of B-Tree node {node} are not increasing.",
+                    pair[0].key, pair[1].key
+                )));
+            }
+        }
+        for entry in &self.entries {
+            if entry.key < keys.start || (keys.end != u64::MAX && entry.key >= keys.end) {
+                return Err(invalid_block(&format!(
+                    "Key {} of B-Tree node {node} is out of range {}..{} of its parent.",
+                    entry.key, keys.start, keys.end
+                )));
+            }
+        }
+
+        match self.r#type {
+            BtreeType::Leaf => {
+                if let Some(entry) = self
+                    .entries
+                    .iter()
+                    .find(|entry| entry.rc > PLAUSIBLE_MAX_RC)
+                {
+                    return Err(invalid_block(&format!(
+                        "Key {} of B-Tree node {node} has implausible reference count {}.",
+                        entry.key, entry.rc
+                    )));
+                }
+            }
+            BtreeType::Internal => {
+                for (i, entry) in self.entries.iter().enumerate() {
+                    if !blocks.insert(entry.value) {
+                        return Err(invalid_block(&format!(
+                            "Block {} is referenced twice in the B-Tree.",
+                            entry.value
+                        )));
+                    }
+                    let mut child = Self::load_block(device, entry.value)?;
+                    child.block_count = entry.value;
+
+                    /* the first child also holds keys less than its separator, see `child_index` */
+                    let start = if i == 0 { keys.start } else { entry.key };
+                    let end = self.entries.get(i + 1).map_or(keys.end, |next| next.key);
+                    child.verify_internal(device, start..end, false, blocks)?;
+                }
+            }
+        }
         Ok(())
     }
     /** Destroy the full B-Tree */
@@ -642,9 +1032,12 @@ impl BtreeNode {
             BtreeType::Leaf => {
                 for entry in self.entries.iter_mut() {
                     if entry.rc == 0 {
-                        subvol.release_block(fs, device, entry.value)?;
+                        /* values of compressed clusters carry flags besides the block count */
+                        let block = entry.value & crate::file::VALUE_BLOCK_MASK;
+                        subvol.release_block(fs, device, block)?;
                     } else {
                         entry.rc -= 1;
+                        subvol.drop_shared_block(fs);
                     }
                 }
             }
@@ -677,7 +1070,8 @@ impl BtreeNode {
             self.rc = 0;
             self.sync(device, self.block_count)?;
 
-            fs.sb.real_used_blocks += 1;
+            subvol.drop_shared_block(fs);
+            fs.record_copy_out();
         }
         Ok(())
     }
@@ -695,10 +1089,341 @@ impl BtreeNode {
             self.rc -= 1;
             self.sync(device, self.block_count)?;
 
-            fs.sb.used_blocks -= 1;
+            subvol.drop_shared_block(fs);
         } else {
             subvol.release_block(fs, device, self.block_count)?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_fs;
+
+    use std::collections::HashSet;
+
+    const COUNT: u64 = 20000;
+
+    #[test]
+    fn insert_lookup_remove() {
+        let (mut fs, mut device, mut subvol) = test_fs(4000);
+        let mut root = BtreeNode {
+            block_count: BtreeNode::allocate_on_block_subvol(&mut fs, &mut subvol, &mut device)
+                .unwrap(),
+            ..Default::default()
+        };
+
+        /* 7919 is coprime with the count, so keys are inserted in a scattered order */
+        let keys = (0..COUNT).map(|i| i * 7919 % COUNT).collect::<Vec<u64>>();
+        for key in &keys {
+            root.insert(&mut fs, &mut subvol, &mut device, *key, key + 1)
+                .unwrap();
+        }
+        assert_eq!(root.r#type, BtreeType::Internal);
+        for key in 0..COUNT {
+            assert_eq!(root.lookup(&mut device, key).unwrap().value, key + 1);
+        }
+        assert!(root.lookup(&mut device, COUNT).is_err());
+
+        for key in (0..COUNT).step_by(3) {
+            root.modify(&mut fs, &mut subvol, &mut device, key, 0)
+                .unwrap();
+        }
+        for key in keys.iter().filter(|key| *key % 2 == 0) {
+            root.remove(&mut fs, &mut subvol, &mut device, *key)
+                .unwrap();
+        }
+        for key in 0..COUNT {
+            match root.lookup(&mut device, key) {
+                Ok(entry) if key % 2 == 1 => {
+                    assert_eq!(entry.value, if key % 3 == 0 { 0 } else { key + 1 })
+                }
+                Err(err) if key % 2 == 0 => assert_eq!(err.kind(), ErrorKind::NotFound),
+                result => panic!("unexpected lookup result of {}: {:?}", key, result),
+            }
+        }
+
+        for key in keys.iter().filter(|key| *key % 2 == 1) {
+            root.remove(&mut fs, &mut subvol, &mut device, *key)
+                .unwrap();
+        }
+        assert_eq!(root.r#type, BtreeType::Leaf);
+        assert!(root.entries.is_empty());
+    }
+
+    #[test]
+    fn range() {
+        let (mut fs, mut device, mut subvol) = test_fs(4000);
+        let mut root = BtreeNode {
+            block_count: BtreeNode::allocate_on_block_subvol(&mut fs, &mut subvol, &mut device)
+                .unwrap(),
+            ..Default::default()
+        };
+        /* only even keys, so ranges start and end between keys too */
+        for key in (0..COUNT).map(|i| i * 7919 % COUNT * 2) {
+            root.insert(&mut fs, &mut subvol, &mut device, key, key + 1)
+                .unwrap();
+        }
+        assert_eq!(root.r#type, BtreeType::Internal);
+
+        for (start, end) in [
+            (0, 2 * COUNT),
+            (0, u64::MAX),
+            (1, 2),
+            (777, 9001),
+            (2 * COUNT - 3, 2 * COUNT + 100),
+            (100, 100),
+            (200, 100),
+        ] {
+            let entries = root.range(&mut device, start, end).unwrap();
+            let expected: Vec<u64> = (start..end.min(2 * COUNT))
+                .filter(|key| key % 2 == 0)
+                .collect();
+            let keys: Vec<u64> = entries.iter().map(|entry| entry.key).collect();
+            assert_eq!(keys, expected, "{start}..{end}");
+            assert!(entries.iter().all(|entry| entry.value == entry.key + 1));
+        }
+    }
+
+    #[test]
+    fn floor() {
+        let (mut fs, mut device, mut subvol) = test_fs(4000);
+        let mut root = BtreeNode {
+            block_count: BtreeNode::allocate_on_block_subvol(&mut fs, &mut subvol, &mut device)
+                .unwrap(),
+            ..Default::default()
+        };
+        assert!(root.floor(&mut device, u64::MAX).unwrap().is_none());
+
+        /* keys from 10 on which are multiples of 10, so some keys have no floor */
+        for key in (1..=COUNT).map(|i| i * 7919 % COUNT * 10 + 10) {
+            root.insert(&mut fs, &mut subvol, &mut device, key, key + 1)
+                .unwrap();
+        }
+        assert_eq!(root.r#type, BtreeType::Internal);
+        /* removing keys leaves separators in internal nodes below the keys of their children */
+        for key in (10..=COUNT * 10).step_by(10).filter(|key| key % 1000 < 500) {
+            root.remove(&mut fs, &mut subvol, &mut device, key).unwrap();
+        }
+
+        for key in [
+            0,
+            9,
+            499,
+            500,
+            999,
+            1000,
+            1500,
+            123457,
+            COUNT * 10,
+            u64::MAX,
+        ] {
+            let expected = (10..=key.min(COUNT * 10))
+                .rev()
+                .find(|key| key % 10 == 0 && key % 1000 >= 500);
+            let entry = root.floor(&mut device, key).unwrap();
+            assert_eq!(entry.map(|entry| entry.key), expected, "{key}");
+            assert!(entry.iter().all(|entry| entry.value == entry.key + 1));
+        }
+    }
+
+    #[test]
+    fn insert_run() {
+        let (mut fs, mut device, mut subvol) = test_fs(4000);
+        let mut root = BtreeNode {
+            block_count: BtreeNode::allocate_on_block_subvol(&mut fs, &mut subvol, &mut device)
+                .unwrap(),
+            ..Default::default()
+        };
+
+        /* runs between single keys, ending with one large enough to grow the tree twice */
+        for key in (0..COUNT).step_by(1000) {
+            root.insert(&mut fs, &mut subvol, &mut device, key, key + 1)
+                .unwrap();
+        }
+        for start in (1..COUNT).step_by(1000) {
+            let count = if start + 1000 > COUNT { 999 } else { 500 };
+            root.insert_run(&mut fs, &mut subvol, &mut device, start, start + 1, count)
+                .unwrap();
+        }
+        root.insert_run(&mut fs, &mut subvol, &mut device, COUNT, COUNT + 1, 50000)
+            .unwrap();
+        root.verify(&mut device).unwrap();
+        assert_eq!(root.r#type, BtreeType::Internal);
+
+        let keys = root
+            .range(&mut device, 0, u64::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect::<Vec<_>>();
+        let expected = (0..COUNT + 50000)
+            .filter(|key| key % 1000 <= 500 || *key >= COUNT - 1000)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+        for key in expected {
+            assert_eq!(root.lookup(&mut device, key).unwrap().value, key + 1);
+        }
+    }
+
+    #[test]
+    fn verify() {
+        let (mut fs, mut device, mut subvol) = test_fs(4000);
+        let mut root = BtreeNode {
+            block_count: BtreeNode::allocate_on_block_subvol(&mut fs, &mut subvol, &mut device)
+                .unwrap(),
+            ..Default::default()
+        };
+        root.verify(&mut device).unwrap();
+        for key in (0..COUNT).map(|i| i * 7919 % COUNT) {
+            root.insert(&mut fs, &mut subvol, &mut device, key, key + 1)
+                .unwrap();
+        }
+        root.verify(&mut device).unwrap();
+        for key in (0..COUNT).step_by(4) {
+            root.remove(&mut fs, &mut subvol, &mut device, key).unwrap();
+        }
+        root.verify(&mut device).unwrap();
+        assert_eq!(root.r#type, BtreeType::Internal);
+
+        let child_block = root.entries[1].value;
+        let child = BtreeNode::load_block(&mut device, child_block).unwrap();
+        let corrupt = |device: &mut std::io::Cursor<Vec<u8>>, node: &BtreeNode, block: u64| {
+            crate::block::save_block(device, block, node.dump()).unwrap();
+            let err = root.verify(device).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            err.to_string()
+        };
+
+        /* walk down to a leaf and swap two of its keys */
+        let mut leaf_block = child_block;
+        let mut leaf = child.clone();
+        while leaf.r#type == BtreeType::Internal {
+            leaf_block = leaf.entries[0].value;
+            leaf = BtreeNode::load_block(&mut device, leaf_block).unwrap();
+        }
+        let mut swapped = leaf.clone();
+        swapped.entries.swap(0, 1);
+        assert!(
+            corrupt(&mut device, &swapped, leaf_block).contains(&format!(
+                "Keys {} and {} of B-Tree node {leaf_block} are not increasing.",
+                leaf.entries[1].key, leaf.entries[0].key
+            ))
+        );
+        crate::block::save_block(&mut device, leaf_block, leaf.dump()).unwrap();
+
+        let mut implausible = leaf.clone();
+        implausible.entries[0].rc = u64::MAX;
+        assert!(corrupt(&mut device, &implausible, leaf_block).contains("implausible"));
+        crate::block::save_block(&mut device, leaf_block, leaf.dump()).unwrap();
+
+        /* a child referenced from two separators */
+        let mut twice = root.clone();
+        twice.entries[2].value = child_block;
+        let message = twice.verify(&mut device).unwrap_err().to_string();
+        assert!(
+            message.contains(&format!("Block {child_block} is referenced twice")),
+            "{message}"
+        );
+        root.verify(&mut device).unwrap();
+    }
+
+    #[test]
+    fn find_unused() {
+        let (mut fs, mut device, mut subvol) = test_fs(4000);
+        let mut root = BtreeNode {
+            block_count: BtreeNode::allocate_on_block_subvol(&mut fs, &mut subvol, &mut device)
+                .unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(root.find_unused(&mut device).unwrap(), 0);
+
+        /* dense keys over several leaves */
+        let count = 4 * MAX_LEAF_COUNT as u64;
+        for key in 0..count {
+            root.insert(&mut fs, &mut subvol, &mut device, key, key)
+                .unwrap();
+        }
+        assert_eq!(root.r#type, BtreeType::Internal);
+        assert_eq!(root.find_unused(&mut device).unwrap(), count);
+
+        /* gaps at the first and last keys of leaves and in the middle of one */
+        let second_leaf = root.entries[1].key;
+        let mut gaps = vec![0, second_leaf - 1, second_leaf, second_leaf + 3, count - 1];
+        gaps.sort();
+        for gap in gaps.iter().rev() {
+            root.remove(&mut fs, &mut subvol, &mut device, *gap)
+                .unwrap();
+            assert_eq!(root.find_unused(&mut device).unwrap(), *gap);
+        }
+        for gap in gaps {
+            assert_eq!(root.find_unused(&mut device).unwrap(), gap);
+            root.insert(&mut fs, &mut subvol, &mut device, gap, gap)
+                .unwrap();
+        }
+        assert_eq!(root.find_unused(&mut device).unwrap(), count);
+    }
+
+    #[test]
+    fn walk() {
+        let (mut fs, mut device, mut subvol) = test_fs(4000);
+        let mut root = BtreeNode {
+            block_count: BtreeNode::allocate_on_block_subvol(&mut fs, &mut subvol, &mut device)
+                .unwrap(),
+            ..Default::default()
+        };
+        let count = 3 * COUNT;
+        for key in (0..count).map(|i| i * 7919 % count) {
+            root.insert(&mut fs, &mut subvol, &mut device, key, key + 1)
+                .unwrap();
+        }
+
+        let mut visited = 0;
+        let mut children = 0;
+        let mut keys = 0;
+        let mut leaf_depths = HashSet::new();
+        root.walk(&mut device, |node, depth| {
+            assert_eq!(depth == 0, visited == 0);
+            visited += 1;
+            match node.r#type {
+                BtreeType::Internal => children += node.entries.len(),
+                BtreeType::Leaf => {
+                    keys += node.entries.len() as u64;
+                    leaf_depths.insert(depth);
+                }
+            }
+        })
+        .unwrap();
+
+        /* every node but the root is referred by exactly one internal entry */
+        assert_eq!(visited, children + 1);
+        assert_eq!(keys, count);
+        assert_eq!(leaf_depths.len(), 1);
+        assert!(*leaf_depths.iter().next().unwrap() >= 2);
+    }
+
+    #[test]
+    fn entry_count_out_of_range() {
+        for (node_type, max_count) in [
+            (BTREE_NODE_TYPE_LEAF, MAX_LEAF_COUNT),
+            (BTREE_NODE_TYPE_INTERNAL, MAX_INTERNAL_COUNT),
+        ] {
+            let mut bytes = [0; BLOCK_SIZE];
+            bytes[..2].copy_from_slice(&u16::MAX.to_be_bytes());
+            bytes[3] = node_type;
+
+            assert_eq!(BtreeNode::load(bytes).entries.len(), max_count);
+            assert_eq!(
+                BtreeNode::try_load(bytes).unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+
+            bytes[..2].copy_from_slice(&(max_count as u16 + 1).to_be_bytes());
+            assert!(BtreeNode::try_load(bytes).is_err());
+            bytes[..2].copy_from_slice(&(max_count as u16).to_be_bytes());
+            assert_eq!(BtreeNode::try_load(bytes).unwrap().entries.len(), max_count);
+        }
+    }
+}
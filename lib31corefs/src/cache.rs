@@ -0,0 +1,404 @@
+use crate::block::BLOCK_SIZE;
+use crate::trim::Trim;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{ErrorKind, Result as IOResult};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/** Default count of blocks kept by `CachedDevice` */
+pub const DEFAULT_CACHE_BLOCKS: usize = 1024;
+
+/**
+ * A device adapter keeping the least recently used blocks in memory
+ *
+ * Reads are served block by block from the cache, writes are passed through to the inner device
+ * and update cached blocks, so the cache never holds stale data.
+ *
+ * Between `begin_batch` and `commit_batch`, writes are kept in the cache instead, and written
+ * back in order of block count when the batch is committed or the device is flushed (e.g. by
+ * `Filesystem::sync_meta_data`). Dirty blocks are never evicted, so a batch may exceed the
+ * capacity of the cache.
+ *
+ * This is how filesystem operations are batched: the filesystem writes every block through the
+ * device it is given, so passing a `CachedDevice` in a batch turns scattered writes of meta data
+ * into fewer sequential ones. Meta data kept by the filesystem itself should be synchronized
+ * before the batch is committed.
+ */
+#[derive(Debug)]
+pub struct CachedDevice<D> {
+    inner: D,
+    capacity: usize,
+    position: u64,
+    /** Cached blocks and the time they were last used */
+    blocks: HashMap<u64, (Box<[u8; BLOCK_SIZE]>, u64)>,
+    /** Cached blocks ordered by the time they were last used */
+    recent: BTreeMap<u64, u64>,
+    time: u64,
+    batching: bool,
+    /** Blocks written in a batch but not written back */
+    dirty: BTreeSet<u64>,
+}
+
+impl<D> CachedDevice<D>
+where
+    D: Read + Write + Seek,
+{
+    /** Wrap a device with a cache of at most `capacity` blocks */
+    pub fn new(inner: D, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            position: 0,
+            blocks: HashMap::new(),
+            recent: BTreeMap::new(),
+            time: 0,
+            batching: false,
+            dirty: BTreeSet::new(),
+        }
+    }
+    pub fn get_ref(&self) -> &D {
+        &self.inner
+    }
+    /** Get the inner device, pending writes of a batch are dropped if not committed */
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+    /** Count of cached blocks */
+    pub fn cached_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+    /** Drop a block from the cache, e.g. when it is discarded
+     *
+     * A pending write of the block is dropped as well. The filesystem doesn't call it when a
+     * block is released: every write goes through the cache, so a cached block always holds what
+     * the device holds, and a released block allocated again is read correctly. It only takes
+     * room until it is evicted, and a pending write of it is harmless as the block is free.
+     * Discarding leaves undefined data on the device, so `Trim::discard` invalidates blocks.
+     */
+    pub fn invalidate(&mut self, block_count: u64) {
+        if let Some((_, time)) = self.blocks.remove(&block_count) {
+            self.recent.remove(&time);
+        }
+        self.dirty.remove(&block_count);
+    }
+    /** Write back pending writes and drop all cached blocks */
+    pub fn clear(&mut self) -> IOResult<()> {
+        self.write_back()?;
+        self.blocks.clear();
+        self.recent.clear();
+        Ok(())
+    }
+    /** Keep following writes in the cache until `commit_batch` */
+    pub fn begin_batch(&mut self) {
+        self.batching = true;
+    }
+    /** Write back writes of the batch and pass following writes through */
+    pub fn commit_batch(&mut self) -> IOResult<()> {
+        self.batching = false;
+        self.write_back()
+    }
+    /** Count of blocks waiting to be written back */
+    pub fn dirty_blocks(&self) -> usize {
+        self.dirty.len()
+    }
+    /** Write dirty blocks in order of block count, consecutive blocks are written at once */
+    fn write_back(&mut self) -> IOResult<()> {
+        let mut run: Vec<u8> = Vec::new();
+        let mut run_start = 0;
+
+        for block_count in std::mem::take(&mut self.dirty) {
+            if run_start + (run.len() / BLOCK_SIZE) as u64 != block_count {
+                if !run.is_empty() {
+                    self.inner
+                        .seek(SeekFrom::Start(run_start * BLOCK_SIZE as u64))?;
+                    self.inner.write_all(&run)?;
+                    run.clear();
+                }
+                run_start = block_count;
+            }
+            run.extend_from_slice(&*self.blocks[&block_count].0);
+        }
+        if !run.is_empty() {
+            self.inner
+                .seek(SeekFrom::Start(run_start * BLOCK_SIZE as u64))?;
+            self.inner.write_all(&run)?;
+        }
+
+        Ok(())
+    }
+    /** Get a block from the cache or load it from the inner device */
+    fn get_block(&mut self, block_count: u64) -> IOResult<Option<&[u8; BLOCK_SIZE]>> {
+        self.time += 1;
+        if let Some((_, time)) = self.blocks.get_mut(&block_count) {
+            self.recent.remove(time);
+            *time = self.time;
+            self.recent.insert(self.time, block_count);
+            return Ok(self.blocks.get(&block_count).map(|(block, _)| &**block));
+        }
+
+        if self.capacity == 0 {
+            return Ok(None);
+        }
+
+        let mut block = Box::new([0; BLOCK_SIZE]);
+        self.inner
+            .seek(SeekFrom::Start(block_count * BLOCK_SIZE as u64))?;
+        match self.inner.read_exact(&mut *block) {
+            Ok(()) => {}
+            /* a partial block at the end of the device is not cached */
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        if self.blocks.len() >= self.capacity {
+            let oldest = self
+                .recent
+                .iter()
+                .find(|(_, block_count)| !self.dirty.contains(block_count))
+                .map(|(time, block_count)| (*time, *block_count));
+            if let Some((time, oldest)) = oldest {
+                self.recent.remove(&time);
+                self.blocks.remove(&oldest);
+            }
+        }
+        self.recent.insert(self.time, block_count);
+        self.blocks.insert(block_count, (block, self.time));
+
+        Ok(self.blocks.get(&block_count).map(|(block, _)| &**block))
+    }
+}
+
+impl<D> Read for CachedDevice<D>
+where
+    D: Read + Write + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let position = self.position;
+        let offset = (position % BLOCK_SIZE as u64) as usize;
+        let len = std::cmp::min(buf.len(), BLOCK_SIZE - offset);
+
+        let len = match self.get_block(position / BLOCK_SIZE as u64)? {
+            Some(block) => {
+                buf[..len].copy_from_slice(&block[offset..offset + len]);
+                len
+            }
+            None => {
+                self.inner.seek(SeekFrom::Start(position))?;
+                self.inner.read(&mut buf[..len])?
+            }
+        };
+
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<D> Write for CachedDevice<D>
+where
+    D: Read + Write + Seek,
+{
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        if self.batching {
+            let block_count = self.position / BLOCK_SIZE as u64;
+            let offset = (self.position % BLOCK_SIZE as u64) as usize;
+            let len = std::cmp::min(buf.len(), BLOCK_SIZE - offset);
+
+            if self.get_block(block_count)?.is_some() {
+                let (block, _) = self.blocks.get_mut(&block_count).unwrap();
+                block[offset..offset + len].copy_from_slice(&buf[..len]);
+                self.dirty.insert(block_count);
+                self.position += len as u64;
+                return Ok(len);
+            }
+        }
+
+        self.inner.seek(SeekFrom::Start(self.position))?;
+        let len = self.inner.write(buf)?;
+
+        /* write through, cached blocks are updated in place */
+        let end = self.position + len as u64;
+        let mut position = self.position;
+        while position < end {
+            let block_count = position / BLOCK_SIZE as u64;
+            let offset = (position % BLOCK_SIZE as u64) as usize;
+            let block_len = std::cmp::min((end - position) as usize, BLOCK_SIZE - offset);
+
+            if let Some((block, _)) = self.blocks.get_mut(&block_count) {
+                let start = (position - self.position) as usize;
+                block[offset..offset + block_len].copy_from_slice(&buf[start..start + block_len]);
+            }
+            position += block_len as u64;
+        }
+
+        self.position = end;
+        Ok(len)
+    }
+    fn flush(&mut self) -> IOResult<()> {
+        self.write_back()?;
+        self.inner.flush()
+    }
+}
+
+impl<D> Trim for CachedDevice<D>
+where
+    D: Read + Write + Seek + Trim,
+{
+    /** Drop the blocks from the cache, including pending writes, and discard them */
+    fn discard(&mut self, block_count: u64, count: u64) -> IOResult<()> {
+        for block_count in block_count..block_count + count {
+            self.invalidate(block_count);
+        }
+        self.inner.discard(block_count, count)
+    }
+}
+
+impl<D> Seek for CachedDevice<D>
+where
+    D: Read + Write + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+        self.position = match pos {
+            SeekFrom::Start(position) => position,
+            SeekFrom::Current(offset) => {
+                self.position.checked_add_signed(offset).ok_or_else(|| {
+                    std::io::Error::new(ErrorKind::InvalidInput, "Invalid seek position.")
+                })?
+            }
+            SeekFrom::End(_) => self.inner.seek(pos)?,
+        };
+
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{test_fs, CountingDevice};
+
+    use std::io::Cursor;
+
+    #[test]
+    fn repeated_traversal() {
+        let (mut fs, device, mut subvol) = test_fs(4000);
+        let mut device = CachedDevice::new(CountingDevice::new(device), DEFAULT_CACHE_BLOCKS);
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        for i in 0..500 {
+            fs.create_file(&mut subvol, &mut device, format!("/dir/{i}"))
+                .unwrap();
+        }
+        device.clear().unwrap();
+
+        let mut traverse = |device: &mut CachedDevice<CountingDevice>| {
+            let reads = device.get_ref().reads;
+            for i in 0..500 {
+                fs.open_file(&mut subvol, device, format!("/dir/{i}"))
+                    .unwrap();
+            }
+            device.get_ref().reads - reads
+        };
+        let first = traverse(&mut device);
+        let second = traverse(&mut device);
+        assert!(first > 0);
+        assert!(second * 10 < first);
+    }
+
+    #[test]
+    fn write_through_and_eviction() {
+        let mut device = CachedDevice::new(Cursor::new(vec![0; 4 * BLOCK_SIZE]), 2);
+        let mut block = [0; BLOCK_SIZE];
+        for i in 0..3 {
+            device.seek(SeekFrom::Start(i * BLOCK_SIZE as u64)).unwrap();
+            device.read_exact(&mut block).unwrap();
+        }
+        assert_eq!(device.cached_blocks(), 2);
+
+        /* a write spanning two blocks updates the cached one */
+        device
+            .seek(SeekFrom::Start(3 * BLOCK_SIZE as u64 - 2))
+            .unwrap();
+        device.write_all(&[1, 2, 3, 4]).unwrap();
+        device
+            .seek(SeekFrom::Start(3 * BLOCK_SIZE as u64 - 4))
+            .unwrap();
+        let mut bytes = [0; 6];
+        device.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [0, 0, 1, 2, 3, 4]);
+        assert_eq!(
+            &device.get_ref().get_ref()[3 * BLOCK_SIZE - 4..3 * BLOCK_SIZE + 2],
+            &bytes
+        );
+
+        device.invalidate(2);
+        device.invalidate(3);
+        assert_eq!(device.cached_blocks(), 0);
+    }
+
+    #[test]
+    fn batch() {
+        let create_files = |device: &mut CachedDevice<CountingDevice>| {
+            let blocks = 4000;
+            let mut fs = crate::Filesystem::create(device, blocks).unwrap();
+            let mut subvol = fs.get_default_subvolume(device).unwrap();
+            for i in 0..200 {
+                fs.create_file(&mut subvol, device, format!("/{i}"))
+                    .unwrap();
+            }
+            subvol.sync_meta_data(&mut fs, device).unwrap();
+            fs.sync_meta_data(device).unwrap();
+        };
+        let new_device = || {
+            CachedDevice::new(
+                CountingDevice::new(Cursor::new(vec![0; 4000 * BLOCK_SIZE])),
+                DEFAULT_CACHE_BLOCKS,
+            )
+        };
+
+        let mut device = new_device();
+        create_files(&mut device);
+        let writes = device.get_ref().writes;
+
+        let mut batched = new_device();
+        batched.begin_batch();
+        create_files(&mut batched);
+        batched.commit_batch().unwrap();
+        assert_eq!(batched.dirty_blocks(), 0);
+        assert!(batched.get_ref().writes * 10 < writes);
+
+        let mut device = batched.into_inner().inner;
+        let mut fs = crate::Filesystem::load(&mut device).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        assert_eq!(
+            fs.list_dir(&mut subvol, &mut device, "/").unwrap().len(),
+            200
+        );
+    }
+
+    #[test]
+    fn batch_reads_pending_writes() {
+        let mut device = CachedDevice::new(Cursor::new(vec![0; 4 * BLOCK_SIZE]), 1);
+        device.begin_batch();
+        for i in 0..3 {
+            device
+                .seek(SeekFrom::Start(i * BLOCK_SIZE as u64 + 10))
+                .unwrap();
+            device.write_all(&[i as u8 + 1; 4]).unwrap();
+        }
+        /* dirty blocks are kept beyond the capacity */
+        assert_eq!(device.dirty_blocks(), 3);
+        assert!(device.get_ref().get_ref().iter().all(|byte| *byte == 0));
+
+        let mut bytes = [0; 4];
+        for i in 0..3 {
+            device
+                .seek(SeekFrom::Start(i * BLOCK_SIZE as u64 + 10))
+                .unwrap();
+            device.read_exact(&mut bytes).unwrap();
+            assert_eq!(bytes, [i as u8 + 1; 4]);
+        }
+
+        device.flush().unwrap();
+        assert_eq!(device.dirty_blocks(), 0);
+        assert_eq!(device.get_ref().get_ref()[2 * BLOCK_SIZE + 10], 3);
+    }
+}
@@ -1,180 +1,1331 @@
+use crate::block::{invalid_block, Block, INodeGroup, BLOCK_SIZE};
+use crate::btree::{BtreeEntry, BtreeNode};
 use crate::file::File;
-use crate::inode::{INode, ACL_DIRECTORY, PERMISSION_BITS};
+use crate::inode::{
+    FileType, INode, ACL_DIRECTORY, ACL_WHITEOUT, INODE_PER_GROUP, PERMISSION_BITS,
+};
 use crate::subvol::Subvolume;
-use crate::symlink::read_link_from_inode;
-use crate::utils::{base_name, dir_path};
-use crate::Filesystem;
+use crate::utils::{base_name, dir_path, normalize};
+use crate::{Filesystem, FsError, FEATURE_INCOMPAT_BTREE_DIR};
 
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::io::{Error, ErrorKind, Result as IOResult};
 use std::io::{Read, Seek, Write};
 use std::path::Path;
 
+/** Maximum length of a file name in bytes */
+pub const FILE_NAME_MAX: usize = 255;
+/** Inode count of a removed entry in a directory without index */
+const DIR_ENTRY_REMOVED: u64 = u64::MAX;
+
+/** Bits of a bucket key below the hash, which tell apart buckets of names sharing a hash */
+const BUCKET_SEQ_BITS: u32 = 8;
+/** Greatest sequence of a bucket key, so that keys stay below `u64::MAX` */
+const BUCKET_SEQ_MAX: u64 = (1 << BUCKET_SEQ_BITS) - 2;
+/** Size of the header of a bucket, which records size of its entries */
+const BUCKET_HEADER_SIZE: usize = 8;
+/** Size of entries a bucket can hold */
+const BUCKET_CAPACITY: usize = BLOCK_SIZE - BUCKET_HEADER_SIZE;
+/** Cookie of `read_entries_from` after the last entry of an indexed directory */
+const COOKIE_END: u64 = u64::MAX;
+
+/** Size of directory content read at a time while scanning */
+const SCAN_SIZE: u64 = 16 * BLOCK_SIZE as u64;
+
+/** Match a name against a glob pattern with `*` and `?` */
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+
+    let (mut p, mut n) = (0, 0);
+    /* position of the last `*` and the name position it is tried at */
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            /* let the last `*` take one more character */
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/** Hash function of file names in indexed directories, stored in the superblock
+ *
+ * Entries are kept in buckets by the hash of their names, so any hash finds all names, but a
+ * good one spreads names evenly and rarely makes names share a hash.
+ */
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirHash {
+    /** FNV-1a, used by filesystems created before the hash was selectable */
+    #[default]
+    Fnv1a,
+    /** SipHash-2-4 keyed with the UUID of the filesystem, so collisions can not be planned */
+    SipHash,
+}
+
+impl DirHash {
+    /** Get the hash by its id in the superblock */
+    pub fn from_id(id: u64) -> Option<Self> {
+        match id {
+            0 => Some(Self::Fnv1a),
+            1 => Some(Self::SipHash),
+            _ => None,
+        }
+    }
+    pub fn id(&self) -> u64 {
+        match self {
+            Self::Fnv1a => 0,
+            Self::SipHash => 1,
+        }
+    }
+    /** Get the hash by its name, as printed by `name` */
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fnv1a" => Some(Self::Fnv1a),
+            "siphash" => Some(Self::SipHash),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Fnv1a => "fnv1a",
+            Self::SipHash => "siphash",
+        }
+    }
+    fn hash(&self, key: &[u8; 16], name: &[u8]) -> u64 {
+        match self {
+            Self::Fnv1a => fnv1a(name),
+            Self::SipHash => siphash(key, name),
+        }
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn siphash(key: &[u8; 16], bytes: &[u8]) -> u64 {
+    let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+    let round = |v: &mut [u64; 4]| {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    };
+
+    let chunks = bytes.chunks_exact(8);
+    let mut last = [0; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = bytes.len() as u8;
+    for word in chunks
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+        .chain([u64::from_le_bytes(last)])
+    {
+        v[3] ^= word;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= word;
+    }
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/** Hash a file name for indexed directories with the hash of the filesystem
+ *
+ * The hash is cut to 56 bits, which leaves room for the sequence in bucket keys. Unknown hashes
+ * are rejected when the filesystem is loaded, so they are only met if the superblock is changed
+ * afterwards.
+ */
+pub(crate) fn name_hash(fs: &Filesystem, name: &str) -> IOResult<u64> {
+    let dir_hash = DirHash::from_id(fs.sb.dir_hash).ok_or_else(|| {
+        Error::new(
+            ErrorKind::Unsupported,
+            format!("Unsupported directory hash {}.", fs.sb.dir_hash),
+        )
+    })?;
+    #[cfg(test)]
+    if tests::NARROW_HASH.with(|narrow| narrow.get()) {
+        return Ok(fnv1a(name.as_bytes()) % 4);
+    }
+
+    Ok(dir_hash.hash(&fs.sb.uuid, name.as_bytes()) >> BUCKET_SEQ_BITS)
+}
+
+/** Parse packed directory entries
+ *
+ * Return offset, inode count and name of each entry.
+ */
+fn parse_entries(dir_data: &[u8], mut offset: usize) -> Vec<(u64, u64, String)> {
+    let mut entries = Vec::new();
+
+    while offset + 9 <= dir_data.len() {
+        let entry_offset = offset as u64;
+        let inode = u64::from_be_bytes(dir_data[offset..offset + 8].try_into().unwrap());
+        offset += 8;
+        let str_len = dir_data[offset] as usize;
+        offset += 1;
+        if offset + str_len > dir_data.len() {
+            break;
+        }
+        let file_name = String::from_utf8_lossy(&dir_data[offset..offset + str_len]).to_string();
+        offset += str_len;
+        entries.push((entry_offset, inode, file_name));
+    }
+
+    entries
+}
+
+/** Directory entry with its file type */
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType,
+}
+
+/** Size of a packed directory entry */
+fn entry_size(name: &str) -> usize {
+    9 + name.len()
+}
+
+#[derive(Default, Debug)]
+/** Entries of an indexed directory kept in a block mapped by the B-Tree of the directory
+ *
+ * The key of a bucket is the least hash of names it holds, shifted left by `BUCKET_SEQ_BITS`,
+ * and a bucket holds the names whose hashes are from its own up to the hash of the next key.
+ * Names of one hash are kept in one bucket, unless they don't fit in a block, then they fill
+ * buckets of consecutive keys from the hash.
+ *
+ * # Data structure
+ *
+ * |Start|End   |Description|
+ * |-----|------|-----------|
+ * |0    |8     |Size of entries (N)|
+ * |8    |8 + N |Directory entries|
+ */
+struct Bucket {
+    key: u64,
+    block: u64,
+    /** Reference count of the block, which is shared with snapshots if not 0 */
+    rc: u64,
+    entries: Vec<(u64, String)>,
+}
+
+impl Block for Bucket {
+    fn load(bytes: [u8; BLOCK_SIZE]) -> Self {
+        /* a corrupted size must not read beyond the block */
+        let size = std::cmp::min(
+            u64::from_be_bytes(bytes[..8].try_into().unwrap()) as usize,
+            BUCKET_CAPACITY,
+        );
+        Self {
+            entries: parse_entries(&bytes[..BUCKET_HEADER_SIZE + size], BUCKET_HEADER_SIZE)
+                .into_iter()
+                .map(|(_, inode, name)| (inode, name))
+                .collect(),
+            ..Default::default()
+        }
+    }
+    fn try_load(bytes: [u8; BLOCK_SIZE]) -> IOResult<Self> {
+        if u64::from_be_bytes(bytes[..8].try_into().unwrap()) > BUCKET_CAPACITY as u64 {
+            return Err(invalid_block(
+                "Size of directory entries exceeds bucket capacity.",
+            ));
+        }
+        Ok(Self::load(bytes))
+    }
+    fn dump(&self) -> [u8; BLOCK_SIZE] {
+        let mut block = [0; BLOCK_SIZE];
+
+        block[..8].copy_from_slice(&(self.size() as u64).to_be_bytes());
+        let mut offset = BUCKET_HEADER_SIZE;
+        for (inode, name) in &self.entries {
+            block[offset..offset + 8].copy_from_slice(&inode.to_be_bytes());
+            block[offset + 8] = name.len() as u8;
+            block[offset + 9..offset + entry_size(name)].copy_from_slice(name.as_bytes());
+            offset += entry_size(name);
+        }
+        block
+    }
+}
+
+impl Bucket {
+    /** Load the bucket mapped by a leaf entry of the directory B-Tree */
+    fn load_entry<D>(device: &mut D, entry: &BtreeEntry) -> IOResult<Self>
+    where
+        D: Read + Write + Seek,
+    {
+        Ok(Self {
+            key: entry.key,
+            block: entry.value,
+            rc: entry.rc,
+            ..Self::load_block(device, entry.value)?
+        })
+    }
+    fn size(&self) -> usize {
+        self.entries.iter().map(|(_, name)| entry_size(name)).sum()
+    }
+    fn position(&self, name: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|(_, this_name)| this_name == name)
+    }
+    /** Write the bucket, a block shared with snapshots is copied out */
+    fn save<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        root: &mut BtreeNode,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        if self.rc == 0 {
+            self.sync(device, self.block)?;
+            fs.record_write(self.block);
+            return Ok(());
+        }
+
+        let block = subvol.new_block(fs, device)?;
+        self.sync(device, block)?;
+        fs.record_write(block);
+        fs.barrier(device)?;
+        if let Err(err) = root.modify(fs, subvol, device, self.key, block) {
+            subvol.release_block(fs, device, block)?;
+            return Err(err);
+        }
+        subvol.drop_shared_block(fs);
+        fs.record_copy_out();
+        self.block = block;
+        self.rc = 0;
+        Ok(())
+    }
+    /** Store entries in a new bucket of the key */
+    fn create<D>(
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        root: &mut BtreeNode,
+        inode: &mut INode,
+        key: u64,
+        entries: Vec<(u64, String)>,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut bucket = Self {
+            key,
+            block: subvol.new_block(fs, device)?,
+            rc: 0,
+            entries,
+        };
+        bucket.sync(device, bucket.block)?;
+        fs.record_write(bucket.block);
+        fs.barrier(device)?;
+        if let Err(err) = root.insert(fs, subvol, device, key, bucket.block) {
+            subvol.release_block(fs, device, bucket.block)?;
+            return Err(err);
+        }
+        inode.size += BLOCK_SIZE as u64;
+        Ok(())
+    }
+    /** Remove the bucket from the B-Tree and release its block */
+    fn release<D>(
+        self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        root: &mut BtreeNode,
+        inode: &mut INode,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        root.remove(fs, subvol, device, self.key)?;
+        if self.rc == 0 {
+            subvol.release_block(fs, device, self.block)?;
+        } else {
+            subvol.drop_shared_block(fs);
+        }
+        inode.size -= BLOCK_SIZE as u64;
+        Ok(())
+    }
+    /** Move the bucket to another key, a block shared with snapshots is left to them
+     *
+     * The bucket is to be saved afterwards.
+     */
+    fn rekey<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        root: &mut BtreeNode,
+        key: u64,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        root.remove(fs, subvol, device, self.key)?;
+        if self.rc > 0 {
+            subvol.drop_shared_block(fs);
+            fs.record_copy_out();
+            self.block = subvol.new_block(fs, device)?;
+            self.rc = 0;
+        }
+        root.insert(fs, subvol, device, key, self.block)?;
+        self.key = key;
+        Ok(())
+    }
+}
+
+/** Get leaf entries of the buckets which may hold names of the hash */
+fn buckets_of<D>(device: &mut D, root: &BtreeNode, hash: u64) -> IOResult<Vec<BtreeEntry>>
+where
+    D: Read + Write + Seek,
+{
+    let first = hash << BUCKET_SEQ_BITS;
+    match root.floor(device, first | BUCKET_SEQ_MAX)? {
+        /* names of the hash fill buckets of consecutive keys */
+        Some(entry) if entry.key > first => root.range(device, first, entry.key + 1),
+        Some(entry) => Ok(vec![entry]),
+        None => Ok(Vec::new()),
+    }
+}
+
+/** Split entries sorted by hash into chunks which fit in buckets
+ *
+ * Entries of one hash are kept in one chunk if they fit in a bucket, otherwise they start a
+ * chunk and fill as many as they need. Chunks are filled about evenly, so that entries added
+ * later have room in both.
+ */
+fn chunk_entries(entries: &[(u64, u64, String)]) -> Vec<std::ops::Range<usize>> {
+    let total = entries
+        .iter()
+        .map(|(_, _, name)| entry_size(name))
+        .sum::<usize>();
+    let target = total / total.div_ceil(BUCKET_CAPACITY);
+
+    let mut chunks = Vec::new();
+    let (mut start, mut size) = (0, 0);
+    let mut run_start = 0;
+    while run_start < entries.len() {
+        let hash = entries[run_start].0;
+        let run_end = entries[run_start..]
+            .iter()
+            .position(|(this_hash, _, _)| *this_hash != hash)
+            .map_or(entries.len(), |len| run_start + len);
+        let run_size = entries[run_start..run_end]
+            .iter()
+            .map(|(_, _, name)| entry_size(name))
+            .sum::<usize>();
+
+        if start < run_start && (size >= target || size + run_size > BUCKET_CAPACITY) {
+            chunks.push(start..run_start);
+            (start, size) = (run_start, 0);
+        }
+        if run_size > BUCKET_CAPACITY {
+            for (i, (_, _, name)) in entries.iter().enumerate().take(run_end).skip(run_start) {
+                if size + entry_size(name) > BUCKET_CAPACITY {
+                    chunks.push(start..i);
+                    (start, size) = (i, 0);
+                }
+                size += entry_size(name);
+            }
+        } else {
+            size += run_size;
+        }
+        run_start = run_end;
+    }
+    chunks.push(start..entries.len());
+    chunks
+}
+
+/**
+ * # Data structure
+ *
+ * Each directory entry is stored as:
+ *
+ * |Start|End   |Description|
+ * |-----|------|-----------|
+ * |0    |8     |Inode count|
+ * |8    |9     |Length of name (N)|
+ * |9    |9 + N |Name       |
+ *
+ * With `FEATURE_INCOMPAT_BTREE_DIR`, entries are kept in buckets keyed with hash of names in
+ * the B-Tree of the directory, see `Bucket`, so a lookup reads a single bucket.
+ *
+ * Otherwise entries are packed as content of the directory. Removed entries are marked with an
+ * inode count of `u64::MAX` rather than moved, so offsets of the other entries stay stable,
+ * only a removed last entry is truncated. Once removed entries take more than half of the
+ * entries, the directory is compacted before the next entries are added, so offsets only
+ * change when entries are added.
+ *
+ * A handle keeps no state of its own: entries are written to the device as soon as they are
+ * added or removed. Allocation meta data kept by `Filesystem` and `Subvolume` and buffers of
+ * the device are only persisted by `flush`.
+ */
 pub struct Directory {
     fd: File,
 }
 
-impl Directory {
-    /** Create a directory */
-    pub(crate) fn create<D, P>(
+impl Directory {
+    /** Create a directory */
+    pub(crate) fn create<D, P>(
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<Self>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let path = normalize(path.as_ref())?;
+        let (parent, name) = (dir_path(&path)?, base_name(&path)?);
+        let inode_count = create(fs, subvol, device)?;
+
+        if let Err(err) = Directory::open(fs, subvol, device, parent)
+            .and_then(|mut dir| dir.add_file(fs, subvol, device, name, inode_count))
+        {
+            crate::file::remove_by_inode(fs, subvol, device, inode_count)?;
+            return Err(err);
+        }
+
+        Ok(Self {
+            fd: File::open_by_inode(subvol, device, inode_count)?,
+        })
+    }
+    pub fn open<D, P>(
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<Self>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let path = normalize(path.as_ref())?;
+        let chain = fs.dir_chain(subvol, device, &path)?;
+
+        Self::open_by_inode(subvol, device, *chain.last().unwrap())
+    }
+    /** Open a directory by inode count */
+    pub(crate) fn open_by_inode<D>(
+        subvol: &mut Subvolume,
+        device: &mut D,
+        inode_count: u64,
+    ) -> IOResult<Self>
+    where
+        D: Read + Write + Seek,
+    {
+        Ok(Self {
+            fd: File::open_by_inode(subvol, device, inode_count)?,
+        })
+    }
+    /** Read all entries of a directory without index
+     *
+     * Return offset, inode count and name of each entry.
+     */
+    fn read_entries<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<Vec<(u64, u64, String)>>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut dir_data = vec![0; self.fd.get_inode().size as usize];
+        self.fd.read(
+            fs,
+            subvol,
+            device,
+            0,
+            &mut dir_data,
+            self.fd.get_inode().size,
+        )?;
+
+        Ok(parse_entries(&dir_data, 0)
+            .into_iter()
+            .filter(|(_, inode, _)| *inode != DIR_ENTRY_REMOVED)
+            .collect())
+    }
+    pub fn list_dir<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<HashMap<String, u64>>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut entries = HashMap::new();
+        self.visit_entries(fs, subvol, device, |inode, name| {
+            entries.insert(name.to_string(), inode);
+            true
+        })?;
+
+        Ok(entries)
+    }
+    /** List entries whose names match a glob pattern
+     *
+     * `*` matches any sequence of characters and `?` matches a single character. Entries are
+     * matched while the directory is scanned, so only matching ones are collected.
+     */
+    pub fn list_matching<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        pattern: &str,
+    ) -> IOResult<HashMap<String, u64>>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut entries = HashMap::new();
+        self.visit_entries(fs, subvol, device, |inode, name| {
+            if glob_match(pattern, name) {
+                entries.insert(name.to_string(), inode);
+            }
+            true
+        })?;
+
+        Ok(entries)
+    }
+    /** Check if the directory has no entry, entries marked removed are not counted */
+    pub fn is_empty<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<bool>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut empty = true;
+        self.visit_entries(fs, subvol, device, |_, _| {
+            empty = false;
+            false
+        })?;
+        Ok(empty)
+    }
+    /** Count entries of the directory, without collecting them */
+    pub fn entry_count<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut count = 0;
+        self.visit_entries(fs, subvol, device, |_, _| {
+            count += 1;
+            true
+        })?;
+        Ok(count)
+    }
+    /** Visit entries of the directory, a bucket or a cluster of content at a time
+     *
+     * `visit` gets inode count and name of each entry, and returns `false` to stop.
+     */
+    fn visit_entries<D, F>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        mut visit: F,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        F: FnMut(u64, &str) -> bool,
+    {
+        if !is_indexed(fs) {
+            return self.scan_entries(fs, subvol, device, 0, |_, inode, name| visit(inode, name));
+        }
+
+        if let Some(root) = self.load_tree(subvol, device)? {
+            for entry in root.range(device, 0, u64::MAX)? {
+                for (inode, name) in Bucket::load_entry(device, &entry)?.entries {
+                    if !visit(inode, &name) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /** Scan entries of a directory without index a cluster at a time from the offset, removed
+     * entries are skipped
+     *
+     * `visit` gets offset, inode count and name of each entry, and returns `false` to stop.
+     */
+    fn scan_entries<D, F>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        from: u64,
+        mut visit: F,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        F: FnMut(u64, u64, &str) -> bool,
+    {
+        let size = self.fd.get_inode().size;
+        /* offset of the first unparsed byte, and offset to read from */
+        let mut start = from;
+        let mut read_offset = start;
+        let mut buffer = Vec::new();
+
+        loop {
+            let mut parsed = 0;
+            while parsed + 9 <= buffer.len() {
+                let str_len = buffer[parsed + 8] as usize;
+                if parsed + 9 + str_len > buffer.len() {
+                    break;
+                }
+                let inode = u64::from_be_bytes(buffer[parsed..parsed + 8].try_into().unwrap());
+                let name = String::from_utf8_lossy(&buffer[parsed + 9..parsed + 9 + str_len]);
+                if inode != DIR_ENTRY_REMOVED && !visit(start + parsed as u64, inode, &name) {
+                    return Ok(());
+                }
+                parsed += 9 + str_len;
+            }
+            buffer.drain(..parsed);
+            start += parsed as u64;
+
+            if read_offset >= size {
+                return Ok(());
+            }
+            let len = std::cmp::min(SCAN_SIZE, size - read_offset);
+            let mut chunk = vec![0; len as usize];
+            self.fd
+                .read(fs, subvol, device, read_offset, &mut chunk, len)?;
+            buffer.extend(chunk);
+            read_offset += len;
+        }
+    }
+    /** List the directory with file types
+     *
+     * Each inode group is loaded once, rather than once per entry.
+     */
+    pub fn list_dir_typed<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<Vec<DirEntry>>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut entries = Vec::new();
+        self.visit_entries(fs, subvol, device, |inode, name| {
+            entries.push((inode, name.to_string()));
+            true
+        })?;
+        typed_entries(subvol, device, entries)
+    }
+    /** Read up to `max` entries with file types from a cookie
+     *
+     * 0 starts from the first entry. Return the entries and the cookie to continue from, no
+     * entries are returned at the end of the directory.
+     *
+     * In an indexed directory, the cookie is the hash of the next name. Names sharing a hash are
+     * returned together, even if they exceed `max` once nothing else is returned. Adding or
+     * removing entries between calls doesn't make the others skipped or repeated.
+     *
+     * Otherwise the cookie is a byte offset into the directory. As removing entries doesn't move
+     * the others, entries removed between calls don't make the rest skipped or repeated. Adding
+     * entries may compact the directory, after which a cookie from before is no longer valid.
+     */
+    pub fn read_entries_from<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        cookie: u64,
+        max: usize,
+    ) -> IOResult<(Vec<DirEntry>, u64)>
+    where
+        D: Read + Write + Seek,
+    {
+        if max == 0 {
+            return Ok((Vec::new(), cookie));
+        }
+        if is_indexed(fs) {
+            let (entries, next) = self.read_buckets_from(fs, subvol, device, cookie, max)?;
+            return Ok((typed_entries(subvol, device, entries)?, next));
+        }
+
+        let size = self.fd.get_inode().size;
+        if cookie >= size {
+            return Ok((Vec::new(), cookie));
+        }
+
+        let mut entries = Vec::new();
+        let mut next = size;
+        self.scan_entries(fs, subvol, device, cookie, |offset, inode, name| {
+            if entries.len() == max {
+                next = offset;
+                return false;
+            }
+            entries.push((inode, name.to_string()));
+            true
+        })?;
+
+        Ok((typed_entries(subvol, device, entries)?, next))
+    }
+    /** Read entries of an indexed directory in order of hashes from the hash of the cookie */
+    fn read_buckets_from<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        cookie: u64,
+        max: usize,
+    ) -> IOResult<(Vec<(u64, String)>, u64)>
+    where
+        D: Read + Write + Seek,
+    {
+        let root = match self.load_tree(subvol, device)? {
+            Some(root) if cookie <= u64::MAX >> BUCKET_SEQ_BITS => root,
+            _ => return Ok((Vec::new(), COOKIE_END)),
+        };
+
+        /* the bucket before the keys of the hash may hold names of it */
+        let start = root
+            .floor(device, (cookie << BUCKET_SEQ_BITS).saturating_sub(1))?
+            .map_or(0, |entry| entry.key);
+        let buckets = root.range(device, start, u64::MAX)?;
+
+        let mut entries = Vec::new();
+        let mut i = 0;
+        while i < buckets.len() {
+            /* buckets of one hash are read together, as their names are unordered */
+            let hash = buckets[i].key >> BUCKET_SEQ_BITS;
+            let mut names = Vec::new();
+            while i < buckets.len() && buckets[i].key >> BUCKET_SEQ_BITS == hash {
+                for (inode, name) in Bucket::load_entry(device, &buckets[i])?.entries {
+                    let name_hash = name_hash(fs, &name)?;
+                    if name_hash >= cookie {
+                        names.push((name_hash, inode, name));
+                    }
+                }
+                i += 1;
+            }
+            names.sort_by_key(|(name_hash, _, _)| *name_hash);
+
+            for run in names.chunk_by(|a, b| a.0 == b.0) {
+                if !entries.is_empty() && entries.len() + run.len() > max {
+                    return Ok((entries, run[0].0));
+                }
+                entries.extend(run.iter().map(|(_, inode, name)| (*inode, name.clone())));
+            }
+        }
+        Ok((entries, COOKIE_END))
+    }
+    /* Find inode under the directory */
+    pub(crate) fn find_inode_by_name<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        name: &str,
+    ) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        if is_indexed(fs) {
+            if let Some(root) = self.load_tree(subvol, device)? {
+                let hash = name_hash(fs, name)?;
+                for entry in buckets_of(device, &root, hash)? {
+                    let bucket = Bucket::load_entry(device, &entry)?;
+                    if let Some(i) = bucket.position(name) {
+                        return Ok(bucket.entries[i].0);
+                    }
+                }
+            }
+            return Err(FsError::NotFound(name.to_string()).into());
+        }
+
+        match self.list_dir(fs, subvol, device)?.get(name) {
+            Some(inode) => Ok(*inode),
+            None => Err(FsError::NotFound(name.to_string()).into()),
+        }
+    }
+    /** Load the B-Tree of an indexed directory, `None` if it has no entry
+     *
+     * The inode is loaded again, as the directory may be changed through another handle.
+     */
+    fn load_tree<D>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<Option<BtreeNode>>
+    where
+        D: Read + Write + Seek,
+    {
+        let btree_root = subvol.get_inode(device, self.get_inode_count())?.btree_root;
+        if btree_root == 0 {
+            return Ok(None);
+        }
+
+        let mut root = BtreeNode::load_block(device, btree_root)?;
+        root.block_count = btree_root;
+        Ok(Some(root))
+    }
+    /** Start changing the B-Tree of an indexed directory, see `finish_tree`
+     *
+     * The inode is copied out first if shared with snapshots. Return the inode and the root
+     * node, which is allocated if the directory has no entry.
+     */
+    fn start_tree<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<(INode, BtreeNode)>
+    where
+        D: Read + Write + Seek,
+    {
+        crate::file::handle_rc_inode(fs, subvol, device, self.get_inode_count())?;
+        let inode = subvol.get_inode(device, self.get_inode_count())?;
+
+        let root = if inode.btree_root == 0 {
+            BtreeNode {
+                block_count: BtreeNode::allocate_on_block_subvol(fs, subvol, device)?,
+                ..Default::default()
+            }
+        } else {
+            let mut root = BtreeNode::load_block(device, inode.btree_root)?;
+            root.block_count = inode.btree_root;
+            root
+        };
+        Ok((inode, root))
+    }
+    /** Record the changed B-Tree in the inode, the B-Tree is released once it has no bucket */
+    fn finish_tree<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        mut inode: INode,
+        mut root: BtreeNode,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        if root.entries.is_empty() {
+            root.destroy(fs, subvol, device)?;
+            inode.btree_root = 0;
+        } else {
+            inode.btree_root = root.block_count;
+        }
+        inode.update_mtime();
+        subvol.set_inode(fs, device, self.get_inode_count(), inode)?;
+
+        self.fd = File::from_inode(subvol, device, self.get_inode_count(), inode)?;
+        Ok(())
+    }
+    pub fn get_inode(&self) -> INode {
+        self.fd.get_inode()
+    }
+    pub fn get_inode_count(&self) -> u64 {
+        self.fd.get_inode_count()
+    }
+    /** Persist changes made through the directory
+     *
+     * Synchronize meta data of the subvolume and the filesystem, then flush the device.
+     */
+    pub fn flush<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        subvol.sync_meta_data(fs, device)?;
+        fs.sync_meta_data(device)?;
+        device.flush()
+    }
+    /** Add file into directory */
+    pub(crate) fn add_file<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        file_name: &str,
+        inode: u64,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.add_files(fs, subvol, device, &[(file_name, inode)])
+    }
+    /** Add files into directory
+     *
+     * Names are checked against the directory once, then entries are added in order of hashes
+     * to an indexed directory, so that each bucket is written once, or appended with a single
+     * write otherwise. Nothing is added if any name is invalid or exists.
+     */
+    pub(crate) fn add_files<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        entries: &[(&str, u64)],
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        subvol.check_writable(fs, device)?;
+
+        let mut names = HashSet::new();
+        for (file_name, _) in entries {
+            if file_name.len() > FILE_NAME_MAX {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("File name '{}' is too long.", file_name),
+                ));
+            }
+            if !names.insert(*file_name) {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("'{}' does already esist", file_name),
+                ));
+            }
+        }
+
+        if is_indexed(fs) {
+            return self.add_files_indexed(fs, subvol, device, entries);
+        }
+
+        if let Some((_, _, file_name)) = self
+            .read_entries(fs, subvol, device)?
+            .into_iter()
+            .find(|(_, _, file_name)| names.contains(file_name.as_str()))
+        {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("'{}' does already esist", file_name),
+            ));
+        }
+
+        /* compact before adding rather than after removing, so removing keeps offsets stable */
+        let size = self.fd.get_inode().size;
+        if size > 0 && self.removed_size(fs, subvol, device)? * 2 > size {
+            self.compact(fs, subvol, device)?;
+        }
+
+        let mut dir_data = Vec::new();
+        for (file_name, inode) in entries {
+            dir_data.extend(inode.to_be_bytes());
+            dir_data.push(file_name.len() as u8);
+            dir_data.extend(file_name.as_bytes());
+        }
+
+        let size = self.fd.get_inode().size;
+        self.fd.write(fs, subvol, device, size, &dir_data)
+    }
+    fn add_files_indexed<D>(
+        &mut self,
         fs: &mut Filesystem,
         subvol: &mut Subvolume,
         device: &mut D,
-        path: P,
-    ) -> IOResult<Self>
+        entries: &[(&str, u64)],
+    ) -> IOResult<()>
     where
         D: Read + Write + Seek,
-        P: AsRef<Path>,
     {
-        let inode_count = create(fs, subvol, device)?;
+        let mut entries = entries
+            .iter()
+            .map(|(file_name, inode)| {
+                Ok((name_hash(fs, file_name)?, *inode, file_name.to_string()))
+            })
+            .collect::<IOResult<Vec<_>>>()?;
+        entries.sort_by_key(|(hash, _, _)| *hash);
 
-        let mut dir = Directory::open(fs, subvol, device, dir_path(path.as_ref()))?;
-        dir.add_file(fs, subvol, device, base_name(path.as_ref()), inode_count)?;
+        let (mut inode, mut root) = self.start_tree(fs, subvol, device)?;
 
-        Ok(Self {
-            fd: File::open_by_inode(subvol, device, inode_count)?,
-        })
+        /* buckets read while checking names are kept for adding entries */
+        let mut buckets: HashMap<u64, Bucket> = HashMap::new();
+        for (hash, _, file_name) in &entries {
+            for entry in buckets_of(device, &root, *hash)? {
+                let bucket = match buckets.entry(entry.key) {
+                    Entry::Occupied(bucket) => bucket.into_mut(),
+                    Entry::Vacant(bucket) => bucket.insert(Bucket::load_entry(device, &entry)?),
+                };
+                if bucket.position(file_name).is_some() {
+                    return Err(Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("'{}' does already esist", file_name),
+                    ));
+                }
+            }
+        }
+
+        let result =
+            Self::insert_entries(fs, subvol, device, &mut root, &mut inode, entries, buckets);
+        let finished = self.finish_tree(fs, subvol, device, inode, root);
+        result.and(finished)
     }
-    pub fn open<D, P>(
+    /** Insert entries sorted by hash into buckets, names are known not to exist
+     *
+     * `buckets` are buckets already loaded, a changed bucket is written once the next entry
+     * goes to another one.
+     */
+    fn insert_entries<D>(
         fs: &mut Filesystem,
         subvol: &mut Subvolume,
         device: &mut D,
-        path: P,
-    ) -> IOResult<Self>
+        root: &mut BtreeNode,
+        inode: &mut INode,
+        entries: Vec<(u64, u64, String)>,
+        mut buckets: HashMap<u64, Bucket>,
+    ) -> IOResult<()>
     where
         D: Read + Write + Seek,
-        P: AsRef<Path>,
     {
-        let mut dir = Self {
-            fd: File::open_by_inode(subvol, device, subvol.entry.root_inode)?,
-        };
-
-        for file in path.as_ref().iter().skip(1) {
-            let dirs = dir.list_dir(fs, subvol, device)?;
-
-            let inode_count;
-            match dirs.get(&file.to_string_lossy().to_string()) {
-                Some(count) => inode_count = *count,
-                None => {
-                    return Err(Error::new(
-                        ErrorKind::NotFound,
-                        format!("'{}' no such file", file.to_string_lossy()),
-                    ))
+        let mut pending: Option<Bucket> = None;
+        for (hash, inode_count, file_name) in entries {
+            let Some(target) = root.floor(device, hash << BUCKET_SEQ_BITS | BUCKET_SEQ_MAX)? else {
+                /* no bucket holds names of hashes this small yet */
+                if let Some(mut bucket) = pending.take() {
+                    bucket.save(fs, subvol, device, root)?;
                 }
-            }
-            let inode = subvol.get_inode(device, inode_count)?;
+                Bucket::create(
+                    fs,
+                    subvol,
+                    device,
+                    root,
+                    inode,
+                    0,
+                    vec![(inode_count, file_name)],
+                )?;
+                continue;
+            };
 
-            /* read link and open orignal directory */
-            if inode.is_symlink() {
-                let original_path = read_link_from_inode(subvol, device, inode_count)?;
-                return Self::open(fs, subvol, device, &original_path);
-            } else if !inode.is_dir() {
-                return Err(Error::new(
-                    ErrorKind::Unsupported,
-                    format!("'{}' is not a directory", file.to_string_lossy()),
-                ));
-            }
-            dir = Self {
-                fd: File::from_inode(device, inode_count, inode)?,
+            let mut bucket = match pending.take() {
+                Some(bucket) if bucket.key == target.key => bucket,
+                other => {
+                    if let Some(mut bucket) = other {
+                        bucket.save(fs, subvol, device, root)?;
+                    }
+                    match buckets.remove(&target.key) {
+                        Some(bucket) => bucket,
+                        None => Bucket::load_entry(device, &target)?,
+                    }
+                }
             };
+            bucket.entries.push((inode_count, file_name));
+            if bucket.size() <= BUCKET_CAPACITY {
+                pending = Some(bucket);
+            } else {
+                Self::split_bucket(fs, subvol, device, root, inode, bucket)?;
+            }
         }
 
-        Ok(dir)
+        if let Some(mut bucket) = pending {
+            bucket.save(fs, subvol, device, root)?;
+        }
+        Ok(())
     }
-    pub fn list_dir<D>(
-        &mut self,
+    /** Spread entries of a bucket beyond its capacity over new buckets, see `Bucket` for keys */
+    fn split_bucket<D>(
         fs: &mut Filesystem,
         subvol: &mut Subvolume,
         device: &mut D,
-    ) -> IOResult<HashMap<String, u64>>
+        root: &mut BtreeNode,
+        inode: &mut INode,
+        mut bucket: Bucket,
+    ) -> IOResult<()>
     where
         D: Read + Write + Seek,
     {
-        let mut files: HashMap<String, u64> = HashMap::new();
-
-        let mut dir_data = vec![0; self.fd.get_inode().size as usize];
-        self.fd.read(
-            fs,
-            subvol,
-            device,
-            0,
-            &mut dir_data,
-            self.fd.get_inode().size,
-        )?;
+        let mut entries = bucket
+            .entries
+            .drain(..)
+            .map(|(inode, file_name)| Ok((name_hash(fs, &file_name)?, inode, file_name)))
+            .collect::<IOResult<Vec<_>>>()?;
+        entries.sort_by_key(|(hash, _, _)| *hash);
+        let chunks = chunk_entries(&entries);
 
-        let mut offset = 0;
-        while offset < self.fd.get_inode().size as usize {
-            let inode = u64::from_be_bytes(dir_data[offset..offset + 8].try_into().unwrap());
-            offset += 8;
-            let str_len = dir_data[offset] as usize;
-            offset += 1;
-            let file_name =
-                String::from_utf8_lossy(&dir_data[offset..offset + str_len]).to_string();
-            offset += str_len;
-            files.insert(file_name, inode);
+        let mut keys = Vec::with_capacity(chunks.len());
+        for (i, chunk) in chunks.iter().enumerate() {
+            let hash = entries[chunk.start].0;
+            let key = if i == 0 {
+                /* names of a hash too many for a bucket take keys of the hash */
+                if entries[chunks[1].start].0 == hash && bucket.key >> BUCKET_SEQ_BITS != hash {
+                    hash << BUCKET_SEQ_BITS
+                } else {
+                    bucket.key
+                }
+            } else if entries[chunk.start - 1].0 == hash {
+                let key = keys[i - 1] + 1;
+                if key & ((1 << BUCKET_SEQ_BITS) - 1) > BUCKET_SEQ_MAX {
+                    return Err(Error::new(
+                        ErrorKind::StorageFull,
+                        format!(
+                            "Too many names share the hash of '{}'.",
+                            entries[chunk.start].2
+                        ),
+                    ));
+                }
+                key
+            } else {
+                hash << BUCKET_SEQ_BITS
+            };
+            keys.push(key);
         }
 
-        Ok(files)
+        /* the bucket keeps the first chunk, once the others are stored */
+        for (chunk, key) in chunks.iter().zip(&keys).skip(1) {
+            let chunk_entries = entries[chunk.clone()]
+                .iter()
+                .map(|(_, inode, file_name)| (*inode, file_name.clone()))
+                .collect();
+            Bucket::create(fs, subvol, device, root, inode, *key, chunk_entries)?;
+        }
+        if keys[0] != bucket.key {
+            bucket.rekey(fs, subvol, device, root, keys[0])?;
+        }
+        bucket.entries = entries
+            .drain(chunks[0].clone())
+            .map(|(_, inode, file_name)| (inode, file_name))
+            .collect();
+        bucket.save(fs, subvol, device, root)
     }
-    /* Find inode under the directory */
-    pub(crate) fn find_inode_by_name<D>(
+    /** Remove a file into directory */
+    pub(crate) fn remove_file<D>(
         &mut self,
         fs: &mut Filesystem,
         subvol: &mut Subvolume,
         device: &mut D,
-        name: &str,
-    ) -> IOResult<u64>
+        file_name: &str,
+    ) -> IOResult<()>
     where
         D: Read + Write + Seek,
     {
-        match self.list_dir(fs, subvol, device)?.get(name) {
-            Some(inode) => Ok(*inode),
-            None => Err(Error::new(
-                ErrorKind::NotFound,
-                format!("No such file '{}'", name),
-            )),
+        subvol.check_writable(fs, device)?;
+
+        if is_indexed(fs) {
+            return self.remove_file_indexed(fs, subvol, device, file_name);
         }
+
+        let mut found = None;
+        self.scan_entries(fs, subvol, device, 0, |offset, _, name| {
+            if name == file_name {
+                found = Some(offset);
+            }
+            found.is_none()
+        })?;
+        let offset = found.ok_or_else(|| Error::from(FsError::NotFound(file_name.to_string())))?;
+
+        self.mark_removed(fs, subvol, device, offset, file_name)
     }
-    pub fn get_inode(&self) -> INode {
-        self.fd.get_inode()
-    }
-    /** Add file into directory */
-    pub(crate) fn add_file<D>(
+    /** Remove an entry from its bucket, a bucket left empty is released rather than merged */
+    fn remove_file_indexed<D>(
         &mut self,
         fs: &mut Filesystem,
         subvol: &mut Subvolume,
         device: &mut D,
         file_name: &str,
-        inode: u64,
     ) -> IOResult<()>
     where
         D: Read + Write + Seek,
     {
-        if self.list_dir(fs, subvol, device)?.contains_key(file_name) {
-            return Err(Error::new(
-                ErrorKind::AlreadyExists,
-                format!("'{}' does already esist", file_name),
-            ));
+        let not_found = || Error::from(FsError::NotFound(file_name.to_string()));
+        if self.load_tree(subvol, device)?.is_none() {
+            return Err(not_found());
         }
-        let mut dir_data = Vec::new();
+        let hash = name_hash(fs, file_name)?;
 
-        dir_data.extend(inode.to_be_bytes());
-        dir_data.push(file_name.len() as u8);
-        dir_data.extend(file_name.as_bytes());
-
-        self.fd
-            .write(fs, subvol, device, self.fd.get_inode().size, &dir_data)?;
+        let (mut inode, mut root) = self.start_tree(fs, subvol, device)?;
+        let mut found = None;
+        for entry in buckets_of(device, &root, hash)? {
+            let bucket = Bucket::load_entry(device, &entry)?;
+            if let Some(i) = bucket.position(file_name) {
+                found = Some((bucket, i));
+                break;
+            }
+        }
+        let (mut bucket, i) = found.ok_or_else(not_found)?;
 
-        Ok(())
+        bucket.entries.remove(i);
+        let result = if bucket.entries.is_empty() {
+            bucket.release(fs, subvol, device, &mut root, &mut inode)
+        } else {
+            bucket.save(fs, subvol, device, &mut root)
+        };
+        let finished = self.finish_tree(fs, subvol, device, inode, root);
+        result.and(finished)
     }
-    /** Remove a file into directory */
-    pub(crate) fn remove_file<D>(
+    /** Mark the entry at the offset removed, or truncate it if it's the last entry */
+    fn mark_removed<D>(
         &mut self,
         fs: &mut Filesystem,
         subvol: &mut Subvolume,
         device: &mut D,
+        offset: u64,
         file_name: &str,
     ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        if offset + 9 + file_name.len() as u64 == self.fd.get_inode().size {
+            self.fd.truncate(fs, subvol, device, offset)
+        } else {
+            self.fd
+                .write(fs, subvol, device, offset, &DIR_ENTRY_REMOVED.to_be_bytes())
+        }
+    }
+    /** Total size of removed entries of a directory without index */
+    fn removed_size<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<u64>
     where
         D: Read + Write + Seek,
     {
@@ -187,28 +1338,30 @@ impl Directory {
             &mut dir_data,
             self.fd.get_inode().size,
         )?;
-
-        let mut offset = 0;
-        while offset < self.fd.get_inode().size as usize {
-            offset += 8;
-            let str_len = dir_data[offset] as usize;
-            offset += 1;
-            let this_file_name =
-                String::from_utf8_lossy(&dir_data[offset..offset + str_len]).to_string();
-            offset += str_len;
-
-            if this_file_name == file_name {
-                for _ in 0..str_len + 8 + 1 {
-                    dir_data.remove(offset - str_len - 8 - 1);
-                }
-                break;
-            }
+        Ok(parse_entries(&dir_data, 0)
+            .into_iter()
+            .filter(|(_, inode, _)| *inode == DIR_ENTRY_REMOVED)
+            .map(|(_, _, file_name)| 9 + file_name.len() as u64)
+            .sum())
+    }
+    /** Drop removed entries of a directory without index */
+    fn compact<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut dir_data = Vec::new();
+        for (_, inode, file_name) in self.read_entries(fs, subvol, device)? {
+            dir_data.extend(inode.to_be_bytes());
+            dir_data.push(file_name.len() as u8);
+            dir_data.extend(file_name.as_bytes());
         }
         self.fd.write(fs, subvol, device, 0, &dir_data)?;
-        self.fd
-            .truncate(fs, subvol, device, dir_data.len() as u64)?;
-
-        Ok(())
+        self.fd.truncate(fs, subvol, device, dir_data.len() as u64)
     }
     /** Create a hard link into directory */
     pub fn add_hard_link<D>(
@@ -222,12 +1375,60 @@ impl Directory {
     where
         D: Read + Write + Seek,
     {
+        self.add_file(fs, subvol, device, file_name, inode)?;
         let mut fd = subvol.get_inode(device, inode)?;
         fd.hlinks += 1;
         subvol.set_inode(fs, device, inode, fd)?;
-        self.add_file(fs, subvol, device, file_name, inode)?;
         Ok(())
     }
+    /** Add a whiteout, which marks the name deleted in an overlay on top of a lower subvolume
+     *
+     * A whiteout takes an inode of its own, it is listed with `FileType::Whiteout` and opening
+     * it fails with `NotFound`. It is removed like a file.
+     */
+    pub fn add_whiteout<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        name: &str,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        subvol.check_writable(fs, device)?;
+
+        let inode_count = subvol.new_inode(fs, device)?;
+        let mut inode = INode {
+            acl: ACL_WHITEOUT << PERMISSION_BITS,
+            ..Default::default()
+        };
+        inode.update_mtime();
+        subvol.set_inode(fs, device, inode_count, inode)?;
+
+        if let Err(err) = self.add_file(fs, subvol, device, name, inode_count) {
+            crate::file::remove_by_inode(fs, subvol, device, inode_count)?;
+            return Err(err);
+        }
+        Ok(())
+    }
+    /** Check if an entry of the directory is a whiteout, `false` if no entry has the name */
+    pub fn is_whiteout<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        name: &str,
+    ) -> IOResult<bool>
+    where
+        D: Read + Write + Seek,
+    {
+        match self.find_inode_by_name(fs, subvol, device, name) {
+            Ok(inode) => Ok(subvol.get_inode(device, inode)?.is_whiteout()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
     /** Remove a directory */
     pub(crate) fn remove<D, P>(
         fs: &mut Filesystem,
@@ -239,26 +1440,61 @@ impl Directory {
         D: Read + Write + Seek,
         P: AsRef<Path>,
     {
-        let dir = Self::open(fs, subvol, device, &path)?;
+        let path = normalize(path.as_ref())?;
+        subvol.check_writable(fs, device)?;
+
+        let (parent, name) = (dir_path(&path)?, base_name(&path)?);
+        let mut dir = Self::open(fs, subvol, device, &path)?;
 
-        if dir.fd.get_inode().size > 0 {
+        if !dir.is_empty(fs, subvol, device)? {
             Err(Error::new(
                 ErrorKind::PermissionDenied,
-                format!("'{}' is not empty.", path.as_ref().to_str().unwrap()),
+                format!("'{}' is not empty.", path.to_str().unwrap()),
             ))
         } else {
             remove_by_inode(fs, subvol, device, dir.fd.get_inode_count())?;
-            Directory::open(fs, subvol, device, dir_path(path.as_ref()))?.remove_file(
-                fs,
-                subvol,
-                device,
-                base_name(path.as_ref()),
-            )?;
+            Directory::open(fs, subvol, device, parent)?.remove_file(fs, subvol, device, name)?;
             Ok(())
         }
     }
 }
 
+#[inline]
+/** Attach file types to entries, loading each inode group once rather than once per entry */
+fn typed_entries<D>(
+    subvol: &mut Subvolume,
+    device: &mut D,
+    entries: Vec<(u64, String)>,
+) -> IOResult<Vec<DirEntry>>
+where
+    D: Read + Write + Seek,
+{
+    let mut inode_groups: HashMap<u64, INodeGroup> = HashMap::new();
+    let mut typed = Vec::with_capacity(entries.len());
+
+    for (inode, name) in entries {
+        let inode_group_count = inode / INODE_PER_GROUP as u64;
+        let inode_group = match inode_groups.entry(inode_group_count) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(subvol.get_inode_group(device, inode_group_count)?)
+            }
+        };
+
+        typed.push(DirEntry {
+            name,
+            inode,
+            file_type: inode_group.inodes[inode as usize % INODE_PER_GROUP].file_type(),
+        });
+    }
+
+    Ok(typed)
+}
+
+fn is_indexed(fs: &Filesystem) -> bool {
+    fs.sb.has_feature_incompat(FEATURE_INCOMPAT_BTREE_DIR)
+}
+
 /** Create a directory and return the inode count */
 pub(crate) fn create<D>(
     fs: &mut Filesystem,
@@ -285,8 +1521,7 @@ pub(crate) fn remove_by_inode<D>(
 where
     D: Read + Write + Seek,
 {
-    let inode = subvol.get_inode(device, inode_count)?;
-    if inode.size > 0 {
+    if !Directory::open_by_inode(subvol, device, inode_count)?.is_empty(fs, subvol, device)? {
         Err(Error::new(
             ErrorKind::PermissionDenied,
             "Directory isn't empty",
@@ -296,3 +1531,575 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{test_fs, CountingDevice};
+
+    thread_local! {
+        /** Make `name_hash` return one of four values on this thread, to test collisions */
+        pub(super) static NARROW_HASH: std::cell::Cell<bool> =
+            const { std::cell::Cell::new(false) };
+    }
+
+    #[test]
+    fn flush() {
+        use crate::cache::{CachedDevice, DEFAULT_CACHE_BLOCKS};
+        use std::io::Cursor;
+
+        let blocks = 2048;
+        let mut device = CachedDevice::new(
+            Cursor::new(vec![0; blocks * crate::block::BLOCK_SIZE]),
+            DEFAULT_CACHE_BLOCKS,
+        );
+        let mut fs = Filesystem::create(&mut device, blocks).unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+
+        device.begin_batch();
+        fs.create_file(&mut subvol, &mut device, "/a").unwrap();
+        fs.create_file(&mut subvol, &mut device, "/b").unwrap();
+        let inode = fs
+            .create_file(&mut subvol, &mut device, "/c")
+            .unwrap()
+            .get_inode_count();
+        let mut dir = Directory::open(&mut fs, &mut subvol, &mut device, "/").unwrap();
+        dir.add_hard_link(&mut fs, &mut subvol, &mut device, inode, "d")
+            .unwrap();
+        dir.remove_file(&mut fs, &mut subvol, &mut device, "b")
+            .unwrap();
+        let expected = dir.list_dir(&mut fs, &mut subvol, &mut device).unwrap();
+        assert!(device.dirty_blocks() > 0);
+
+        dir.flush(&mut fs, &mut subvol, &mut device).unwrap();
+        assert_eq!(device.dirty_blocks(), 0);
+
+        let mut device = device.into_inner();
+        let mut fs = Filesystem::load(&mut device).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        let mut dir = Directory::open(&mut fs, &mut subvol, &mut device, "/").unwrap();
+        assert_eq!(
+            dir.list_dir(&mut fs, &mut subvol, &mut device).unwrap(),
+            expected
+        );
+        assert_eq!(expected.len(), 3);
+        assert_eq!(expected["d"], inode);
+        assert_eq!(subvol.get_inode(&mut device, inode).unwrap().hlinks, 1);
+    }
+
+    #[test]
+    fn siphash_vectors() {
+        /* vectors of the reference implementation, keyed with bytes 0 to 15 */
+        let key: [u8; 16] = std::array::from_fn(|i| i as u8);
+        let message: Vec<u8> = (0..16).collect();
+        assert_eq!(siphash(&key, &message[..0]), 0x726fdb47dd0e0e31);
+        assert_eq!(siphash(&key, &message[..1]), 0x74f839c593dc67fd);
+        assert_eq!(siphash(&key, &message[..8]), 0x93f5f5799a932462);
+        assert_eq!(siphash(&key, &message[..15]), 0xa129ca6149be45e5);
+    }
+
+    #[test]
+    fn dir_hashes() {
+        for (dir_hash, narrow) in [
+            (DirHash::Fnv1a, false),
+            (DirHash::SipHash, false),
+            (DirHash::Fnv1a, true),
+        ] {
+            NARROW_HASH.with(|narrow_hash| narrow_hash.set(narrow));
+            let blocks = 4000;
+            let mut device = std::io::Cursor::new(vec![0; blocks * crate::block::BLOCK_SIZE]);
+            let options = crate::FormatOptions {
+                dir_hash,
+                ..Default::default()
+            };
+            let mut fs = Filesystem::create_with_options(&mut device, blocks, &options).unwrap();
+            fs.sync_meta_data(&mut device).unwrap();
+            let mut fs = Filesystem::load(&mut device).unwrap();
+            assert_eq!(DirHash::from_id(fs.sb.dir_hash), Some(dir_hash));
+            let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+
+            /* long names, so that names of a narrow hash take several buckets */
+            let names: Vec<String> = (0..600).map(|i| format!("name{i:0>40}")).collect();
+            let inodes: Vec<u64> = names
+                .iter()
+                .map(|name| {
+                    fs.create_file(&mut subvol, &mut device, format!("/{name}"))
+                        .unwrap()
+                        .get_inode_count()
+                })
+                .collect();
+            /* remove some names from buckets shared with other names */
+            for name in names.iter().step_by(3) {
+                fs.remove_file(&mut subvol, &mut device, format!("/{name}"))
+                    .unwrap();
+            }
+
+            let mut root = Directory::open(&mut fs, &mut subvol, &mut device, "/").unwrap();
+            assert_eq!(
+                root.entry_count(&mut fs, &mut subvol, &mut device).unwrap(),
+                400
+            );
+            for (i, (name, inode)) in names.iter().zip(&inodes).enumerate() {
+                let found = root.find_inode_by_name(&mut fs, &mut subvol, &mut device, name);
+                if i % 3 == 0 {
+                    assert_eq!(found.unwrap_err().kind(), ErrorKind::NotFound);
+                } else {
+                    assert_eq!(found.unwrap(), *inode, "{dir_hash:?} {narrow} {name}");
+                }
+            }
+        }
+
+        /* each name of the narrow hash collides with about a quarter of the others */
+        let mut fs = Filesystem::default();
+        assert!(name_hash(&fs, "a").unwrap() < 4 && name_hash(&fs, "b").unwrap() < 4);
+        NARROW_HASH.with(|narrow_hash| narrow_hash.set(false));
+
+        fs.sb.dir_hash = 7;
+        assert_eq!(
+            name_hash(&fs, "a").unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn unknown_dir_hash() {
+        let blocks = 2000;
+        let mut device = std::io::Cursor::new(vec![0; blocks * crate::block::BLOCK_SIZE]);
+        let mut fs = Filesystem::create(&mut device, blocks).unwrap();
+        fs.sb.dir_hash = DirHash::SipHash.id();
+        fs.sync_meta_data(&mut device).unwrap();
+        assert_eq!(
+            Filesystem::load(&mut device).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        fs.sb.dir_hash = 7;
+        fs.sb.feature_incompat |= crate::FEATURE_INCOMPAT_DIR_HASH;
+        fs.sync_meta_data(&mut device).unwrap();
+        assert_eq!(
+            Filesystem::load(&mut device).unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn churn_keeps_directory_compact() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        for i in 0..10 {
+            fs.create_file(&mut subvol, &mut device, format!("/keep{}", i))
+                .unwrap();
+        }
+        let size = subvol
+            .get_inode(&mut device, subvol.entry.root_inode)
+            .unwrap()
+            .size;
+        let used_blocks = fs.sb.used_blocks;
+
+        for i in 0..1000 {
+            let path = format!("/tmp{}", i);
+            fs.create_file(&mut subvol, &mut device, &path).unwrap();
+            fs.remove_file(&mut subvol, &mut device, &path).unwrap();
+        }
+
+        let root = subvol
+            .get_inode(&mut device, subvol.entry.root_inode)
+            .unwrap();
+        assert!(root.size <= 2 * size, "{} > 2 * {}", root.size, size);
+        assert_eq!(fs.sb.used_blocks, used_blocks);
+
+        let mut names = fs.list_dir(&mut subvol, &mut device, "/").unwrap();
+        names.sort();
+        assert_eq!(
+            names,
+            (0..10).map(|i| format!("keep{}", i)).collect::<Vec<_>>()
+        );
+        for i in 0..10 {
+            assert!(fs.is_file(&mut subvol, &mut device, format!("/keep{}", i)));
+        }
+    }
+
+    #[test]
+    fn snapshot_keeps_buckets() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        for i in 0..200 {
+            fs.create_file(&mut subvol, &mut device, format!("/f{}", i))
+                .unwrap();
+        }
+        let snap = fs.create_snapshot(&mut device, subvol.entry.id).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+
+        /* removing most entries copies out buckets of the origin */
+        for i in 0..190 {
+            fs.remove_file(&mut subvol, &mut device, format!("/f{}", i))
+                .unwrap();
+        }
+        assert_eq!(
+            fs.list_dir(&mut subvol, &mut device, "/").unwrap().len(),
+            10
+        );
+        assert!(fs.is_file(&mut subvol, &mut device, "/f195"));
+
+        let mut snap = fs.get_subvolume(&mut device, snap).unwrap();
+        assert_eq!(fs.list_dir(&mut snap, &mut device, "/").unwrap().len(), 200);
+        for i in 0..200 {
+            assert!(fs.is_file(&mut snap, &mut device, format!("/f{}", i)));
+        }
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        assert!(fs.verify_sharing(&mut device, false).unwrap().is_empty());
+        fs.check_used_blocks(&mut device).unwrap();
+    }
+
+    #[test]
+    fn add_files() {
+        let (mut fs, device, mut subvol) = test_fs(4000);
+        let mut device = CountingDevice::new(device);
+        let inodes = (0..1000)
+            .map(|_| crate::file::create(&mut fs, &mut subvol, &mut device).unwrap())
+            .collect::<Vec<_>>();
+        let names = (0..1000).map(|i| format!("f{i}")).collect::<Vec<_>>();
+        let entries = names
+            .iter()
+            .map(String::as_str)
+            .zip(inodes.iter().copied())
+            .collect::<Vec<_>>();
+
+        fs.mkdir(&mut subvol, &mut device, "/single").unwrap();
+        let mut dir = Directory::open(&mut fs, &mut subvol, &mut device, "/single").unwrap();
+        let reads = device.reads;
+        for (name, inode) in &entries {
+            dir.add_file(&mut fs, &mut subvol, &mut device, name, *inode)
+                .unwrap();
+        }
+        let single_reads = device.reads - reads;
+
+        fs.mkdir(&mut subvol, &mut device, "/batch").unwrap();
+        let mut dir = Directory::open(&mut fs, &mut subvol, &mut device, "/batch").unwrap();
+        let reads = device.reads;
+        dir.add_files(&mut fs, &mut subvol, &mut device, &entries)
+            .unwrap();
+        let batch_reads = device.reads - reads;
+        assert!(batch_reads * 5 < single_reads);
+
+        let listed = dir.list_dir(&mut fs, &mut subvol, &mut device).unwrap();
+        assert_eq!(listed.len(), 1000);
+        for (name, inode) in &entries {
+            assert_eq!(listed[*name], *inode);
+            assert_eq!(
+                dir.find_inode_by_name(&mut fs, &mut subvol, &mut device, name)
+                    .unwrap(),
+                *inode
+            );
+        }
+
+        /* nothing is added if any name exists */
+        let size = dir.get_inode().size;
+        for entries in [
+            vec![("new", inodes[0]), ("f500", inodes[1])],
+            vec![("new", inodes[0]), ("new", inodes[1])],
+        ] {
+            assert_eq!(
+                dir.add_files(&mut fs, &mut subvol, &mut device, &entries)
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::AlreadyExists
+            );
+        }
+        assert_eq!(dir.get_inode().size, size);
+    }
+
+    #[test]
+    fn large_directory() {
+        let (mut fs, device, mut subvol) = test_fs(8000);
+        let mut device = CountingDevice::new(device);
+        let io = |device: &CountingDevice| device.reads + device.writes;
+
+        /* I/O of creating and looking up a window of files, at the current size */
+        let window = |fs: &mut Filesystem,
+                      subvol: &mut Subvolume,
+                      device: &mut CountingDevice,
+                      start: usize| {
+            let before = io(device);
+            for i in start..start + 500 {
+                fs.create_file(subvol, device, format!("/f{i}")).unwrap();
+            }
+            let created = io(device) - before;
+            let before = io(device);
+            let mut root = Directory::open(fs, subvol, device, "/").unwrap();
+            for i in (0..start + 500).step_by((start + 500) / 500) {
+                root.find_inode_by_name(fs, subvol, device, &format!("f{i}"))
+                    .unwrap();
+            }
+            (created, io(device) - before)
+        };
+        let (small_create, small_lookup) = window(&mut fs, &mut subvol, &mut device, 0);
+        for i in 500..19500 {
+            fs.create_file(&mut subvol, &mut device, format!("/f{i}"))
+                .unwrap();
+        }
+        let (large_create, large_lookup) = window(&mut fs, &mut subvol, &mut device, 19500);
+
+        /* Growing the directory 40 times must not grow the cost of a single operation with it */
+        assert!(large_create < small_create * 2);
+        assert!(large_lookup < small_lookup * 2);
+
+        let mut root = Directory::open(&mut fs, &mut subvol, &mut device, "/").unwrap();
+        for i in 0..20000 {
+            root.find_inode_by_name(&mut fs, &mut subvol, &mut device, &format!("f{i}"))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn list_matching() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        for name in [
+            "log", "log.1", "logs", "catalog", "blog", "lo", "data.log", "lag",
+        ] {
+            fs.create_file(&mut subvol, &mut device, format!("/{name}"))
+                .unwrap();
+        }
+        /* removed entries are skipped */
+        fs.remove_file(&mut subvol, &mut device, "/logs").unwrap();
+
+        let mut dir = Directory::open(&mut fs, &mut subvol, &mut device, "/").unwrap();
+        let mut matching = |pattern| {
+            let mut names = dir
+                .list_matching(&mut fs, &mut subvol, &mut device, pattern)
+                .unwrap()
+                .into_keys()
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        assert_eq!(matching("log*"), ["log", "log.1"]);
+        assert_eq!(matching("l?g"), ["lag", "log"]);
+        assert_eq!(matching("*log"), ["blog", "catalog", "data.log", "log"]);
+        assert_eq!(matching("*.*"), ["data.log", "log.1"]);
+        assert!(matching("x*").is_empty());
+
+        /* entries spanning chunks of the scan */
+        for i in 0..1000 {
+            fs.create_file(&mut subvol, &mut device, format!("/f{i}"))
+                .unwrap();
+        }
+        let mut dir = Directory::open(&mut fs, &mut subvol, &mut device, "/").unwrap();
+        let names = dir
+            .list_matching(&mut fs, &mut subvol, &mut device, "f99*")
+            .unwrap();
+        assert_eq!(names.len(), 11);
+        assert_eq!(
+            dir.list_matching(&mut fs, &mut subvol, &mut device, "*")
+                .unwrap()
+                .len(),
+            1007
+        );
+    }
+
+    #[test]
+    fn whiteout() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        fs.create_file(&mut subvol, &mut device, "/file").unwrap();
+        let mut root = Directory::open(&mut fs, &mut subvol, &mut device, "/").unwrap();
+        root.add_whiteout(&mut fs, &mut subvol, &mut device, "gone")
+            .unwrap();
+        assert!(root
+            .is_whiteout(&mut fs, &mut subvol, &mut device, "gone")
+            .unwrap());
+        assert!(!root
+            .is_whiteout(&mut fs, &mut subvol, &mut device, "file")
+            .unwrap());
+        assert!(!root
+            .is_whiteout(&mut fs, &mut subvol, &mut device, "missing")
+            .unwrap());
+        assert_eq!(
+            root.add_whiteout(&mut fs, &mut subvol, &mut device, "file")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::AlreadyExists
+        );
+
+        let mut entries = root
+            .list_dir_typed(&mut fs, &mut subvol, &mut device)
+            .unwrap();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let types: Vec<(&str, FileType)> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.file_type))
+            .collect();
+        assert_eq!(
+            types,
+            [
+                ("file", FileType::RegularFile),
+                ("gone", FileType::Whiteout)
+            ]
+        );
+
+        /* a whiteout is not followed as a file or a directory */
+        let err = fs.open_file(&mut subvol, &mut device, "/gone").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(
+            FsError::from_io(&err),
+            Some(&FsError::NotFound("gone".to_string()))
+        );
+        assert_eq!(
+            fs.stat(&mut subvol, &mut device, "/gone")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            fs.open_file(&mut subvol, &mut device, "/gone/f")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        assert!(fs
+            .lstat(&mut subvol, &mut device, "/gone")
+            .unwrap()
+            .is_whiteout());
+
+        /* it is removed like a file, then the name can be taken again */
+        let (used, _) = subvol.inode_stats(&mut device).unwrap();
+        fs.remove_file(&mut subvol, &mut device, "/gone").unwrap();
+        assert_eq!(subvol.inode_stats(&mut device).unwrap().0, used - 1);
+        fs.create_file(&mut subvol, &mut device, "/gone").unwrap();
+    }
+
+    #[test]
+    fn emptiness() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        let mut dir = fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        assert!(dir.is_empty(&mut fs, &mut subvol, &mut device).unwrap());
+        for name in ["a", "b", "c"] {
+            fs.create_file(&mut subvol, &mut device, format!("/dir/{name}"))
+                .unwrap();
+        }
+        let mut dir = Directory::open(&mut fs, &mut subvol, &mut device, "/dir").unwrap();
+        assert!(!dir.is_empty(&mut fs, &mut subvol, &mut device).unwrap());
+        assert_eq!(
+            dir.entry_count(&mut fs, &mut subvol, &mut device).unwrap(),
+            3
+        );
+        for name in ["a", "b", "c"] {
+            fs.remove_file(&mut subvol, &mut device, format!("/dir/{name}"))
+                .unwrap();
+        }
+        /* the last bucket is released along with the B-Tree */
+        let used_blocks = fs.sb.used_blocks;
+        let mut dir = Directory::open(&mut fs, &mut subvol, &mut device, "/dir").unwrap();
+        assert_eq!(dir.get_inode().btree_root, 0);
+        assert_eq!(dir.get_inode().size, 0);
+        assert!(dir.is_empty(&mut fs, &mut subvol, &mut device).unwrap());
+        fs.rmdir(&mut subvol, &mut device, "/dir").unwrap();
+        assert!(fs.sb.used_blocks < used_blocks);
+
+        /* an entry marked removed is left behind in a directory without index */
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        fs.sb.feature_incompat &= !FEATURE_INCOMPAT_BTREE_DIR;
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        let mut placeholder = DIR_ENTRY_REMOVED.to_be_bytes().to_vec();
+        placeholder.push(1);
+        placeholder.push(b'x');
+        let mut dir = Directory::open(&mut fs, &mut subvol, &mut device, "/dir").unwrap();
+        dir.fd
+            .write(&mut fs, &mut subvol, &mut device, 0, &placeholder)
+            .unwrap();
+        assert!(dir.get_inode().size > 0);
+        assert!(dir.is_empty(&mut fs, &mut subvol, &mut device).unwrap());
+        assert_eq!(
+            dir.entry_count(&mut fs, &mut subvol, &mut device).unwrap(),
+            0
+        );
+        /* renaming over the directory and removing it see no entry */
+        fs.mkdir(&mut subvol, &mut device, "/empty").unwrap();
+        fs.rename(&mut subvol, &mut device, "/empty", "/dir")
+            .unwrap();
+        let mut dir = Directory::open(&mut fs, &mut subvol, &mut device, "/dir").unwrap();
+        dir.fd
+            .write(&mut fs, &mut subvol, &mut device, 0, &placeholder)
+            .unwrap();
+        fs.rmdir(&mut subvol, &mut device, "/dir").unwrap();
+        assert!(!fs.is_dir(&mut subvol, &mut device, "/dir"));
+    }
+
+    #[test]
+    fn read_entries_from() {
+        let (mut fs, mut device, mut subvol) = test_fs(4000);
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/dir/sub").unwrap();
+        for i in 0..999 {
+            fs.create_file(&mut subvol, &mut device, format!("/dir/f{i}"))
+                .unwrap();
+        }
+        let mut dir = Directory::open(&mut fs, &mut subvol, &mut device, "/dir").unwrap();
+
+        let mut names = Vec::new();
+        let mut cookie = 0;
+        loop {
+            let (entries, next) = dir
+                .read_entries_from(&mut fs, &mut subvol, &mut device, cookie, 64)
+                .unwrap();
+            if entries.is_empty() {
+                break;
+            }
+            assert!(entries.len() <= 64);
+            for entry in entries {
+                let expected = if entry.name == "sub" {
+                    FileType::Directory
+                } else {
+                    FileType::RegularFile
+                };
+                assert_eq!(entry.file_type, expected);
+                names.push(entry.name);
+            }
+            cookie = next;
+        }
+        assert_eq!(names.len(), 1000);
+        assert_eq!(names.iter().collect::<HashSet<_>>().len(), 1000);
+
+        /* remove entries already read and entries ahead while paging, as `rm -rf` does */
+        let mut names = Vec::new();
+        let mut first_page = Vec::new();
+        let mut cookie = 0;
+        loop {
+            let (entries, next) = dir
+                .read_entries_from(&mut fs, &mut subvol, &mut device, cookie, 64)
+                .unwrap();
+            if entries.is_empty() {
+                break;
+            }
+            for entry in entries {
+                if entry.name != "sub" {
+                    fs.remove_file(&mut subvol, &mut device, format!("/dir/{}", entry.name))
+                        .unwrap();
+                }
+                names.push(entry.name);
+            }
+            if first_page.is_empty() {
+                first_page = names.clone();
+            }
+            /* every tenth file ahead is removed by someone else */
+            for i in (0..999).filter(|i| i % 10 == 0) {
+                let name = format!("f{i}");
+                if !names.contains(&name) {
+                    let _ = fs.remove_file(&mut subvol, &mut device, format!("/dir/{name}"));
+                }
+            }
+            cookie = next;
+        }
+        assert_eq!(names.iter().collect::<HashSet<_>>().len(), names.len());
+        assert!(names.contains(&"sub".to_string()));
+        for i in 0..999 {
+            let name = format!("f{i}");
+            assert_eq!(
+                names.contains(&name),
+                i % 10 != 0 || first_page.contains(&name)
+            );
+        }
+        assert_eq!(
+            fs.list_dir(&mut subvol, &mut device, "/dir").unwrap(),
+            vec!["sub".to_string()]
+        );
+    }
+}
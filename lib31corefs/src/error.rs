@@ -0,0 +1,91 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+/**
+ * Errors of filesystem operations
+ *
+ * Operations return `std::io::Error`, which carries an `FsError` as its inner error when the
+ * failure is one of these, so callers can tell e.g. running out of space from corruption:
+ *
+ * ```ignore
+ * if let Some(FsError::NoSpace) = FsError::from_io(&err) { ... }
+ * ```
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsError {
+    /** No free block is left on the device */
+    NoSpace,
+    /** Disk quota of the subvolume is exceeded */
+    QuotaExceeded(u64),
+    /** Meta data on the device is inconsistent */
+    CorruptedMetadata(String),
+    /** No file of the name exists in the directory */
+    NotFound(String),
+    /** The path is expected to be a directory */
+    NotADirectory(String),
+    /** The subvolume is read-only */
+    ReadOnly(u64),
+    /** Max count of inodes of the subvolume is reached */
+    NoInodes(u64),
+    /** More than `SYMLINK_MAX_FOLLOW` symbol links are followed, like `ELOOP` */
+    TooManyLinks,
+}
+
+impl FsError {
+    /** Kind of the `std::io::Error` carrying the error */
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::NoSpace => ErrorKind::StorageFull,
+            Self::QuotaExceeded(_) => ErrorKind::Other,
+            Self::CorruptedMetadata(_) => ErrorKind::InvalidData,
+            Self::NotFound(_) => ErrorKind::NotFound,
+            Self::NotADirectory(_) => ErrorKind::Unsupported,
+            Self::ReadOnly(_) => ErrorKind::PermissionDenied,
+            Self::NoInodes(_) => ErrorKind::StorageFull,
+            Self::TooManyLinks => ErrorKind::Other,
+        }
+    }
+    /** Get the error carried by an `std::io::Error` returned by the filesystem */
+    pub fn from_io(err: &Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSpace => write!(f, "No space left on the device."),
+            Self::QuotaExceeded(id) => write!(f, "Disk quota of subvolume {id} exceeded."),
+            Self::CorruptedMetadata(msg) => write!(f, "{msg}"),
+            Self::NotFound(name) => write!(f, "No such file '{name}'"),
+            Self::NotADirectory(path) => write!(f, "'{path}' is not a directory."),
+            Self::ReadOnly(id) => write!(f, "Subvolume {id} is read-only."),
+            Self::NoInodes(id) => write!(f, "No inode left in subvolume {id}."),
+            Self::TooManyLinks => write!(f, "Too many levels of symbol links."),
+        }
+    }
+}
+
+impl std::error::Error for FsError {}
+
+impl From<FsError> for Error {
+    fn from(err: FsError) -> Self {
+        Error::new(err.kind(), err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn into_io_error() {
+        let err = Error::from(FsError::QuotaExceeded(3));
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert_eq!(err.to_string(), "Disk quota of subvolume 3 exceeded.");
+        assert_eq!(FsError::from_io(&err), Some(&FsError::QuotaExceeded(3)));
+
+        assert_eq!(FsError::from_io(&Error::other("other")), None);
+        assert_eq!(FsError::from_io(&Error::from(ErrorKind::NotFound)), None);
+    }
+}
@@ -1,21 +1,199 @@
-use crate::block::{load_block, save_block, Block, INodeGroup, BLOCK_SIZE};
-use crate::btree::{BtreeNode, BtreeType};
+use crate::block::{
+    load_block, save_block, save_blocks, Block, INodeGroup, LinkedContentTable, BLOCK_SIZE,
+};
+use crate::btree::{BtreeEntry, BtreeNode, BtreeType};
 use crate::dir::Directory;
 use crate::inode::{INode, ACL_REGULAR_FILE, INODE_PER_GROUP, PERMISSION_BITS};
 use crate::subvol::Subvolume;
-use crate::symlink::read_link_from_inode;
-use crate::utils::{base_name, dir_path};
-use crate::Filesystem;
+use crate::symlink::{read_link_from_inode, release_content, too_many_links};
+use crate::utils::{base_name, dir_path, normalize};
+use crate::{xattr, Filesystem, FsError, FEATURE_INCOMPAT_COMPRESSION, SYMLINK_MAX_FOLLOW};
 
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
 use std::io::{Error, ErrorKind, Result as IOResult};
 use std::io::{Read, Seek, Write};
 use std::path::Path;
 
+/** Name of the extended attribute enabling compression of a regular file */
+pub const COMPRESSION_XATTR: &[u8] = b"system.compression";
+
+/** Options of `File::copy_with_options` */
+#[derive(Default, Debug, Clone)]
+pub struct CopyOptions {
+    /** Keep owner, access and modification times and extended attributes, like `cp -p` */
+    pub preserve: bool,
+}
+
+/** Count of data blocks compressed together */
+pub(crate) const CLUSTER_BLOCKS: u64 = 16;
+/** Free blocks a write leaves to the block tree when allocating a run of data blocks */
+const RUN_TREE_MARGIN: u64 = 16;
+const CLUSTER_SIZE: usize = CLUSTER_BLOCKS as usize * BLOCK_SIZE;
+
+/** Flag of the first B-Tree value of a compressed cluster */
+const VALUE_COMPRESSED: u64 = 1 << 63;
+const VALUE_LEN_SHIFT: u64 = 40;
+/** Mask of the block count in a B-Tree value of data blocks */
+pub(crate) const VALUE_BLOCK_MASK: u64 = (1 << VALUE_LEN_SHIFT) - 1;
+
+/** A block newly mapped by `File::write`, and the entry it replaced */
+struct MappedBlock {
+    key: u64,
+    block: u64,
+    old: Option<BtreeEntry>,
+}
+
+/**
+ * Options to open a regular file, like `std::fs::OpenOptions`, see `Filesystem::open_options`
+ *
+ * A file opened otherwise is readable and writable.
+ */
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
 #[derive(Debug)]
 pub struct File {
     inode: INode,
     inode_count: u64,
     btree_root: Option<BtreeNode>,
+    /** Compression is enabled by `COMPRESSION_XATTR` when the file is opened */
+    compress: bool,
+    readable: bool,
+    writable: bool,
+    /** Every write goes to the end of the file */
+    append: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OpenOptions {
+    /** Options with every access disabled */
+    pub fn new() -> Self {
+        Self {
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+        }
+    }
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+    /** Write at the end of the file whatever the offset, implies `write` */
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+    /** Truncate an existing file to zero length */
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+    /** Create the file if it doesn't exist, a dangling symbol link creates its target */
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+    /** Create the file, failing with `AlreadyExists` if the path exists, even as a symbol link
+     *
+     * `create` and `truncate` are ignored then.
+     */
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+    /** Open a file with the options */
+    pub fn open<D, P>(
+        &self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<File>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        self.check()?;
+        let mut path = normalize(path.as_ref())?;
+
+        if self.create_new {
+            return match fs.resolve_nofollow(subvol, device, &path) {
+                Ok(_) => Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("'{}' already exists.", path.display()),
+                )),
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    Ok(self.apply(File::create(fs, subvol, device, &path)?))
+                }
+                Err(err) => Err(err),
+            };
+        }
+
+        for _ in 0..=SYMLINK_MAX_FOLLOW {
+            let inode_count = match fs.resolve_nofollow(subvol, device, &path) {
+                Ok((_, _, inode_count)) => inode_count,
+                /* the parent directory must exist, which `File::create` reports otherwise */
+                Err(err) if err.kind() == ErrorKind::NotFound && self.create => {
+                    return Ok(self.apply(File::create(fs, subvol, device, &path)?));
+                }
+                Err(err) => return Err(err),
+            };
+
+            if subvol.get_inode(device, inode_count)?.is_symlink() {
+                let point_to = read_link_from_inode(subvol, device, inode_count)?;
+                path = normalize(&dir_path(&path)?.join(point_to))?;
+                continue;
+            }
+            let mut fd = File::open(fs, subvol, device, &path)?;
+            if self.truncate {
+                fd.truncate(fs, subvol, device, 0)?;
+            }
+            return Ok(self.apply(fd));
+        }
+
+        Err(too_many_links())
+    }
+    /** Reject combinations `std::fs::OpenOptions` rejects */
+    fn check(&self) -> IOResult<()> {
+        let writable = self.write || self.append;
+        let message = if !self.read && !writable {
+            "Neither read nor write access is requested."
+        } else if (self.truncate || self.create || self.create_new) && !writable {
+            "Creating or truncating a file requires write access."
+        } else if self.truncate && self.append {
+            "A file can't be both truncated and appended."
+        } else {
+            return Ok(());
+        };
+        Err(Error::new(ErrorKind::InvalidInput, message))
+    }
+    fn apply(&self, mut fd: File) -> File {
+        fd.readable = self.read;
+        fd.writable = self.write || self.append;
+        fd.append = self.append;
+        fd
+    }
 }
 
 impl File {
@@ -30,14 +208,85 @@ impl File {
         D: Read + Write + Seek,
         P: AsRef<Path>,
     {
+        let path = normalize(path.as_ref())?;
+        let (parent, name) = (dir_path(&path)?, base_name(&path)?);
         let inode_count = create(fs, subvol, device)?;
 
-        let mut dir = Directory::open(fs, subvol, device, dir_path(path.as_ref()))?;
-        dir.add_file(fs, subvol, device, base_name(path.as_ref()), inode_count)?;
+        if let Err(err) = Directory::open(fs, subvol, device, parent)
+            .and_then(|mut dir| dir.add_file(fs, subvol, device, name, inode_count))
+        {
+            remove_by_inode(fs, subvol, device, inode_count)?;
+            return Err(err);
+        }
 
         Self::open_by_inode(subvol, device, inode_count)
     }
-    pub(crate) fn from_inode<D>(device: &mut D, inode_count: u64, inode: INode) -> IOResult<Self>
+    /** Create files in a directory, which is updated once for all of them
+     *
+     * Nothing is created if any name is invalid or exists.
+     */
+    pub fn create_all<D, P>(
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        dir: P,
+        names: &[&str],
+    ) -> IOResult<Vec<Self>>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        if let Some(name) = names
+            .iter()
+            .find(|name| matches!(**name, "" | "." | "..") || name.contains(['/', '\0']))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid file name '{name}'."),
+            ));
+        }
+        let mut dir = Directory::open(fs, subvol, device, dir)?;
+
+        let mut inodes = Vec::with_capacity(names.len());
+        let result = names
+            .iter()
+            .try_for_each(|_| {
+                inodes.push(create(fs, subvol, device)?);
+                Ok(())
+            })
+            .and_then(|()| {
+                let entries: Vec<(&str, u64)> =
+                    names.iter().copied().zip(inodes.iter().copied()).collect();
+                dir.add_files(fs, subvol, device, &entries)
+            });
+        if let Err(err) = result {
+            for inode_count in inodes {
+                remove_by_inode(fs, subvol, device, inode_count)?;
+            }
+            return Err(err);
+        }
+
+        inodes
+            .into_iter()
+            .map(|inode_count| Self::open_by_inode(subvol, device, inode_count))
+            .collect()
+    }
+    /** Return `PermissionDenied` if the file is not opened for an access */
+    fn check_access(&self, allowed: bool) -> IOResult<()> {
+        if !allowed {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "File is not opened for the access.",
+            ));
+        }
+        Ok(())
+    }
+    pub(crate) fn from_inode<D>(
+        subvol: &Subvolume,
+        device: &mut D,
+        inode_count: u64,
+        inode: INode,
+    ) -> IOResult<Self>
     where
         D: Read + Write + Seek,
     {
@@ -48,11 +297,18 @@ impl File {
         } else {
             None
         };
+        let compress = inode.is_file()
+            && inode.xattr != 0
+            && xattr::get(subvol, device, inode_count, COMPRESSION_XATTR)?.is_some();
 
         Ok(Self {
             inode,
             inode_count,
             btree_root,
+            compress,
+            readable: true,
+            writable: true,
+            append: false,
         })
     }
     /** Open regular file by absolute path */
@@ -66,19 +322,39 @@ impl File {
         D: Read + Write + Seek,
         P: AsRef<Path>,
     {
-        let inode_count = Directory::open(fs, subvol, device, dir_path(path.as_ref()))?
-            .find_inode_by_name(fs, subvol, device, base_name(path.as_ref()))?;
+        Self::open_following(fs, subvol, device, &normalize(path.as_ref())?, 0)
+    }
+    /** Open a file after following `links` symbol links */
+    fn open_following<D>(
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: &Path,
+        links: usize,
+    ) -> IOResult<Self>
+    where
+        D: Read + Write + Seek,
+    {
+        let inode_count = Directory::open(fs, subvol, device, dir_path(path)?)?
+            .find_inode_by_name(fs, subvol, device, base_name(path)?)?;
 
         let inode = subvol.get_inode(device, inode_count)?;
 
         /* read link and open orignal file */
-        if inode.is_symlink() {
-            let real_path = read_link_from_inode(subvol, device, inode_count)?;
-            Self::open(fs, subvol, device, &real_path)
+        if inode.is_whiteout() {
+            Err(FsError::NotFound(base_name(path)?.to_string()).into())
+        } else if inode.is_symlink() {
+            if links >= SYMLINK_MAX_FOLLOW {
+                return Err(too_many_links());
+            }
+            /* relative targets start from the directory of the link */
+            let real_path =
+                dir_path(path)?.join(read_link_from_inode(subvol, device, inode_count)?);
+            Self::open_following(fs, subvol, device, &normalize(&real_path)?, links + 1)
         } else if inode.is_dir() {
             Err(Error::new(
                 ErrorKind::Unsupported,
-                format!("'{}' is a directory.", path.as_ref().to_str().unwrap()),
+                format!("'{}' is a directory.", path.to_str().unwrap()),
             ))
         } else {
             Self::open_by_inode(subvol, device, inode_count)
@@ -94,22 +370,14 @@ impl File {
         D: Read + Write + Seek,
     {
         let inode = subvol.get_inode(device, inode_count)?;
-
-        let btree_root = if inode.btree_root != 0 {
-            let mut node = BtreeNode::load_block(device, inode.btree_root)?;
-            node.block_count = inode.btree_root;
-            Some(node)
-        } else {
-            None
-        };
-
-        Ok(Self {
-            inode,
-            inode_count,
-            btree_root,
-        })
+        Self::from_inode(subvol, device, inode_count, inode)
     }
-    /** Write data */
+    /** Write data
+     *
+     * Blocks which are not mapped or shared with snapshots are allocated before any data is
+     * overwritten, so running out of space or quota leaves the file as it was. Compressed
+     * clusters are kept whole, but those written before the failure keep the new data.
+     */
     pub fn write<D>(
         &mut self,
         fs: &mut Filesystem,
@@ -121,64 +389,408 @@ impl File {
     where
         D: Read + Write + Seek,
     {
+        self.check_access(self.writable)?;
+        subvol.check_writable(fs, device)?;
         self.handle_rc_inode(fs, subvol, device)?;
-
-        if self.btree_root.is_none() {
-            self.inode.btree_root = BtreeNode::allocate_on_block_subvol(fs, subvol, device)?;
-            self.btree_root = Some(BtreeNode {
-                block_count: self.inode.btree_root,
-                r#type: BtreeType::Leaf,
-                ..Default::default()
-            });
+        if self.append {
+            offset = self.inode.size;
         }
 
+        let new_root = self.create_btree_root(fs, subvol, device)?;
+        let mut mapped = Vec::new();
+        /* blocks allocated ahead in a run are released if they are not mapped */
+        let mut run = 0..0;
+        let result = self.map_blocks(fs, subvol, device, offset, data, &mut mapped, &mut run);
+        self.finish_mapping(fs, subvol, device, result, &mut mapped, run, new_root)?;
+
+        let clustered = fs.sb.has_feature_incompat(FEATURE_INCOMPAT_COMPRESSION);
+        let compress = clustered && self.compress;
+        /* whether the last looked up cluster is compressed */
+        let mut checked_cluster: Option<(u64, bool)> = None;
+
         while !data.is_empty() {
             let block_count = offset / BLOCK_SIZE as u64; // the block count to be write
             let block_offset = offset % BLOCK_SIZE as u64; // the relative offset to the block
 
+            /* compressed clusters are rewritten as a whole */
+            let cluster = block_count / CLUSTER_BLOCKS;
+            if clustered
+                && (compress || self.is_compressed_cluster(device, cluster, &mut checked_cluster))
+            {
+                let written_size =
+                    self.write_cluster_range(fs, subvol, device, offset, data, compress)?;
+                checked_cluster = Some((cluster, compress));
+                data = &data[written_size..];
+                offset += written_size as u64;
+                continue;
+            }
+
             let written_size = std::cmp::min(data.len(), BLOCK_SIZE - block_offset as usize);
-            if let Some(btree_root) = &mut self.btree_root {
-                /* data block has been allocated */
-                if let Ok(entry) = btree_root.lookup(device, block_count) {
-                    let block = entry.value;
-                    let mut data_block = load_block(device, block)?;
-
-                    data_block[block_offset as usize..block_offset as usize + written_size]
-                        .copy_from_slice(&data[..written_size]);
-
-                    if entry.rc > 0 {
-                        let new_block = crate::block::block_copy_out(fs, subvol, device, block)?;
-                        btree_root.modify(fs, subvol, device, block_count, new_block)?;
-                        self.inode.btree_root = btree_root.block_count;
-                        save_block(device, new_block, data_block)?;
-                    } else {
-                        save_block(device, block, data_block)?;
-                    }
-                } else {
-                    let data_block_count = subvol.new_block(fs, device)?;
-                    btree_root.insert(fs, subvol, device, block_count, data_block_count)?;
-                    self.inode.btree_root = btree_root.block_count;
+            /* newly mapped blocks already hold the data, they are mapped in order of key */
+            if mapped
+                .binary_search_by_key(&block_count, |block| block.key)
+                .is_err()
+            {
+                let block = self
+                    .btree_root
+                    .as_ref()
+                    .unwrap()
+                    .lookup(device, block_count)?
+                    .value;
+                let mut data_block = load_block(device, block)?;
 
-                    let mut block_data = [0; BLOCK_SIZE];
-                    block_data[block_offset as usize..block_offset as usize + written_size]
-                        .copy_from_slice(&data[..written_size]);
+                data_block[block_offset as usize..block_offset as usize + written_size]
+                    .copy_from_slice(&data[..written_size]);
+                save_block(device, block, data_block)?;
+                fs.record_write(block);
+            }
 
-                    save_block(device, data_block_count, block_data)?;
-                }
+            if offset + written_size as u64 > self.inode.size {
+                self.inode.size = offset + written_size as u64;
+            }
 
-                if offset + written_size as u64 > self.inode.size {
-                    self.inode.size = offset + written_size as u64;
-                }
+            data = &data[written_size..];
+            offset += written_size as u64;
+        }
 
-                data = &data[written_size..];
-                offset += written_size as u64;
+        self.inode.update_mtime();
+        subvol.set_inode(fs, device, self.inode_count, self.inode)?;
+        Ok(())
+    }
+    /** Allocate zeroed blocks for holes in `offset..offset + len` and grow the file to cover
+     * the range, like `fallocate`
+     *
+     * Writing the range afterwards allocates no block, except blocks shared with snapshots,
+     * which are still copied when written. Running out of space or quota leaves the file as it
+     * was.
+     */
+    pub fn allocate<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        offset: u64,
+        len: u64,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        /* count of blocks mapped at once, which bounds the buffer of zeros */
+        const CHUNK_BLOCKS: u64 = 256;
+
+        self.check_access(self.writable)?;
+        subvol.check_writable(fs, device)?;
+        if self.compress {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Space of a compressed file can't be allocated.",
+            ));
+        }
+        let end = offset
+            .checked_add(len)
+            .filter(|_| len > 0)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid range to allocate."))?;
+        self.handle_rc_inode(fs, subvol, device)?;
+
+        let new_root = self.create_btree_root(fs, subvol, device)?;
+        let (first, last) = (offset / BLOCK_SIZE as u64, end.div_ceil(BLOCK_SIZE as u64));
+        let present = self
+            .btree_root
+            .as_ref()
+            .unwrap()
+            .range(device, first, last)?;
+        let mut holes = Vec::new();
+        let mut start = first;
+        for key in present.iter().map(|entry| entry.key).chain([last]) {
+            if key > start {
+                holes.push(start..key);
+            }
+            start = key + 1;
+        }
+
+        let zeros = vec![0; CHUNK_BLOCKS as usize * BLOCK_SIZE];
+        let mut mapped = Vec::new();
+        let mut run = 0..0;
+        let mut result = Ok(());
+        'holes: for hole in holes {
+            for chunk in hole.clone().step_by(CHUNK_BLOCKS as usize) {
+                let count = std::cmp::min(CHUNK_BLOCKS, hole.end - chunk);
+                result = self.map_blocks(
+                    fs,
+                    subvol,
+                    device,
+                    chunk * BLOCK_SIZE as u64,
+                    &zeros[..count as usize * BLOCK_SIZE],
+                    &mut mapped,
+                    &mut run,
+                );
+                if result.is_err() {
+                    break 'holes;
+                }
             }
         }
+        self.finish_mapping(fs, subvol, device, result, &mut mapped, run, new_root)?;
 
+        if end > self.inode.size {
+            self.inode.size = end;
+        }
         self.inode.update_mtime();
-        subvol.set_inode(fs, device, self.inode_count, self.inode)?;
+        subvol.set_inode(fs, device, self.inode_count, self.inode)
+    }
+    /** Create an empty block tree if the file has none, return whether it was created */
+    fn create_btree_root<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<bool>
+    where
+        D: Read + Write + Seek,
+    {
+        if self.btree_root.is_some() {
+            return Ok(false);
+        }
+        self.inode.btree_root = BtreeNode::allocate_on_block_subvol(fs, subvol, device)?;
+        self.btree_root = Some(BtreeNode {
+            block_count: self.inode.btree_root,
+            r#type: BtreeType::Leaf,
+            ..Default::default()
+        });
+        Ok(true)
+    }
+    /** Release blocks left in the run after `map_blocks`, and undo the mapping if it failed */
+    #[allow(clippy::too_many_arguments)]
+    fn finish_mapping<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        result: IOResult<()>,
+        mapped: &mut Vec<MappedBlock>,
+        run: std::ops::Range<u64>,
+        new_root: bool,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        for block in run {
+            let released = subvol.release_block(fs, device, block);
+            if result.is_ok() {
+                released?;
+            }
+        }
+        if let Err(err) = result {
+            self.unmap_blocks(fs, subvol, device, std::mem::take(mapped), new_root);
+            /* the block tree may have been cloned from one shared with snapshots */
+            let _ = subvol.set_inode(fs, device, self.inode_count, self.inode);
+            return Err(err);
+        }
+        Ok(())
+    }
+    /** Allocate blocks of a write which are not mapped or shared, and write data into them
+     *
+     * Each newly mapped block is recorded in `mapped`, so that it can be unmapped if a later
+     * allocation fails. Blocks of compressed clusters are left to `write_cluster_range`. Data
+     * blocks are allocated in runs up to the next block written in place, blocks of the run
+     * left in `run` are not mapped.
+     */
+    #[allow(clippy::too_many_arguments)]
+    fn map_blocks<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        mut offset: u64,
+        mut data: &[u8],
+        mapped: &mut Vec<MappedBlock>,
+        run: &mut std::ops::Range<u64>,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let clustered = fs.sb.has_feature_incompat(FEATURE_INCOMPAT_COMPRESSION);
+        let compress = clustered && self.compress;
+        let is_dir = self.inode.is_dir();
+        let mut checked_cluster: Option<(u64, bool)> = None;
+
+        while !data.is_empty() {
+            if !clustered && !is_dir && run.is_empty() {
+                let consumed = self.map_new_run(fs, subvol, device, offset, data, mapped)?;
+                if consumed > 0 {
+                    data = &data[consumed..];
+                    offset += consumed as u64;
+                    continue;
+                }
+            }
+
+            let block_count = offset / BLOCK_SIZE as u64;
+            let block_offset = offset as usize % BLOCK_SIZE;
+            let written_size = std::cmp::min(data.len(), BLOCK_SIZE - block_offset);
+            let cluster = block_count / CLUSTER_BLOCKS;
+            let chunk = &data[..written_size];
+            data = &data[written_size..];
+            offset += written_size as u64;
+
+            if clustered
+                && (compress || self.is_compressed_cluster(device, cluster, &mut checked_cluster))
+            {
+                continue;
+            }
+            let btree_root = self.btree_root.as_mut().unwrap();
+            let old = btree_root.lookup(device, block_count).ok();
+            if old.as_ref().is_some_and(|entry| entry.rc == 0) {
+                continue;
+            }
+
+            let mut block_data = match &old {
+                Some(entry) => load_block(device, entry.value)?,
+                None => [0; BLOCK_SIZE],
+            };
+            block_data[block_offset..block_offset + written_size].copy_from_slice(chunk);
+
+            let block = if is_dir {
+                subvol.new_block(fs, device)?
+            } else {
+                if run.is_empty() {
+                    let end = (offset + data.len() as u64).div_ceil(BLOCK_SIZE as u64);
+                    let next_owned = btree_root
+                        .range(device, block_count + 1, end)?
+                        .into_iter()
+                        .find(|entry| entry.rc == 0)
+                        .map_or(end, |entry| entry.key);
+                    let (first, len) = subvol.new_data_blocks(
+                        fs,
+                        device,
+                        run_request(fs, next_owned - block_count),
+                    )?;
+                    *run = first..first + len;
+                }
+                run.next().unwrap()
+            };
+            save_block(device, block, block_data)?;
+            fs.record_write(block);
+            fs.barrier(device)?;
+
+            let result = match &old {
+                Some(_) => btree_root.modify(fs, subvol, device, block_count, block),
+                None => btree_root.insert(fs, subvol, device, block_count, block),
+            };
+            self.inode.btree_root = btree_root.block_count;
+            if let Err(err) = result {
+                subvol.release_block(fs, device, block)?;
+                return Err(err);
+            }
+            if old.is_some() {
+                subvol.drop_shared_block(fs);
+                fs.record_copy_out();
+            }
+            mapped.push(MappedBlock {
+                key: block_count,
+                block,
+                old,
+            });
+        }
         Ok(())
     }
+    /** Map blocks not mapped yet from the offset with a run of contiguous blocks
+     *
+     * The run is written with a single write and inserted into the block tree at once, rather
+     * than block by block. Nothing is mapped if fewer than two blocks in a row are not mapped,
+     * return count of bytes of `data` mapped.
+     */
+    fn map_new_run<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        offset: u64,
+        data: &[u8],
+        mapped: &mut Vec<MappedBlock>,
+    ) -> IOResult<usize>
+    where
+        D: Read + Write + Seek,
+    {
+        let btree_root = self.btree_root.as_mut().unwrap();
+        let first_key = offset / BLOCK_SIZE as u64;
+        let end = (offset + data.len() as u64).div_ceil(BLOCK_SIZE as u64);
+        let next_mapped = btree_root
+            .range(device, first_key, end)?
+            .first()
+            .map_or(end, |entry| entry.key);
+        if next_mapped < first_key + 2 {
+            return Ok(0);
+        }
+
+        let (first, len) =
+            subvol.new_data_blocks(fs, device, run_request(fs, next_mapped - first_key))?;
+        let head = offset as usize % BLOCK_SIZE;
+        let consumed = std::cmp::min(data.len(), len as usize * BLOCK_SIZE - head);
+        let mut run_data = vec![0; len as usize * BLOCK_SIZE];
+        run_data[head..head + consumed].copy_from_slice(&data[..consumed]);
+        save_blocks(device, first, &run_data)?;
+        for block in first..first + len {
+            fs.record_write(block);
+        }
+        fs.barrier(device)?;
+
+        let result = btree_root.insert_run(fs, subvol, device, first_key, first, len);
+        self.inode.btree_root = btree_root.block_count;
+        if let Err(err) = result {
+            /* keys inserted before the failure point at blocks released below */
+            for key in first_key..first_key + len {
+                if btree_root.lookup(device, key).is_ok() {
+                    let _ = btree_root.remove(fs, subvol, device, key);
+                }
+            }
+            self.inode.btree_root = btree_root.block_count;
+            for block in first..first + len {
+                subvol.release_block(fs, device, block)?;
+            }
+            return Err(err);
+        }
+
+        mapped.extend((0..len).map(|i| MappedBlock {
+            key: first_key + i,
+            block: first + i,
+            old: None,
+        }));
+        Ok(consumed)
+    }
+    /** Undo `map_blocks` after it failed, errors are ignored as the failure is reported */
+    fn unmap_blocks<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        mapped: Vec<MappedBlock>,
+        new_root: bool,
+    ) where
+        D: Read + Write + Seek,
+    {
+        let btree_root = self.btree_root.as_mut().unwrap();
+        if new_root {
+            /* the whole tree was created by the write */
+            let _ = btree_root.destroy(fs, subvol, device);
+            self.btree_root = None;
+            self.inode.btree_root = 0;
+            return;
+        }
+
+        for mapped in mapped.into_iter().rev() {
+            match mapped.old {
+                Some(old) => {
+                    let _ = btree_root
+                        .modify_with_rc(fs, subvol, device, mapped.key, old.value, old.rc);
+                    subvol.add_shared_blocks(fs, 1);
+                }
+                None => {
+                    let _ = btree_root.remove(fs, subvol, device, mapped.key);
+                }
+            }
+            let _ = subvol.release_block(fs, device, mapped.block);
+        }
+        self.inode.btree_root = btree_root.block_count;
+    }
     /** Read from file */
     pub fn read<D>(
         &mut self,
@@ -192,13 +804,48 @@ impl File {
     where
         D: Read + Write + Seek,
     {
+        self.check_access(self.readable)?;
+        let clustered = fs.sb.has_feature_incompat(FEATURE_INCOMPAT_COMPRESSION);
+        /* the last decompressed cluster */
+        let mut cached_cluster: Option<(u64, Vec<u8>)> = None;
+        /* whether the last looked up cluster is compressed */
+        let mut checked_cluster: Option<(u64, bool)> = None;
+
         if let Some(btree_root) = &mut self.btree_root {
+            /* block mappings of the whole read are looked up at once */
+            let first_block = offset / BLOCK_SIZE as u64;
+            let mut mappings = btree_root
+                .range(
+                    device,
+                    first_block,
+                    (offset + size).div_ceil(BLOCK_SIZE as u64),
+                )?
+                .into_iter()
+                .peekable();
+
             while size > 0 {
                 let block_count = offset / BLOCK_SIZE as u64; // the block count to be write
                 let block_offset = offset % BLOCK_SIZE as u64; // the relative offset to the block
 
+                let cluster = block_count / CLUSTER_BLOCKS;
                 let read_size;
-                if let Ok(entry) = btree_root.lookup(device, block_count) {
+                if clustered
+                    && is_compressed_cluster(device, btree_root, cluster, &mut checked_cluster)
+                {
+                    if cached_cluster
+                        .as_ref()
+                        .is_none_or(|(count, _)| *count != cluster)
+                    {
+                        cached_cluster =
+                            Some((cluster, read_cluster(device, btree_root, cluster)?));
+                    }
+                    let cluster_data = &cached_cluster.as_ref().unwrap().1;
+                    let cluster_offset = (offset % CLUSTER_SIZE as u64) as usize;
+
+                    read_size = std::cmp::min(size as usize, CLUSTER_SIZE - cluster_offset);
+                    buffer[..read_size]
+                        .copy_from_slice(&cluster_data[cluster_offset..cluster_offset + read_size]);
+                } else if let Some(entry) = next_mapping(&mut mappings, block_count) {
                     let block = load_block(device, entry.value)?;
                     read_size = std::cmp::min(size as usize, BLOCK_SIZE - block_offset as usize);
                     buffer[..read_size].copy_from_slice(
@@ -207,7 +854,7 @@ impl File {
                 }
                 /* section with unallocated data block in sparse file, fill zero bytes */
                 else {
-                    read_size = std::cmp::min(size as usize, BLOCK_SIZE);
+                    read_size = std::cmp::min(size as usize, BLOCK_SIZE - block_offset as usize);
 
                     buffer[..read_size].copy_from_slice(&[0].repeat(read_size));
                 }
@@ -220,11 +867,24 @@ impl File {
             buffer[..size as usize].fill(0);
         }
 
-        self.inode.update_atime();
-        subvol.set_inode(fs, device, self.inode_count, self.inode)?;
+        /* access time is not updated on read-only subvolumes or filesystems */
+        if !subvol.entry.is_readonly()
+            && !fs.is_readonly()
+            && fs
+                .atime_mode()
+                .should_update(&self.inode, crate::utils::get_sys_time())
+        {
+            self.inode.update_atime();
+            subvol.set_inode(fs, device, self.inode_count, self.inode)?;
+        }
         Ok(())
     }
-    /** Adjust file size */
+    /** Adjust file size
+     *
+     * Growing a file maps no block, the extended region is a hole which reads as zeros.
+     * Shrinking it releases blocks past the new size and zeroes the tail of the last block,
+     * so that it doesn't show up again when the file grows.
+     */
     pub fn truncate<D>(
         &mut self,
         fs: &mut Filesystem,
@@ -235,16 +895,53 @@ impl File {
     where
         D: Read + Write + Seek,
     {
+        self.check_access(self.writable)?;
+        subvol.check_writable(fs, device)?;
+        if size >= self.inode.size && size > 0 {
+            self.inode.size = size;
+            self.inode.update_mtime();
+            return subvol.set_inode(fs, device, self.inode_count, self.inode);
+        }
         self.handle_rc_inode(fs, subvol, device)?;
 
+        /* clear the tail of a compressed cluster which is cut off */
+        let cluster = size / CLUSTER_SIZE as u64;
+        if size > 0
+            && size < self.inode.size
+            && !size.is_multiple_of(CLUSTER_SIZE as u64)
+            && fs.sb.has_feature_incompat(FEATURE_INCOMPAT_COMPRESSION)
+            && self.is_compressed_cluster(device, cluster, &mut None)
+        {
+            let btree = self.btree_root.as_ref().unwrap();
+            let mut cluster_data = read_cluster(device, btree, cluster)?;
+            cluster_data[(size % CLUSTER_SIZE as u64) as usize..].fill(0);
+
+            let compress = self.compress;
+            self.write_cluster(fs, subvol, device, cluster, &cluster_data, size, compress)?;
+        } else if !size.is_multiple_of(BLOCK_SIZE as u64)
+            && size < self.inode.size
+            && self
+                .btree_root
+                .as_ref()
+                .is_some_and(|btree| btree.lookup(device, size / BLOCK_SIZE as u64).is_ok())
+        {
+            let tail = (BLOCK_SIZE as u64 - size % BLOCK_SIZE as u64).min(self.inode.size - size);
+            self.write(fs, subvol, device, size, &vec![0; tail as usize])?;
+        }
+
         if let Some(btree) = &mut self.btree_root {
             /* reduce file size */
             if size > 0 && size < self.inode.size {
                 let start_block = size.div_ceil(BLOCK_SIZE as u64);
-                let end_block = self.inode.size / BLOCK_SIZE as u64;
+                let end_block = self.inode.size.div_ceil(BLOCK_SIZE as u64);
 
                 for i in start_block..end_block {
-                    if btree.lookup(device, i).is_ok() {
+                    if let Ok(entry) = btree.lookup(device, i) {
+                        if entry.rc == 0 {
+                            subvol.release_block(fs, device, entry.value & VALUE_BLOCK_MASK)?;
+                        } else {
+                            subvol.drop_shared_block(fs);
+                        }
                         btree.remove(fs, subvol, device, i)?;
                     }
                 }
@@ -261,35 +958,495 @@ impl File {
         subvol.set_inode(fs, device, self.inode_count, self.inode)?;
         Ok(())
     }
-    pub fn get_inode_count(&self) -> u64 {
-        self.inode_count
-    }
-    pub fn get_inode(&self) -> INode {
-        self.inode
-    }
-    /** Copy a regular file or a symbol link */
-    pub fn copy<D, P>(
-        fs: &mut Filesystem,
-        subvol: &mut Subvolume,
-        device: &mut D,
-        src: P,
-        dst: P,
-    ) -> IOResult<()>
+    /** Check that no data block is mapped beyond the size of the file
+     *
+     * Blocks of the tail may be unmapped, as sparse regions are. Directories are checked the same
+     * way as regular files.
+     */
+    pub fn verify<D>(&self, device: &mut D) -> IOResult<()>
     where
         D: Read + Write + Seek,
-        P: AsRef<Path>,
     {
-        let fd = Self::open(fs, subvol, device, &src)?;
-        let inode = copy_by_inode(fs, subvol, device, fd.inode_count)?;
-
-        Directory::open(fs, subvol, device, dir_path(src.as_ref()))?.add_file(
-            fs,
-            subvol,
-            device,
-            base_name(dst.as_ref()),
-            inode,
+        if let Some(btree_root) = &self.btree_root {
+            let blocks = self.inode.size.div_ceil(BLOCK_SIZE as u64);
+            if let Some(entry) = btree_root.range(device, blocks, u64::MAX)?.first() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Inode {} maps block {} beyond its size {}.",
+                        self.inode_count, entry.key, self.inode.size
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+    /** Get the first offset at or after `offset` containing data, like `lseek(SEEK_DATA)`
+     *
+     * Fails with `NotFound`, as `ENXIO`, if there is no data from `offset` to the end of the file.
+     */
+    pub fn seek_data<D>(&self, device: &mut D, offset: u64) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        self.check_seek(offset)?;
+        let block = offset / BLOCK_SIZE as u64;
+        self.data_extents(device, block)?
+            .into_iter()
+            .find(|extent| extent.end > block)
+            .map(|extent| offset.max(extent.start * BLOCK_SIZE as u64))
+            .filter(|data| *data < self.inode.size)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No data after the offset."))
+    }
+    /** Get the first offset at or after `offset` in a hole, like `lseek(SEEK_HOLE)`
+     *
+     * The end of the file counts as a hole, so it is returned if there is no hole before it.
+     */
+    pub fn seek_hole<D>(&self, device: &mut D, offset: u64) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        self.check_seek(offset)?;
+        let mut block = offset / BLOCK_SIZE as u64;
+        for extent in self.data_extents(device, block)? {
+            if extent.start > block {
+                break;
+            }
+            block = block.max(extent.end);
+        }
+        Ok(offset.max(block * BLOCK_SIZE as u64).min(self.inode.size))
+    }
+    fn check_seek(&self, offset: u64) -> IOResult<()> {
+        if offset >= self.inode.size {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "Offset is beyond the end of the file.",
+            ));
+        }
+        Ok(())
+    }
+    /** Get ranges of data blocks from the cluster of `block` on, in order
+     *
+     * Only leading blocks of a compressed cluster are mapped, but it is data as a whole.
+     */
+    fn data_extents<D>(&self, device: &mut D, block: u64) -> IOResult<Vec<std::ops::Range<u64>>>
+    where
+        D: Read + Write + Seek,
+    {
+        let Some(btree_root) = &self.btree_root else {
+            return Ok(Vec::new());
+        };
+
+        let mut extents: Vec<std::ops::Range<u64>> = Vec::new();
+        let first = block / CLUSTER_BLOCKS * CLUSTER_BLOCKS;
+        for entry in btree_root.range(device, first, u64::MAX)? {
+            let extent = if entry.value & VALUE_COMPRESSED != 0 {
+                entry.key..(entry.key / CLUSTER_BLOCKS + 1) * CLUSTER_BLOCKS
+            } else {
+                entry.key..entry.key + 1
+            };
+            match extents.last_mut() {
+                Some(last) if last.end >= extent.start => last.end = last.end.max(extent.end),
+                _ => extents.push(extent),
+            }
+        }
+        Ok(extents)
+    }
+    /** List data blocks of the file, shared blocks included */
+    pub(crate) fn data_blocks<D>(&self, device: &mut D) -> IOResult<Vec<u64>>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut blocks = Vec::new();
+        if let Some(btree_root) = &self.btree_root {
+            btree_root.walk(device, |node, _| {
+                if node.r#type == BtreeType::Leaf {
+                    blocks.extend(
+                        node.entries
+                            .iter()
+                            .map(|entry| entry.value & VALUE_BLOCK_MASK),
+                    );
+                }
+            })?;
+        }
+        Ok(blocks)
+    }
+    /** Move data blocks of the file into a run of contiguous blocks in order of offsets
+     *
+     * Blocks shared with snapshots or other files are copied and kept for them. Blocks of
+     * compressed clusters are left as they are. Return count of blocks moved.
+     */
+    pub fn defragment<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        subvol.check_writable(fs, device)?;
+        /* reference counts of entries are raised if the inode group is copied */
+        self.handle_rc_inode(fs, subvol, device)?;
+        let Some(btree_root) = &self.btree_root else {
+            return Ok(0);
+        };
+
+        let entries: Vec<BtreeEntry> = btree_root
+            .range(device, 0, u64::MAX)?
+            .into_iter()
+            .filter(|entry| entry.value & VALUE_COMPRESSED == 0)
+            .collect();
+        if entries
+            .windows(2)
+            .all(|pair| pair[1].value == pair[0].value + 1)
+        {
+            return Ok(0);
+        }
+
+        let count = entries.len() as u64;
+        let first = subvol.new_data_run(fs, device, count)?;
+        for (block, entry) in (first..).zip(&entries) {
+            let data = load_block(device, entry.value)?;
+            save_block(device, block, data)?;
+            fs.record_write(block);
+        }
+        fs.barrier(device)?;
+
+        let btree_root = self.btree_root.as_mut().unwrap();
+        for (block, entry) in (first..).zip(&entries) {
+            let result = btree_root.modify(fs, subvol, device, entry.key, block);
+            self.inode.btree_root = btree_root.block_count;
+            if let Err(err) = result {
+                /* blocks not mapped yet are released, moved ones stay in place */
+                for block in block..first + count {
+                    subvol.release_block(fs, device, block)?;
+                }
+                subvol.set_inode(fs, device, self.inode_count, self.inode)?;
+                return Err(err);
+            }
+
+            if entry.rc == 0 {
+                subvol.release_block(fs, device, entry.value)?;
+            } else {
+                subvol.drop_shared_block(fs);
+            }
+        }
+
+        subvol.set_inode(fs, device, self.inode_count, self.inode)?;
+        Ok(count)
+    }
+    /** Copy `len` bytes at `src_offset` of `src` to `dst_offset` of this file
+     *
+     * `src` of `None` copies within this file. Whole blocks are shared with the source rather
+     * than copied when both offsets are equally aligned, unless the ranges are in the same file
+     * or the filesystem is compressed. The copy stops at the end of the source, return count of
+     * bytes copied.
+     */
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn copy_range_from<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        mut src: Option<&mut File>,
+        src_offset: u64,
+        dst_offset: u64,
+        len: u64,
+    ) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        let src_size = src.as_ref().map_or(self.inode.size, |src| src.inode.size);
+        let len = len.min(src_size.saturating_sub(src_offset));
+        if src.is_none() && src_offset < dst_offset + len && dst_offset < src_offset + len {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Source and destination ranges overlap.",
+            ));
+        }
+
+        let block_size = BLOCK_SIZE as u64;
+        let (head, blocks) = if src.is_some()
+            && src_offset % block_size == dst_offset % block_size
+            && !fs.sb.has_feature_incompat(FEATURE_INCOMPAT_COMPRESSION)
+        {
+            let head = ((block_size - src_offset % block_size) % block_size).min(len);
+            (head, (len - head) / block_size)
+        } else {
+            (len, 0)
+        };
+        let shared = blocks * block_size;
+
+        self.copy_bytes_from(
+            fs,
+            subvol,
+            device,
+            src.as_deref_mut(),
+            src_offset,
+            dst_offset,
+            head,
+        )?;
+        if let Some(src) = src.as_deref_mut() {
+            if blocks > 0 {
+                self.share_blocks_from(
+                    fs,
+                    subvol,
+                    device,
+                    src,
+                    (src_offset + head) / block_size,
+                    (dst_offset + head) / block_size,
+                    blocks,
+                )?;
+            }
+        }
+        self.copy_bytes_from(
+            fs,
+            subvol,
+            device,
+            src,
+            src_offset + head + shared,
+            dst_offset + head + shared,
+            len - head - shared,
         )?;
 
+        if self.inode.size < dst_offset + len {
+            self.inode.size = dst_offset + len;
+        }
+        self.inode.update_mtime();
+        subvol.set_inode(fs, device, self.inode_count, self.inode)?;
+        Ok(len)
+    }
+    /** Copy bytes through a buffer, `src` of `None` copies within this file */
+    #[allow(clippy::too_many_arguments)]
+    fn copy_bytes_from<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        mut src: Option<&mut File>,
+        mut src_offset: u64,
+        mut dst_offset: u64,
+        mut len: u64,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut buffer = vec![0; CLUSTER_SIZE];
+        while len > 0 {
+            let size = len.min(CLUSTER_SIZE as u64);
+            let buffer = &mut buffer[..size as usize];
+            match src.as_deref_mut() {
+                Some(src) => src.read(fs, subvol, device, src_offset, buffer, size)?,
+                None => self.read(fs, subvol, device, src_offset, buffer, size)?,
+            }
+            self.write(fs, subvol, device, dst_offset, buffer)?;
+
+            src_offset += size;
+            dst_offset += size;
+            len -= size;
+        }
+        Ok(())
+    }
+    /** Map `count` blocks of `src` from `src_block` at `dst_block` of this file
+     *
+     * Shared blocks are referenced once more by both files, so either copies them out when
+     * written. Holes of the source punch holes in this file.
+     */
+    #[allow(clippy::too_many_arguments)]
+    fn share_blocks_from<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        src: &mut File,
+        src_block: u64,
+        dst_block: u64,
+        count: u64,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        subvol.check_writable(fs, device)?;
+        self.handle_rc_inode(fs, subvol, device)?;
+        src.handle_rc_inode(fs, subvol, device)?;
+
+        if self.btree_root.is_none() {
+            self.inode.btree_root = BtreeNode::allocate_on_block_subvol(fs, subvol, device)?;
+            self.btree_root = Some(BtreeNode {
+                block_count: self.inode.btree_root,
+                r#type: BtreeType::Leaf,
+                ..Default::default()
+            });
+        }
+        let src_entries = match &src.btree_root {
+            Some(btree) => btree.range(device, src_block, src_block + count)?,
+            None => Vec::new(),
+        };
+        let dst_btree = self.btree_root.as_mut().unwrap();
+
+        let mut src_entries = src_entries.into_iter().peekable();
+        for i in 0..count {
+            let source = src_entries.next_if(|entry| entry.key == src_block + i);
+            let existing = dst_btree.lookup(device, dst_block + i).ok();
+            if let (Some(source), Some(existing)) = (&source, &existing) {
+                if source.value == existing.value {
+                    continue;
+                }
+            }
+            if let Some(existing) = &existing {
+                if existing.rc == 0 {
+                    subvol.release_block(fs, device, existing.value & VALUE_BLOCK_MASK)?;
+                } else {
+                    subvol.drop_shared_block(fs);
+                }
+            }
+
+            match source {
+                Some(source) => {
+                    let rc = source.rc + 1;
+                    let src_btree = src.btree_root.as_mut().unwrap();
+                    src_btree.modify_with_rc(fs, subvol, device, source.key, source.value, rc)?;
+                    if existing.is_none() {
+                        dst_btree.insert(fs, subvol, device, dst_block + i, source.value)?;
+                    }
+                    dst_btree.modify_with_rc(
+                        fs,
+                        subvol,
+                        device,
+                        dst_block + i,
+                        source.value,
+                        rc,
+                    )?;
+                    subvol.add_shared_blocks(fs, 1);
+                }
+                None if existing.is_some() => {
+                    dst_btree.remove(fs, subvol, device, dst_block + i)?
+                }
+                None => {}
+            }
+        }
+
+        self.inode.btree_root = dst_btree.block_count;
+        if let Some(src_btree) = &src.btree_root {
+            src.inode.btree_root = src_btree.block_count;
+            subvol.set_inode(fs, device, src.inode_count, src.inode)?;
+        }
+        Ok(())
+    }
+    /** Flush data written to the file to the device
+     *
+     * Meta data, such as the size of the file, is persisted by `sync_all`.
+     */
+    pub fn flush<D>(&mut self, device: &mut D) -> IOResult<()>
+    where
+        D: Write,
+    {
+        device.flush()
+    }
+    /** Persist the file without synchronizing meta data of the whole filesystem, like `fsync`
+     *
+     * The inode and the block tree are written as the file is changed, so only block groups
+     * changed since meta data was last synchronized and the subvolume entry are written before
+     * flushing the device. The superblock stays dirty until `Filesystem::sync_meta_data`.
+     */
+    pub fn sync<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        if fs.is_readonly() {
+            return Ok(());
+        }
+        fs.sync_dirty_groups(device)?;
+        subvol.write_entry(fs, device)?;
+        device.flush()
+    }
+    /** Persist data and meta data of the file
+     *
+     * Synchronize meta data of the subvolume and the filesystem, then flush the device. The
+     * entry of a newly created file is only durable after `sync_all_with_parent`.
+     */
+    pub fn sync_all<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        subvol.sync_meta_data(fs, device)?;
+        fs.sync_meta_data(device)?;
+        device.flush()
+    }
+    /** Persist the file, then the directory containing it, like `fsync` on both */
+    pub fn sync_all_with_parent<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        parent: &mut Directory,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.sync_all(fs, subvol, device)?;
+        parent.flush(fs, subvol, device)
+    }
+    pub fn get_inode_count(&self) -> u64 {
+        self.inode_count
+    }
+    pub fn get_inode(&self) -> INode {
+        self.inode
+    }
+    /** Copy a regular file, a symbol link is followed to the file it points to
+     *
+     * The copy keeps type and permissions of the source and shares its blocks until either is
+     * written. See `copy_with_options` to keep other metadata.
+     */
+    pub fn copy<D, P>(
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        src: P,
+        dst: P,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        Self::copy_with_options(fs, subvol, device, src, dst, &CopyOptions::default())
+    }
+    pub fn copy_with_options<D, P>(
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        src: P,
+        dst: P,
+        options: &CopyOptions,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let src = normalize(src.as_ref())?;
+        let dst = normalize(dst.as_ref())?;
+        let (parent, name) = (dir_path(&dst)?, base_name(&dst)?);
+        let fd = Self::open(fs, subvol, device, &src)?;
+        let inode = copy_by_inode(fs, subvol, device, fd.inode_count, options.preserve)?;
+
+        if let Err(err) = Directory::open(fs, subvol, device, parent)
+            .and_then(|mut dir| dir.add_file(fs, subvol, device, name, inode))
+        {
+            remove_by_inode(fs, subvol, device, inode)?;
+            return Err(err);
+        }
+
         Ok(())
     }
     /** Remove a regular file or a symbol link */
@@ -303,7 +1460,22 @@ impl File {
         D: Read + Write + Seek,
         P: AsRef<Path>,
     {
-        let mut fd = Self::open(fs, subvol, device, &path)?;
+        let path = normalize(path.as_ref())?;
+        subvol.check_writable(fs, device)?;
+
+        let inode_count = Directory::open(fs, subvol, device, dir_path(path.as_ref())?)?
+            .find_inode_by_name(fs, subvol, device, base_name(path.as_ref())?)?;
+
+        /* symbol links are removed themselves instead of their targets */
+        let mut fd = Self {
+            inode: subvol.get_inode(device, inode_count)?,
+            inode_count,
+            btree_root: None,
+            compress: false,
+            readable: true,
+            writable: true,
+            append: false,
+        };
 
         fd.handle_rc_inode(fs, subvol, device)?;
 
@@ -312,68 +1484,312 @@ impl File {
         } else {
             remove_by_inode(fs, subvol, device, fd.inode_count)?;
 
-            Directory::open(fs, subvol, device, dir_path(path.as_ref()))?.remove_file(
+            Directory::open(fs, subvol, device, dir_path(path.as_ref())?)?.remove_file(
                 fs,
                 subvol,
                 device,
-                base_name(path.as_ref()),
+                base_name(path.as_ref())?,
             )?;
         }
 
         Ok(())
     }
-    /** Before writing a multi-referenced file, first do these steps:
-     * * Clone data blocks of each inode in the group
-     * * Clone the inode group
+    fn is_compressed_cluster<D>(
+        &self,
+        device: &mut D,
+        cluster: u64,
+        checked: &mut Option<(u64, bool)>,
+    ) -> bool
+    where
+        D: Read + Write + Seek,
+    {
+        self.btree_root
+            .as_ref()
+            .is_some_and(|root| is_compressed_cluster(device, root, cluster, checked))
+    }
+    /** Write data into a cluster and return the written size */
+    fn write_cluster_range<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        offset: u64,
+        data: &[u8],
+        compress: bool,
+    ) -> IOResult<usize>
+    where
+        D: Read + Write + Seek,
+    {
+        let cluster = offset / CLUSTER_SIZE as u64;
+        let cluster_offset = (offset % CLUSTER_SIZE as u64) as usize;
+        let written_size = std::cmp::min(data.len(), CLUSTER_SIZE - cluster_offset);
+
+        let mut cluster_data = read_cluster(device, self.btree_root.as_ref().unwrap(), cluster)?;
+        cluster_data[cluster_offset..cluster_offset + written_size]
+            .copy_from_slice(&data[..written_size]);
+
+        if offset + written_size as u64 > self.inode.size {
+            self.inode.size = offset + written_size as u64;
+        }
+        self.write_cluster(
+            fs,
+            subvol,
+            device,
+            cluster,
+            &cluster_data,
+            self.inode.size,
+            compress,
+        )?;
+
+        Ok(written_size)
+    }
+    /** Replace data blocks of a cluster
+     *
+     * The cluster is compressed if the compressed data takes fewer blocks than the cluster
+     * within file size `size`, otherwise it is stored raw without blocks filled with zero.
      */
-    fn handle_rc_inode<D>(
+    #[allow(clippy::too_many_arguments)]
+    fn write_cluster<D>(
         &mut self,
         fs: &mut Filesystem,
         subvol: &mut Subvolume,
         device: &mut D,
+        cluster: u64,
+        data: &[u8],
+        size: u64,
+        compress: bool,
     ) -> IOResult<()>
     where
         D: Read + Write + Seek,
     {
-        let inode_group_count = self.inode_count / INODE_PER_GROUP as u64;
-        /* check if the inode is multiple referenced */
-        let btree_query_result = subvol.igroup_mgt_btree.lookup(device, inode_group_count)?;
-        let inode_group_block = btree_query_result.value;
-        if btree_query_result.rc > 0 {
-            let mut inode_group = INodeGroup::load(load_block(device, inode_group_block)?);
-            /* clone data blocks of each inode in the group */
-            for (i, inode) in inode_group.inodes.iter().enumerate() {
-                if !inode.is_empty_inode() {
-                    clone_by_inode(
-                        subvol,
-                        device,
-                        self.inode_count - (self.inode_count % INODE_PER_GROUP as u64) + i as u64,
-                    )?;
+        let first = cluster * CLUSTER_BLOCKS;
+        let blocks = (size.saturating_sub(first * BLOCK_SIZE as u64))
+            .div_ceil(BLOCK_SIZE as u64)
+            .min(CLUSTER_BLOCKS);
+
+        let compressed = if compress {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            Some(encoder.finish()?)
+        } else {
+            None
+        }
+        .filter(|compressed| (compressed.len().div_ceil(BLOCK_SIZE) as u64) < blocks);
+
+        let chunks: Vec<(u64, &[u8])> = match &compressed {
+            Some(compressed) => compressed
+                .chunks(BLOCK_SIZE)
+                .enumerate()
+                .map(|(i, chunk)| (first + i as u64, chunk))
+                .collect(),
+            None => data
+                .chunks(BLOCK_SIZE)
+                .enumerate()
+                .filter(|(_, chunk)| chunk.iter().any(|byte| *byte != 0))
+                .map(|(i, chunk)| (first + i as u64, chunk))
+                .collect(),
+        };
+
+        /* new data is written before old blocks are dropped, so that the cluster is kept if
+         * running out of space or quota */
+        let mut values = Vec::with_capacity(chunks.len());
+        for (count, chunk) in chunks {
+            let block = match new_content_block(fs, subvol, device, self.inode.is_dir()) {
+                Ok(block) => block,
+                Err(err) => {
+                    for (_, value) in values {
+                        subvol.release_block(fs, device, value & VALUE_BLOCK_MASK)?;
+                    }
+                    return Err(err);
                 }
+            };
+
+            let mut block_data = [0; BLOCK_SIZE];
+            block_data[..chunk.len()].copy_from_slice(chunk);
+            save_block(device, block, block_data)?;
+            fs.record_write(block);
+
+            let value = match &compressed {
+                Some(compressed) if count == first => {
+                    block | VALUE_COMPRESSED | (compressed.len() as u64) << VALUE_LEN_SHIFT
+                }
+                _ => block,
+            };
+            values.push((count, value));
+        }
+        fs.barrier(device)?;
+
+        let btree_root = self.btree_root.as_mut().unwrap();
+        let mut old_entries = Vec::new();
+        for count in first..first + CLUSTER_BLOCKS {
+            let old_entry = btree_root.lookup(device, count).ok();
+            let value = values
+                .iter()
+                .find(|(value_count, _)| *value_count == count)
+                .map(|(_, value)| *value);
+            match (&old_entry, value) {
+                (Some(_), Some(value)) => btree_root.modify(fs, subvol, device, count, value)?,
+                (Some(_), None) => btree_root.remove(fs, subvol, device, count)?,
+                (None, Some(value)) => btree_root.insert(fs, subvol, device, count, value)?,
+                (None, None) => {}
+            }
+            old_entries.extend(old_entry);
+        }
+
+        /* release old data blocks, shared blocks are kept for other references */
+        for entry in old_entries {
+            if entry.rc == 0 {
+                subvol.release_block(fs, device, entry.value & VALUE_BLOCK_MASK)?;
+            } else {
+                subvol.drop_shared_block(fs);
             }
-            /* clone inode group */
-            let new_inode_group_block = subvol.new_block(fs, device)?;
-            inode_group.sync(device, new_inode_group_block)?;
-            subvol.igroup_mgt_btree.modify(
-                fs,
-                &mut subvol.clone(),
-                device,
-                inode_group_count,
-                new_inode_group_block,
-            )?;
-            subvol.entry.inode_tree_root = subvol.igroup_mgt_btree.block_count;
-            crate::subvol::SubvolumeManager::set_subvolume(
-                device,
-                fs.sb.subvol_mgr,
-                subvol.entry.id,
-                subvol.entry,
-            )?;
         }
 
+        self.inode.btree_root = btree_root.block_count;
+        Ok(())
+    }
+    /** Clone the inode group before writing if it is multiple referenced */
+    fn handle_rc_inode<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        if handle_rc_inode(fs, subvol, device, self.inode_count)? {
+            /* the block tree is referenced once more by the copied inode group */
+            if let Some(btree_root) = &mut self.btree_root {
+                let block_count = btree_root.block_count;
+                *btree_root = BtreeNode::load_block(device, block_count)?;
+                btree_root.block_count = block_count;
+            }
+        }
         Ok(())
     }
 }
 
+/** Allocate a block for content of a file, which is metadata for directories */
+fn new_content_block<D>(
+    fs: &mut Filesystem,
+    subvol: &mut Subvolume,
+    device: &mut D,
+    is_dir: bool,
+) -> IOResult<u64>
+where
+    D: Read + Write + Seek,
+{
+    if is_dir {
+        subvol.new_block(fs, device)
+    } else {
+        subvol.new_data_block(fs, device)
+    }
+}
+
+/** Get compressed length of a cluster, `None` if the cluster is not compressed */
+pub(crate) fn compressed_len<D>(device: &mut D, root: &BtreeNode, cluster: u64) -> Option<usize>
+where
+    D: Read + Write + Seek,
+{
+    root.lookup(device, cluster * CLUSTER_BLOCKS)
+        .ok()
+        .filter(|entry| entry.value & VALUE_COMPRESSED != 0)
+        .map(|entry| ((entry.value & !VALUE_COMPRESSED) >> VALUE_LEN_SHIFT) as usize)
+}
+
+/** Check if a cluster is compressed, the B-Tree is only looked up once for each cluster
+ * remembered by `checked`
+ */
+fn is_compressed_cluster<D>(
+    device: &mut D,
+    root: &BtreeNode,
+    cluster: u64,
+    checked: &mut Option<(u64, bool)>,
+) -> bool
+where
+    D: Read + Write + Seek,
+{
+    match checked {
+        Some((count, compressed)) if *count == cluster => *compressed,
+        _ => {
+            let compressed = compressed_len(device, root, cluster).is_some();
+            *checked = Some((cluster, compressed));
+            compressed
+        }
+    }
+}
+
+/** Read data of a cluster, holes are filled with zero */
+pub(crate) fn read_cluster<D>(device: &mut D, root: &BtreeNode, cluster: u64) -> IOResult<Vec<u8>>
+where
+    D: Read + Write + Seek,
+{
+    let first = cluster * CLUSTER_BLOCKS;
+    let mut data = vec![0; CLUSTER_SIZE];
+
+    if let Some(len) = compressed_len(device, root, cluster) {
+        let mut compressed = Vec::with_capacity(len.next_multiple_of(BLOCK_SIZE));
+        for i in 0..len.div_ceil(BLOCK_SIZE) as u64 {
+            let entry = root.lookup(device, first + i)?;
+            compressed.extend_from_slice(&load_block(device, entry.value & VALUE_BLOCK_MASK)?);
+        }
+
+        ZlibDecoder::new(&compressed[..len])
+            .read_exact(&mut data)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Corrupted compressed cluster."))?;
+    } else {
+        for (i, chunk) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+            if let Ok(entry) = root.lookup(device, first + i as u64) {
+                chunk.copy_from_slice(&load_block(device, entry.value)?);
+            }
+        }
+    }
+
+    Ok(data)
+}
+
+/** Before writing a multi-referenced file, first do these steps:
+ * * Clone data blocks of each inode in the group
+ * * Clone the inode group
+ *
+ * Return whether the inode group was cloned.
+ */
+pub(crate) fn handle_rc_inode<D>(
+    fs: &mut Filesystem,
+    subvol: &mut Subvolume,
+    device: &mut D,
+    inode_count: u64,
+) -> IOResult<bool>
+where
+    D: Read + Write + Seek,
+{
+    let inode_group_count = inode_count / INODE_PER_GROUP as u64;
+    /* check if the inode is multiple referenced */
+    let btree_query_result = subvol.igroup_entry(device, inode_group_count)?;
+    let inode_group_block = btree_query_result.value;
+    if btree_query_result.rc > 0 {
+        let mut inode_group = INodeGroup::load(load_block(device, inode_group_block)?);
+        /* clone data blocks of each inode in the group */
+        for inode in inode_group.inodes.iter_mut() {
+            if !inode.is_empty_inode() {
+                clone_by_inode(device, inode)?;
+            }
+        }
+        /* clone inode group */
+        let new_inode_group_block = subvol.new_block(fs, device)?;
+        inode_group.sync(device, new_inode_group_block)?;
+        subvol.modify_igroup_btree(|btree, subvol| {
+            btree.modify(fs, subvol, device, inode_group_count, new_inode_group_block)
+        })?;
+        subvol.drop_shared_block(fs);
+        subvol.write_entry(fs, device)?;
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
 /** Create a file and return the inode count */
 pub(crate) fn create<D>(
     fs: &mut Filesystem,
@@ -383,17 +1799,41 @@ pub(crate) fn create<D>(
 where
     D: Read + Write + Seek,
 {
+    subvol.check_writable(fs, device)?;
+
     let inode_count = subvol.new_inode(fs, device)?;
 
-    let inode = INode {
+    let mut inode = INode {
         acl: ACL_REGULAR_FILE << PERMISSION_BITS,
         ..Default::default()
     };
+    inode.update_atime();
+    inode.update_mtime();
     subvol.set_inode(fs, device, inode_count, inode)?;
 
     Ok(inode_count)
 }
 
+/** Take the mapping of a block from mappings sorted by block counts, skipping earlier ones */
+fn next_mapping<I>(mappings: &mut std::iter::Peekable<I>, block_count: u64) -> Option<BtreeEntry>
+where
+    I: Iterator<Item = BtreeEntry>,
+{
+    while mappings.next_if(|entry| entry.key < block_count).is_some() {}
+    mappings.next_if(|entry| entry.key == block_count)
+}
+
+/** Count of blocks to request for a run of `wanted` blocks
+ *
+ * Free blocks are left for the block tree nodes the mapping copies or splits.
+ */
+fn run_request(fs: &Filesystem, wanted: u64) -> u64 {
+    let spare = fs
+        .free_blocks()
+        .saturating_sub(fs.sb.metadata_reserve + RUN_TREE_MARGIN + wanted / RUN_TREE_MARGIN);
+    wanted.min(spare).max(1)
+}
+
 /** Remove a file */
 pub(crate) fn remove_by_inode<D>(
     fs: &mut Filesystem,
@@ -406,16 +1846,23 @@ where
 {
     let mut inode = subvol.get_inode(device, inode_count)?;
 
+    /* other links still refer to the inode */
     if inode.hlinks > 0 {
         inode.hlinks -= 1;
         subvol.set_inode(fs, device, inode_count, inode)?;
+        return Ok(());
+    }
+
+    LinkedContentTable::release_chain(fs, subvol, device, inode.xattr)?;
+    if inode.is_symlink() {
+        release_content(fs, subvol, device, &inode)?;
     } else if inode.btree_root != 0 {
         let mut btree_root = BtreeNode::load_block(device, inode.btree_root)?;
         btree_root.block_count = inode.btree_root;
 
         btree_root.destroy(fs, subvol, device)?;
-        subvol.release_inode(fs, device, inode_count)?;
     }
+    subvol.release_inode(fs, device, inode_count)?;
     Ok(())
 }
 
@@ -425,36 +1872,1171 @@ pub(crate) fn copy_by_inode<D>(
     subvol: &mut Subvolume,
     device: &mut D,
     inode_count: u64,
+    preserve: bool,
 ) -> IOResult<u64>
 where
     D: Read + Write + Seek,
 {
-    let inode = subvol.get_inode(device, inode_count)?;
+    let mut inode = subvol.get_inode(device, inode_count)?;
     let new_inode_count = subvol.new_inode(fs, device)?;
-    let mut new_inode = INode::default();
+    /* the copy is a new file of the same type and permissions */
+    let mut new_inode = INode {
+        acl: inode.acl,
+        ..Default::default()
+    };
+    new_inode.update_atime();
+    new_inode.update_mtime();
+    if preserve {
+        new_inode.uid = inode.uid;
+        new_inode.gid = inode.gid;
+        new_inode.atime = inode.atime;
+        new_inode.mtime = inode.mtime;
+        /* chains are only shared between subvolumes */
+        new_inode.xattr = LinkedContentTable::copy_chain(fs, subvol, device, inode.xattr)?;
+    }
 
-    clone_by_inode(subvol, device, inode_count)?;
+    let shared_blocks = clone_by_inode(device, &mut inode)?;
+    subvol.add_shared_blocks(fs, shared_blocks);
     new_inode.size = inode.size;
     new_inode.btree_root = inode.btree_root;
     subvol.set_inode(fs, device, new_inode_count, new_inode)?;
     Ok(new_inode_count)
 }
 
-/** Clone a file, do not allocate inode */
-pub(crate) fn clone_by_inode<D>(
-    subvol: &mut Subvolume,
-    device: &mut D,
-    inode_count: u64,
-) -> IOResult<()>
+/** Clone a file, do not allocate inode, return count of blocks referenced once more
+ *
+ * B-Trees are reference counted, while chains of extended attributes and symbol link content are
+ * shared as is, see `LinkedContentTable::release_chain`.
+ */
+pub(crate) fn clone_by_inode<D>(device: &mut D, inode: &mut INode) -> IOResult<u64>
 where
     D: Read + Write + Seek,
 {
-    let inode = subvol.get_inode(device, inode_count)?;
+    /* extended attributes and symbol link content are shared until they are replaced */
+    if inode.is_symlink() {
+        return Ok(0);
+    }
 
     if inode.btree_root != 0 {
         let mut btree_root = BtreeNode::load_block(device, inode.btree_root)?;
         btree_root.block_count = inode.btree_root;
-        btree_root.clone_tree(device)?;
+        return btree_root.clone_tree(device);
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FormatOptions;
+
+    use std::io::Cursor;
+
+    fn compression_fs(blocks: usize) -> (Filesystem, Cursor<Vec<u8>>, Subvolume) {
+        let mut device = Cursor::new(vec![0; blocks * BLOCK_SIZE]);
+        let options = FormatOptions {
+            compression: true,
+            ..Default::default()
+        };
+        let mut fs = Filesystem::create_with_options(&mut device, blocks, &options).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fs.set_compression(&mut subvol, &mut device, "/f", true)
+            .unwrap();
+
+        (fs, device, subvol)
+    }
+
+    fn read_all(
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut Cursor<Vec<u8>>,
+    ) -> Vec<u8> {
+        let mut fd = fs.open_file(subvol, device, "/f").unwrap();
+        let size = fd.get_inode().size;
+        let mut buffer = vec![0; size as usize];
+        fd.read(fs, subvol, device, 0, &mut buffer, size).unwrap();
+        buffer
+    }
+
+    /** Bytes which zlib can not compress */
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        let (mut fs, mut device, mut subvol) = compression_fs(4000);
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/f").unwrap();
+
+        /* two compressible clusters and an incompressible one */
+        let mut expected = b"31corefs ".repeat(2 * CLUSTER_SIZE / 9 + 1);
+        expected.truncate(2 * CLUSTER_SIZE);
+        expected.extend(noise(CLUSTER_SIZE));
+        let used_blocks = subvol.entry.used_blocks;
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &expected)
+            .unwrap();
+        assert!(compressed_len(&mut device, fd.btree_root.as_ref().unwrap(), 0).is_some());
+        assert!(compressed_len(&mut device, fd.btree_root.as_ref().unwrap(), 2).is_none());
+        assert!(subvol.entry.used_blocks - used_blocks < 3 * CLUSTER_BLOCKS);
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+
+        /* partial overwrites across a cluster boundary */
+        let offset = CLUSTER_SIZE - 100;
+        fd.write(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            offset as u64,
+            &[0xaa; 300],
+        )
+        .unwrap();
+        expected[offset..offset + 300].fill(0xaa);
+        let offset = 2 * CLUSTER_SIZE + 5000;
+        fd.write(&mut fs, &mut subvol, &mut device, offset as u64, b"raw")
+            .unwrap();
+        expected[offset..offset + 3].copy_from_slice(b"raw");
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+
+        /* the cut off tail of a compressed cluster reads as zero when the file grows again */
+        let size = CLUSTER_SIZE + 1234;
+        fd.truncate(&mut fs, &mut subvol, &mut device, size as u64)
+            .unwrap();
+        expected.truncate(size);
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+        fd.truncate(&mut fs, &mut subvol, &mut device, 2 * CLUSTER_SIZE as u64)
+            .unwrap();
+        expected.resize(2 * CLUSTER_SIZE, 0);
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+    }
+
+    #[test]
+    fn compressed_write_exceeding_quota() {
+        let (mut fs, mut device, mut subvol) = compression_fs(4000);
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/f").unwrap();
+        let expected = noise(CLUSTER_SIZE);
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &expected)
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+
+        let id = subvol.entry.id;
+        fs.set_quota(&mut device, id, Some(subvol.entry.used_blocks + 2))
+            .unwrap();
+        let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+        let used_blocks = subvol.entry.used_blocks;
+        fd.write(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            0,
+            &noise(CLUSTER_SIZE / 2 + 1)[1..],
+        )
+        .unwrap_err();
+
+        assert_eq!(subvol.entry.used_blocks, used_blocks);
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Event {
+        Write(u64),
+        Flush,
+    }
+
+    /** A device recording the blocks written to and flushes */
+    struct OrderingDevice {
+        inner: Cursor<Vec<u8>>,
+        events: Vec<Event>,
+    }
+
+    impl Read for OrderingDevice {
+        fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for OrderingDevice {
+        fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+            let start = self.inner.position() / BLOCK_SIZE as u64;
+            let end = (self.inner.position() + buf.len() as u64).div_ceil(BLOCK_SIZE as u64);
+            self.events.extend((start..end).map(Event::Write));
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> IOResult<()> {
+            self.events.push(Event::Flush);
+            self.inner.flush()
+        }
+    }
+
+    impl Seek for OrderingDevice {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> IOResult<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    /** Write a new file of three blocks, then get the events and its data blocks */
+    fn write_ordered(barriers: bool) -> (Vec<Event>, Vec<u64>, u64) {
+        let (mut fs, device, mut subvol) = crate::utils::test_fs(2000);
+        let mut device = OrderingDevice {
+            inner: device,
+            events: Vec::new(),
+        };
+        fs.set_write_barriers(barriers);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        device.events.clear();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 3 * BLOCK_SIZE])
+            .unwrap();
+
+        let root = fd.btree_root.clone().unwrap();
+        let data_blocks = (0..3)
+            .map(|count| root.lookup(&mut device, count).unwrap().value)
+            .collect();
+        (device.events, data_blocks, root.block_count)
+    }
+
+    #[test]
+    fn read_range() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(2000);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        let data = noise(256 * BLOCK_SIZE);
+        /* holes in the middle and at the end of the file */
+        for (start, end) in [(0, 100), (110, 256)] {
+            fd.write(
+                &mut fs,
+                &mut subvol,
+                &mut device,
+                (start * BLOCK_SIZE) as u64,
+                &data[start * BLOCK_SIZE..end * BLOCK_SIZE],
+            )
+            .unwrap();
+        }
+        fd.truncate(&mut fs, &mut subvol, &mut device, 300 * BLOCK_SIZE as u64)
+            .unwrap();
+        let root = fd.btree_root.clone().unwrap();
+        assert!(root.lookup(&mut device, 105).is_err());
+
+        let size = 300 * BLOCK_SIZE;
+        let mut per_block = vec![0; size];
+        for (i, chunk) in per_block.chunks_mut(BLOCK_SIZE).enumerate() {
+            fd.read(
+                &mut fs,
+                &mut subvol,
+                &mut device,
+                (i * BLOCK_SIZE) as u64,
+                chunk,
+                BLOCK_SIZE as u64,
+            )
+            .unwrap();
+        }
+        let mut expected = data.clone();
+        expected[100 * BLOCK_SIZE..110 * BLOCK_SIZE].fill(0);
+        expected.resize(size, 0);
+        assert_eq!(per_block, expected);
+
+        let mut whole = vec![0; size];
+        fd.read(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            0,
+            &mut whole,
+            size as u64,
+        )
+        .unwrap();
+        assert_eq!(whole, per_block);
+
+        /* reads starting in the middle of blocks and holes */
+        for offset in [
+            1,
+            BLOCK_SIZE / 2,
+            100 * BLOCK_SIZE + 5,
+            255 * BLOCK_SIZE + 7,
+        ] {
+            let mut buffer = vec![0xff; 3 * BLOCK_SIZE];
+            fd.read(
+                &mut fs,
+                &mut subvol,
+                &mut device,
+                offset as u64,
+                &mut buffer,
+                3 * BLOCK_SIZE as u64,
+            )
+            .unwrap();
+            assert_eq!(buffer, per_block[offset..offset + 3 * BLOCK_SIZE]);
+        }
+    }
+
+    #[test]
+    fn verify() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(2000);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.verify(&mut device).unwrap();
+        fd.write(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            0,
+            &noise(3 * BLOCK_SIZE + 10),
+        )
+        .unwrap();
+        fd.verify(&mut device).unwrap();
+        fd.truncate(&mut fs, &mut subvol, &mut device, BLOCK_SIZE as u64 + 1)
+            .unwrap();
+        fd.verify(&mut device).unwrap();
+
+        /* a sparse file, whose tail and middle are not mapped */
+        let mut sparse = fs.create_file(&mut subvol, &mut device, "/sparse").unwrap();
+        sparse
+            .write(
+                &mut fs,
+                &mut subvol,
+                &mut device,
+                5 * BLOCK_SIZE as u64,
+                b"data",
+            )
+            .unwrap();
+        sparse
+            .truncate(&mut fs, &mut subvol, &mut device, 20 * BLOCK_SIZE as u64)
+            .unwrap();
+        sparse.verify(&mut device).unwrap();
+
+        /* size cut off the last block without unmapping it */
+        let mut inode = fd.get_inode();
+        inode.size = BLOCK_SIZE as u64;
+        subvol
+            .set_inode(&mut fs, &mut device, fd.get_inode_count(), inode)
+            .unwrap();
+        let fd = fs.open_file(&mut subvol, &mut device, "/f").unwrap();
+        let err = fd.verify(&mut device).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("maps block 1 beyond its size"));
+    }
+
+    #[test]
+    fn copy_preserve() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(2000);
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/src").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"content")
+            .unwrap();
+        fs.set_xattr(&mut subvol, &mut device, "/src", b"user.tag", b"value")
+            .unwrap();
+        let mut inode = subvol.get_inode(&mut device, fd.get_inode_count()).unwrap();
+        inode.acl = ACL_REGULAR_FILE << PERMISSION_BITS | 0o640;
+        inode.uid = 1000;
+        inode.gid = 100;
+        inode.atime = 1_000_000_000;
+        inode.mtime = 2_000_000_000;
+        subvol
+            .set_inode(&mut fs, &mut device, fd.get_inode_count(), inode)
+            .unwrap();
+
+        let options = CopyOptions { preserve: true };
+        File::copy_with_options(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            "/src",
+            "/dir/kept",
+            &options,
+        )
+        .unwrap();
+        let kept = fs.lstat(&mut subvol, &mut device, "/dir/kept").unwrap();
+        assert_eq!(kept.acl, inode.acl);
+        assert_eq!((kept.uid, kept.gid), (1000, 100));
+        assert_eq!((kept.atime, kept.mtime), (inode.atime, inode.mtime));
+        assert_eq!(kept.size, inode.size);
+        assert_eq!(
+            fs.get_xattr(&mut subvol, &mut device, "/dir/kept", b"user.tag")
+                .unwrap(),
+            Some(b"value".to_vec())
+        );
+
+        /* without preserving, only type and permissions are kept */
+        File::copy(&mut fs, &mut subvol, &mut device, "/src", "/dir/plain").unwrap();
+        let plain = fs.lstat(&mut subvol, &mut device, "/dir/plain").unwrap();
+        assert_eq!(plain.acl, inode.acl);
+        assert_eq!((plain.uid, plain.gid), (0, 0));
+        assert!(plain.mtime > inode.mtime);
+        assert_eq!(
+            fs.get_xattr(&mut subvol, &mut device, "/dir/plain", b"user.tag")
+                .unwrap(),
+            None
+        );
+        assert!(!fs.is_file(&mut subvol, &mut device, "/plain"));
+    }
+
+    #[test]
+    fn seek_data_hole() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(2000);
+        let block = BLOCK_SIZE as u64;
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        /* data in blocks 0-1 and 4, holes in blocks 2-3 and from 5 to the end */
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &noise(2 * BLOCK_SIZE))
+            .unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 4 * block + 100, b"data")
+            .unwrap();
+        fd.truncate(&mut fs, &mut subvol, &mut device, 8 * block)
+            .unwrap();
+
+        for (offset, data, hole) in [
+            (0, 0, 2 * block),
+            (block + 5, block + 5, 2 * block),
+            (2 * block, 4 * block, 2 * block),
+            (3 * block + 7, 4 * block, 3 * block + 7),
+            (4 * block, 4 * block, 5 * block),
+            (4 * block + 200, 4 * block + 200, 5 * block),
+        ] {
+            assert_eq!(fd.seek_data(&mut device, offset).unwrap(), data, "{offset}");
+            assert_eq!(fd.seek_hole(&mut device, offset).unwrap(), hole, "{offset}");
+        }
+        assert_eq!(
+            fd.seek_data(&mut device, 5 * block).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(fd.seek_hole(&mut device, 6 * block).unwrap(), 6 * block);
+        for offset in [8 * block, 9 * block] {
+            assert_eq!(
+                fd.seek_data(&mut device, offset).unwrap_err().kind(),
+                ErrorKind::NotFound
+            );
+            assert_eq!(
+                fd.seek_hole(&mut device, offset).unwrap_err().kind(),
+                ErrorKind::NotFound
+            );
+        }
+
+        /* a file ending in data has its only hole at the end */
+        let mut dense = fs.create_file(&mut subvol, &mut device, "/dense").unwrap();
+        dense
+            .write(
+                &mut fs,
+                &mut subvol,
+                &mut device,
+                0,
+                &noise(BLOCK_SIZE + 10),
+            )
+            .unwrap();
+        assert_eq!(dense.seek_hole(&mut device, 0).unwrap(), block + 10);
+    }
+
+    #[test]
+    fn seek_compressed() {
+        let (mut fs, mut device, mut subvol) = compression_fs(2000);
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/f").unwrap();
+        /* a compressed cluster maps fewer blocks than it holds */
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &vec![7; CLUSTER_SIZE])
+            .unwrap();
+        fd.truncate(&mut fs, &mut subvol, &mut device, 2 * CLUSTER_SIZE as u64)
+            .unwrap();
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/f").unwrap();
+        assert!(fd
+            .btree_root
+            .clone()
+            .unwrap()
+            .lookup(&mut device, 5)
+            .is_err());
+
+        let middle = CLUSTER_SIZE as u64 / 2;
+        assert_eq!(fd.seek_data(&mut device, middle).unwrap(), middle);
+        assert_eq!(fd.seek_hole(&mut device, 0).unwrap(), CLUSTER_SIZE as u64);
+        assert_eq!(
+            fd.seek_data(&mut device, CLUSTER_SIZE as u64)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        let mut buffer = vec![0; BLOCK_SIZE];
+        fd.read(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            middle,
+            &mut buffer,
+            BLOCK_SIZE as u64,
+        )
+        .unwrap();
+        assert_eq!(buffer, vec![7; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn relative_symlinks() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(2000);
+        fs.mkdir(&mut subvol, &mut device, "/a").unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/a/b").unwrap();
+        let target = fs
+            .create_file(&mut subvol, &mut device, "/a/b/target")
+            .unwrap()
+            .get_inode_count();
+        /* a file of the same name elsewhere, which a wrong base would find */
+        fs.create_file(&mut subvol, &mut device, "/target").unwrap();
+
+        fs.link(&mut subvol, &mut device, "/a/absolute", "/a/b/target")
+            .unwrap();
+        fs.link(&mut subvol, &mut device, "/a/b/sibling", "./target")
+            .unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/a/b/c").unwrap();
+        fs.link(&mut subvol, &mut device, "/a/b/c/uncle", "../target")
+            .unwrap();
+        fs.link(&mut subvol, &mut device, "/a/b/c/up", "..")
+            .unwrap();
+
+        for path in [
+            "/a/absolute",
+            "/a/b/sibling",
+            "/a/b/c/uncle",
+            "/a/b/c/up/target",
+            "/a/b/c/up/c/uncle",
+        ] {
+            let fd = File::open(&mut fs, &mut subvol, &mut device, path).unwrap();
+            assert_eq!(fd.get_inode_count(), target, "{path}");
+        }
+        assert_eq!(
+            fs.read_link(&mut subvol, &mut device, "/a/b/c/uncle")
+                .unwrap(),
+            Path::new("../target")
+        );
+    }
+
+    #[test]
+    fn symlink_loop() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(2000);
+        fs.link(&mut subvol, &mut device, "/a", "/b").unwrap();
+        fs.link(&mut subvol, &mut device, "/b", "a").unwrap();
+
+        for path in ["/a", "/b"] {
+            assert_eq!(
+                File::open(&mut fs, &mut subvol, &mut device, path)
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::Other
+            );
+            assert_eq!(
+                Directory::open(&mut fs, &mut subvol, &mut device, path)
+                    .err()
+                    .unwrap()
+                    .kind(),
+                ErrorKind::Other
+            );
+        }
+
+        /* the longest allowed chain of links is still followed */
+        fs.create_file(&mut subvol, &mut device, "/f0").unwrap();
+        for i in 1..=SYMLINK_MAX_FOLLOW {
+            fs.link(
+                &mut subvol,
+                &mut device,
+                format!("/f{i}"),
+                &format!("f{}", i - 1),
+            )
+            .unwrap();
+        }
+        let last = format!("/f{SYMLINK_MAX_FOLLOW}");
+        File::open(&mut fs, &mut subvol, &mut device, &last).unwrap();
+        fs.link(&mut subvol, &mut device, "/f", &last).unwrap();
+        assert_eq!(
+            File::open(&mut fs, &mut subvol, &mut device, "/f")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::Other
+        );
+    }
+
+    #[test]
+    fn write_barriers() {
+        let (events, data_blocks, root) = write_ordered(true);
+        for block in data_blocks {
+            let written = events
+                .iter()
+                .position(|event| *event == Event::Write(block))
+                .unwrap();
+            /* the block tree is written after the data block, with a flush in between */
+            let referenced = written
+                + events[written..]
+                    .iter()
+                    .position(|event| *event == Event::Write(root))
+                    .unwrap();
+            assert!(events[written..referenced].contains(&Event::Flush));
+        }
+
+        let (events, _, _) = write_ordered(false);
+        assert!(!events.contains(&Event::Flush));
+    }
+
+    #[test]
+    fn sync_all_with_parent() {
+        let (mut fs, device, mut subvol) = crate::utils::test_fs(2000);
+        let mut device = OrderingDevice {
+            inner: device,
+            events: Vec::new(),
+        };
+        let mut dir = fs.mkdir(&mut subvol, &mut device, "/d").unwrap();
+        device.events.clear();
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/d/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 2 * BLOCK_SIZE])
+            .unwrap();
+        let synced = device.events.len();
+        fd.sync_all_with_parent(&mut fs, &mut subvol, &mut device, &mut dir)
+            .unwrap();
+
+        let parent = dir.get_inode_count();
+        let mut blocks = File::open_by_inode(&mut subvol, &mut device, parent)
+            .unwrap()
+            .data_blocks(&mut device)
+            .unwrap();
+        let igroup_count = parent / INODE_PER_GROUP as u64;
+        blocks.push(
+            subvol
+                .igroup_entry(&mut device, igroup_count)
+                .unwrap()
+                .value,
+        );
+        blocks.extend(fd.data_blocks(&mut device).unwrap());
+
+        /* the file is flushed first, then the directory */
+        let events = &device.events;
+        let flushes: Vec<usize> = (synced..events.len())
+            .filter(|i| events[*i] == Event::Flush)
+            .collect();
+        assert!(flushes.len() >= 2);
+        assert_eq!(*flushes.last().unwrap(), events.len() - 1);
+        for block in blocks {
+            let written = events
+                .iter()
+                .rposition(|event| *event == Event::Write(block))
+                .unwrap();
+            assert!(written < events.len() - 1);
+        }
+
+        /* the entry survives reloading the device */
+        let mut device = device.inner;
+        let mut fs = Filesystem::load(&mut device).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        let fd = fs.open_file(&mut subvol, &mut device, "/d/f").unwrap();
+        assert_eq!(fd.get_inode().size, 2 * BLOCK_SIZE as u64);
+    }
+
+    #[test]
+    fn sync_single_file() {
+        let group_blocks = 2 + 8 * BLOCK_SIZE;
+        let (mut fs, device, mut subvol) = crate::utils::test_fs(4 * group_blocks);
+        assert_eq!(fs.groups.len(), 4);
+        let mut device = crate::utils::CountingDevice::new(device);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 2 * BLOCK_SIZE])
+            .unwrap();
+
+        let (writes, flushes) = (device.writes, device.flushes);
+        fd.sync(&mut fs, &mut subvol, &mut device).unwrap();
+        let file_writes = device.writes - writes;
+        assert_eq!(device.flushes, flushes + 1);
+
+        /* the file survives a crash before meta data of the filesystem is synchronized */
+        let mut crashed = device.inner.clone();
+        let mut reloaded = Filesystem::load(&mut crashed).unwrap();
+        assert!(reloaded.was_dirty());
+        let mut reloaded_subvol = reloaded.get_default_subvolume(&mut crashed).unwrap();
+        let mut reopened = reloaded
+            .open_file(&mut reloaded_subvol, &mut crashed, "/f")
+            .unwrap();
+        let mut buffer = vec![0; 2 * BLOCK_SIZE];
+        reopened
+            .read(
+                &mut reloaded,
+                &mut reloaded_subvol,
+                &mut crashed,
+                0,
+                &mut buffer,
+                2 * BLOCK_SIZE as u64,
+            )
+            .unwrap();
+        assert!(buffer.iter().all(|byte| *byte == 1));
+        assert!(reloaded
+            .verify_sharing(&mut crashed, false)
+            .unwrap()
+            .is_empty());
+
+        /* only the changed block group is written, the others are left alone */
+        let writes = device.writes;
+        fs.sync_meta_data(&mut device).unwrap();
+        let fs_writes = device.writes - writes;
+        assert!(file_writes * 2 < fs_writes, "{file_writes} {fs_writes}");
+    }
+
+    #[test]
+    fn write_no_space_rollback() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(300);
+        let expected = noise(4 * BLOCK_SIZE + 100);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &expected)
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        /* blocks shared with the snapshot are copied by the write */
+        let id = subvol.entry.id;
+        let snapshot_id = fs.create_snapshot(&mut device, id).unwrap();
+        let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+        let empty = fs.create_file(&mut subvol, &mut device, "/empty").unwrap();
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/f").unwrap();
+        let used_blocks = (fs.sb.used_blocks, subvol.entry.used_blocks);
+
+        let err = fd
+            .write(
+                &mut fs,
+                &mut subvol,
+                &mut device,
+                100,
+                &[1; 300 * BLOCK_SIZE],
+            )
+            .unwrap_err();
+        assert_eq!(FsError::from_io(&err), Some(&FsError::NoSpace));
+        assert_eq!(fd.get_inode().size, expected.len() as u64);
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+        assert_eq!((fs.sb.used_blocks, subvol.entry.used_blocks), used_blocks);
+
+        /* a new file gets no block tree */
+        let mut fd = empty;
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 300 * BLOCK_SIZE])
+            .unwrap_err();
+        assert_eq!(fd.get_inode().size, 0);
+        assert_eq!(fd.get_inode().btree_root, 0);
+        assert_eq!((fs.sb.used_blocks, subvol.entry.used_blocks), used_blocks);
+
+        /* blocks freed by the rollback can be used */
+        fs.open_file(&mut subvol, &mut device, "/f")
+            .unwrap()
+            .write(&mut fs, &mut subvol, &mut device, 0, &[2; BLOCK_SIZE])
+            .unwrap();
+        let mut snapshot = fs.get_subvolume(&mut device, snapshot_id).unwrap();
+        assert_eq!(read_all(&mut fs, &mut snapshot, &mut device), expected);
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        assert!(fs.verify_sharing(&mut device, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn write_handle_opened_before_copy() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(2000);
+        fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        let id = fs.clone_subvolume(&mut device, subvol.entry.id).unwrap();
+        let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+        let expected = noise(4 * BLOCK_SIZE);
+        fs.open_file(&mut subvol, &mut device, "/f")
+            .unwrap()
+            .write(&mut fs, &mut subvol, &mut device, 0, &expected)
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        let snapshot_id = fs.create_snapshot(&mut device, id).unwrap();
+        let clone_id = fs.clone_subvolume(&mut device, id).unwrap();
+
+        /* the handle is opened while the inode group is still shared */
+        let mut clone = fs.get_subvolume(&mut device, clone_id).unwrap();
+        let mut fd = fs.open_file(&mut clone, &mut device, "/f").unwrap();
+        fd.write(
+            &mut fs,
+            &mut clone,
+            &mut device,
+            2 * BLOCK_SIZE as u64,
+            &[1; 3 * BLOCK_SIZE],
+        )
+        .unwrap();
+
+        for id in [id, snapshot_id] {
+            let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+            assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+        }
+        let mut written = expected[..2 * BLOCK_SIZE].to_vec();
+        written.extend_from_slice(&[1; 3 * BLOCK_SIZE]);
+        assert_eq!(read_all(&mut fs, &mut clone, &mut device), written);
+    }
+
+    #[test]
+    fn open_options() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(500);
+        let expected = noise(3 * BLOCK_SIZE);
+        fs.write(&mut subvol, &mut device, "/f", &expected).unwrap();
+        fs.link(&mut subvol, &mut device, "/dangling", "g").unwrap();
+
+        /* create_new fails on any existing path */
+        for path in ["/f", "/dangling"] {
+            let err = fs
+                .open_options()
+                .write(true)
+                .create_new(true)
+                .open(&mut fs, &mut subvol, &mut device, path)
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        }
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+        let mut fd = fs
+            .open_options()
+            .write(true)
+            .create_new(true)
+            .open(&mut fs, &mut subvol, &mut device, "/new")
+            .unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"new")
+            .unwrap();
+
+        /* create opens an existing file as it is */
+        let fd = fs
+            .open_options()
+            .write(true)
+            .create(true)
+            .open(&mut fs, &mut subvol, &mut device, "/f")
+            .unwrap();
+        assert_eq!(fd.get_inode().size, expected.len() as u64);
+        fs.open_options()
+            .write(true)
+            .create(true)
+            .open(&mut fs, &mut subvol, &mut device, "/dangling")
+            .unwrap();
+        assert!(fs.is_file(&mut subvol, &mut device, "/g"));
+
+        /* truncate empties an existing file */
+        let fd = fs
+            .open_options()
+            .write(true)
+            .truncate(true)
+            .open(&mut fs, &mut subvol, &mut device, "/f")
+            .unwrap();
+        assert_eq!(fd.get_inode().size, 0);
+        assert!(read_all(&mut fs, &mut subvol, &mut device).is_empty());
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.check_used_blocks(&mut device).unwrap();
+
+        let err = fs
+            .open_options()
+            .read(true)
+            .open(&mut fs, &mut subvol, &mut device, "/missing")
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        for options in [
+            OpenOptions::new(),
+            OpenOptions::new().read(true).create(true).clone(),
+            OpenOptions::new().append(true).truncate(true).clone(),
+        ] {
+            let err = options
+                .open(&mut fs, &mut subvol, &mut device, "/f")
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn open_options_access() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(500);
+        fs.write(&mut subvol, &mut device, "/f", b"abc").unwrap();
+
+        let mut fd = fs
+            .open_options()
+            .read(true)
+            .open(&mut fs, &mut subvol, &mut device, "/f")
+            .unwrap();
+        let err = fd
+            .write(&mut fs, &mut subvol, &mut device, 0, b"x")
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        let err = fd
+            .truncate(&mut fs, &mut subvol, &mut device, 0)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+        let mut fd = fs
+            .open_options()
+            .write(true)
+            .open(&mut fs, &mut subvol, &mut device, "/f")
+            .unwrap();
+        let mut buffer = [0; 3];
+        let err = fd
+            .read(&mut fs, &mut subvol, &mut device, 0, &mut buffer, 3)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+        /* appending ignores the offset */
+        let mut fd = fs
+            .open_options()
+            .append(true)
+            .open(&mut fs, &mut subvol, &mut device, "/f")
+            .unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"def")
+            .unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"g")
+            .unwrap();
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), b"abcdefg");
+    }
+
+    #[test]
+    fn allocate() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(2000);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"head")
+            .unwrap();
+
+        let free_blocks = fs.free_blocks();
+        fd.allocate(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            10,
+            100 * BLOCK_SIZE as u64,
+        )
+        .unwrap();
+        assert_eq!(fs.free_blocks(), free_blocks - 100);
+        assert_eq!(fd.get_inode().size, 10 + 100 * BLOCK_SIZE as u64);
+        let mut expected = vec![0; 10 + 100 * BLOCK_SIZE];
+        expected[..4].copy_from_slice(b"head");
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+
+        /* writing the range allocates nothing */
+        let data = noise(100 * BLOCK_SIZE);
+        fd.write(&mut fs, &mut subvol, &mut device, 10, &data)
+            .unwrap();
+        assert_eq!(fs.free_blocks(), free_blocks - 100);
+        expected[10..].copy_from_slice(&data);
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+
+        /* allocating mapped blocks keeps their data, the size never shrinks */
+        fd.allocate(&mut fs, &mut subvol, &mut device, 0, 4)
+            .unwrap();
+        assert_eq!(fs.free_blocks(), free_blocks - 100);
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+
+        /* running out of space leaves the file as it was */
+        let used = (fs.sb.used_blocks, subvol.entry.used_blocks);
+        let err = fd
+            .allocate(
+                &mut fs,
+                &mut subvol,
+                &mut device,
+                0,
+                4000 * BLOCK_SIZE as u64,
+            )
+            .unwrap_err();
+        assert_eq!(FsError::from_io(&err), Some(&FsError::NoSpace));
+        assert_eq!((fs.sb.used_blocks, subvol.entry.used_blocks), used);
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+
+        assert_eq!(
+            fd.allocate(&mut fs, &mut subvol, &mut device, 0, 0)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.check_used_blocks(&mut device).unwrap();
+    }
+
+    #[test]
+    fn write_contiguous_blocks() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(2000);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        let expected = noise(64 * BLOCK_SIZE);
+
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &expected)
+            .unwrap();
+        let blocks = fd.data_blocks(&mut device).unwrap();
+        assert_eq!(blocks.len(), 64);
+        assert!(blocks.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.check_used_blocks(&mut device).unwrap();
+    }
+    #[test]
+    fn write_run_fast_path() {
+        /* reads of nodes of the block tree of the file, which are written by the write */
+        fn node_reads(device: &mut crate::utils::CountingDevice, fd: &File) -> usize {
+            let mut nodes = std::collections::HashSet::new();
+            fd.btree_root
+                .as_ref()
+                .unwrap()
+                .walk(device, |node, _| {
+                    nodes.insert(node.block_count);
+                })
+                .unwrap();
+            device
+                .read_blocks
+                .iter()
+                .filter(|block| nodes.contains(*block))
+                .count()
+        }
+
+        let (mut fs, device, mut subvol) = crate::utils::test_fs(4000);
+        let mut device = crate::utils::CountingDevice::new(device);
+        let expected = noise(512 * BLOCK_SIZE);
+
+        /* block by block, each block is looked up and inserted from the root */
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/naive").unwrap();
+        device.read_blocks.clear();
+        for (i, chunk) in expected.chunks(BLOCK_SIZE).enumerate() {
+            fd.write(
+                &mut fs,
+                &mut subvol,
+                &mut device,
+                (i * BLOCK_SIZE) as u64,
+                chunk,
+            )
+            .unwrap();
+        }
+        let naive_reads = node_reads(&mut device, &fd);
+
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        device.read_blocks.clear();
+        fd.write(&mut fs, &mut subvol, &mut device, 100, &expected)
+            .unwrap();
+        let reads = node_reads(&mut device, &fd);
+        assert!(
+            reads * 10 < naive_reads,
+            "{reads} node reads, {naive_reads} block by block"
+        );
+
+        let blocks = fd.data_blocks(&mut device).unwrap();
+        assert_eq!(blocks.len(), 513);
+        assert!(blocks.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        fd.btree_root.as_ref().unwrap().verify(&mut device).unwrap();
+        let mut fd = File::open(&mut fs, &mut subvol, &mut device, "/f").unwrap();
+        let mut data = vec![0; expected.len() + 100];
+        fd.read(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            0,
+            &mut data,
+            expected.len() as u64 + 100,
+        )
+        .unwrap();
+        assert_eq!(&data[..100], &[0; 100]);
+        assert_eq!(&data[100..], &expected[..]);
+
+        /* overwrites go block by block, as mapped blocks are written in place */
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &expected)
+            .unwrap();
+        assert_eq!(fd.data_blocks(&mut device).unwrap(), blocks);
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.check_used_blocks(&mut device).unwrap();
+    }
+    #[test]
+    fn defragment() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(2000);
+        let expected = noise(8 * BLOCK_SIZE);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        let mut other = fs.create_file(&mut subvol, &mut device, "/g").unwrap();
+        /* interleave blocks with another file, from the end */
+        for i in (0..8).rev() {
+            let offset = i * BLOCK_SIZE;
+            fd.write(
+                &mut fs,
+                &mut subvol,
+                &mut device,
+                offset as u64,
+                &expected[offset..offset + BLOCK_SIZE],
+            )
+            .unwrap();
+            other
+                .write(&mut fs, &mut subvol, &mut device, offset as u64, &[1; 1])
+                .unwrap();
+        }
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        let id = subvol.entry.id;
+        let snapshot_id = fs.create_snapshot(&mut device, id).unwrap();
+        let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+
+        assert_eq!(
+            fs.defragment_file(&mut subvol, &mut device, "/f").unwrap(),
+            8
+        );
+        let fd = fs.open_file(&mut subvol, &mut device, "/f").unwrap();
+        let blocks = fd.data_blocks(&mut device).unwrap();
+        assert!(blocks.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        assert_eq!(read_all(&mut fs, &mut subvol, &mut device), expected);
+        /* already contiguous */
+        assert_eq!(
+            fs.defragment_file(&mut subvol, &mut device, "/f").unwrap(),
+            0
+        );
+
+        let mut snapshot = fs.get_subvolume(&mut device, snapshot_id).unwrap();
+        assert_eq!(read_all(&mut fs, &mut snapshot, &mut device), expected);
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        assert!(fs.verify_sharing(&mut device, false).unwrap().is_empty());
+        fs.check_used_blocks(&mut device).unwrap();
+        assert_eq!(
+            fs.defragment_file(&mut subvol, &mut device, "/")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn truncate_grow() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(2000);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        let data = noise(2 * BLOCK_SIZE);
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &data)
+            .unwrap();
+        /* cut off the tail of the last block, it must not show up again */
+        let size = BLOCK_SIZE + 100;
+        fd.truncate(&mut fs, &mut subvol, &mut device, size as u64)
+            .unwrap();
+
+        let used_blocks = fs.sb.used_blocks;
+        let mtime = fd.get_inode().mtime;
+        let grown = 1 << 20;
+        fd.truncate(&mut fs, &mut subvol, &mut device, grown as u64)
+            .unwrap();
+        assert_eq!(fs.sb.used_blocks, used_blocks);
+        assert_eq!(fd.data_blocks(&mut device).unwrap().len(), 2);
+        assert!(fd.get_inode().mtime >= mtime);
+
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/f").unwrap();
+        assert_eq!(fd.get_inode().size, grown as u64);
+        let mut buf = vec![0xff; grown];
+        fd.read(&mut fs, &mut subvol, &mut device, 0, &mut buf, grown as u64)
+            .unwrap();
+        assert_eq!(buf[..size], data[..size]);
+        assert!(buf[size..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn atime_modes() {
+        use crate::utils::CountingDevice;
+        use crate::AtimeMode;
+
+        let (mut fs, device, mut subvol) = crate::utils::test_fs(2000);
+        let mut device = CountingDevice::new(device);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"data")
+            .unwrap();
+        let mut read =
+            |fs: &mut Filesystem, subvol: &mut Subvolume, device: &mut CountingDevice| {
+                let writes = device.writes;
+                let mut buffer = [0; 4];
+                fd.read(fs, subvol, device, 0, &mut buffer, 4).unwrap();
+                assert_eq!(&buffer, b"data");
+                (device.writes - writes, fd.get_inode().atime)
+            };
+
+        fs.set_atime_mode(AtimeMode::Never);
+        let (writes, atime) = read(&mut fs, &mut subvol, &mut device);
+        assert_eq!(writes, 0);
+        assert_eq!(read(&mut fs, &mut subvol, &mut device), (0, atime));
+
+        /* the first read after a modification updates access time, later ones don't */
+        assert_eq!(fs.atime_mode(), AtimeMode::Never);
+        fs.set_atime_mode(AtimeMode::Relatime);
+        let (writes, updated) = read(&mut fs, &mut subvol, &mut device);
+        assert!(writes > 0 && updated > atime);
+        assert_eq!(read(&mut fs, &mut subvol, &mut device), (0, updated));
+
+        fs.set_atime_mode(AtimeMode::Always);
+        let (writes, atime) = read(&mut fs, &mut subvol, &mut device);
+        assert!(writes > 0 && atime >= updated);
     }
-    Ok(())
 }
@@ -0,0 +1,80 @@
+use crate::block::{
+    BitmapBlock, BitmapIndexBlock, Block, BlockGroupMeta, INodeGroup, LinkedContentTable,
+    SuperBlock, BLOCK_SIZE,
+};
+use crate::btree::BtreeNode;
+use crate::subvol::{IGroupBitmap, SubvolumeManager};
+
+fn parse<B: Block>(bytes: [u8; BLOCK_SIZE]) {
+    if let Ok(block) = B::try_load(bytes) {
+        B::try_load(block.dump()).expect("dumped block fails to load");
+    }
+}
+
+/** Parse arbitrary bytes as every kind of block
+ *
+ * Input is zero-padded or truncated to `BLOCK_SIZE`. Panics only if a parser does.
+ */
+pub fn parse_blocks(data: &[u8]) {
+    let mut bytes = [0; BLOCK_SIZE];
+    let len = std::cmp::min(data.len(), BLOCK_SIZE);
+    bytes[..len].copy_from_slice(&data[..len]);
+
+    parse::<SuperBlock>(bytes);
+    parse::<BlockGroupMeta>(bytes);
+    parse::<BitmapBlock>(bytes);
+    parse::<BitmapIndexBlock>(bytes);
+    parse::<INodeGroup>(bytes);
+    parse::<LinkedContentTable>(bytes);
+    parse::<BtreeNode>(bytes);
+    parse::<SubvolumeManager>(bytes);
+    parse::<IGroupBitmap>(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::load_block;
+    use crate::utils::test_fs;
+
+    #[test]
+    fn malformed_blocks() {
+        parse_blocks(&[]);
+        parse_blocks(&[0xff; BLOCK_SIZE]);
+        parse_blocks(&[0xff; BLOCK_SIZE + 1]);
+
+        /* xorshift, so that the corpus is stable */
+        let mut state = 0x31c08ef5u64;
+        for _ in 0..256 {
+            let block = (0..BLOCK_SIZE)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect::<Vec<u8>>();
+            parse_blocks(&block);
+        }
+    }
+
+    #[test]
+    fn truncated_and_corrupted_metadata() {
+        let (mut fs, mut device, mut subvol) = test_fs(200);
+        fs.create_file(&mut subvol, &mut device, "/file").unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+
+        /* every block of a formatted image, truncated and with flipped bytes */
+        for count in 0..200 {
+            let block = load_block(&mut device, count).unwrap();
+            for len in [0, 1, 2, 16, 100, BLOCK_SIZE / 2] {
+                parse_blocks(&block[..len]);
+            }
+            for offset in [0, 1, 2, 3, 8, 9, 15, 16, 128] {
+                let mut corrupted = block;
+                corrupted[offset] ^= 0xff;
+                parse_blocks(&corrupted);
+            }
+        }
+    }
+}
@@ -8,10 +8,23 @@ pub const ACL_DIRECTORY: u16 = 0x2;
 pub const ACL_SYMBOLLINK: u16 = 0x4;
 pub const ACL_CHAR: u16 = 0x8;
 pub const ACL_BLOCK: u16 = 0x10;
+/** A name deleted in an overlay, hiding the file of the same name in lower layers */
+pub const ACL_WHITEOUT: u16 = 0x20;
 
 pub const PERMISSION_BITS: usize = 9;
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    RegularFile,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Whiteout,
+    Unknown,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 /**
  * # Data structure
  *
@@ -28,7 +41,9 @@ pub const PERMISSION_BITS: usize = 9;
  * |30   |32 |Hard links |
  * |32   |40 |Size       |
  * |40   |48 |B-Tree root|
- * |48   |64 |Reserved   |
+ * |48   |56 |Extended attributes|
+ * |56   |60 |Device major|
+ * |60   |64 |Device minor|
  */
 pub struct INode {
     pub acl: u16,
@@ -40,6 +55,9 @@ pub struct INode {
     pub hlinks: u16,
     pub size: u64,
     pub btree_root: u64,
+    pub xattr: u64,
+    pub dev_major: u32,
+    pub dev_minor: u32,
 }
 
 impl INode {
@@ -61,6 +79,9 @@ impl INode {
             hlinks: u16::from_be_bytes(bytes[30..32].try_into().unwrap()),
             size: u64::from_be_bytes(bytes[32..40].try_into().unwrap()),
             btree_root: u64::from_be_bytes(bytes[40..48].try_into().unwrap()),
+            xattr: u64::from_be_bytes(bytes[48..56].try_into().unwrap()),
+            dev_major: u32::from_be_bytes(bytes[56..60].try_into().unwrap()),
+            dev_minor: u32::from_be_bytes(bytes[60..64].try_into().unwrap()),
         }
     }
     /** Dump to bytes */
@@ -76,12 +97,26 @@ impl INode {
         inode_bytes[30..32].copy_from_slice(&self.hlinks.to_be_bytes());
         inode_bytes[32..40].copy_from_slice(&self.size.to_be_bytes());
         inode_bytes[40..48].copy_from_slice(&self.btree_root.to_be_bytes());
+        inode_bytes[48..56].copy_from_slice(&self.xattr.to_be_bytes());
+        inode_bytes[56..60].copy_from_slice(&self.dev_major.to_be_bytes());
+        inode_bytes[60..64].copy_from_slice(&self.dev_minor.to_be_bytes());
 
         inode_bytes
     }
     pub fn acl_type(&self) -> u16 {
         self.acl >> PERMISSION_BITS
     }
+    pub fn file_type(&self) -> FileType {
+        match self.acl_type() {
+            ACL_REGULAR_FILE => FileType::RegularFile,
+            ACL_DIRECTORY => FileType::Directory,
+            ACL_SYMBOLLINK => FileType::Symlink,
+            ACL_CHAR => FileType::CharDevice,
+            ACL_BLOCK => FileType::BlockDevice,
+            ACL_WHITEOUT => FileType::Whiteout,
+            _ => FileType::Unknown,
+        }
+    }
     pub fn is_dir(&self) -> bool {
         self.acl_type() == ACL_DIRECTORY
     }
@@ -97,6 +132,13 @@ impl INode {
     pub fn is_block(&self) -> bool {
         self.acl_type() == ACL_BLOCK
     }
+    pub fn is_whiteout(&self) -> bool {
+        self.acl_type() == ACL_WHITEOUT
+    }
+    /** Get major and minor device numbers of a character or block device */
+    pub fn device_numbers(&self) -> (u32, u32) {
+        (self.dev_major, self.dev_minor)
+    }
     pub fn is_empty_inode(&self) -> bool {
         self.acl == 0xffff
     }
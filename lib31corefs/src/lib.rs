@@ -1,32 +1,290 @@
 pub mod block;
 pub mod inode;
 
+/** Asynchronous devices, built with the `async` feature */
+#[cfg(any(feature = "async", test))]
+mod async_device;
 mod btree;
+mod cache;
 mod dir;
+mod error;
 mod file;
+/** Entry points for fuzzing block parsers, built with `--cfg fuzzing` (set by cargo-fuzz) */
+#[cfg(any(fuzzing, test))]
+pub mod fuzz;
+mod memory;
+/** Recording and replaying filesystem operations, built with the `replay` feature */
+#[cfg(any(feature = "replay", test))]
+pub mod replay;
+mod retry;
+mod send;
 mod subvol;
 mod symlink;
+mod trim;
 mod utils;
+mod xattr;
 
-pub use dir::Directory;
-pub use file::File;
-pub use subvol::Subvolume;
+#[cfg(any(feature = "async", test))]
+pub use async_device::AsyncDevice;
+pub use cache::{CachedDevice, DEFAULT_CACHE_BLOCKS};
+pub use dir::{DirEntry, DirHash, Directory, FILE_NAME_MAX};
+pub use error::FsError;
+pub use file::{CopyOptions, File, OpenOptions, COMPRESSION_XATTR};
+pub use memory::MemoryDevice;
+pub use retry::{RetryDevice, RetryPolicy};
+pub use subvol::{SharingIssue, Subvolume, SubvolumeEntry, SubvolumeNode};
+pub use trim::Trim;
 
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::io::{Error, ErrorKind, Result as IOResult};
-use std::io::{Read, Seek, Write};
-use std::path::{Path, PathBuf};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
 
-use block::{Block, BlockGroup, SuperBlock};
-use subvol::{SubvolumeEntry, SubvolumeManager, SUBVOLUME_STATE_ALLOCATED};
-use utils::{base_name, dir_path, get_sys_time};
+use block::{Block, BlockGroup, LinkedContentTable, SuperBlock};
+use inode::{FileType, INode, ACL_BLOCK, ACL_CHAR, ACL_WHITEOUT, PERMISSION_BITS};
+use subvol::{SubvolumeManager, SUBVOLUME_STATE_ALLOCATED};
+use utils::{base_name, dir_path, get_sys_time, normalize};
 
 pub const FS_MAGIC_HEADER: [u8; 4] = [0x31, 0xc0, 0x8e, 0xf5];
 pub const FS_VERSION: u8 = 1;
 
+/** Count writes of data blocks for each block group */
+pub const FEATURE_COMPAT_HEAT_MAP: u64 = 0x1;
+
+/** A copy of the superblock is kept at the last block of the filesystem */
+pub const FEATURE_COMPAT_BACKUP_SUPERBLOCK: u64 = 0x2;
+
+/** Entries of directories are kept in their B-Trees, keyed with hash of file names */
+pub const FEATURE_INCOMPAT_BTREE_DIR: u64 = 0x1;
+
+/** Data of regular files can be compressed */
+pub const FEATURE_INCOMPAT_COMPRESSION: u64 = 0x2;
+
+/** Block groups have other than one bitmap block */
+pub const FEATURE_INCOMPAT_BLOCK_MAP_SIZE: u64 = 0x4;
+
+/** Directory indexes hash names with other than FNV-1a */
+pub const FEATURE_INCOMPAT_DIR_HASH: u64 = 0x8;
+
+const FEATURE_INCOMPAT_SUPPORTED: u64 = FEATURE_INCOMPAT_BTREE_DIR
+    | FEATURE_INCOMPAT_COMPRESSION
+    | FEATURE_INCOMPAT_BLOCK_MAP_SIZE
+    | FEATURE_INCOMPAT_DIR_HASH;
+
+const FEATURE_COMPAT_NAMES: &[(u64, &str)] = &[
+    (FEATURE_COMPAT_HEAT_MAP, "heat_map"),
+    (FEATURE_COMPAT_BACKUP_SUPERBLOCK, "backup_superblock"),
+];
+const FEATURE_INCOMPAT_NAMES: &[(u64, &str)] = &[
+    (FEATURE_INCOMPAT_BTREE_DIR, "btree_dir"),
+    (FEATURE_INCOMPAT_COMPRESSION, "compression"),
+    (FEATURE_INCOMPAT_BLOCK_MAP_SIZE, "block_map_size"),
+    (FEATURE_INCOMPAT_DIR_HASH, "dir_hash"),
+];
+
+/** Superblock value of `default_subvol` when no default subvolume is set */
+pub const NO_DEFAULT_SUBVOLUME: u64 = u64::MAX;
+
+/** Superblock state when meta data was synchronized after the last change */
+pub const FS_STATE_CLEAN: u64 = 0;
+/** Superblock state when the filesystem is changed and not synchronized yet */
+pub const FS_STATE_DIRTY: u64 = 1;
+
+/** Maximum length of a path in bytes, like `PATH_MAX` */
+pub const PATH_MAX: usize = 4096;
+
+/** Maximum number of symbol links followed while resolving a path */
+const SYMLINK_MAX_FOLLOW: usize = 40;
+
+/** Features enabled on a filesystem, see `Filesystem::features` */
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    pub compat: u64,
+    pub incompat: u64,
+    pub ro_compat: u64,
+}
+
+impl Features {
+    pub fn heat_map(&self) -> bool {
+        self.compat & FEATURE_COMPAT_HEAT_MAP != 0
+    }
+    pub fn backup_superblock(&self) -> bool {
+        self.compat & FEATURE_COMPAT_BACKUP_SUPERBLOCK != 0
+    }
+    pub fn btree_dir(&self) -> bool {
+        self.incompat & FEATURE_INCOMPAT_BTREE_DIR != 0
+    }
+    pub fn compression(&self) -> bool {
+        self.incompat & FEATURE_INCOMPAT_COMPRESSION != 0
+    }
+    pub fn block_map_size(&self) -> bool {
+        self.incompat & FEATURE_INCOMPAT_BLOCK_MAP_SIZE != 0
+    }
+    pub fn dir_hash(&self) -> bool {
+        self.incompat & FEATURE_INCOMPAT_DIR_HASH != 0
+    }
+    /** Names of enabled features, unknown features are named by their bit */
+    pub fn names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for (mask, kind, known) in [
+            (self.compat, "compat", FEATURE_COMPAT_NAMES),
+            (self.incompat, "incompat", FEATURE_INCOMPAT_NAMES),
+            (self.ro_compat, "ro_compat", &[]),
+        ] {
+            for feature in (0..u64::BITS).map(|bit| 1 << bit) {
+                if mask & feature == 0 {
+                    continue;
+                }
+                match known.iter().find(|(known, _)| *known == feature) {
+                    Some((_, name)) => names.push(name.to_string()),
+                    None => names.push(format!("{kind}_{feature:#x}")),
+                }
+            }
+        }
+        names
+    }
+}
+
+/** Space used by a directory tree, see `Filesystem::disk_usage` */
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsage {
+    /** Sum of sizes of regular files */
+    pub apparent_size: u64,
+    /** Count of data blocks of regular files, each shared block counted once */
+    pub blocks: u64,
+}
+
+/** Allocation statistics, see `Filesystem::allocation_report` */
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct AllocReport {
+    /** Ratio of used data blocks of each block group, from 0 to 1 */
+    pub group_fill: Vec<f64>,
+    /** Count of blocks recorded by shared bitmaps of subvolumes, i.e. shared with snapshots */
+    pub shared_blocks: u64,
+    /** Count of blocks copied out on write since the filesystem was loaded */
+    pub cow_copies: u64,
+}
+
+/** Blocks used by a subvolume, see `Filesystem::subvolume_usage` */
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /** Blocks released by removing the subvolume */
+    pub exclusive: u64,
+    /** Blocks used by the subvolume which are kept for its origin or snapshots */
+    pub shared: u64,
+    /** Sum of exclusive and shared blocks */
+    pub total: u64,
+}
+
+/** Layout of a block group, see `Filesystem::describe` */
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupDescription {
+    pub id: u64,
+    pub start_block: u64,
+    /** Count of data blocks, excluding the group meta data and bitmaps */
+    pub capacity: u64,
+    pub free_blocks: u64,
+}
+
+/** A subvolume entry with its root inode, see `Filesystem::describe` */
+#[derive(Debug, Clone)]
+pub struct SubvolumeDescription {
+    pub entry: SubvolumeEntry,
+    /** `None` if the subvolume is removed */
+    pub root_inode: Option<INode>,
+}
+
+/** Layout of block groups and subvolumes, see `Filesystem::describe` */
+#[derive(Default, Debug, Clone)]
+pub struct FsDescription {
+    pub groups: Vec<GroupDescription>,
+    /** All subvolumes, removed ones included */
+    pub subvolumes: Vec<SubvolumeDescription>,
+}
+
+#[derive(Debug, Clone)]
+/** Options used to format a filesystem */
+pub struct FormatOptions {
+    /** Create a subvolume and set it as default */
+    pub default_subvolume: bool,
+    /** Enable `FEATURE_COMPAT_HEAT_MAP` */
+    pub heat_map: bool,
+    /** Enable `FEATURE_INCOMPAT_COMPRESSION` */
+    pub compression: bool,
+    /** Count of bitmap blocks in each block group, larger groups reduce count of groups */
+    pub block_map_size: u64,
+    /** Count of free blocks kept for metadata, see `Filesystem::set_metadata_reserve` */
+    pub metadata_reserve: u64,
+    /** Hash of names in directory indexes, other than FNV-1a enables `FEATURE_INCOMPAT_DIR_HASH` */
+    pub dir_hash: DirHash,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            default_subvolume: true,
+            heat_map: false,
+            compression: false,
+            block_map_size: block::DEFAULT_BLOCK_MAP_SIZE,
+            metadata_reserve: 0,
+            dir_hash: DirHash::default(),
+        }
+    }
+}
+
+/** When reading a file updates its access time */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AtimeMode {
+    /** Update on every read */
+    Always,
+    /** Update if the access time is not later than the modification or change time, or is
+     * older than a day */
+    #[default]
+    Relatime,
+    /** Never update on read */
+    Never,
+}
+
+impl AtimeMode {
+    /** Check if reading a file at `now` should update its access time */
+    pub(crate) fn should_update(&self, inode: &INode, now: u64) -> bool {
+        const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+        match self {
+            Self::Always => true,
+            Self::Relatime => {
+                inode.atime <= inode.mtime
+                    || inode.atime <= inode.ctime
+                    || now.saturating_sub(inode.atime) >= DAY
+            }
+            Self::Never => false,
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct Filesystem {
     pub sb: SuperBlock,
     groups: Vec<BlockGroup>,
+    /** Block groups changed since meta data was last synchronized */
+    dirty_groups: BTreeSet<usize>,
+    /** Blocks allocated by an operation which is rolled back if it fails */
+    allocated: Option<Vec<u64>>,
+    /** Check newly allocated blocks against bitmaps of subvolumes */
+    verify_allocation: bool,
+    /** Flush written data before the metadata referencing it */
+    write_barriers: bool,
+    /** When reads update access time */
+    atime_mode: AtimeMode,
+    /** The superblock was dirty when loaded */
+    was_dirty: bool,
+    /** Loaded from the backup superblock as block 0 is invalid */
+    from_backup: bool,
+    /** Opened by `load_readonly`, nothing is written to the device */
+    readonly: bool,
+    /** Released blocks waiting to be discarded, kept if discarding is enabled */
+    released: Option<Vec<u64>>,
+    /** Count of blocks copied out on write since loaded, see `Filesystem::allocation_report` */
+    cow_copies: u64,
 }
 
 impl Filesystem {
@@ -34,26 +292,82 @@ impl Filesystem {
     where
         D: Read + Write + Seek,
     {
-        const BLOCK_GROUP_MINIMAL_SZIE: usize = 3;
+        Self::create_with_options(device, block_size, &FormatOptions::default())
+    }
+    pub fn create_with_options<D>(
+        device: &mut D,
+        block_size: usize,
+        options: &FormatOptions,
+    ) -> IOResult<Self>
+    where
+        D: Read + Write + Seek,
+    {
+        if options.block_map_size == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Block group requires at least one bitmap block.",
+            ));
+        }
 
         let mut fs = Self::default();
+        /* clean once the new filesystem is synchronized */
+        fs.sb.state = FS_STATE_DIRTY;
+        fs.sb.block_map_size = options.block_map_size;
+        fs.sb.metadata_reserve = options.metadata_reserve;
         fs.sb.uuid = *uuid::Uuid::new_v4().as_bytes();
         fs.sb.total_blocks = block_size as u64;
+        fs.sb.feature_compat = FEATURE_COMPAT_BACKUP_SUPERBLOCK;
+        fs.sb.feature_incompat = FEATURE_INCOMPAT_BTREE_DIR;
+        if options.heat_map {
+            fs.sb.feature_compat |= FEATURE_COMPAT_HEAT_MAP;
+        }
+        if options.compression {
+            fs.sb.feature_incompat |= FEATURE_INCOMPAT_COMPRESSION;
+        }
+        if options.block_map_size != block::DEFAULT_BLOCK_MAP_SIZE {
+            fs.sb.feature_incompat |= FEATURE_INCOMPAT_BLOCK_MAP_SIZE;
+        }
+        fs.sb.dir_hash = options.dir_hash.id();
+        if options.dir_hash != DirHash::Fnv1a {
+            fs.sb.feature_incompat |= FEATURE_INCOMPAT_DIR_HASH;
+        }
+
+        /* meta block, bitmap blocks and at least one data block */
+        let group_minimal_size = 2 + options.block_map_size;
+        /* the last block is left for the backup superblock */
+        let groups_end = block_size as u64 - 1;
+        if (block_size as u64) < 2 + group_minimal_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Device is too small for a block group.",
+            ));
+        }
 
         let mut group_start = 1;
-        while group_start <= (block_size - BLOCK_GROUP_MINIMAL_SZIE) as u64 {
-            let mut group = BlockGroup::create(group_start, block_size as u64 - group_start);
+        loop {
+            let mut group = BlockGroup::create(
+                group_start,
+                groups_end - group_start,
+                options.block_map_size,
+            );
             group.meta_data.id = fs.groups.len() as u64;
 
-            group_start += group.blocks();
+            group_start = group.meta_data.next_group;
             fs.groups.push(group);
+            if group_start == 0 {
+                break;
+            }
         }
 
         fs.sb.groups = fs.groups.len() as u64;
         fs.sb.subvol_mgr = SubvolumeManager::allocate_on_block(&mut fs, device)?;
         fs.sb.creation_time = get_sys_time();
 
-        fs.sb.default_subvol = fs.new_subvolume(device)?;
+        fs.sb.default_subvol = if options.default_subvolume {
+            fs.new_subvolume(device, None)?
+        } else {
+            NO_DEFAULT_SUBVOLUME
+        };
 
         Ok(fs)
     }
@@ -61,14 +375,48 @@ impl Filesystem {
     where
         D: Read + Write + Seek,
     {
-        let sb_block = block::load_block(device, 0)?;
+        let mut sb_block = block::load_block(device, 0)?;
+        let mut from_backup = false;
         if !SuperBlock::is_valid(&sb_block) {
+            sb_block = Self::load_backup_superblock(device).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid fs type or incorrect version.",
+                )
+            })?;
+            from_backup = true;
+        }
+        let mut sb = SuperBlock::load(sb_block);
+        if sb.feature_incompat & !FEATURE_INCOMPAT_SUPPORTED != 0 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Unsupported filesystem features.",
+            ));
+        }
+        if sb.block_map_size != block::DEFAULT_BLOCK_MAP_SIZE
+            && !sb.has_feature_incompat(FEATURE_INCOMPAT_BLOCK_MAP_SIZE)
+        {
             return Err(Error::new(
                 ErrorKind::InvalidData,
-                "Invalid fs type or incorrect version.",
+                "Block map size is set without its feature flag.",
             ));
         }
-        let sb = SuperBlock::load(sb_block);
+        match DirHash::from_id(sb.dir_hash) {
+            Some(DirHash::Fnv1a) => {}
+            Some(_) if sb.has_feature_incompat(FEATURE_INCOMPAT_DIR_HASH) => {}
+            Some(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Directory hash is set without its feature flag.",
+                ))
+            }
+            None => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("Unsupported directory hash {}.", sb.dir_hash),
+                ))
+            }
+        }
 
         let mut groups = Vec::new();
 
@@ -76,6 +424,7 @@ impl Filesystem {
         loop {
             let mut group = BlockGroup {
                 start_block: group_start,
+                block_map_size: sb.block_map_size,
                 ..Default::default()
             };
             group.load(device)?;
@@ -88,165 +437,1028 @@ impl Filesystem {
             }
         }
 
-        Ok(Self { sb, groups })
+        /* images created before subvolumes were counted have the count left zero */
+        if sb.subvols == 0 {
+            sb.subvols = SubvolumeManager::list_subvols(device, sb.subvol_mgr)?.len() as u64;
+        }
+
+        Ok(Self {
+            was_dirty: sb.state != FS_STATE_CLEAN,
+            from_backup,
+            sb,
+            groups,
+            ..Default::default()
+        })
+    }
+    /** Find the backup superblock at the last block of the device
+     *
+     * It is only found if the filesystem covers the whole device, `None` is returned if the block
+     * is not a valid superblock recording itself as a backup at that position.
+     */
+    fn load_backup_superblock<D>(device: &mut D) -> Option<[u8; block::BLOCK_SIZE]>
+    where
+        D: Read + Write + Seek,
+    {
+        let total_blocks = device.seek(SeekFrom::End(0)).ok()? / block::BLOCK_SIZE as u64;
+        let backup = block::load_block(device, total_blocks.checked_sub(1)?).ok()?;
+        if !SuperBlock::is_valid(&backup) {
+            return None;
+        }
+        let sb = SuperBlock::load(backup);
+        (sb.has_feature_compat(FEATURE_COMPAT_BACKUP_SUPERBLOCK) && sb.total_blocks == total_blocks)
+            .then_some(backup)
+    }
+    /** Check if the filesystem was loaded from the backup superblock as block 0 is invalid
+     *
+     * Block 0 is not repaired by loading, see `Filesystem::restore_superblock`.
+     */
+    pub fn loaded_from_backup(&self) -> bool {
+        self.from_backup
+    }
+    /** Rewrite block 0 from the backup superblock */
+    pub fn restore_superblock<D>(&mut self, device: &mut D) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.check_writable()?;
+        if !self.sb.has_feature_compat(FEATURE_COMPAT_BACKUP_SUPERBLOCK) {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "Filesystem has no backup superblock.",
+            ));
+        }
+        let backup = block::load_block(device, self.sb.total_blocks - 1)?;
+        if !SuperBlock::is_valid(&backup) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Backup superblock is invalid.",
+            ));
+        }
+        block::save_block(device, 0, backup)?;
+        device.flush()?;
+        self.from_backup = false;
+        Ok(())
+    }
+    /** Write the superblock to block 0, and to its backup if the filesystem keeps one */
+    fn sync_superblock<D>(&mut self, device: &mut D) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.sb.sync(device, 0)?;
+        if self.sb.has_feature_compat(FEATURE_COMPAT_BACKUP_SUPERBLOCK) {
+            let backup = self.sb.total_blocks - 1;
+            self.sb.sync(device, backup)?;
+        }
+        Ok(())
+    }
+    /** Load a filesystem for inspection, every change is refused with `PermissionDenied`
+     *
+     * Reading files does not update their access time, and synchronizing meta data writes
+     * nothing.
+     */
+    pub fn load_readonly<D>(device: &mut D) -> IOResult<Self>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut fs = Self::load(device)?;
+        fs.readonly = true;
+        Ok(fs)
+    }
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
     }
     /** Allocate a data block */
     pub(crate) fn new_block(&mut self) -> IOResult<u64> {
-        for group in &mut self.groups {
-            if let Some(count) = group.new_block() {
+        for i in 0..self.groups.len() {
+            if let Some(relative_count) = self.groups[i].new_block() {
+                let count = self.groups[i].to_absolute_block(relative_count);
+                /* a corrupted free count could hand out blocks past the device */
+                if !self.is_data_block(count) {
+                    self.groups[i].release_block(relative_count);
+                    return Err(block::invalid_block(&format!(
+                        "Allocated block {count} is outside the data region."
+                    )));
+                }
+                self.dirty_groups.insert(i);
                 self.sb.used_blocks += 1;
                 self.sb.real_used_blocks += 1;
-                return Ok(group.to_absolute_block(count));
+                if let Some(allocated) = &mut self.allocated {
+                    allocated.push(count);
+                }
+                return Ok(count);
             }
         }
-        Err(Error::new(ErrorKind::Other, "No enough block"))
+        Err(FsError::NoSpace.into())
     }
-    /** Release a data block */
-    pub(crate) fn release_block(&mut self, count: u64) {
-        let mut group_count = 0;
-        while !(group_count < self.groups.len() - 2
-            && count > self.groups[group_count].start_block
-            && count < self.groups[group_count + 1].start_block)
-        {
-            group_count += 1;
+    /** Allocate a block for file data, which can not take blocks reserved for metadata */
+    pub(crate) fn new_data_block(&mut self) -> IOResult<u64> {
+        if self.free_blocks() <= self.sb.metadata_reserve {
+            return Err(FsError::NoSpace.into());
+        }
+        self.new_block()
+    }
+    /** Allocate `count` contiguous blocks for file data and return the first one */
+    pub(crate) fn new_data_run(&mut self, count: u64) -> IOResult<u64> {
+        if self.free_blocks() < self.sb.metadata_reserve + count {
+            return Err(FsError::NoSpace.into());
         }
 
-        let relative_count = self.groups[group_count].to_relative_block(count);
-        self.groups[group_count].release_block(relative_count);
-        self.sb.used_blocks -= 1;
-        self.sb.real_used_blocks -= 1;
+        let group = self
+            .groups
+            .iter()
+            .position(|group| group.find_free_run(count).is_some())
+            .ok_or(FsError::NoSpace)?;
+        Ok(self.allocate_run_in(group, count)?.0)
     }
-    /** Synchronize meta data to disk */
-    pub fn sync_meta_data<D>(&mut self, device: &mut D) -> IOResult<()>
+    /** Allocate up to `count` contiguous blocks for file data
+     *
+     * A group holding all blocks in a run is preferred, otherwise the longest run of the first
+     * group with free blocks is taken. Return the first block and count of blocks allocated.
+     */
+    pub(crate) fn new_data_blocks(&mut self, count: u64) -> IOResult<(u64, u64)> {
+        let count = std::cmp::min(
+            count,
+            self.free_blocks().saturating_sub(self.sb.metadata_reserve),
+        );
+        if count == 0 {
+            return Err(FsError::NoSpace.into());
+        }
+
+        let group = self
+            .groups
+            .iter()
+            .position(|group| group.find_free_run(count).is_some())
+            .or_else(|| {
+                self.groups
+                    .iter()
+                    .position(|group| group.meta_data.free_blocks > 0)
+            })
+            .ok_or(FsError::NoSpace)?;
+        self.allocate_run_in(group, count)
+    }
+    /** Allocate a run of up to `count` blocks in a group and count them as used */
+    fn allocate_run_in(&mut self, group: usize, count: u64) -> IOResult<(u64, u64)> {
+        let (start, len) = self.groups[group]
+            .allocate_run(count)
+            .ok_or(FsError::NoSpace)?;
+        let first = self.groups[group].to_absolute_block(start);
+        /* a corrupted free count could hand out blocks past the device */
+        if !self.is_data_block(first + len - 1) {
+            for relative_count in start..start + len {
+                self.groups[group].release_block(relative_count);
+            }
+            return Err(block::invalid_block(&format!(
+                "Allocated block {} is outside the data region.",
+                first + len - 1
+            )));
+        }
+        self.dirty_groups.insert(group);
+
+        self.sb.used_blocks += len;
+        self.sb.real_used_blocks += len;
+        if let Some(allocated) = &mut self.allocated {
+            allocated.extend(first..first + len);
+        }
+        Ok((first, len))
+    }
+    /** Get features enabled on the filesystem */
+    pub fn features(&self) -> Features {
+        Features {
+            compat: self.sb.feature_compat,
+            incompat: self.sb.feature_incompat,
+            ro_compat: self.sb.feature_ro_compat,
+        }
+    }
+    /** Copy usage counters of the superblock
+     *
+     * The copy is taken while no operation can modify the filesystem, so the counters are
+     * consistent with each other and stay unchanged by later operations.
+     */
+    pub fn superblock_snapshot(&self) -> block::SuperBlockView {
+        block::SuperBlockView {
+            groups: self.sb.groups,
+            total_blocks: self.sb.total_blocks,
+            used_blocks: self.sb.used_blocks,
+            real_used_blocks: self.sb.real_used_blocks,
+            free_blocks: self.free_blocks(),
+            metadata_reserve: self.sb.metadata_reserve,
+            subvols: self.subvolume_count(),
+        }
+    }
+    /** Get count of free blocks, including blocks reserved for metadata */
+    pub fn free_blocks(&self) -> u64 {
+        self.groups
+            .iter()
+            .map(|group| group.meta_data.free_blocks)
+            .sum()
+    }
+    /** Keep `blocks` free blocks for metadata
+     *
+     * File data is not allocated from the last `blocks` free blocks, so that snapshots and
+     * removals, which only allocate metadata, can still complete when file data fills the
+     * filesystem. Content of directories is allocated as metadata.
+     */
+    pub fn set_metadata_reserve(&mut self, blocks: u64) {
+        self.sb.metadata_reserve = blocks;
+    }
+    /** Check each newly allocated block against bitmaps of all subvolumes
+     *
+     * Allocating a block which is still marked by a subvolume fails with `InvalidData`, instead of
+     * corrupting data later. It reads every subvolume bitmap on each allocation, so it is meant
+     * for testing.
+     */
+    pub fn set_verify_allocation(&mut self, enabled: bool) {
+        self.verify_allocation = enabled;
+    }
+    /** Flush data blocks to the device before writing metadata referencing them
+     *
+     * Without barriers, a device reordering writes may store a block tree or an inode before the
+     * data blocks it points to, so a file may contain garbage after a power loss. Each barrier
+     * flushes the device, which makes writing slower.
+     */
+    pub fn set_write_barriers(&mut self, enabled: bool) {
+        self.write_barriers = enabled;
+    }
+    /** Keep released blocks so that `discard_released` discards them
+     *
+     * Releasing a block only marks it free in bitmaps, which device adapters can't see, so
+     * blocks are discarded in batches rather than as they are released.
+     */
+    pub fn set_discard(&mut self, enabled: bool) {
+        if !enabled {
+            self.released = None;
+        } else if self.released.is_none() {
+            self.released = Some(Vec::new());
+        }
+    }
+    /** Choose when reading a file updates its access time, `AtimeMode::Relatime` by default
+     *
+     * Each update writes the inode, so reads of a snapshot taken before may copy its inode group.
+     */
+    pub fn set_atime_mode(&mut self, mode: AtimeMode) {
+        self.atime_mode = mode;
+    }
+    pub fn atime_mode(&self) -> AtimeMode {
+        self.atime_mode
+    }
+    /** Flush the device if write barriers are enabled */
+    pub(crate) fn barrier<D>(&self, device: &mut D) -> IOResult<()>
     where
-        D: Read + Write + Seek,
+        D: Write,
     {
-        self.sb.sync(device, 0)?;
-        for group in &mut self.groups {
-            group.sync(device)?;
+        if self.write_barriers {
+            device.flush()?;
+        }
+        Ok(())
+    }
+    /** Release a data block, which must be a data block of a block group */
+    pub(crate) fn release_block(&mut self, count: u64) -> IOResult<()> {
+        if !self.is_data_block(count) {
+            return Err(block::invalid_block(&format!(
+                "Block {count} is not a data block of any block group."
+            )));
         }
 
+        if let Some(allocated) = &mut self.allocated {
+            allocated.retain(|block| *block != count);
+        }
+        self.unmark_block(count);
+        self.sb.used_blocks -= 1;
+        self.sb.real_used_blocks -= 1;
         Ok(())
     }
-    /** Create a subvolume and return it's ID */
-    pub fn new_subvolume<D>(&mut self, device: &mut D) -> IOResult<u64>
+    /** Run an operation and release blocks allocated by it if it fails */
+    pub(crate) fn rollback_on_error<T, F>(&mut self, operation: F) -> IOResult<T>
     where
-        D: Read + Write + Seek,
+        F: FnOnce(&mut Self) -> IOResult<T>,
     {
-        SubvolumeManager::new_subvolume(self, device)
+        let outer = self.allocated.replace(Vec::new());
+        let result = operation(self);
+        let allocated = std::mem::replace(&mut self.allocated, outer).unwrap_or_default();
+
+        if result.is_err() {
+            /* logged blocks were allocated from block groups, so they are always released */
+            for count in allocated {
+                let _ = self.release_block(count);
+            }
+        } else if let Some(outer) = &mut self.allocated {
+            outer.extend(allocated);
+        }
+        result
     }
-    pub fn remove_subvolume<D>(&mut self, device: &mut D, id: u64) -> IOResult<()>
-    where
-        D: Read + Write + Seek,
-    {
-        if id == self.sb.default_subvol {
-            Err(Error::new(
-                ErrorKind::Unsupported,
-                "Removing default subvolume is not supported.",
-            ))
+    /** Mark a data block free in its block group, without updating counters of superblock */
+    pub(crate) fn unmark_block(&mut self, count: u64) {
+        if let Some(index) = self.group_of(count) {
+            let group = &mut self.groups[index];
+            if count >= group.to_absolute_block(0) {
+                let relative_count = group.to_relative_block(count);
+                if group.get_used(relative_count) {
+                    group.release_block(relative_count);
+                    self.dirty_groups.insert(index);
+                    if let Some(released) = &mut self.released {
+                        released.push(count);
+                    }
+                }
+            }
+        }
+    }
+    /** Count of bitmap blocks needed by a subvolume bitmap to cover all blocks */
+    pub(crate) fn subvol_bitmap_size(&self) -> usize {
+        self.sb.total_blocks.div_ceil(8 * block::BLOCK_SIZE as u64) as usize
+    }
+    /** Count a write to a data block in heat map */
+    pub(crate) fn record_write(&mut self, count: u64) {
+        if !self.sb.has_feature_compat(FEATURE_COMPAT_HEAT_MAP) {
+            return;
+        }
+
+        if let Some(group) = self.group_of(count) {
+            self.groups[group].meta_data.writes += 1;
+            self.dirty_groups.insert(group);
+        }
+    }
+    /** Count a block copied out on write */
+    pub(crate) fn record_copy_out(&mut self) {
+        self.cow_copies += 1;
+    }
+    /** Find the block group containing a block */
+    fn group_of(&self, count: u64) -> Option<usize> {
+        /* the range of the last group may extend beyond the device */
+        if count >= self.sb.total_blocks {
+            return None;
+        }
+        self.groups
+            .iter()
+            .position(|group| group.block_range().contains(&count))
+    }
+    /** Check if a block is a data block of a group, not a superblock or group meta data */
+    fn is_data_block(&self, count: u64) -> bool {
+        /* the backup superblock is the last block of the last group, but never allocated */
+        if self.sb.has_feature_compat(FEATURE_COMPAT_BACKUP_SUPERBLOCK)
+            && count == self.sb.total_blocks - 1
+        {
+            return false;
+        }
+        matches!(self.group_of(count), Some(group) if count >= self.groups[group].to_absolute_block(0))
+    }
+    /** Check if a data block is allocated in block groups */
+    pub(crate) fn is_block_allocated(&self, count: u64) -> bool {
+        match self.group_of(count) {
+            Some(group) if count >= self.groups[group].to_absolute_block(0) => {
+                let relative_count = self.groups[group].to_relative_block(count);
+                self.groups[group].get_used(relative_count)
+            }
+            _ => false,
+        }
+    }
+    /** Mark a data block allocated if it is free */
+    pub(crate) fn mark_block_allocated(&mut self, count: u64) {
+        if let Some(index) = self.group_of(count) {
+            let group = &mut self.groups[index];
+            if count < group.to_absolute_block(0) {
+                return;
+            }
+
+            let relative_count = group.to_relative_block(count);
+            if !group.get_used(relative_count) {
+                group.set_used(relative_count);
+                group.meta_data.free_blocks -= 1;
+                self.dirty_groups.insert(index);
+                self.sb.used_blocks += 1;
+                self.sb.real_used_blocks += 1;
+            }
+        }
+    }
+    /** Get counts of data block writes of each block group, `None` if heat map is disabled */
+    pub fn heat_map(&self) -> Option<Vec<u64>> {
+        if self.sb.has_feature_compat(FEATURE_COMPAT_HEAT_MAP) {
+            Some(
+                self.groups
+                    .iter()
+                    .map(|group| group.meta_data.writes)
+                    .collect(),
+            )
         } else {
-            SubvolumeManager::remove_subvolume(self, device, id)
+            None
         }
     }
-    pub fn get_subvolume<D>(&self, device: &mut D, id: u64) -> IOResult<Subvolume>
+    /** Describe each block group and each subvolume with its root inode, for debugging */
+    pub fn describe<D>(&self, device: &mut D) -> IOResult<FsDescription>
     where
         D: Read + Write + Seek,
     {
-        let subvol = SubvolumeManager::get_subvolume(device, self.sb.subvol_mgr, id)?;
-        if subvol.entry.state != SUBVOLUME_STATE_ALLOCATED {
-            Err(Error::new(
-                ErrorKind::NotFound,
-                format!("No such subvolume '{id}'"),
-            ))
-        } else {
-            Ok(subvol)
+        let groups = self
+            .groups
+            .iter()
+            .map(|group| GroupDescription {
+                id: group.meta_data.id,
+                start_block: group.start_block,
+                capacity: group.data_capacity(),
+                free_blocks: group.meta_data.free_blocks,
+            })
+            .collect();
+
+        let mut subvolumes = Vec::new();
+        if self.sb.subvol_mgr != 0 {
+            for entry in SubvolumeManager::list_all(device, self.sb.subvol_mgr)? {
+                let root_inode = if entry.state == SUBVOLUME_STATE_ALLOCATED {
+                    let subvol = self.get_subvolume(device, entry.id)?;
+                    Some(subvol.get_inode(device, entry.root_inode)?)
+                } else {
+                    None
+                };
+                subvolumes.push(SubvolumeDescription { entry, root_inode });
+            }
         }
+
+        Ok(FsDescription { groups, subvolumes })
     }
-    pub fn get_default_subvolume<D>(&self, device: &mut D) -> IOResult<Subvolume>
+    /** Tell how many blocks used by a subvolume are its own and how many are shared
+     *
+     * Blocks recorded by the bitmap of the subvolume are exclusive, so are those of its shared
+     * bitmap once no snapshot refers to them. The rest of used blocks are inherited from the
+     * origin or kept for snapshots.
+     */
+    pub fn subvolume_usage<D>(&self, device: &mut D, id: u64) -> IOResult<Usage>
     where
         D: Read + Write + Seek,
     {
-        SubvolumeManager::get_subvolume(device, self.sb.subvol_mgr, self.sb.default_subvol)
+        let subvol = self.get_subvolume(device, id)?;
+        let exclusive = SubvolumeManager::exclusive_blocks(device, &subvol.entry)?;
+        let shared = subvol.entry.used_blocks.saturating_sub(exclusive);
+        Ok(Usage {
+            exclusive,
+            shared,
+            total: exclusive + shared,
+        })
     }
-    /** Create a snapshot and return it's ID */
-    pub fn create_snapshot<D>(&mut self, device: &mut D, id: u64) -> IOResult<u64>
+    /** Report how full each block group is, how many blocks are shared with snapshots and how
+     * many blocks were copied out on write since loaded
+     */
+    pub fn allocation_report<D>(&self, device: &mut D) -> IOResult<AllocReport>
     where
         D: Read + Write + Seek,
     {
-        SubvolumeManager::create_snapshot(self, device, id)
+        let group_fill = self
+            .groups
+            .iter()
+            .map(|group| match group.data_capacity() {
+                0 => 1.0,
+                capacity => 1.0 - group.meta_data.free_blocks as f64 / capacity as f64,
+            })
+            .collect();
+
+        Ok(AllocReport {
+            group_fill,
+            shared_blocks: SubvolumeManager::shared_blocks(self, device)?,
+            cow_copies: self.cow_copies,
+        })
     }
-    /** List submolumes */
-    pub fn list_subvolumes<D>(&mut self, device: &mut D) -> IOResult<Vec<SubvolumeEntry>>
+    /** Find blocks with inconsistent sharing state between subvolumes
+     *
+     * If `repair` is set, blocks recorded by subvolumes but free in block groups are marked
+     * allocated again, meta data should be synchronized afterwards.
+     */
+    pub fn verify_sharing<D>(&mut self, device: &mut D, repair: bool) -> IOResult<Vec<SharingIssue>>
     where
         D: Read + Write + Seek,
     {
-        SubvolumeManager::list_subvols(device, self.sb.subvol_mgr)
+        if repair {
+            self.check_writable()?;
+        }
+        SubvolumeManager::verify_sharing(self, device, repair)
     }
-    /** Create a regular file */
-    pub fn create_file<D, P>(
-        &mut self,
-        subvol: &mut Subvolume,
-        device: &mut D,
-        path: P,
-    ) -> IOResult<File>
+    /** Check if used blocks of the superblock match the sum of those of subvolumes
+     *
+     * Blocks recorded by no subvolume, such as bitmaps, are counted as meta data. Meta data of
+     * subvolumes should be synchronized before.
+     */
+    pub fn check_used_blocks<D>(&self, device: &mut D) -> IOResult<()>
     where
         D: Read + Write + Seek,
-        P: AsRef<Path>,
     {
-        File::create(self, subvol, device, path)
+        SubvolumeManager::check_used_blocks(self, device)
     }
-    /** Open a regular file */
-    pub fn open_file<D, P>(
+    /** Free inodes referenced by no directory entry reachable from the root, return their counts
+     *
+     * Such inodes are left behind by a crash between allocating an inode and linking it into a
+     * directory. Only inodes without links are freed with their contents, an unreferenced inode
+     * which still counts links is kept, since its count can not be trusted to be wrong.
+     */
+    pub fn collect_orphans<D>(
         &mut self,
         subvol: &mut Subvolume,
         device: &mut D,
-        path: P,
-    ) -> IOResult<File>
+    ) -> IOResult<Vec<u64>>
     where
         D: Read + Write + Seek,
-        P: AsRef<Path>,
     {
-        File::open(self, subvol, device, path)
+        subvol.check_writable(self, device)?;
+
+        let mut referenced = HashSet::from([subvol.entry.root_inode]);
+        let mut dirs = vec![subvol.entry.root_inode];
+        while let Some(dir_inode) = dirs.pop() {
+            let entries = Directory::open_by_inode(subvol, device, dir_inode)?
+                .list_dir_typed(self, subvol, device)?;
+            for entry in entries {
+                if referenced.insert(entry.inode) && entry.file_type == FileType::Directory {
+                    dirs.push(entry.inode);
+                }
+            }
+        }
+
+        let orphans = subvol
+            .iter_inodes(device)
+            .filter(|inode| match inode {
+                Ok((count, inode)) => !referenced.contains(count) && inode.hlinks == 0,
+                Err(_) => true,
+            })
+            .map(|inode| inode.map(|(count, _)| count))
+            .collect::<IOResult<Vec<_>>>()?;
+
+        for inode_count in &orphans {
+            file::remove_by_inode(self, subvol, device, *inode_count)?;
+        }
+
+        Ok(orphans)
     }
-    /** Remove a regular file or a symbol link */
-    pub fn remove_file<D, P>(
-        &mut self,
-        subvol: &mut Subvolume,
-        device: &mut D,
-        path: P,
-    ) -> IOResult<()>
+    /** Synchronize meta data to disk and flush the device */
+    pub fn sync_meta_data<D>(&mut self, device: &mut D) -> IOResult<()>
     where
         D: Read + Write + Seek,
-        P: AsRef<Path>,
     {
-        File::remove(self, subvol, device, path)
+        if self.readonly {
+            return Ok(());
+        }
+        for group in &mut self.groups {
+            group.sync(device)?;
+        }
+        self.dirty_groups.clear();
+        self.sb.state = FS_STATE_CLEAN;
+        self.sync_superblock(device)?;
+
+        device.flush()
     }
-    pub fn is_file<D, P>(&mut self, subvol: &mut Subvolume, device: &mut D, path: P) -> bool
+    /** Write block groups changed since meta data was last synchronized
+     *
+     * The superblock is left dirty, its counters are only written by `sync_meta_data`.
+     */
+    pub(crate) fn sync_dirty_groups<D>(&mut self, device: &mut D) -> IOResult<()>
     where
         D: Read + Write + Seek,
-        P: AsRef<Path>,
     {
-        File::open(self, subvol, device, path.as_ref()).is_ok()
+        for index in std::mem::take(&mut self.dirty_groups) {
+            self.groups[index].sync(device)?;
+        }
+        Ok(())
     }
-    pub fn is_dir<D, P>(&mut self, subvol: &mut Subvolume, device: &mut D, path: P) -> bool
+    /** Discard blocks released since discarding was enabled or last discarded
+     *
+     * Meta data is synchronized first, so that no block is discarded while the meta data on the
+     * device still references it. Blocks allocated again are kept. Return count of blocks
+     * discarded.
+     */
+    pub fn discard_released<D>(&mut self, device: &mut D) -> IOResult<u64>
     where
-        D: Read + Write + Seek,
-        P: AsRef<Path>,
+        D: Read + Write + Seek + Trim,
     {
-        Directory::open(self, subvol, device, path).is_ok()
+        self.check_writable()?;
+        let mut released = match &mut self.released {
+            Some(released) => std::mem::take(released),
+            None => return Ok(0),
+        };
+        self.sync_meta_data(device)?;
+
+        released.sort_unstable();
+        released.dedup();
+        released.retain(|count| !self.is_block_allocated(*count));
+        Self::discard_runs(device, released)
     }
-    pub fn is_link<D, P>(&mut self, subvol: &mut Subvolume, device: &mut D, path: P) -> bool
+    /** Discard every free data block, e.g. after mounting a filesystem on a thin-provisioned
+     * device without discarding, return count of blocks discarded
+     */
+    pub fn trim_free_space<D>(&mut self, device: &mut D) -> IOResult<u64>
+    where
+        D: Read + Write + Seek + Trim,
+    {
+        self.check_writable()?;
+        if let Some(released) = &mut self.released {
+            released.clear();
+        }
+        self.sync_meta_data(device)?;
+
+        let mut discarded = 0;
+        for group in &self.groups {
+            for (start, len) in group.free_runs() {
+                device.discard(group.to_absolute_block(start), len)?;
+                discarded += len;
+            }
+        }
+        Ok(discarded)
+    }
+    /** Discard sorted blocks, consecutive blocks are discarded at once */
+    fn discard_runs<D>(device: &mut D, blocks: Vec<u64>) -> IOResult<u64>
+    where
+        D: Trim,
+    {
+        let mut run = 0..0;
+        for count in &blocks {
+            if run.end != *count {
+                if !run.is_empty() {
+                    device.discard(run.start, run.end - run.start)?;
+                }
+                run = *count..*count;
+            }
+            run.end += 1;
+        }
+        if !run.is_empty() {
+            device.discard(run.start, run.end - run.start)?;
+        }
+        Ok(blocks.len() as u64)
+    }
+    /** Synchronize meta data and flush every block the device keeps in memory
+     *
+     * Data written to files is passed to the device right away, so after flushing, writes kept
+     * by a buffered device such as a batch of `CachedDevice` are on disk as well.
+     */
+    pub fn sync_all<D>(&mut self, device: &mut D) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.sync_meta_data(device)
+    }
+    /** Check if the filesystem was left dirty when it was loaded
+     *
+     * A filesystem is dirty from its first change until meta data is synchronized, so it may be
+     * inconsistent if the program changing it stopped in between, and should be checked.
+     */
+    pub fn was_dirty(&self) -> bool {
+        self.was_dirty
+    }
+    /** Return `PermissionDenied` if the filesystem is loaded read-only */
+    pub(crate) fn check_writable(&self) -> IOResult<()> {
+        if self.readonly {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Filesystem is loaded read-only.",
+            ));
+        }
+        Ok(())
+    }
+    /** Mark the superblock dirty on the device before the first change after synchronization
+     *
+     * Every change passes here, so it fails if the filesystem is loaded read-only.
+     */
+    pub(crate) fn mark_dirty<D>(&mut self, device: &mut D) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.check_writable()?;
+        if self.sb.state == FS_STATE_CLEAN {
+            self.sb.state = FS_STATE_DIRTY;
+            self.sync_superblock(device)?;
+            device.flush()?;
+        }
+        Ok(())
+    }
+    /** Read every allocated block and return those which can't be read
+     *
+     * Blocks are only checked for I/O errors, as no checksum is stored for them.
+     */
+    pub fn scrub<D>(&self, device: &mut D) -> IOResult<Vec<u64>>
     where
         D: Read + Write + Seek,
-        P: AsRef<Path>,
     {
-        if let Ok(fd) = file::File::open(self, subvol, device, path.as_ref()) {
-            if fd.get_inode().is_symlink() {
-                return true;
+        let mut bad_blocks = Vec::new();
+        let blocks = std::iter::once(0).chain(self.groups.iter().flat_map(|g| g.used_blocks()));
+        for count in blocks {
+            if block::load_block(device, count).is_err() {
+                bad_blocks.push(count);
             }
         }
 
-        false
+        Ok(bad_blocks)
+    }
+    /** Create a subvolume and return it's ID */
+    pub fn new_subvolume<D>(&mut self, device: &mut D, name: Option<&str>) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        self.mark_dirty(device)?;
+        SubvolumeManager::new_subvolume(self, device, name)
+    }
+    /** Set name of a subvolume, which must be unique */
+    pub fn set_subvolume_name<D>(&mut self, device: &mut D, id: u64, name: &str) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.get_subvolume(device, id)?;
+        self.mark_dirty(device)?;
+        SubvolumeManager::set_subvolume_name(device, self.sb.subvol_mgr, id, name)
+    }
+    /** Set quota of a subvolume in blocks, `None` for unlimited
+     *
+     * Subvolumes loaded before should be reloaded to apply the quota.
+     */
+    pub fn set_quota<D>(&mut self, device: &mut D, id: u64, quota: Option<u64>) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.get_subvolume(device, id)?;
+        self.mark_dirty(device)?;
+        SubvolumeManager::set_quota(device, self.sb.subvol_mgr, id, quota)
+    }
+    /** Set max count of inodes of a subvolume, `None` for unlimited
+     *
+     * Inodes allocated beyond the limit are kept, but no more is allocated until enough are
+     * released.
+     */
+    pub fn set_max_inodes<D>(
+        &mut self,
+        device: &mut D,
+        id: u64,
+        max_inodes: Option<u32>,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.get_subvolume(device, id)?;
+        self.mark_dirty(device)?;
+        SubvolumeManager::set_max_inodes(device, self.sb.subvol_mgr, id, max_inodes)
+    }
+    /** Mark a subvolume read-only or writable, snapshots are read-only when created */
+    pub fn set_subvolume_readonly<D>(
+        &mut self,
+        device: &mut D,
+        id: u64,
+        readonly: bool,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.get_subvolume(device, id)?;
+        self.mark_dirty(device)?;
+        SubvolumeManager::set_subvolume_readonly(device, self.sb.subvol_mgr, id, readonly)
+    }
+    /** Get a subvolume by name */
+    pub fn get_subvolume_by_name<D>(&self, device: &mut D, name: &str) -> IOResult<Subvolume>
+    where
+        D: Read + Write + Seek,
+    {
+        let id = SubvolumeManager::get_subvolume_by_name(device, self.sb.subvol_mgr, name)?
+            .entry
+            .id;
+        SubvolumeManager::open_subvolume(self, device, id)
+    }
+    pub fn remove_subvolume<D>(&mut self, device: &mut D, id: u64) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        if id == self.sb.default_subvol {
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "Removing default subvolume is not supported.",
+            ))
+        } else {
+            self.mark_dirty(device)?;
+            SubvolumeManager::remove_subvolume(self, device, id)
+        }
+    }
+    pub fn get_subvolume<D>(&self, device: &mut D, id: u64) -> IOResult<Subvolume>
+    where
+        D: Read + Write + Seek,
+    {
+        let subvol = SubvolumeManager::open_subvolume(self, device, id)?;
+        if subvol.entry.state != SUBVOLUME_STATE_ALLOCATED {
+            Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No such subvolume '{id}'"),
+            ))
+        } else {
+            Ok(subvol)
+        }
+    }
+    pub fn get_default_subvolume<D>(&self, device: &mut D) -> IOResult<Subvolume>
+    where
+        D: Read + Write + Seek,
+    {
+        if self.sb.default_subvol == NO_DEFAULT_SUBVOLUME {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "No default subvolume is set.",
+            ));
+        }
+        SubvolumeManager::open_subvolume(self, device, self.sb.default_subvol)
+    }
+    /** Set default subvolume
+     *
+     * Fail with `NotFound` if the subvolume doesn't exist or is removed. The superblock is
+     * persisted by `sync_meta_data`.
+     */
+    pub fn set_default_subvolume<D>(&mut self, device: &mut D, id: u64) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.get_subvolume(device, id)?;
+        self.mark_dirty(device)?;
+        self.sb.default_subvol = id;
+
+        Ok(())
+    }
+    /** Create a snapshot and return it's ID */
+    pub fn create_snapshot<D>(&mut self, device: &mut D, id: u64) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        self.mark_dirty(device)?;
+        SubvolumeManager::create_snapshot(self, device, id)
+    }
+    /** Write difference between a subvolume and its parent to a stream
+     *
+     * Only data blocks which are not shared with the parent are sent. If `parent_id` is `None`,
+     * all files are sent.
+     */
+    pub fn send<D, W>(
+        &mut self,
+        device: &mut D,
+        parent_id: Option<u64>,
+        child_id: u64,
+        writer: &mut W,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        W: Write,
+    {
+        send::send(self, device, parent_id, child_id, writer)
+    }
+    /** Apply a stream written by `send` and return ID of the received subvolume
+     *
+     * The stream is applied onto a writable clone of `base_id`, which should be the received
+     * copy of the parent used to send, or a new subvolume if `base_id` is `None`. The received
+     * subvolume is marked read-only.
+     */
+    pub fn receive<D, R>(
+        &mut self,
+        device: &mut D,
+        base_id: Option<u64>,
+        reader: &mut R,
+    ) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+        R: Read,
+    {
+        self.mark_dirty(device)?;
+        send::receive(self, device, base_id, reader)
+    }
+    /** Create a writable clone of a subvolume */
+    pub fn clone_subvolume<D>(&mut self, device: &mut D, id: u64) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        self.mark_dirty(device)?;
+        SubvolumeManager::clone_subvolume(self, device, id)
+    }
+    /** Get ID of the subvolume which a snapshot is created from
+     *
+     * Return `None` if the subvolume is not a snapshot.
+     */
+    pub fn snapshot_origin<D>(&self, device: &mut D, id: u64) -> IOResult<Option<u64>>
+    where
+        D: Read + Write + Seek,
+    {
+        let subvol = self.get_subvolume(device, id)?;
+        Ok(subvol
+            .entry
+            .is_snapshot()
+            .then_some(subvol.entry.parent_subvol))
+    }
+    /** Get count of subvolumes
+     *
+     * Removed subvolumes which are still kept for their snapshots are not counted.
+     */
+    pub fn subvolume_count(&self) -> u64 {
+        self.sb.subvols
+    }
+    /** List submolumes */
+    pub fn list_subvolumes<D>(&mut self, device: &mut D) -> IOResult<Vec<SubvolumeEntry>>
+    where
+        D: Read + Write + Seek,
+    {
+        SubvolumeManager::list_subvols(device, self.sb.subvol_mgr)
+    }
+    /** Get trees of subvolumes and their snapshots, including removed subvolumes */
+    pub fn subvolume_tree<D>(&mut self, device: &mut D) -> IOResult<Vec<SubvolumeNode>>
+    where
+        D: Read + Write + Seek,
+    {
+        let entries = SubvolumeManager::list_all(device, self.sb.subvol_mgr)?;
+        Ok(SubvolumeNode::build_tree(&entries))
+    }
+    /** Create a regular file */
+    pub fn create_file<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<File>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        File::create(self, subvol, device, path)
+    }
+    /** Create regular files in a directory, adding all of them to it with one write
+     *
+     * This is faster than creating them one by one, e.g. when extracting an archive. Nothing is
+     * created if any name is invalid or exists.
+     */
+    pub fn create_files<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        dir: P,
+        names: &[&str],
+    ) -> IOResult<Vec<File>>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        File::create_all(self, subvol, device, dir, names)
+    }
+    /** Open a regular file */
+    pub fn open_file<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<File>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        File::open(self, subvol, device, path)
+    }
+    /** Remove a regular file or a symbol link */
+    pub fn remove_file<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        File::remove(self, subvol, device, path)
+    }
+    pub fn is_file<D, P>(&mut self, subvol: &mut Subvolume, device: &mut D, path: P) -> bool
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        File::open(self, subvol, device, path.as_ref()).is_ok()
+    }
+    pub fn is_dir<D, P>(&mut self, subvol: &mut Subvolume, device: &mut D, path: P) -> bool
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        Directory::open(self, subvol, device, path).is_ok()
+    }
+    pub fn is_link<D, P>(&mut self, subvol: &mut Subvolume, device: &mut D, path: P) -> bool
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        self.lstat(subvol, device, path)
+            .is_ok_and(|inode| inode.is_symlink())
+    }
+    /** Check if a path exists, following symbol links like `std::path::Path::exists`
+     *
+     * A dangling symbol link doesn't exist, see `symlink_metadata` to check the link itself.
+     */
+    pub fn exists<D, P>(&mut self, subvol: &mut Subvolume, device: &mut D, path: P) -> bool
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        self.stat(subvol, device, path).is_ok()
+    }
+    /** Get type and inode of a path with one lookup, a symbol link itself rather than its
+     * target, like `std::fs::symlink_metadata`
+     */
+    pub fn symlink_metadata<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<(FileType, INode)>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let inode = self.lstat(subvol, device, path)?;
+        Ok((inode.file_type(), inode))
     }
     /** List a diretory */
     pub fn list_dir<D, P>(
@@ -265,82 +1477,3531 @@ impl Filesystem {
             .cloned()
             .collect::<Vec<String>>())
     }
-    /** Create a directory */
-    pub fn mkdir<D, P>(
+    /** List names in a directory matching a glob pattern with `*` and `?` */
+    pub fn list_matching<D, P>(
         &mut self,
         subvol: &mut Subvolume,
         device: &mut D,
         path: P,
-    ) -> IOResult<Directory>
+        pattern: &str,
+    ) -> IOResult<Vec<String>>
     where
         D: Read + Write + Seek,
         P: AsRef<Path>,
     {
-        Directory::create(self, subvol, device, path)
+        Ok(Directory::open(self, subvol, device, path)?
+            .list_matching(self, subvol, device, pattern)?
+            .into_keys()
+            .collect())
     }
-    /** Remove a directory */
-    pub fn rmdir<D, P>(&mut self, subvol: &mut Subvolume, device: &mut D, path: P) -> IOResult<()>
+    /** Create a directory */
+    pub fn mkdir<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<Directory>
     where
         D: Read + Write + Seek,
         P: AsRef<Path>,
     {
-        Directory::remove(self, subvol, device, path)
+        Directory::create(self, subvol, device, path)
     }
-    /** Create sybmol link */
-    pub fn link<D, P>(
+    /** Create a directory and all its missing parents, like `mkdir -p`
+     *
+     * Directories which exist already are kept, symbol links to directories are followed. A
+     * component which exists as another type fails with `FsError::NotADirectory` or
+     * `AlreadyExists`.
+     */
+    pub fn create_dir_all<D, P>(
         &mut self,
         subvol: &mut Subvolume,
         device: &mut D,
         path: P,
-        point_to: &str,
     ) -> IOResult<()>
     where
         D: Read + Write + Seek,
         P: AsRef<Path>,
     {
-        symlink::create(self, subvol, device, path, point_to)?;
+        let path = normalize(path.as_ref())?;
+        let mut current = PathBuf::from("/");
+        for component in path.components() {
+            if let Component::Normal(name) = component {
+                current.push(name);
+            } else {
+                continue;
+            }
 
+            match Directory::open(self, subvol, device, &current) {
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    Directory::create(self, subvol, device, &current)?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
         Ok(())
     }
-    pub fn read_link<D, P>(
+    /** Read the whole content of a file, like `std::fs::read` */
+    pub fn read<D, P>(
         &mut self,
         subvol: &mut Subvolume,
         device: &mut D,
         path: P,
-    ) -> IOResult<PathBuf>
+    ) -> IOResult<Vec<u8>>
     where
         D: Read + Write + Seek,
         P: AsRef<Path>,
     {
-        symlink::read_link(self, subvol, device, path)
+        let mut fd = File::open(self, subvol, device, path)?;
+        let size = fd.get_inode().size;
+        let mut data = vec![0; size as usize];
+        fd.read(self, subvol, device, 0, &mut data, size)?;
+        Ok(data)
     }
-    /** Rename a regular file, directory or a symbol link */
-    pub fn rename<D, P>(
+    /** Replace content of a file, which is created if it doesn't exist, like `std::fs::write`
+     *
+     * A symbol link is followed, a dangling one creates the file it points to. Unlike
+     * `write_file_atomic`, the file is truncated before writing, so a failed write leaves it
+     * partially written.
+     */
+    pub fn write<D, P>(
         &mut self,
         subvol: &mut Subvolume,
         device: &mut D,
-        src: P,
-        dst: P,
+        path: P,
+        data: &[u8],
     ) -> IOResult<()>
     where
         D: Read + Write + Seek,
         P: AsRef<Path>,
     {
-        let mut src_dir = Directory::open(self, subvol, device, dir_path(src.as_ref()))?;
-        let inode = *src_dir
-            .list_dir(self, subvol, device)?
-            .get(base_name(src.as_ref()))
-            .unwrap();
-        src_dir.remove_file(self, subvol, device, base_name(src.as_ref()))?;
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self, subvol, device, path)?
+            .write(self, subvol, device, 0, data)
+    }
+    /** Options to open a file, like `std::fs::OpenOptions` */
+    pub fn open_options(&self) -> OpenOptions {
+        OpenOptions::new()
+    }
+    /** Replace content of a file atomically
+     *
+     * Data is written to a hidden temporary file in the same directory, which is then renamed
+     * over the target, so the target is never left partially written.
+     */
+    pub fn write_file_atomic<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+        data: &[u8],
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let path = normalize(path.as_ref())?;
+        let name = base_name(path.as_ref())?;
+        let mut dir = Directory::open(self, subvol, device, dir_path(path.as_ref())?)?;
 
-        Directory::open(self, subvol, device, dir_path(dst.as_ref()))?.add_file(
-            self,
+        /* never touch an existing file, which may be another write in progress */
+        let mut tmp_name;
+        let mut i = 0;
+        loop {
+            tmp_name = format!(".{}.{}.tmp", name, i);
+            if tmp_name.len() > dir::FILE_NAME_MAX {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("File name '{}' is too long.", name),
+                ));
+            }
+            match dir.find_inode_by_name(self, subvol, device, &tmp_name) {
+                Err(err) if err.kind() == ErrorKind::NotFound => break,
+                Err(err) => return Err(err),
+                Ok(_) => i += 1,
+            }
+        }
+        let tmp_path = dir_path(path.as_ref())?.join(tmp_name);
+
+        let mut fd = File::create(self, subvol, device, &tmp_path)?;
+        let result = fd
+            .write(self, subvol, device, 0, data)
+            .and_then(|_| fd.sync_all(self, subvol, device))
+            .and_then(|_| self.rename(subvol, device, tmp_path.as_path(), path.as_ref()));
+        if let Err(err) = result {
+            let _ = File::remove(self, subvol, device, &tmp_path);
+            return Err(err);
+        }
+
+        /* the renamed entry is durable once the directory is flushed */
+        dir.flush(self, subvol, device)
+    }
+    /** Get value of an extended attribute of a file */
+    pub fn get_xattr<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+        name: &[u8],
+    ) -> IOResult<Option<Vec<u8>>>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let (_, _, inode) = self.resolve_nofollow(subvol, device, path)?;
+        xattr::get(subvol, device, inode, name)
+    }
+    /** Create or overwrite an extended attribute of a file */
+    pub fn set_xattr<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+        name: &[u8],
+        value: &[u8],
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let (_, _, inode) = self.resolve_nofollow(subvol, device, path)?;
+        xattr::set(self, subvol, device, inode, name, value)
+    }
+    /** List names of extended attributes of a file */
+    pub fn list_xattr<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<Vec<Vec<u8>>>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let (_, _, inode) = self.resolve_nofollow(subvol, device, path)?;
+        xattr::list(subvol, device, inode)
+    }
+    /** Remove an extended attribute of a file */
+    pub fn remove_xattr<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+        name: &[u8],
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let (_, _, inode) = self.resolve_nofollow(subvol, device, path)?;
+        xattr::remove(self, subvol, device, inode, name)
+    }
+    /** Enable or disable compression of data written to a regular file opened afterwards */
+    pub fn set_compression<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+        enabled: bool,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        if !self.sb.has_feature_incompat(FEATURE_INCOMPAT_COMPRESSION) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Compression is not enabled on the filesystem.",
+            ));
+        }
+
+        let fd = File::open(self, subvol, device, path)?;
+        if !fd.get_inode().is_file() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Only regular files can be compressed.",
+            ));
+        }
+
+        let inode = fd.get_inode_count();
+        if enabled {
+            xattr::set(self, subvol, device, inode, COMPRESSION_XATTR, b"zlib")
+        } else if xattr::get(subvol, device, inode, COMPRESSION_XATTR)?.is_some() {
+            xattr::remove(self, subvol, device, inode, COMPRESSION_XATTR)
+        } else {
+            Ok(())
+        }
+    }
+    /** Resolve a path without following the last symbol link
+     *
+     * Return inode count of the parent directory, name and inode count of the last component.
+     * The root directory is regarded as its own parent with an empty name.
+     */
+    pub fn resolve_nofollow<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<(u64, String, u64)>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let path = normalize(path.as_ref())?;
+        match path.components().next_back() {
+            Some(Component::Normal(name)) => {
+                let name = name.to_string_lossy().to_string();
+                let dir_inode = *self
+                    .dir_chain(subvol, device, dir_path(path.as_ref())?)?
+                    .last()
+                    .unwrap();
+                let inode = Directory::open_by_inode(subvol, device, dir_inode)?
+                    .find_inode_by_name(self, subvol, device, &name)?;
+                Ok((dir_inode, name, inode))
+            }
+            _ => Ok((
+                subvol.entry.root_inode,
+                String::new(),
+                subvol.entry.root_inode,
+            )),
+        }
+    }
+    /** Get the inode of a path, following symbol links like `File::open` */
+    pub fn stat<D, P>(&mut self, subvol: &mut Subvolume, device: &mut D, path: P) -> IOResult<INode>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let mut path = normalize(path.as_ref())?;
+        for _ in 0..=SYMLINK_MAX_FOLLOW {
+            let (_, _, inode_count) = self.resolve_nofollow(subvol, device, &path)?;
+            let inode = subvol.get_inode(device, inode_count)?;
+            if inode.is_whiteout() {
+                return Err(FsError::NotFound(base_name(&path)?.to_string()).into());
+            }
+            if !inode.is_symlink() {
+                return Ok(inode);
+            }
+            /* relative targets start from the directory of the link */
+            let point_to = symlink::read_link_from_inode(subvol, device, inode_count)?;
+            path = normalize(&dir_path(&path)?.join(point_to))?;
+        }
+
+        Err(symlink::too_many_links())
+    }
+    /** Get the inode of a path, a symbol link itself rather than its target */
+    pub fn lstat<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<INode>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let (_, _, inode_count) = self.resolve_nofollow(subvol, device, path)?;
+        subvol.get_inode(device, inode_count)
+    }
+    /** Remove a directory */
+    pub fn rmdir<D, P>(&mut self, subvol: &mut Subvolume, device: &mut D, path: P) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        Directory::remove(self, subvol, device, path)
+    }
+    /** Remove a directory with all of its contents
+     *
+     * Symbol links are removed themselves rather than followed, including the last component of
+     * `path`.
+     */
+    pub fn remove_dir_all<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let path = normalize(path.as_ref())?;
+        /* the root directory can not be removed */
+        dir_path(&path)?;
+        let (_, _, inode) = self.resolve_nofollow(subvol, device, &path)?;
+        if !subvol.get_inode(device, inode)?.is_dir() {
+            return Err(FsError::NotADirectory(path.display().to_string()).into());
+        }
+
+        self.remove_tree(subvol, device, path.as_ref())
+    }
+    fn remove_tree<D>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: &Path,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let entries =
+            Directory::open(self, subvol, device, path)?.list_dir_typed(self, subvol, device)?;
+        for entry in entries {
+            let child = path.join(&entry.name);
+            if entry.file_type == FileType::Directory {
+                self.remove_tree(subvol, device, &child)?;
+            } else {
+                File::remove(self, subvol, device, &child)?;
+            }
+        }
+
+        Directory::remove(self, subvol, device, path)
+    }
+    /** Compute space used by regular files under a path, like `du -s`
+     *
+     * Symbol links are not followed. Files hard linked more than once are counted once, and so
+     * are blocks shared between files, so the result is the space freed if the tree is removed
+     * without other references to its blocks.
+     */
+    pub fn disk_usage<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<DiskUsage>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let (_, _, inode_count) = self.resolve_nofollow(subvol, device, path)?;
+        let mut inodes = HashSet::new();
+        let mut blocks = HashSet::new();
+        let mut usage = DiskUsage::default();
+        self.usage_of(
             subvol,
             device,
-            base_name(dst.as_ref()),
-            inode,
+            inode_count,
+            &mut inodes,
+            &mut blocks,
+            &mut usage,
         )?;
+        usage.blocks = blocks.len() as u64;
+        Ok(usage)
+    }
+    fn usage_of<D>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        inode_count: u64,
+        inodes: &mut HashSet<u64>,
+        blocks: &mut HashSet<u64>,
+        usage: &mut DiskUsage,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        if !inodes.insert(inode_count) {
+            return Ok(());
+        }
+
+        let inode = subvol.get_inode(device, inode_count)?;
+        if inode.is_dir() {
+            let entries = Directory::open_by_inode(subvol, device, inode_count)?
+                .list_dir_typed(self, subvol, device)?;
+            for entry in entries {
+                self.usage_of(subvol, device, entry.inode, inodes, blocks, usage)?;
+            }
+        } else if inode.is_file() {
+            usage.apparent_size += inode.size;
+            blocks.extend(File::open_by_inode(subvol, device, inode_count)?.data_blocks(device)?);
+        }
+        Ok(())
+    }
+    /** Iterate files, directories and links modified at or after `since`, in nanoseconds
+     *
+     * An entry is yielded if its mtime or ctime is not older than `since`, so changes of
+     * attributes are found as well as changes of contents. Files hard linked more than once are
+     * yielded for each path.
+     */
+    pub fn modified_since<'a, D>(
+        &'a mut self,
+        subvol: &'a mut Subvolume,
+        device: &'a mut D,
+        since: u64,
+    ) -> impl Iterator<Item = IOResult<(PathBuf, INode)>> + 'a
+    where
+        D: Read + Write + Seek,
+    {
+        ModifiedSince {
+            fs: self,
+            subvol,
+            device,
+            since,
+            dirs: vec![PathBuf::from("/")],
+            entries: VecDeque::new(),
+        }
+    }
+    /** Copy `len` bytes at `src_offset` of a file to `dst_offset` of another, like `copy_file_range`
+     *
+     * Blocks fully covered by the range are shared between both files until either is written,
+     * when both offsets are equally aligned and the filesystem is not compressed. Unaligned
+     * edges are copied byte by byte. The copy stops at the end of the source, return count of
+     * bytes copied. Ranges in the same file must not overlap.
+     */
+    #[allow(clippy::too_many_arguments)]
+    pub fn copy_range<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        src: P,
+        src_offset: u64,
+        dst: P,
+        dst_offset: u64,
+        len: u64,
+    ) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let mut src = File::open(self, subvol, device, src)?;
+        let mut dst = File::open(self, subvol, device, dst)?;
+        let src = if src.get_inode_count() == dst.get_inode_count() {
+            None
+        } else {
+            Some(&mut src)
+        };
+        dst.copy_range_from(self, subvol, device, src, src_offset, dst_offset, len)
+    }
+    /** Move data blocks of a file into contiguous blocks, return count of blocks moved
+     *
+     * Blocks shared with snapshots are copied, so snapshots keep their contents.
+     */
+    pub fn defragment_file<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let mut fd = File::open(self, subvol, device, path)?;
+        if !fd.get_inode().is_file() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Only regular files can be defragmented.",
+            ));
+        }
+        fd.defragment(self, subvol, device)
+    }
+    /** Copy a directory with all of its contents
+     *
+     * Regular files share their blocks with the source until either is written and keep their
+     * metadata as with `CopyOptions::preserve`, symbol links are recreated with the same target,
+     * and files hard linked within the tree stay hard linked in the copy.
+     */
+    pub fn copy_dir<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        src: P,
+        dst: P,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let src = normalize(src.as_ref())?;
+        let dst = normalize(dst.as_ref())?;
+        if dst.starts_with(&src) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Cannot copy '{}' into itself.", src.to_string_lossy()),
+            ));
+        }
+
+        /* fail before creating the destination */
+        Directory::open(self, subvol, device, &src)?;
+        self.mkdir(subvol, device, &dst)?;
+
+        let mut copied = HashMap::new();
+        if let Err(err) = self.copy_tree(subvol, device, src.as_ref(), dst.as_ref(), &mut copied) {
+            /* the cleanup likely fails on the same fault, the copy error is the one to report */
+            let _ = self.remove_tree(subvol, device, dst.as_ref());
+            return Err(err);
+        }
+
+        Ok(())
+    }
+    /** Copy children of `src` into `dst`, `copied` maps inodes of the source to their copies */
+    fn copy_tree<D>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        src: &Path,
+        dst: &Path,
+        copied: &mut HashMap<u64, u64>,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let entries =
+            Directory::open(self, subvol, device, src)?.list_dir_typed(self, subvol, device)?;
+        let mut dst_dir = Directory::open(self, subvol, device, dst)?;
+
+        for entry in entries {
+            let src_child = src.join(&entry.name);
+            let dst_child = dst.join(&entry.name);
+
+            if let Some(&inode) = copied.get(&entry.inode) {
+                dst_dir.add_hard_link(self, subvol, device, inode, &entry.name)?;
+                continue;
+            }
+
+            match entry.file_type {
+                FileType::Directory => {
+                    self.mkdir(subvol, device, &dst_child)?;
+                    self.copy_tree(subvol, device, &src_child, &dst_child, copied)?;
+                }
+                FileType::Symlink => {
+                    let point_to = self.read_link(subvol, device, &src_child)?;
+                    let inode = symlink::create(
+                        self,
+                        subvol,
+                        device,
+                        &dst_child,
+                        &point_to.to_string_lossy(),
+                    )?;
+                    copied.insert(entry.inode, inode);
+                }
+                FileType::CharDevice | FileType::BlockDevice | FileType::Whiteout => {
+                    let inode = subvol.get_inode(device, entry.inode)?;
+                    self.mknod(
+                        subvol,
+                        device,
+                        &dst_child,
+                        entry.file_type,
+                        inode.dev_major,
+                        inode.dev_minor,
+                    )?;
+                }
+                _ => {
+                    let inode = file::copy_by_inode(self, subvol, device, entry.inode, true)?;
+                    if let Err(err) = dst_dir.add_file(self, subvol, device, &entry.name, inode) {
+                        file::remove_by_inode(self, subvol, device, inode)?;
+                        return Err(err);
+                    }
+                    copied.insert(entry.inode, inode);
+                }
+            }
+
+            /* other entries were added through handles of their own */
+            dst_dir = Directory::open(self, subvol, device, dst)?;
+        }
+
+        Ok(())
+    }
+    /** Create sybmol link
+     *
+     * A relative `point_to` is resolved from the directory containing the link, an absolute one
+     * from the root of the subvolume.
+     */
+    pub fn link<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+        point_to: &str,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        symlink::create(self, subvol, device, path, point_to)?;
 
         Ok(())
     }
+    /** Create a character or block device, or a whiteout, see `Directory::add_whiteout` */
+    pub fn mknod<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+        file_type: FileType,
+        major: u32,
+        minor: u32,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let path = normalize(path.as_ref())?;
+        let acl_type = match file_type {
+            FileType::CharDevice => ACL_CHAR,
+            FileType::BlockDevice => ACL_BLOCK,
+            FileType::Whiteout => ACL_WHITEOUT,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Only character and block devices and whiteouts can be created.",
+                ))
+            }
+        };
+
+        subvol.check_writable(self, device)?;
+
+        let (parent, name) = (dir_path(&path)?, base_name(&path)?);
+        let inode_count = subvol.new_inode(self, device)?;
+        let inode = INode {
+            acl: acl_type << PERMISSION_BITS,
+            dev_major: major,
+            dev_minor: minor,
+            ..Default::default()
+        };
+        subvol.set_inode(self, device, inode_count, inode)?;
+
+        if let Err(err) = Directory::open(self, subvol, device, parent)
+            .and_then(|mut dir| dir.add_file(self, subvol, device, name, inode_count))
+        {
+            file::remove_by_inode(self, subvol, device, inode_count)?;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+    /** Create a hard link
+     *
+     * Both paths are in the same subvolume, as inodes are not shared across subvolumes.
+     */
+    pub fn hard_link<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        src: P,
+        dst: P,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let src = normalize(src.as_ref())?;
+        let dst = normalize(dst.as_ref())?;
+        let (_, _, inode) = self.resolve_nofollow(subvol, device, &src)?;
+
+        if subvol.get_inode(device, inode)?.is_dir() {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("'{}' is a directory.", src.to_string_lossy()),
+            ));
+        }
+
+        Directory::open(self, subvol, device, dir_path(dst.as_ref())?)?.add_hard_link(
+            self,
+            subvol,
+            device,
+            inode,
+            base_name(dst.as_ref())?,
+        )
+    }
+    pub fn read_link<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: P,
+    ) -> IOResult<PathBuf>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        symlink::read_link(self, subvol, device, path)
+    }
+    /** Return inode counts of directories from the root to `path`, following symbol links */
+    fn dir_chain<D>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: &Path,
+    ) -> IOResult<Vec<u64>>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut chain = vec![subvol.entry.root_inode];
+        let mut components: VecDeque<PathBuf> = path
+            .components()
+            .map(|c| PathBuf::from(c.as_os_str()))
+            .collect();
+        let mut links = 0;
+
+        while let Some(component) = components.pop_front() {
+            match component.components().next() {
+                Some(Component::RootDir) => chain.truncate(1),
+                Some(Component::ParentDir) if chain.len() > 1 => {
+                    chain.pop();
+                }
+                Some(Component::Normal(name)) => {
+                    let inode_count =
+                        Directory::open_by_inode(subvol, device, *chain.last().unwrap())?
+                            .find_inode_by_name(self, subvol, device, &name.to_string_lossy())?;
+                    let inode = subvol.get_inode(device, inode_count)?;
+
+                    if inode.is_symlink() {
+                        links += 1;
+                        if links > SYMLINK_MAX_FOLLOW {
+                            return Err(symlink::too_many_links());
+                        }
+                        let point_to = symlink::read_link_from_inode(subvol, device, inode_count)?;
+                        for c in point_to.components().rev() {
+                            components.push_front(PathBuf::from(c.as_os_str()));
+                        }
+                    } else if inode.is_dir() {
+                        chain.push(inode_count);
+                    } else if inode.is_whiteout() {
+                        return Err(FsError::NotFound(name.to_string_lossy().to_string()).into());
+                    } else {
+                        return Err(
+                            FsError::NotADirectory(name.to_string_lossy().to_string()).into()
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(chain)
+    }
+    /** Rename a regular file, directory or a symbol link */
+    pub fn rename<D, P>(
+        &mut self,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        src: P,
+        dst: P,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        let src = normalize(src.as_ref())?;
+        let dst = normalize(dst.as_ref())?;
+        subvol.check_writable(self, device)?;
+
+        let inode = Directory::open(self, subvol, device, dir_path(src.as_ref())?)?
+            .find_inode_by_name(self, subvol, device, base_name(src.as_ref())?)?;
+        let src_is_dir = subvol.get_inode(device, inode)?.is_dir();
+
+        /* compare inode counts, as paths may refer to a directory in different ways */
+        if src_is_dir {
+            let dst_chain = self.dir_chain(subvol, device, dir_path(dst.as_ref())?)?;
+            if dst_chain.contains(&inode) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Cannot move '{}' into itself.", src.to_string_lossy()),
+                ));
+            }
+        }
+
+        let dst_name = base_name(dst.as_ref())?;
+        let replaced = match Directory::open(self, subvol, device, dir_path(dst.as_ref())?)?
+            .find_inode_by_name(self, subvol, device, dst_name)
+        {
+            Ok(dst_inode) if dst_inode == inode => return Ok(()),
+            Ok(dst_inode) => {
+                let dst_fd = subvol.get_inode(device, dst_inode)?;
+                if dst_fd.is_dir() {
+                    if !src_is_dir {
+                        return Err(Error::new(
+                            ErrorKind::PermissionDenied,
+                            format!("'{}' is a directory.", dst.to_string_lossy()),
+                        ));
+                    }
+                    if !Directory::open_by_inode(subvol, device, dst_inode)?
+                        .is_empty(self, subvol, device)?
+                    {
+                        return Err(Error::new(
+                            ErrorKind::DirectoryNotEmpty,
+                            format!("'{}' is not empty.", dst.to_string_lossy()),
+                        ));
+                    }
+                } else if src_is_dir {
+                    return Err(FsError::NotADirectory(dst.to_string_lossy().to_string()).into());
+                }
+                Some((dst_inode, dst_fd.is_dir()))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+
+        /* link the source under the new name before the destination is released */
+        if let Some((dst_inode, _)) = replaced {
+            Directory::open(self, subvol, device, dir_path(dst.as_ref())?)?
+                .remove_file(self, subvol, device, dst_name)?;
+            if let Err(err) = Directory::open(self, subvol, device, dir_path(dst.as_ref())?)?
+                .add_file(self, subvol, device, dst_name, inode)
+            {
+                Directory::open(self, subvol, device, dir_path(dst.as_ref())?)?
+                    .add_file(self, subvol, device, dst_name, dst_inode)?;
+                return Err(err);
+            }
+        } else {
+            Directory::open(self, subvol, device, dir_path(dst.as_ref())?)?
+                .add_file(self, subvol, device, dst_name, inode)?;
+        }
+
+        Directory::open(self, subvol, device, dir_path(src.as_ref())?)?.remove_file(
+            self,
+            subvol,
+            device,
+            base_name(src.as_ref())?,
+        )?;
+
+        if let Some((dst_inode, dst_is_dir)) = replaced {
+            file::handle_rc_inode(self, subvol, device, dst_inode)?;
+            if dst_is_dir {
+                dir::remove_by_inode(self, subvol, device, dst_inode)?;
+            } else {
+                file::remove_by_inode(self, subvol, device, dst_inode)?;
+            }
+        }
+
+        Ok(())
+    }
+    /** Move a regular file, directory or symbol link to another subvolume
+     *
+     * Subvolumes only share blocks through snapshots, so the content is copied into the
+     * destination, which must not exist, and the source is removed afterwards. Holes, hard links
+     * within a moved directory, owners, permissions, times and extended attributes are kept.
+     * Within one subvolume, this is `rename`.
+     */
+    pub fn move_across<D, P>(
+        &mut self,
+        src_subvol: &mut Subvolume,
+        dst_subvol: &mut Subvolume,
+        device: &mut D,
+        src: P,
+        dst: P,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+        P: AsRef<Path>,
+    {
+        if src_subvol.entry.id == dst_subvol.entry.id {
+            let result = self.rename(src_subvol, device, src, dst);
+            /* both handles must see the changed inode tree */
+            *dst_subvol = src_subvol.clone();
+            return result;
+        }
+
+        let src = normalize(src.as_ref())?;
+        let dst = normalize(dst.as_ref())?;
+        src_subvol.check_writable(self, device)?;
+        dst_subvol.check_writable(self, device)?;
+        let (_, name, inode_count) = self.resolve_nofollow(src_subvol, device, &src)?;
+        if name.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Cannot move the root directory.",
+            ));
+        }
+        match self.lstat(dst_subvol, device, &dst) {
+            Ok(_) => {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("'{}' already exists.", dst.to_string_lossy()),
+                ))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let mut copied = HashMap::new();
+        if let Err(err) = self.copy_across(
+            src_subvol,
+            dst_subvol,
+            device,
+            inode_count,
+            &dst,
+            &mut copied,
+        ) {
+            /* the destination didn't exist, so all of it is the partial copy */
+            if self.is_dir(dst_subvol, device, &dst) {
+                let _ = self.remove_tree(dst_subvol, device, &dst);
+            } else {
+                let _ = File::remove(self, dst_subvol, device, &dst);
+            }
+            return Err(err);
+        }
+
+        if src_subvol.get_inode(device, inode_count)?.is_dir() {
+            self.remove_tree(src_subvol, device, &src)
+        } else {
+            File::remove(self, src_subvol, device, &src)
+        }
+    }
+    /** Copy an inode of a subvolume to a path of another one, see `move_across`
+     *
+     * `copied` maps inodes copied to their copies, so that hard links are linked again.
+     */
+    fn copy_across<D>(
+        &mut self,
+        src_subvol: &mut Subvolume,
+        dst_subvol: &mut Subvolume,
+        device: &mut D,
+        inode_count: u64,
+        dst: &Path,
+        copied: &mut HashMap<u64, u64>,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        /* count of blocks copied at once */
+        const CHUNK_BLOCKS: u64 = 64;
+
+        let inode = src_subvol.get_inode(device, inode_count)?;
+        let new_inode_count = match inode.file_type() {
+            FileType::Directory => {
+                let new_inode_count =
+                    Directory::create(self, dst_subvol, device, dst)?.get_inode_count();
+                let entries = Directory::open_by_inode(src_subvol, device, inode_count)?
+                    .list_dir_typed(self, src_subvol, device)?;
+                for entry in entries {
+                    match copied.get(&entry.inode) {
+                        Some(&linked) => {
+                            Directory::open_by_inode(dst_subvol, device, new_inode_count)?
+                                .add_hard_link(self, dst_subvol, device, linked, &entry.name)?
+                        }
+                        None => self.copy_across(
+                            src_subvol,
+                            dst_subvol,
+                            device,
+                            entry.inode,
+                            &dst.join(&entry.name),
+                            copied,
+                        )?,
+                    }
+                }
+                new_inode_count
+            }
+            FileType::Symlink => {
+                let point_to = symlink::read_link_from_inode(src_subvol, device, inode_count)?;
+                symlink::create(self, dst_subvol, device, dst, &point_to.to_string_lossy())?
+            }
+            FileType::CharDevice | FileType::BlockDevice | FileType::Whiteout => {
+                self.mknod(
+                    dst_subvol,
+                    device,
+                    dst,
+                    inode.file_type(),
+                    inode.dev_major,
+                    inode.dev_minor,
+                )?;
+                self.resolve_nofollow(dst_subvol, device, dst)?.2
+            }
+            _ => {
+                let mut src_fd = File::open_by_inode(src_subvol, device, inode_count)?;
+                let mut dst_fd = File::create(self, dst_subvol, device, dst)?;
+                let mut buffer = vec![0; CHUNK_BLOCKS as usize * block::BLOCK_SIZE];
+                let mut offset = 0;
+                /* only data is copied, holes stay holes */
+                while let Ok(start) = src_fd.seek_data(device, offset) {
+                    let end = src_fd.seek_hole(device, start)?;
+                    offset = start;
+                    while offset < end {
+                        let len = std::cmp::min(end - offset, buffer.len() as u64);
+                        let chunk = &mut buffer[..len as usize];
+                        src_fd.read(self, src_subvol, device, offset, chunk, len)?;
+                        dst_fd.write(self, dst_subvol, device, offset, chunk)?;
+                        offset += len;
+                    }
+                    if offset >= inode.size {
+                        break;
+                    }
+                }
+                if inode.size > 0 {
+                    dst_fd.truncate(self, dst_subvol, device, inode.size)?;
+                }
+                dst_fd.get_inode_count()
+            }
+        };
+        if !inode.is_dir() {
+            copied.insert(inode_count, new_inode_count);
+        }
+
+        /* keep owner, permissions, times and extended attributes */
+        let mut new_inode = dst_subvol.get_inode(device, new_inode_count)?;
+        new_inode.acl = inode.acl;
+        new_inode.uid = inode.uid;
+        new_inode.gid = inode.gid;
+        new_inode.atime = inode.atime;
+        new_inode.mtime = inode.mtime;
+        new_inode.xattr = LinkedContentTable::copy_chain(self, dst_subvol, device, inode.xattr)?;
+        dst_subvol.set_inode(self, device, new_inode_count, new_inode)
+    }
+}
+
+/** Walker of `Filesystem::modified_since`, reading one directory at a time */
+struct ModifiedSince<'a, D> {
+    fs: &'a mut Filesystem,
+    subvol: &'a mut Subvolume,
+    device: &'a mut D,
+    since: u64,
+    /** Directories not read yet */
+    dirs: Vec<PathBuf>,
+    /** Modified entries of the last read directory */
+    entries: VecDeque<(PathBuf, INode)>,
+}
+
+impl<D> ModifiedSince<'_, D>
+where
+    D: Read + Write + Seek,
+{
+    fn read_dir(&mut self, path: &Path) -> IOResult<()> {
+        let mut entries = Directory::open(self.fs, self.subvol, self.device, path)?
+            .list_dir_typed(self.fs, self.subvol, self.device)?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for entry in entries {
+            let child = path.join(&entry.name);
+            let inode = self.subvol.get_inode(self.device, entry.inode)?;
+            if entry.file_type == FileType::Directory {
+                self.dirs.push(child.clone());
+            }
+            if inode.mtime.max(inode.ctime) >= self.since {
+                self.entries.push_back((child, inode));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D> Iterator for ModifiedSince<'_, D>
+where
+    D: Read + Write + Seek,
+{
+    type Item = IOResult<(PathBuf, INode)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.pop_front() {
+                return Some(Ok(entry));
+            }
+            let dir = self.dirs.pop()?;
+            if let Err(err) = self.read_dir(&dir) {
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inode::INODE_PER_GROUP;
+    use crate::utils::test_fs;
+
+    #[test]
+    fn legacy_subvolume_count() {
+        let (mut fs, mut device, _) = test_fs(2000);
+        let id = fs.new_subvolume(&mut device, None).unwrap();
+
+        /* images created before the count was recorded */
+        fs.sb.subvols = 0;
+        fs.sync_meta_data(&mut device).unwrap();
+
+        let mut fs = Filesystem::load(&mut device).unwrap();
+        let listed = |fs: &mut Filesystem, device: &mut std::io::Cursor<Vec<u8>>| {
+            fs.list_subvolumes(device).unwrap().len() as u64
+        };
+        assert_eq!(fs.subvolume_count(), 2);
+        assert_eq!(fs.subvolume_count(), listed(&mut fs, &mut device));
+
+        let second = fs.new_subvolume(&mut device, None).unwrap();
+        let snap = fs.create_snapshot(&mut device, second).unwrap();
+        assert_eq!(fs.subvolume_count(), 4);
+        assert_eq!(fs.subvolume_count(), listed(&mut fs, &mut device));
+
+        /* a removed subvolume kept for its snapshot is neither counted nor listed */
+        for id in [id, second, snap] {
+            fs.remove_subvolume(&mut device, id).unwrap();
+            assert_eq!(fs.subvolume_count(), listed(&mut fs, &mut device));
+        }
+        assert_eq!(fs.subvolume_count(), 1);
+    }
+
+    #[test]
+    fn block_map_size() {
+        let blocks = 80000;
+        let mut device = std::io::Cursor::new(vec![0; blocks * block::BLOCK_SIZE]);
+        let options = FormatOptions {
+            block_map_size: 2,
+            ..Default::default()
+        };
+        let mut fs = Filesystem::create_with_options(&mut device, blocks, &options).unwrap();
+        assert!(fs.sb.has_feature_incompat(FEATURE_INCOMPAT_BLOCK_MAP_SIZE));
+        fs.sync_meta_data(&mut device).unwrap();
+
+        let mut fs = Filesystem::load(&mut device).unwrap();
+        assert_eq!(fs.sb.block_map_size, 2);
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/a").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"data")
+            .unwrap();
+
+        /* older implementations would misread the group layout */
+        fs.sb.feature_incompat &= !FEATURE_INCOMPAT_BLOCK_MAP_SIZE;
+        fs.sync_meta_data(&mut device).unwrap();
+        assert_eq!(
+            Filesystem::load(&mut device).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn heat_map() {
+        let blocks = 2 + 8 * block::BLOCK_SIZE + 1000;
+        let mut device = std::io::Cursor::new(vec![0; blocks * block::BLOCK_SIZE]);
+        let options = FormatOptions {
+            heat_map: true,
+            ..Default::default()
+        };
+        let mut fs = Filesystem::create_with_options(&mut device, blocks, &options).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        assert_eq!(fs.heat_map().unwrap(), [0, 0]);
+
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/hot").unwrap();
+        for i in 0..50 {
+            fd.write(&mut fs, &mut subvol, &mut device, 0, &[i; 4096])
+                .unwrap();
+        }
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+
+        let fs = Filesystem::load(&mut device).unwrap();
+        let heat_map = fs.heat_map().unwrap();
+        assert!(heat_map[0] >= 50, "{heat_map:?}");
+        assert!(heat_map[0] > heat_map[1], "{heat_map:?}");
+
+        let (fs, _, _) = test_fs(200);
+        assert_eq!(fs.heat_map(), None);
+    }
+
+    #[test]
+    fn metadata_reserve() {
+        let blocks = 3000;
+        let mut device = std::io::Cursor::new(vec![0; blocks * block::BLOCK_SIZE]);
+        let options = FormatOptions {
+            metadata_reserve: 64,
+            ..Default::default()
+        };
+        let mut fs = Filesystem::create_with_options(&mut device, blocks, &options).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/fill").unwrap();
+
+        /* fill the data area */
+        let mut offset = 0;
+        while fd
+            .write(&mut fs, &mut subvol, &mut device, offset, &[3; 4096])
+            .is_ok()
+        {
+            offset += 4096;
+        }
+        assert!(offset > 0);
+        assert!(fs.free_blocks() > 0);
+        assert!(fs.free_blocks() <= 64);
+
+        let snap = fs.create_snapshot(&mut device, subvol.entry.id).unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        fs.remove_file(&mut subvol, &mut device, "/fill").unwrap();
+        fs.remove_subvolume(&mut device, snap).unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+
+        let fs = Filesystem::load(&mut device).unwrap();
+        assert_eq!(fs.sb.metadata_reserve, 64);
+    }
+
+    #[test]
+    fn group_layout() {
+        let group_blocks = 2 + 8 * block::BLOCK_SIZE as u64;
+        /* the last of these leaves two blocks after a full group */
+        for blocks in [6, 4097, 8191, group_blocks + 2, 100000, group_blocks + 4] {
+            let mut device = std::io::Cursor::new(vec![0; blocks as usize * block::BLOCK_SIZE]);
+            let options = FormatOptions {
+                default_subvolume: false,
+                ..Default::default()
+            };
+            let mut fs =
+                Filesystem::create_with_options(&mut device, blocks as usize, &options).unwrap();
+            fs.sync_meta_data(&mut device).unwrap();
+            let mut fs = Filesystem::load(&mut device).unwrap();
+
+            /* superblock and its backup, meta data and capacity of groups */
+            let covered: u64 = fs
+                .groups
+                .iter()
+                .map(|group| group.meta_data.free_blocks + group.used_blocks().count() as u64)
+                .sum();
+            assert_eq!(2 + covered, blocks, "{blocks}");
+
+            let mut allocated = std::collections::HashSet::new();
+            let free_blocks = fs.free_blocks();
+            while let Ok(block) = fs.new_block() {
+                assert!(block < blocks, "{blocks}");
+                assert!(allocated.insert(block), "{blocks}");
+            }
+            assert_eq!(allocated.len() as u64, free_blocks, "{blocks}");
+        }
+
+        let mut device = std::io::Cursor::new(vec![0; 3 * block::BLOCK_SIZE]);
+        assert_eq!(
+            Filesystem::create(&mut device, 3).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn allocate_data_region_only() {
+        let group_blocks = 2 + 8 * block::BLOCK_SIZE as u64;
+        let blocks = 1 + group_blocks + 100;
+        let mut device = std::io::Cursor::new(vec![0; blocks as usize * block::BLOCK_SIZE]);
+        let mut fs = Filesystem::create(&mut device, blocks as usize).unwrap();
+        assert_eq!(fs.groups.len(), 2);
+
+        let meta_blocks: HashSet<u64> = [0, blocks - 1]
+            .into_iter()
+            .chain(
+                fs.groups
+                    .iter()
+                    .flat_map(|group| group.start_block..group.to_absolute_block(0)),
+            )
+            .collect();
+        let mut allocated = HashSet::new();
+        while let Ok(count) = fs.new_block() {
+            assert!(!meta_blocks.contains(&count), "block {count} is meta data");
+            assert!(count < blocks);
+            assert!(allocated.insert(count));
+        }
+        assert_eq!(fs.free_blocks(), 0);
+        /* every data block on the device is taken */
+        assert!((0..blocks)
+            .filter(|count| !meta_blocks.contains(count))
+            .all(|count| fs.is_block_allocated(count)));
+    }
+    #[test]
+    fn corrupted_free_count() {
+        let group_blocks = 2 + 8 * block::BLOCK_SIZE as u64;
+        let blocks = 1 + group_blocks + 100;
+        let mut device = std::io::Cursor::new(vec![0; blocks as usize * block::BLOCK_SIZE]);
+        let mut fs = Filesystem::create(&mut device, blocks as usize).unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+
+        /* fill the device, then claim the last group has blocks past it */
+        while fs.new_block().is_ok() {}
+        let last = fs.groups.len() - 1;
+        fs.groups[last].meta_data.free_blocks = 10;
+        let bitmap = fs.groups[last].block_map[0].bytes;
+        let used_blocks = fs.sb.used_blocks;
+        fs.dirty_groups.clear();
+
+        for result in [
+            fs.new_block().map(|_| ()),
+            fs.allocate_run_in(last, 4).map(|_| ()),
+        ] {
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+            assert_eq!(fs.groups[last].block_map[0].bytes, bitmap);
+            assert_eq!(fs.groups[last].meta_data.free_blocks, 10);
+            assert_eq!(fs.sb.used_blocks, used_blocks);
+            assert!(fs.dirty_groups.is_empty());
+        }
+    }
+    #[test]
+    fn copy_range() {
+        fn write(
+            fs: &mut Filesystem,
+            subvol: &mut Subvolume,
+            device: &mut std::io::Cursor<Vec<u8>>,
+            path: &str,
+            offset: usize,
+            data: &[u8],
+        ) {
+            let mut fd = File::open(fs, subvol, device, path).unwrap();
+            fd.write(fs, subvol, device, offset as u64, data).unwrap();
+        }
+        fn read(
+            fs: &mut Filesystem,
+            subvol: &mut Subvolume,
+            device: &mut std::io::Cursor<Vec<u8>>,
+            path: &str,
+        ) -> Vec<u8> {
+            let mut fd = File::open(fs, subvol, device, path).unwrap();
+            let size = fd.get_inode().size;
+            let mut buffer = vec![0; size as usize];
+            fd.read(fs, subvol, device, 0, &mut buffer, size).unwrap();
+            buffer
+        }
+
+        let (mut fs, mut device, mut subvol) = test_fs(4000);
+        let bs = block::BLOCK_SIZE;
+        let data: Vec<u8> = (0..32 * bs).map(|i| (i * 7 % 251) as u8).collect();
+        fs.create_file(&mut subvol, &mut device, "/src").unwrap();
+        write(&mut fs, &mut subvol, &mut device, "/src", 0, &data);
+        let prefix = vec![0xaa; bs + 10];
+        fs.create_file(&mut subvol, &mut device, "/dst").unwrap();
+        write(&mut fs, &mut subvol, &mut device, "/dst", 0, &prefix);
+
+        /* an aligned 64 KiB range shares its blocks */
+        let len = 64 * 1024;
+        let real_used_blocks = fs.sb.real_used_blocks;
+        let copied = fs
+            .copy_range(
+                &mut subvol,
+                &mut device,
+                "/src",
+                4 * bs as u64,
+                "/dst",
+                2 * bs as u64,
+                len,
+            )
+            .unwrap();
+        assert_eq!(copied, len);
+        assert_eq!(fs.sb.real_used_blocks, real_used_blocks);
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        assert!(fs.verify_sharing(&mut device, false).unwrap().is_empty());
+
+        let mut expected = prefix.clone();
+        expected.resize(2 * bs, 0);
+        expected.extend_from_slice(&data[4 * bs..4 * bs + len as usize]);
+        assert_eq!(read(&mut fs, &mut subvol, &mut device, "/dst"), expected);
+
+        /* writes to either file don't show up in the other */
+        write(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            "/src",
+            4 * bs,
+            b"changed",
+        );
+        write(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            "/dst",
+            3 * bs,
+            b"changed",
+        );
+        expected[3 * bs..3 * bs + 7].copy_from_slice(b"changed");
+        assert_eq!(read(&mut fs, &mut subvol, &mut device, "/dst"), expected);
+        let mut src_expected = data.clone();
+        src_expected[4 * bs..4 * bs + 7].copy_from_slice(b"changed");
+        assert_eq!(
+            read(&mut fs, &mut subvol, &mut device, "/src"),
+            src_expected
+        );
+
+        /* unaligned ranges and ranges past the end of the source are copied byte by byte */
+        let copied = fs
+            .copy_range(
+                &mut subvol,
+                &mut device,
+                "/src",
+                30 * bs as u64 + 3,
+                "/dst",
+                5,
+                10 * bs as u64,
+            )
+            .unwrap();
+        assert_eq!(copied, 2 * bs as u64 - 3);
+        expected[5..5 + copied as usize].copy_from_slice(&src_expected[30 * bs + 3..]);
+        assert_eq!(read(&mut fs, &mut subvol, &mut device, "/dst"), expected);
+
+        /* overlapping ranges in the same file are rejected */
+        assert_eq!(
+            fs.copy_range(
+                &mut subvol,
+                &mut device,
+                "/src",
+                0,
+                "/src",
+                bs as u64,
+                2 * bs as u64
+            )
+            .unwrap_err()
+            .kind(),
+            ErrorKind::InvalidInput
+        );
+        fs.copy_range(
+            &mut subvol,
+            &mut device,
+            "/src",
+            0,
+            "/src",
+            32 * bs as u64,
+            bs as u64,
+        )
+        .unwrap();
+        src_expected.extend_from_within(..bs);
+        assert_eq!(
+            read(&mut fs, &mut subvol, &mut device, "/src"),
+            src_expected
+        );
+    }
+    #[test]
+    fn no_space_error() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(300);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        let err = fd
+            .write(
+                &mut fs,
+                &mut subvol,
+                &mut device,
+                0,
+                &[1; 300 * block::BLOCK_SIZE],
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert_eq!(FsError::from_io(&err), Some(&FsError::NoSpace));
+
+        let err = fs
+            .open_file(&mut subvol, &mut device, "/missing")
+            .unwrap_err();
+        assert_eq!(
+            FsError::from_io(&err),
+            Some(&FsError::NotFound("missing".to_string()))
+        );
+        let err = fs
+            .create_file(&mut subvol, &mut device, "/f/g")
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn small_group_counts() {
+        let group_blocks = 2 + 8 * block::BLOCK_SIZE;
+        /* one group, a minimal last group and a small last group */
+        for (blocks, groups) in [
+            (64, 1),
+            (1 + group_blocks, 1),
+            (1 + group_blocks + 3, 2),
+            (1 + group_blocks + 10, 2),
+        ] {
+            let mut device = std::io::Cursor::new(vec![0; blocks * block::BLOCK_SIZE]);
+            let mut fs = Filesystem::create(&mut device, blocks).unwrap();
+            assert_eq!(fs.groups.len(), groups);
+            fs.sync_meta_data(&mut device).unwrap();
+            let free_blocks: Vec<u64> = fs.groups.iter().map(|g| g.meta_data.free_blocks).collect();
+            let used_blocks = fs.sb.used_blocks;
+
+            /* each block is freed into the group it was allocated from */
+            let mut allocated = Vec::new();
+            while let Ok(count) = fs.new_block() {
+                assert!(count < blocks as u64);
+                allocated.push(count);
+            }
+            assert_eq!(allocated.len() as u64, free_blocks.iter().sum::<u64>());
+            for count in allocated {
+                fs.release_block(count).unwrap();
+            }
+            let after: Vec<u64> = fs.groups.iter().map(|g| g.meta_data.free_blocks).collect();
+            assert_eq!(after, free_blocks);
+            assert_eq!(fs.sb.used_blocks, used_blocks);
+
+            let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+            for i in 0..3 {
+                let mut fd = fs
+                    .create_file(&mut subvol, &mut device, format!("/{i}"))
+                    .unwrap();
+                fd.write(&mut fs, &mut subvol, &mut device, 0, &[i; 100])
+                    .unwrap();
+            }
+            fs.remove_file(&mut subvol, &mut device, "/1").unwrap();
+            subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+            fs.sync_meta_data(&mut device).unwrap();
+
+            let mut fs = Filesystem::load(&mut device).unwrap();
+            assert_eq!(fs.groups.len(), groups);
+            let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+            let mut fd = fs.open_file(&mut subvol, &mut device, "/2").unwrap();
+            let mut buffer = [0; 100];
+            fd.read(&mut fs, &mut subvol, &mut device, 0, &mut buffer, 100)
+                .unwrap();
+            assert_eq!(buffer, [2; 100]);
+            assert!(!fs.is_file(&mut subvol, &mut device, "/1"));
+        }
+    }
+    #[test]
+    fn load_readonly() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(2000);
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        fs.create_file(&mut subvol, &mut device, "/dir/f")
+            .unwrap()
+            .write(&mut fs, &mut subvol, &mut device, 0, b"data")
+            .unwrap();
+        fs.link(&mut subvol, &mut device, "/link", "dir/f").unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+        let image = device.get_ref().clone();
+
+        let mut fs = Filesystem::load_readonly(&mut device).unwrap();
+        assert!(fs.is_readonly());
+        fs.set_atime_mode(AtimeMode::Always);
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/link").unwrap();
+        let mut buf = [0; 4];
+        fd.read(&mut fs, &mut subvol, &mut device, 0, &mut buf, 4)
+            .unwrap();
+        assert_eq!(&buf, b"data");
+        assert_eq!(
+            fs.list_dir(&mut subvol, &mut device, "/dir").unwrap(),
+            vec!["f"]
+        );
+        assert_eq!(
+            fs.read_link(&mut subvol, &mut device, "/link").unwrap(),
+            Path::new("dir/f")
+        );
+        assert!(fs
+            .stat(&mut subvol, &mut device, "/dir/f")
+            .unwrap()
+            .is_file());
+
+        let id = subvol.entry.id;
+        for err in [
+            fs.create_file(&mut subvol, &mut device, "/g").unwrap_err(),
+            fd.write(&mut fs, &mut subvol, &mut device, 0, b"x")
+                .unwrap_err(),
+            fs.remove_file(&mut subvol, &mut device, "/dir/f")
+                .unwrap_err(),
+            fs.new_subvolume(&mut device, None).unwrap_err(),
+            fs.create_snapshot(&mut device, id).unwrap_err(),
+            fs.verify_sharing(&mut device, true).unwrap_err(),
+        ] {
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        }
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.sync_all(&mut device).unwrap();
+        assert!(device.get_ref() == &image);
+    }
+
+    #[test]
+    fn dirty_state() {
+        let (mut fs, mut device, mut subvol) = test_fs(4000);
+        assert!(!Filesystem::load(&mut device).unwrap().was_dirty());
+
+        /* a crash after a change leaves the filesystem dirty */
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"data")
+            .unwrap();
+        let crashed = device.clone();
+        let mut reopened = crashed.clone();
+        assert!(Filesystem::load(&mut reopened).unwrap().was_dirty());
+
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+        assert!(!Filesystem::load(&mut device).unwrap().was_dirty());
+
+        /* a dirty filesystem is clean again once synchronized */
+        let mut reopened = crashed;
+        let mut fs = Filesystem::load(&mut reopened).unwrap();
+        assert!(fs.was_dirty());
+        fs.sync_meta_data(&mut reopened).unwrap();
+        assert!(!Filesystem::load(&mut reopened).unwrap().was_dirty());
+
+        /* subvolume operations mark it dirty too */
+        let mut fs = Filesystem::load(&mut device).unwrap();
+        fs.new_subvolume(&mut device, None).unwrap();
+        assert!(Filesystem::load(&mut device).unwrap().was_dirty());
+        fs.sync_meta_data(&mut device).unwrap();
+        /* reading doesn't */
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        fs.list_dir(&mut subvol, &mut device, "/").unwrap();
+        assert!(!Filesystem::load(&mut device).unwrap().was_dirty());
+    }
+    #[test]
+    fn backup_superblock() {
+        let (mut fs, mut device, mut subvol) = test_fs(4000);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"data")
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+        assert!(!fs.is_block_allocated(fs.sb.total_blocks - 1));
+
+        /* block 0 is lost, the backup is loaded instead */
+        device.get_mut()[..block::BLOCK_SIZE].fill(0);
+        let mut fs = Filesystem::load(&mut device).unwrap();
+        assert!(fs.loaded_from_backup());
+        assert!(!fs.was_dirty());
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        let mut fd = File::open(&mut fs, &mut subvol, &mut device, "/f").unwrap();
+        let mut buffer = [0; 4];
+        fd.read(&mut fs, &mut subvol, &mut device, 0, &mut buffer, 4)
+            .unwrap();
+        assert_eq!(&buffer, b"data");
+
+        fs.restore_superblock(&mut device).unwrap();
+        assert!(!fs.loaded_from_backup());
+        let fs = Filesystem::load(&mut device).unwrap();
+        assert!(!fs.loaded_from_backup());
+        assert!(!fs.was_dirty());
+
+        /* without a valid backup the original error is returned */
+        let end = device.get_ref().len();
+        device.get_mut()[..block::BLOCK_SIZE].fill(0);
+        device.get_mut()[end - block::BLOCK_SIZE..].fill(0);
+        assert_eq!(
+            Filesystem::load(&mut device).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+    #[test]
+    fn new_data_run_within_device() {
+        let (mut fs, _, _) = crate::utils::test_fs(200);
+        let free_blocks = fs.free_blocks();
+        let used_blocks = fs.sb.used_blocks;
+
+        let first = fs.new_data_run(10).unwrap();
+        assert!((first..first + 10).all(|block| fs.is_block_allocated(block)));
+        assert_eq!(fs.free_blocks(), free_blocks - 10);
+        assert_eq!(fs.sb.used_blocks, used_blocks + 10);
+
+        /* a single free block is skipped */
+        fs.release_block(first + 5).unwrap();
+        assert!(fs.new_data_run(2).unwrap() >= first + 10);
+
+        /* the last group extends beyond the backup superblock, where no run may be taken */
+        fs.sb.metadata_reserve = 0;
+        let tail = fs.free_blocks() - 1;
+        assert_eq!(
+            FsError::from_io(&fs.new_data_run(tail + 1).unwrap_err()),
+            Some(&FsError::NoSpace)
+        );
+        let last = fs.new_data_run(tail).unwrap();
+        assert_eq!(last + tail, fs.sb.total_blocks - 1);
+    }
+
+    #[test]
+    fn allocate_run() {
+        let bits = 8 * block::BLOCK_SIZE as u64;
+        let mut group = block::BlockGroup::create(0, 3 + bits + 100, 2);
+        let free_blocks = group.meta_data.free_blocks;
+
+        /* runs across the bitmap blocks */
+        assert_eq!(group.allocate_run(bits - 8), Some((0, bits - 8)));
+        assert_eq!(group.allocate_run(16), Some((bits - 8, 16)));
+        assert_eq!(group.meta_data.free_blocks, free_blocks - bits - 8);
+        /* none past the device */
+        assert_eq!(group.allocate_run(1000), Some((bits + 8, 92)));
+        assert_eq!(group.allocate_run(1), None);
+
+        /* the first run long enough is taken, otherwise the longest */
+        for block in (10..13).chain(100..108).chain(200..206) {
+            group.release_block(block);
+        }
+        assert_eq!(group.find_free_run(8), Some(100));
+        assert_eq!(group.allocate_run(8), Some((100, 8)));
+        assert_eq!(group.find_free_run(8), None);
+        assert_eq!(group.allocate_run(4), Some((200, 4)));
+        assert_eq!(group.allocate_run(5), Some((10, 3)));
+        assert!((10..13).chain(200..204).all(|block| group.get_used(block)));
+        assert!(!group.get_used(204));
+
+        assert_eq!(group.allocate_run(1), Some((204, 1)));
+        assert_eq!(group.allocate_run(1), Some((205, 1)));
+        assert_eq!(group.allocate_run(1), None);
+        assert_eq!(group.meta_data.free_blocks, 0);
+    }
+
+    #[test]
+    fn read_write() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        let blob: Vec<u8> = (0..5 * block::BLOCK_SIZE + 123)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+
+        fs.write(&mut subvol, &mut device, "/f", &blob).unwrap();
+        assert_eq!(fs.read(&mut subvol, &mut device, "/f").unwrap(), blob);
+        /* an existing file is truncated */
+        fs.write(&mut subvol, &mut device, "/f", b"short").unwrap();
+        assert_eq!(fs.read(&mut subvol, &mut device, "/f").unwrap(), b"short");
+        fs.write(&mut subvol, &mut device, "/f", &blob).unwrap();
+
+        /* symbol links are followed, a dangling one creates its target */
+        fs.link(&mut subvol, &mut device, "/l", "f").unwrap();
+        assert_eq!(fs.read(&mut subvol, &mut device, "/l").unwrap(), blob);
+        fs.link(&mut subvol, &mut device, "/dangling", "g").unwrap();
+        fs.write(&mut subvol, &mut device, "/dangling", b"new")
+            .unwrap();
+        assert_eq!(fs.read(&mut subvol, &mut device, "/g").unwrap(), b"new");
+        assert!(fs.is_link(&mut subvol, &mut device, "/dangling"));
+
+        let err = fs
+            .write(&mut subvol, &mut device, "/missing/f", b"data")
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(
+            fs.read(&mut subvol, &mut device, "/missing")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        fs.mkdir(&mut subvol, &mut device, "/d").unwrap();
+        assert!(fs.write(&mut subvol, &mut device, "/d", b"data").is_err());
+        assert!(fs.read(&mut subvol, &mut device, "/d").is_err());
+        assert_eq!(fs.read(&mut subvol, &mut device, "/f").unwrap(), blob);
+
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.check_used_blocks(&mut device).unwrap();
+    }
+
+    #[test]
+    fn discard_released() {
+        let (mut fs, device, mut subvol) = test_fs(2000);
+        let mut device = crate::utils::TrimDevice::new(device);
+        fs.set_discard(true);
+
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            0,
+            &[1; 8 * block::BLOCK_SIZE],
+        )
+        .unwrap();
+        let blocks = fd.data_blocks(&mut device).unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.remove_file(&mut subvol, &mut device, "/f").unwrap();
+        /* a released block allocated again is not discarded */
+        let mut other = fs.create_file(&mut subvol, &mut device, "/g").unwrap();
+        other
+            .write(&mut fs, &mut subvol, &mut device, 0, &[2; 1])
+            .unwrap();
+        let reused = other.data_blocks(&mut device).unwrap()[0];
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+
+        let discarded = fs.discard_released(&mut device).unwrap();
+        let blocks_discarded = device.discarded_blocks();
+        assert_eq!(discarded, blocks_discarded.len() as u64);
+        assert!(blocks
+            .iter()
+            .filter(|block| **block != reused)
+            .all(|block| blocks_discarded.contains(block)));
+        assert!(blocks_discarded
+            .iter()
+            .all(|block| !fs.is_block_allocated(*block)));
+        /* consecutive blocks are discarded at once */
+        assert!(device.discarded.len() < blocks_discarded.len());
+
+        assert_eq!(fs.discard_released(&mut device).unwrap(), 0);
+        let mut fs = Filesystem::load(&mut device).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/g").unwrap();
+        let mut buf = [0; 1];
+        fd.read(&mut fs, &mut subvol, &mut device, 0, &mut buf, 1)
+            .unwrap();
+        assert_eq!(buf, [2]);
+    }
+
+    #[test]
+    fn trim_free_space() {
+        let (mut fs, device, mut subvol) = test_fs(2000);
+        let mut device = crate::utils::TrimDevice::new(device);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            0,
+            &[1; 8 * block::BLOCK_SIZE],
+        )
+        .unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 1])
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+
+        assert_eq!(fs.trim_free_space(&mut device).unwrap(), fs.free_blocks());
+        let discarded = device.discarded_blocks();
+        assert_eq!(discarded.len() as u64, fs.free_blocks());
+        assert!(discarded.iter().all(|block| fs.is_data_block(*block)
+            && !fs.is_block_allocated(*block)
+            && *block < fs.sb.total_blocks));
+
+        fs.check_used_blocks(&mut device).unwrap();
+        let mut fs = Filesystem::load_readonly(&mut device).unwrap();
+        assert_eq!(
+            fs.trim_free_space(&mut device).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn release_block_in_groups() {
+        let group_blocks = 2 + 8 * block::BLOCK_SIZE as u64;
+        let blocks = 1 + 2 * group_blocks + 100;
+        let mut device = std::io::Cursor::new(vec![0; blocks as usize * block::BLOCK_SIZE]);
+        let mut fs = Filesystem::create(&mut device, blocks as usize).unwrap();
+        assert_eq!(fs.groups.len(), 3);
+
+        for group in 0..3 {
+            let data_blocks = fs.groups[group].to_absolute_block(0)
+                ..fs.groups[group].block_range().end.min(blocks - 1);
+            /* the first and last blocks not taken by the format */
+            let first = data_blocks
+                .clone()
+                .find(|count| !fs.is_block_allocated(*count))
+                .unwrap();
+            let last = data_blocks
+                .rev()
+                .find(|count| !fs.is_block_allocated(*count))
+                .unwrap();
+            for count in [first, last] {
+                let free_blocks: Vec<u64> =
+                    fs.groups.iter().map(|g| g.meta_data.free_blocks).collect();
+                let used_blocks = fs.sb.used_blocks;
+                fs.mark_block_allocated(count);
+                assert_eq!(
+                    fs.groups[group].meta_data.free_blocks,
+                    free_blocks[group] - 1
+                );
+                assert!(fs.is_block_allocated(count));
+
+                fs.release_block(count).unwrap();
+                assert!(!fs.is_block_allocated(count));
+                assert_eq!(fs.sb.used_blocks, used_blocks);
+                let after: Vec<u64> = fs.groups.iter().map(|g| g.meta_data.free_blocks).collect();
+                assert_eq!(after, free_blocks);
+            }
+        }
+
+        /* superblocks, meta data of groups and blocks past the device are not data blocks */
+        for count in [0, 1, fs.groups[1].start_block + 1, blocks - 1, blocks] {
+            assert_eq!(
+                fs.release_block(count).unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[test]
+    fn features() {
+        let blocks = 20000;
+        let format = |options: FormatOptions| {
+            let mut device = std::io::Cursor::new(vec![0; blocks * block::BLOCK_SIZE]);
+            let mut fs = Filesystem::create_with_options(&mut device, blocks, &options).unwrap();
+            fs.sync_meta_data(&mut device).unwrap();
+            Filesystem::load(&mut device).unwrap().features()
+        };
+
+        let features = format(FormatOptions::default());
+        assert!(features.btree_dir() && features.backup_superblock());
+        assert!(!features.heat_map() && !features.compression() && !features.block_map_size());
+        assert_eq!(features.names(), ["backup_superblock", "btree_dir"]);
+
+        let features = format(FormatOptions {
+            heat_map: true,
+            compression: true,
+            block_map_size: 2,
+            dir_hash: DirHash::SipHash,
+            ..Default::default()
+        });
+        assert!(features.heat_map() && features.compression() && features.block_map_size());
+        assert!(features.dir_hash());
+        assert_eq!(
+            features.names(),
+            [
+                "heat_map",
+                "backup_superblock",
+                "btree_dir",
+                "compression",
+                "block_map_size",
+                "dir_hash"
+            ]
+        );
+
+        let features = Features {
+            compat: 0x4,
+            ro_compat: 0x1,
+            ..Default::default()
+        };
+        assert_eq!(features.names(), ["compat_0x4", "ro_compat_0x1"]);
+    }
+
+    #[test]
+    fn paths_without_name() {
+        let (mut fs, mut device, mut subvol) = test_fs(200);
+        fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        let used_blocks = fs.sb.used_blocks;
+
+        for path in ["/", "", "..", "/f/..", "//."] {
+            let kind = |result: IOResult<()>| result.unwrap_err().kind();
+            assert_eq!(
+                kind(fs.open_file(&mut subvol, &mut device, path).map(|_| ())),
+                ErrorKind::InvalidInput,
+                "{path}"
+            );
+            assert_eq!(
+                kind(fs.mkdir(&mut subvol, &mut device, path).map(|_| ())),
+                ErrorKind::InvalidInput,
+                "{path}"
+            );
+            assert_eq!(
+                kind(fs.remove_file(&mut subvol, &mut device, path)),
+                ErrorKind::InvalidInput,
+                "{path}"
+            );
+            assert_eq!(
+                kind(fs.rmdir(&mut subvol, &mut device, path)),
+                ErrorKind::InvalidInput,
+                "{path}"
+            );
+            assert_eq!(
+                kind(fs.remove_dir_all(&mut subvol, &mut device, path)),
+                ErrorKind::InvalidInput,
+                "{path}"
+            );
+            assert_eq!(
+                kind(fs.link(&mut subvol, &mut device, path, "/f")),
+                ErrorKind::InvalidInput,
+                "{path}"
+            );
+            assert_eq!(
+                kind(fs.rename(&mut subvol, &mut device, "/f", path)),
+                ErrorKind::InvalidInput,
+                "{path}"
+            );
+        }
+
+        assert_eq!(fs.sb.used_blocks, used_blocks);
+        assert_eq!(fs.list_dir(&mut subvol, &mut device, "/").unwrap(), ["f"]);
+    }
+
+    #[test]
+    fn superblock_snapshot() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        let view = fs.superblock_snapshot();
+        assert_eq!(view.used_blocks, fs.sb.used_blocks);
+        assert_eq!(view.free_blocks, fs.free_blocks());
+
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 40000])
+            .unwrap();
+        fs.new_subvolume(&mut device, None).unwrap();
+
+        /* the view keeps the numbers of the moment it was taken */
+        assert!(fs.sb.used_blocks > view.used_blocks);
+        assert!(fs.free_blocks() < view.free_blocks);
+        let later = fs.superblock_snapshot();
+        assert_eq!(
+            view.free_blocks - later.free_blocks,
+            later.real_used_blocks - view.real_used_blocks
+        );
+        assert_eq!(later.subvols, view.subvols + 1);
+        assert_eq!(later.total_blocks, view.total_blocks);
+    }
+
+    #[test]
+    fn modified_since() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        for path in ["/a", "/b", "/dir/c", "/dir/d"] {
+            let mut fd = fs.create_file(&mut subvol, &mut device, path).unwrap();
+            fd.write(&mut fs, &mut subvol, &mut device, 0, b"old")
+                .unwrap();
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let since = get_sys_time();
+        assert!(fs
+            .modified_since(&mut subvol, &mut device, since)
+            .next()
+            .is_none());
+
+        for path in ["/b", "/dir/d"] {
+            let mut fd = fs.open_file(&mut subvol, &mut device, path).unwrap();
+            fd.write(&mut fs, &mut subvol, &mut device, 3, b"new")
+                .unwrap();
+        }
+        let modified: Vec<(PathBuf, INode)> = fs
+            .modified_since(&mut subvol, &mut device, since)
+            .collect::<IOResult<_>>()
+            .unwrap();
+        let paths: Vec<&Path> = modified.iter().map(|(path, _)| path.as_path()).collect();
+        assert_eq!(paths, [Path::new("/b"), Path::new("/dir/d")]);
+        assert!(modified.iter().all(|(_, inode)| inode.size == 6));
+
+        /* a new entry modifies its directory too */
+        fs.create_file(&mut subvol, &mut device, "/dir/e").unwrap();
+        let paths: Vec<PathBuf> = fs
+            .modified_since(&mut subvol, &mut device, since)
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(paths, ["/b", "/dir", "/dir/d", "/dir/e"].map(PathBuf::from));
+    }
+
+    #[test]
+    fn read_link() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+
+        /* the target spans two tables */
+        let long = format!("{}target", "d/".repeat(2497));
+        assert_eq!(long.len(), 5000);
+        fs.link(&mut subvol, &mut device, "/dir/long", &long)
+            .unwrap();
+        assert_eq!(
+            fs.read_link(&mut subvol, &mut device, "/dir/long").unwrap(),
+            PathBuf::from(&long)
+        );
+
+        let unicode = "/目录/ファイル-ü";
+        fs.link(&mut subvol, &mut device, "/unicode", unicode)
+            .unwrap();
+        assert_eq!(
+            fs.read_link(&mut subvol, &mut device, "/unicode").unwrap(),
+            PathBuf::from(unicode)
+        );
+
+        /* the length is stored, so targets filling tables or holding zero bytes are kept */
+        for target in [
+            "f".repeat(block::BLOCK_SIZE - 8),
+            "f".repeat(2 * (block::BLOCK_SIZE - 8)),
+            "a\0b".to_string(),
+        ] {
+            fs.link(&mut subvol, &mut device, "/exact", &target)
+                .unwrap();
+            assert_eq!(
+                fs.read_link(&mut subvol, &mut device, "/exact").unwrap(),
+                PathBuf::from(&target)
+            );
+            assert_eq!(
+                fs.lstat(&mut subvol, &mut device, "/exact").unwrap().size,
+                target.len() as u64
+            );
+            fs.remove_file(&mut subvol, &mut device, "/exact").unwrap();
+        }
+
+        let too_long = "a".repeat(symlink::TARGET_MAX + 1);
+        assert_eq!(
+            fs.link(&mut subvol, &mut device, "/too_long", &too_long)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+        fs.remove_file(&mut subvol, &mut device, "/dir/long")
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.check_used_blocks(&mut device).unwrap();
+    }
+
+    #[test]
+    fn stat() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        let mut fd = fs
+            .create_file(&mut subvol, &mut device, "/dir/file")
+            .unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 5000])
+            .unwrap();
+        fs.link(&mut subvol, &mut device, "/link", "/dir/file")
+            .unwrap();
+        fs.link(&mut subvol, &mut device, "/dir/up", "..").unwrap();
+
+        let stat = fs.stat(&mut subvol, &mut device, "/link").unwrap();
+        assert!(stat.is_file());
+        assert_eq!(stat.size, 5000);
+        assert_eq!(
+            stat,
+            fs.stat(&mut subvol, &mut device, "/dir/file").unwrap()
+        );
+
+        let lstat = fs.lstat(&mut subvol, &mut device, "/link").unwrap();
+        assert!(lstat.is_symlink());
+        assert_ne!(lstat, stat);
+        assert!(fs
+            .lstat(&mut subvol, &mut device, "/dir/file")
+            .unwrap()
+            .is_file());
+
+        /* relative targets are resolved from the directory of the link */
+        assert!(fs
+            .stat(&mut subvol, &mut device, "/dir/up")
+            .unwrap()
+            .is_dir());
+        assert!(fs
+            .lstat(&mut subvol, &mut device, "/dir/up")
+            .unwrap()
+            .is_symlink());
+
+        fs.link(&mut subvol, &mut device, "/dangling", "/missing")
+            .unwrap();
+        assert_eq!(
+            fs.stat(&mut subvol, &mut device, "/dangling")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        assert!(fs
+            .lstat(&mut subvol, &mut device, "/dangling")
+            .unwrap()
+            .is_symlink());
+
+        fs.link(&mut subvol, &mut device, "/loop", "/loop").unwrap();
+        assert_eq!(
+            fs.stat(&mut subvol, &mut device, "/loop")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::Other
+        );
+    }
+
+    #[test]
+    fn move_across() {
+        let (mut fs, mut device, mut src_subvol) = test_fs(3000);
+        let id = fs.new_subvolume(&mut device, None).unwrap();
+        let mut dst_subvol = fs.get_subvolume(&mut device, id).unwrap();
+        let data: Vec<u8> = (0..3 * block::BLOCK_SIZE + 77).map(|i| i as u8).collect();
+
+        fs.write(&mut src_subvol, &mut device, "/f", &data).unwrap();
+        fs.set_xattr(&mut src_subvol, &mut device, "/f", b"user.a", b"1")
+            .unwrap();
+        let mtime = fs.stat(&mut src_subvol, &mut device, "/f").unwrap().mtime;
+        fs.move_across(&mut src_subvol, &mut dst_subvol, &mut device, "/f", "/g")
+            .unwrap();
+        assert!(!fs.exists(&mut src_subvol, &mut device, "/f"));
+        assert_eq!(fs.read(&mut dst_subvol, &mut device, "/g").unwrap(), data);
+        assert_eq!(
+            fs.get_xattr(&mut dst_subvol, &mut device, "/g", b"user.a")
+                .unwrap(),
+            Some(b"1".to_vec())
+        );
+        assert_eq!(
+            fs.stat(&mut dst_subvol, &mut device, "/g").unwrap().mtime,
+            mtime
+        );
+
+        /* directories move recursively, keeping holes, links and hard links */
+        fs.create_dir_all(&mut src_subvol, &mut device, "/d/e")
+            .unwrap();
+        let mut fd = fs
+            .create_file(&mut src_subvol, &mut device, "/d/e/sparse")
+            .unwrap();
+        fd.write(&mut fs, &mut src_subvol, &mut device, 0, b"start")
+            .unwrap();
+        fd.write(
+            &mut fs,
+            &mut src_subvol,
+            &mut device,
+            100 * block::BLOCK_SIZE as u64,
+            b"end",
+        )
+        .unwrap();
+        fs.hard_link(&mut src_subvol, &mut device, "/d/e/sparse", "/d/hard")
+            .unwrap();
+        fs.link(&mut src_subvol, &mut device, "/d/link", "e/sparse")
+            .unwrap();
+        fs.move_across(
+            &mut src_subvol,
+            &mut dst_subvol,
+            &mut device,
+            "/d",
+            "/moved",
+        )
+        .unwrap();
+        assert!(!fs.exists(&mut src_subvol, &mut device, "/d"));
+        let sparse = fs
+            .read(&mut dst_subvol, &mut device, "/moved/link")
+            .unwrap();
+        assert_eq!(sparse.len(), 100 * block::BLOCK_SIZE + 3);
+        assert_eq!(
+            (&sparse[..5], &sparse[sparse.len() - 3..]),
+            (&b"start"[..], &b"end"[..])
+        );
+        let fd = fs
+            .open_file(&mut dst_subvol, &mut device, "/moved/e/sparse")
+            .unwrap();
+        assert_eq!(fd.data_blocks(&mut device).unwrap().len(), 2);
+        assert_eq!(
+            fs.lstat(&mut dst_subvol, &mut device, "/moved/hard")
+                .unwrap(),
+            fd.get_inode()
+        );
+        assert_eq!(fd.get_inode().hlinks, 1);
+
+        fs.write(&mut src_subvol, &mut device, "/x", b"x").unwrap();
+        assert_eq!(
+            fs.move_across(&mut src_subvol, &mut dst_subvol, &mut device, "/x", "/g")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::AlreadyExists
+        );
+        assert!(fs.exists(&mut src_subvol, &mut device, "/x"));
+
+        src_subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        dst_subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.check_used_blocks(&mut device).unwrap();
+        assert!(fs.verify_sharing(&mut device, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn create_dir_all() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        fs.create_dir_all(&mut subvol, &mut device, "/a/b/c/d")
+            .unwrap();
+        for path in ["/a", "/a/b", "/a/b/c", "/a/b/c/d"] {
+            assert!(fs.is_dir(&mut subvol, &mut device, path), "{path}");
+        }
+        fs.write(&mut subvol, &mut device, "/a/b/c/d/f", b"data")
+            .unwrap();
+
+        /* existing directories are kept */
+        fs.create_dir_all(&mut subvol, &mut device, "/a/b/c/d")
+            .unwrap();
+        fs.create_dir_all(&mut subvol, &mut device, "/").unwrap();
+        assert_eq!(
+            fs.read(&mut subvol, &mut device, "/a/b/c/d/f").unwrap(),
+            b"data"
+        );
+        fs.link(&mut subvol, &mut device, "/l", "a/b").unwrap();
+        fs.create_dir_all(&mut subvol, &mut device, "/l/e").unwrap();
+        assert!(fs.is_dir(&mut subvol, &mut device, "/a/b/e"));
+
+        fs.write(&mut subvol, &mut device, "/x", b"file").unwrap();
+        for path in ["/x", "/x/y/z", "/a/b/c/d/f/g"] {
+            assert!(
+                fs.create_dir_all(&mut subvol, &mut device, path).is_err(),
+                "{path}"
+            );
+        }
+        assert!(!fs.exists(&mut subvol, &mut device, "/x/y"));
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.check_used_blocks(&mut device).unwrap();
+    }
+
+    #[test]
+    fn describe() {
+        let (mut fs, mut device, mut subvol) = test_fs(60000);
+        fs.write(&mut subvol, &mut device, "/f", &[1; 64 * block::BLOCK_SIZE])
+            .unwrap();
+        /* the entry of a removed subvolume is kept while its snapshot refers to it */
+        let removed = fs.new_subvolume(&mut device, Some("removed")).unwrap();
+        let snapshot = fs.create_snapshot(&mut device, removed).unwrap();
+        fs.remove_subvolume(&mut device, removed).unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+
+        let description = fs.describe(&mut device).unwrap();
+        assert_eq!(description.groups.len() as u64, fs.sb.groups);
+        assert!(description.groups.len() > 1);
+        assert_eq!(description.groups[0].start_block, 1);
+        for (i, group) in description.groups.iter().enumerate() {
+            assert_eq!(group.id, i as u64);
+            assert!(group.free_blocks <= group.capacity);
+        }
+
+        /* blocks not free are data blocks in use or meta data of groups and the superblocks */
+        let capacity: u64 = description.groups.iter().map(|group| group.capacity).sum();
+        let free: u64 = description
+            .groups
+            .iter()
+            .map(|group| group.free_blocks)
+            .sum();
+        assert_eq!(free, fs.free_blocks());
+        assert_eq!(capacity - free, fs.sb.real_used_blocks);
+        let group_meta: u64 = fs.groups.iter().map(|group| 1 + group.block_map_size).sum();
+        assert_eq!(capacity + group_meta + 2, fs.sb.total_blocks);
+
+        assert_eq!(description.subvolumes.len(), 3);
+        let root = &description.subvolumes[0];
+        assert_eq!(root.entry.id, subvol.entry.id);
+        assert_eq!(root.entry.used_blocks, subvol.entry.used_blocks);
+        let root_inode = root.root_inode.unwrap();
+        assert!(root_inode.is_dir());
+        assert_eq!(
+            root_inode.size,
+            subvol
+                .get_inode(&mut device, subvol.entry.root_inode)
+                .unwrap()
+                .size
+        );
+        let removed = description
+            .subvolumes
+            .iter()
+            .find(|subvol| subvol.entry.id == removed)
+            .unwrap();
+        assert_eq!(removed.entry.state, subvol::SUBVOLUME_STATE_REMOVED);
+        assert!(removed.root_inode.is_none());
+        let snapshot = description
+            .subvolumes
+            .iter()
+            .find(|subvol| subvol.entry.id == snapshot)
+            .unwrap();
+        assert!(snapshot.root_inode.unwrap().is_dir());
+    }
+
+    #[test]
+    fn set_default_subvolume() {
+        let (mut fs, mut device, subvol) = test_fs(2000);
+        let first = subvol.entry.id;
+        let second = fs.new_subvolume(&mut device, None).unwrap();
+        let removed = fs.new_subvolume(&mut device, None).unwrap();
+
+        fs.set_default_subvolume(&mut device, second).unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+        let mut fs = Filesystem::load(&mut device).unwrap();
+        assert_eq!(fs.sb.default_subvol, second);
+        assert_eq!(
+            fs.get_default_subvolume(&mut device).unwrap().entry.id,
+            second
+        );
+
+        fs.remove_subvolume(&mut device, removed).unwrap();
+        for id in [removed, removed + 100] {
+            assert_eq!(
+                fs.set_default_subvolume(&mut device, id)
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::NotFound
+            );
+            assert_eq!(fs.sb.default_subvol, second);
+        }
+
+        fs.set_default_subvolume(&mut device, first).unwrap();
+        assert_eq!(fs.sb.default_subvol, first);
+    }
+
+    #[test]
+    fn no_default_subvolume() {
+        let mut device = std::io::Cursor::new(vec![0; 2000 * block::BLOCK_SIZE]);
+        let options = FormatOptions {
+            default_subvolume: false,
+            ..Default::default()
+        };
+        let mut fs = Filesystem::create_with_options(&mut device, 2000, &options).unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+        let mut fs = Filesystem::load(&mut device).unwrap();
+        assert_eq!(fs.sb.default_subvol, NO_DEFAULT_SUBVOLUME);
+        assert_eq!(fs.subvolume_count(), 0);
+        assert_eq!(
+            fs.get_default_subvolume(&mut device).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        let id = fs.new_subvolume(&mut device, None).unwrap();
+        fs.set_default_subvolume(&mut device, id).unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+        let mut fs = Filesystem::load(&mut device).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        assert_eq!(subvol.entry.id, id);
+        fs.write(&mut subvol, &mut device, "/f", b"data").unwrap();
+        assert_eq!(fs.read(&mut subvol, &mut device, "/f").unwrap(), b"data");
+    }
+
+    #[test]
+    fn subvolume_names() {
+        let (mut fs, mut device, _) = test_fs(2000);
+        let first = fs.new_subvolume(&mut device, Some("first")).unwrap();
+        let second = fs.new_subvolume(&mut device, Some("second")).unwrap();
+        assert_eq!(
+            fs.new_subvolume(&mut device, Some("first"))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::AlreadyExists
+        );
+        assert_eq!(fs.subvolume_count(), 3);
+
+        let subvol = fs.get_subvolume_by_name(&mut device, "second").unwrap();
+        assert_eq!(subvol.entry.id, second);
+        assert_eq!(subvol.entry.get_name(), "second");
+
+        /* names are unique and non-empty, a subvolume may keep its own name */
+        for (name, kind) in [
+            ("first", ErrorKind::AlreadyExists),
+            ("", ErrorKind::InvalidInput),
+        ] {
+            assert_eq!(
+                fs.set_subvolume_name(&mut device, second, name)
+                    .unwrap_err()
+                    .kind(),
+                kind
+            );
+        }
+        fs.set_subvolume_name(&mut device, second, "second")
+            .unwrap();
+
+        fs.set_subvolume_name(&mut device, first, "renamed")
+            .unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+        let mut fs = Filesystem::load(&mut device).unwrap();
+        assert_eq!(
+            fs.get_subvolume_by_name(&mut device, "renamed")
+                .unwrap()
+                .entry
+                .id,
+            first
+        );
+        assert_eq!(
+            fs.get_subvolume_by_name(&mut device, "first")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        fs.set_subvolume_name(&mut device, second, "first").unwrap();
+        assert_eq!(
+            fs.get_subvolume_by_name(&mut device, "first")
+                .unwrap()
+                .entry
+                .id,
+            second
+        );
+    }
+
+    #[test]
+    fn snapshot_origin() {
+        let (mut fs, mut device, _) = test_fs(2000);
+        let id = fs.new_subvolume(&mut device, None).unwrap();
+        let snap = fs.create_snapshot(&mut device, id).unwrap();
+        let nested = fs.create_snapshot(&mut device, snap).unwrap();
+
+        let subvol = fs.get_subvolume(&mut device, id).unwrap();
+        assert!(!subvol.entry.is_snapshot());
+        assert_eq!(fs.snapshot_origin(&mut device, id).unwrap(), None);
+        assert!(fs
+            .get_subvolume(&mut device, snap)
+            .unwrap()
+            .entry
+            .is_snapshot());
+        assert_eq!(fs.snapshot_origin(&mut device, snap).unwrap(), Some(id));
+        assert_eq!(fs.snapshot_origin(&mut device, nested).unwrap(), Some(snap));
+
+        assert_eq!(
+            fs.snapshot_origin(&mut device, nested + 100)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn subvolume_tree() {
+        let (mut fs, mut device, subvol) = test_fs(2000);
+        let default = subvol.entry.id;
+        let id = fs.new_subvolume(&mut device, None).unwrap();
+        let removed = fs.create_snapshot(&mut device, id).unwrap();
+        let kept = fs.create_snapshot(&mut device, id).unwrap();
+        fs.remove_subvolume(&mut device, removed).unwrap();
+
+        let tree = fs.subvolume_tree(&mut device).unwrap();
+        let roots: Vec<u64> = tree.iter().map(|node| node.entry.id).collect();
+        assert_eq!(roots, [default, id]);
+        assert!(tree[0].children.is_empty());
+        let children: Vec<u64> = tree[1].children.iter().map(|node| node.entry.id).collect();
+        assert_eq!(children, [kept]);
+        assert!(tree.iter().all(|node| !node.is_removed()));
+        assert_eq!(tree[1].entry.snaps, 1);
+        assert!(tree[1].children[0].children.is_empty());
+
+        /* the origin is kept in the tree for its snapshot */
+        fs.remove_subvolume(&mut device, id).unwrap();
+        let tree = fs.subvolume_tree(&mut device).unwrap();
+        assert_eq!(tree.len(), 2);
+        assert!(tree[1].is_removed());
+        assert_eq!(tree[1].children[0].entry.id, kept);
+        assert!(!tree[1].children[0].is_removed());
+        assert!(fs
+            .list_subvolumes(&mut device)
+            .unwrap()
+            .iter()
+            .all(|entry| entry.id != id));
+    }
+
+    #[test]
+    fn snapshot_readonly() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        fs.write(&mut subvol, &mut device, "/f", b"data").unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        let id = subvol.entry.id;
+        let snap_id = fs.create_snapshot(&mut device, id).unwrap();
+        let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+        let mut snap = fs.get_subvolume(&mut device, snap_id).unwrap();
+        assert!(snap.entry.is_readonly());
+        assert!(!subvol.entry.is_readonly());
+
+        let mut fd = fs.open_file(&mut snap, &mut device, "/f").unwrap();
+        assert_eq!(
+            fd.write(&mut fs, &mut snap, &mut device, 0, b"new")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            fs.mkdir(&mut snap, &mut device, "/dir")
+                .err()
+                .unwrap()
+                .kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            fs.remove_file(&mut snap, &mut device, "/f")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::PermissionDenied
+        );
+
+        /* the origin is still writable */
+        fs.write(&mut subvol, &mut device, "/f", b"new").unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        assert_eq!(fs.read(&mut subvol, &mut device, "/f").unwrap(), b"new");
+        assert_eq!(fs.read(&mut snap, &mut device, "/f").unwrap(), b"data");
+        assert!(!fs.exists(&mut snap, &mut device, "/dir"));
+
+        fs.set_subvolume_readonly(&mut device, snap_id, false)
+            .unwrap();
+        fs.mkdir(&mut snap, &mut device, "/dir").unwrap();
+    }
+
+    #[test]
+    fn subvolume_usage() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        let id = subvol.entry.id;
+        fs.write(&mut subvol, &mut device, "/f", &[1; 4 * block::BLOCK_SIZE])
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        let usage = fs.subvolume_usage(&mut device, id).unwrap();
+        assert_eq!(usage.shared, 0);
+        assert_eq!(usage.exclusive, subvol.entry.used_blocks);
+        let inherited = usage.total;
+
+        /* blocks of the clone are all shared with its origin at first */
+        let clone_id = fs.clone_subvolume(&mut device, id).unwrap();
+        let usage = fs.subvolume_usage(&mut device, clone_id).unwrap();
+        assert_eq!(
+            usage,
+            Usage {
+                exclusive: 0,
+                shared: inherited,
+                total: inherited,
+            }
+        );
+        let origin = fs.subvolume_usage(&mut device, id).unwrap();
+        assert_eq!((origin.exclusive, origin.shared), (0, inherited));
+
+        /* new blocks, including copied meta data, are the clone's own */
+        let mut clone = fs.get_subvolume(&mut device, clone_id).unwrap();
+        let allocated = fs.sb.real_used_blocks;
+        fs.write(&mut clone, &mut device, "/g", &[2; 8 * block::BLOCK_SIZE])
+            .unwrap();
+        clone.sync_meta_data(&mut fs, &mut device).unwrap();
+        let written = fs.sb.real_used_blocks - allocated;
+        let usage = fs.subvolume_usage(&mut device, clone_id).unwrap();
+        assert!(written >= 8);
+        assert_eq!(usage.exclusive, written);
+        assert!(usage.shared > 0 && usage.shared <= inherited);
+        assert_eq!(usage.total, clone.entry.used_blocks);
+        assert_eq!(fs.subvolume_usage(&mut device, id).unwrap(), origin);
+
+        /* removing the clone releases its exclusive blocks, not those of the origin */
+        fs.remove_subvolume(&mut device, clone_id).unwrap();
+        assert_eq!(fs.sb.real_used_blocks, allocated);
+        assert_eq!(
+            fs.subvolume_usage(&mut device, clone_id)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+    }
+    #[test]
+    fn allocation_report() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        let report = fs.allocation_report(&mut device).unwrap();
+        assert_eq!(report.group_fill.len(), fs.groups.len());
+        assert!(report
+            .group_fill
+            .iter()
+            .all(|fill| (0.0..1.0).contains(fill)));
+        assert_eq!(report.shared_blocks, 0);
+        assert_eq!(report.cow_copies, 0);
+
+        fs.write(&mut subvol, &mut device, "/f", &[1; 4 * block::BLOCK_SIZE])
+            .unwrap();
+        let fill = fs.allocation_report(&mut device).unwrap().group_fill;
+        assert!(fill[0] > report.group_fill[0]);
+        assert_eq!(fs.allocation_report(&mut device).unwrap().cow_copies, 0);
+
+        fs.create_snapshot(&mut device, subvol.entry.id).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        let report = fs.allocation_report(&mut device).unwrap();
+        assert!(report.shared_blocks >= 4);
+
+        /* the first write copies the block and the meta data leading to it */
+        let mut file = File::open(&mut fs, &mut subvol, &mut device, "/f").unwrap();
+        file.write(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            0,
+            &[2; block::BLOCK_SIZE],
+        )
+        .unwrap();
+        let copies = fs.allocation_report(&mut device).unwrap().cow_copies;
+        assert!(copies > report.cow_copies);
+
+        /* once owned, writing the block again copies nothing */
+        file.write(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            0,
+            &[3; block::BLOCK_SIZE],
+        )
+        .unwrap();
+        assert_eq!(
+            fs.allocation_report(&mut device).unwrap().cow_copies,
+            copies
+        );
+
+        /* the first write of each other block copies only that block */
+        for i in 1..4 {
+            file.write(
+                &mut fs,
+                &mut subvol,
+                &mut device,
+                (i * block::BLOCK_SIZE) as u64,
+                &[4; block::BLOCK_SIZE],
+            )
+            .unwrap();
+            assert_eq!(
+                fs.allocation_report(&mut device).unwrap().cow_copies,
+                copies + i as u64
+            );
+        }
+    }
+
+    #[test]
+    fn collect_orphans() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        fs.write(&mut subvol, &mut device, "/dir/file", &[1; 8192])
+            .unwrap();
+        fs.hard_link(&mut subvol, &mut device, "/dir/file", "/hard")
+            .unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+        let used_blocks = fs.sb.used_blocks;
+        let inodes = subvol.iter_inodes(&mut device).count();
+
+        /* allocated without being linked into a directory, as by a crash */
+        let leaked = file::create(&mut fs, &mut subvol, &mut device).unwrap();
+        File::open_by_inode(&mut subvol, &mut device, leaked)
+            .unwrap()
+            .write(&mut fs, &mut subvol, &mut device, 0, &[2; 16384])
+            .unwrap();
+        assert!(fs.sb.used_blocks > used_blocks);
+
+        assert_eq!(
+            fs.collect_orphans(&mut subvol, &mut device).unwrap(),
+            vec![leaked]
+        );
+        fs.sync_meta_data(&mut device).unwrap();
+        assert_eq!(fs.sb.used_blocks, used_blocks);
+        assert_eq!(subvol.iter_inodes(&mut device).count(), inodes);
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.check_used_blocks(&mut device).unwrap();
+
+        assert!(fs
+            .collect_orphans(&mut subvol, &mut device)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            fs.read(&mut subvol, &mut device, "/hard").unwrap(),
+            vec![1; 8192]
+        );
+
+        /* an unreferenced inode still counting links is kept with its data */
+        let linked = file::create(&mut fs, &mut subvol, &mut device).unwrap();
+        File::open_by_inode(&mut subvol, &mut device, linked)
+            .unwrap()
+            .write(&mut fs, &mut subvol, &mut device, 0, &[3; 8192])
+            .unwrap();
+        let mut inode = subvol.get_inode(&mut device, linked).unwrap();
+        inode.hlinks = 1;
+        subvol
+            .set_inode(&mut fs, &mut device, linked, inode)
+            .unwrap();
+        let used_blocks = fs.sb.used_blocks;
+
+        assert!(fs
+            .collect_orphans(&mut subvol, &mut device)
+            .unwrap()
+            .is_empty());
+        assert_eq!(fs.sb.used_blocks, used_blocks);
+        assert_eq!(subvol.get_inode(&mut device, linked).unwrap().hlinks, 1);
+        let mut data = vec![0; 8192];
+        File::open_by_inode(&mut subvol, &mut device, linked)
+            .unwrap()
+            .read(&mut fs, &mut subvol, &mut device, 0, &mut data, 8192)
+            .unwrap();
+        assert_eq!(data, vec![3; 8192]);
+    }
+
+    #[test]
+    fn exists_symlink_metadata() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        fs.write(&mut subvol, &mut device, "/dir/file", b"data")
+            .unwrap();
+        fs.link(&mut subvol, &mut device, "/link", "dir/file")
+            .unwrap();
+        fs.link(&mut subvol, &mut device, "/dangling", "missing")
+            .unwrap();
+        fs.mknod(
+            &mut subvol,
+            &mut device,
+            "/char",
+            FileType::CharDevice,
+            1,
+            3,
+        )
+        .unwrap();
+        fs.mknod(
+            &mut subvol,
+            &mut device,
+            "/block",
+            FileType::BlockDevice,
+            8,
+            0,
+        )
+        .unwrap();
+
+        for path in ["/", "/dir", "/dir/file", "/link", "/char", "/block"] {
+            assert!(fs.exists(&mut subvol, &mut device, path), "{path}");
+        }
+        for path in ["/missing", "/dir/missing", "/missing/file", "/dangling"] {
+            assert!(!fs.exists(&mut subvol, &mut device, path), "{path}");
+        }
+
+        for (path, file_type) in [
+            ("/dir", FileType::Directory),
+            ("/dir/file", FileType::RegularFile),
+            ("/link", FileType::Symlink),
+            ("/dangling", FileType::Symlink),
+            ("/char", FileType::CharDevice),
+            ("/block", FileType::BlockDevice),
+        ] {
+            let (found, inode) = fs.symlink_metadata(&mut subvol, &mut device, path).unwrap();
+            assert_eq!(found, file_type, "{path}");
+            assert_eq!(inode, fs.lstat(&mut subvol, &mut device, path).unwrap());
+        }
+        assert_eq!(
+            fs.symlink_metadata(&mut subvol, &mut device, "/missing")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn disk_usage() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/dir/sub").unwrap();
+        let mut fd = fs
+            .create_file(&mut subvol, &mut device, "/dir/file")
+            .unwrap();
+        fd.write(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            0,
+            &[1; 2 * block::BLOCK_SIZE],
+        )
+        .unwrap();
+        /* a sparse file with a single data block */
+        let mut fd = fs
+            .create_file(&mut subvol, &mut device, "/dir/sub/sparse")
+            .unwrap();
+        fd.write(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            10 * block::BLOCK_SIZE as u64,
+            b"end",
+        )
+        .unwrap();
+        fs.hard_link(&mut subvol, &mut device, "/dir/file", "/dir/sub/hard")
+            .unwrap();
+        fs.link(&mut subvol, &mut device, "/dir/link", "/outside")
+            .unwrap();
+        let mut fd = fs
+            .create_file(&mut subvol, &mut device, "/outside")
+            .unwrap();
+        fd.write(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            0,
+            &[1; block::BLOCK_SIZE],
+        )
+        .unwrap();
+
+        let usage = fs.disk_usage(&mut subvol, &mut device, "/dir").unwrap();
+        assert_eq!(
+            usage,
+            DiskUsage {
+                apparent_size: 2 * block::BLOCK_SIZE as u64 + 10 * block::BLOCK_SIZE as u64 + 3,
+                blocks: 3,
+            }
+        );
+        assert_eq!(
+            fs.disk_usage(&mut subvol, &mut device, "/dir/sub/sparse")
+                .unwrap(),
+            DiskUsage {
+                apparent_size: 10 * block::BLOCK_SIZE as u64 + 3,
+                blocks: 1,
+            }
+        );
+
+        /* a copy shares blocks with its source */
+        File::copy(&mut fs, &mut subvol, &mut device, "/dir/file", "/dir/copy").unwrap();
+        let copied = fs.disk_usage(&mut subvol, &mut device, "/dir").unwrap();
+        assert_eq!(
+            copied.apparent_size,
+            usage.apparent_size + 2 * block::BLOCK_SIZE as u64
+        );
+        assert_eq!(copied.blocks, usage.blocks);
+        assert!(fs
+            .lstat(&mut subvol, &mut device, "/dir/copy")
+            .unwrap()
+            .is_file());
+    }
+
+    #[test]
+    fn copy_dir() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        let used_blocks = fs.sb.used_blocks;
+        fs.mkdir(&mut subvol, &mut device, "/src").unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/src/a").unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/src/a/b").unwrap();
+        let data: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        for path in ["/src/f", "/src/a/f", "/src/a/b/f"] {
+            let mut fd = fs.create_file(&mut subvol, &mut device, path).unwrap();
+            fd.write(&mut fs, &mut subvol, &mut device, 0, &data)
+                .unwrap();
+        }
+        fs.link(&mut subvol, &mut device, "/src/a/link", "../f")
+            .unwrap();
+        fs.hard_link(&mut subvol, &mut device, "/src/a/f", "/src/a/b/h")
+            .unwrap();
+
+        assert_eq!(
+            fs.copy_dir(&mut subvol, &mut device, "/src", "/src/a/dst")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+
+        let real_used_blocks = fs.sb.real_used_blocks;
+        fs.copy_dir(&mut subvol, &mut device, "/src", "/dst")
+            .unwrap();
+        /* only metadata is allocated, file content is shared */
+        assert!(fs.sb.real_used_blocks - real_used_blocks < 30);
+
+        let mut names = fs.list_dir(&mut subvol, &mut device, "/dst/a").unwrap();
+        names.sort();
+        assert_eq!(names, ["b", "f", "link"]);
+        assert_eq!(
+            fs.read_link(&mut subvol, &mut device, "/dst/a/link")
+                .unwrap(),
+            PathBuf::from("../f")
+        );
+        for path in ["/dst/f", "/dst/a/f", "/dst/a/b/f", "/dst/a/b/h"] {
+            let mut fd = fs.open_file(&mut subvol, &mut device, path).unwrap();
+            let mut buffer = vec![0; data.len()];
+            fd.read(
+                &mut fs,
+                &mut subvol,
+                &mut device,
+                0,
+                &mut buffer,
+                data.len() as u64,
+            )
+            .unwrap();
+            assert_eq!(buffer, data);
+        }
+
+        let (_, _, linked) = fs
+            .resolve_nofollow(&mut subvol, &mut device, "/dst/a/f")
+            .unwrap();
+        let (_, _, hard_link) = fs
+            .resolve_nofollow(&mut subvol, &mut device, "/dst/a/b/h")
+            .unwrap();
+        let (_, _, origin) = fs
+            .resolve_nofollow(&mut subvol, &mut device, "/src/a/f")
+            .unwrap();
+        assert_eq!(linked, hard_link);
+        assert_ne!(linked, origin);
+
+        /* writing the copy leaves the source alone */
+        let mut fd = fs
+            .open_file(&mut subvol, &mut device, "/dst/a/b/h")
+            .unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"changed")
+            .unwrap();
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/src/a/f").unwrap();
+        let mut buffer = [0; 7];
+        fd.read(&mut fs, &mut subvol, &mut device, 0, &mut buffer, 7)
+            .unwrap();
+        assert_eq!(buffer, data[..7]);
+
+        fs.remove_dir_all(&mut subvol, &mut device, "/dst").unwrap();
+        fs.remove_dir_all(&mut subvol, &mut device, "/src").unwrap();
+        assert_eq!(fs.sb.used_blocks, used_blocks);
+    }
+
+    #[test]
+    fn remove_dir_all() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        fs.mkdir(&mut subvol, &mut device, "/outside").unwrap();
+        let mut fd = fs
+            .create_file(&mut subvol, &mut device, "/outside/kept")
+            .unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 5000])
+            .unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/tree").unwrap();
+        let used_blocks = fs.sb.used_blocks;
+
+        fs.mkdir(&mut subvol, &mut device, "/tree/a").unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/tree/a/b").unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/tree/a/b/c").unwrap();
+        for dir in ["/tree", "/tree/a", "/tree/a/b", "/tree/a/b/c"] {
+            for i in 0..3 {
+                let mut fd = fs
+                    .create_file(&mut subvol, &mut device, format!("{dir}/f{i}"))
+                    .unwrap();
+                fd.write(&mut fs, &mut subvol, &mut device, 0, &[2; 9000])
+                    .unwrap();
+            }
+            fs.link(&mut subvol, &mut device, format!("{dir}/out"), "/outside")
+                .unwrap();
+        }
+        fs.hard_link(&mut subvol, &mut device, "/tree/a/f0", "/tree/a/b/h")
+            .unwrap();
+        fs.link(&mut subvol, &mut device, "/link", "/tree").unwrap();
+
+        /* the link itself is not a directory */
+        assert_eq!(
+            fs.remove_dir_all(&mut subvol, &mut device, "/link")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::Unsupported
+        );
+        fs.remove_file(&mut subvol, &mut device, "/link").unwrap();
+
+        fs.remove_dir_all(&mut subvol, &mut device, "/tree/a")
+            .unwrap();
+        assert_eq!(
+            fs.list_dir(&mut subvol, &mut device, "/tree")
+                .unwrap()
+                .len(),
+            4
+        );
+        fs.remove_dir_all(&mut subvol, &mut device, "/tree")
+            .unwrap();
+
+        assert!(!fs.is_dir(&mut subvol, &mut device, "/tree"));
+        assert_eq!(
+            fs.list_dir(&mut subvol, &mut device, "/outside").unwrap(),
+            ["kept"]
+        );
+        fs.rmdir(&mut subvol, &mut device, "/tree").unwrap_err();
+        fs.mkdir(&mut subvol, &mut device, "/tree").unwrap();
+        assert_eq!(fs.sb.used_blocks, used_blocks);
+    }
+
+    #[test]
+    fn hard_link() {
+        let (mut fs, mut device, mut subvol) = test_fs(200);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/a").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"shared")
+            .unwrap();
+        fs.hard_link(&mut subvol, &mut device, "/a", "/b").unwrap();
+
+        let (_, _, inode_a) = fs.resolve_nofollow(&mut subvol, &mut device, "/a").unwrap();
+        let (_, _, inode_b) = fs.resolve_nofollow(&mut subvol, &mut device, "/b").unwrap();
+        assert_eq!(inode_a, inode_b);
+        assert_eq!(subvol.get_inode(&mut device, inode_a).unwrap().hlinks, 1);
+
+        /* data written through one name is visible through the other */
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/b").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"SH")
+            .unwrap();
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/a").unwrap();
+        let mut buffer = [0; 6];
+        fd.read(&mut fs, &mut subvol, &mut device, 0, &mut buffer, 6)
+            .unwrap();
+        assert_eq!(&buffer, b"SHared");
+
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        assert_eq!(
+            fs.hard_link(&mut subvol, &mut device, "/dir", "/dir2")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::PermissionDenied
+        );
+        fs.create_file(&mut subvol, &mut device, "/c").unwrap();
+        assert_eq!(
+            fs.hard_link(&mut subvol, &mut device, "/a", "/c")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::AlreadyExists
+        );
+        assert_eq!(subvol.get_inode(&mut device, inode_a).unwrap().hlinks, 1);
+
+        /* data stays until the last link is removed */
+        fs.remove_file(&mut subvol, &mut device, "/a").unwrap();
+        assert_eq!(subvol.get_inode(&mut device, inode_b).unwrap().hlinks, 0);
+        assert!(fs.is_file(&mut subvol, &mut device, "/b"));
+    }
+
+    /** A device which fails to read the given blocks */
+    struct FaultyDevice {
+        inner: std::io::Cursor<Vec<u8>>,
+        bad_blocks: Vec<u64>,
+    }
+
+    impl Read for FaultyDevice {
+        fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+            let block = self.inner.position() / block::BLOCK_SIZE as u64;
+            if self.bad_blocks.contains(&block) {
+                return Err(Error::other(format!("Bad block {block}.")));
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for FaultyDevice {
+        fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> IOResult<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl Seek for FaultyDevice {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> IOResult<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn scrub() {
+        let (mut fs, mut device, mut subvol) = test_fs(200);
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/a").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 4096])
+            .unwrap();
+        let data_block = btree::BtreeNode::load_block(&mut device, fd.get_inode().btree_root)
+            .unwrap()
+            .lookup(&mut device, 0)
+            .unwrap()
+            .value;
+        let free_block = (0..200).find(|c| !fs.is_block_allocated(*c)).unwrap();
+
+        let mut device = FaultyDevice {
+            inner: device,
+            bad_blocks: Vec::new(),
+        };
+        assert!(fs.scrub(&mut device).unwrap().is_empty());
+
+        /* only allocated blocks are read */
+        device.bad_blocks = vec![free_block, data_block, 0];
+        let mut bad_blocks = fs.scrub(&mut device).unwrap();
+        bad_blocks.sort();
+        assert_eq!(bad_blocks, vec![0, data_block]);
+    }
+
+    #[test]
+    fn rename() {
+        let (mut fs, mut device, mut subvol) = test_fs(200);
+        fs.mkdir(&mut subvol, &mut device, "/a").unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/a/b").unwrap();
+        fs.link(&mut subvol, &mut device, "/l", "/a/b").unwrap();
+
+        /* a directory can't be moved below itself, even through a symbol link */
+        for dst in ["/a/b/c", "/l/c", "/a/c"] {
+            assert_eq!(
+                fs.rename(&mut subvol, &mut device, "/a", dst)
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+        fs.rename(&mut subvol, &mut device, "/a/b", "/b").unwrap();
+        assert!(fs.is_dir(&mut subvol, &mut device, "/b"));
+
+        /* an existing file is replaced */
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/x").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"new")
+            .unwrap();
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/y").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 8192])
+            .unwrap();
+        let used_blocks = fs.sb.used_blocks;
+        fs.rename(&mut subvol, &mut device, "/x", "/y").unwrap();
+        assert!(!fs.is_file(&mut subvol, &mut device, "/x"));
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/y").unwrap();
+        let mut buffer = [0; 3];
+        fd.read(&mut fs, &mut subvol, &mut device, 0, &mut buffer, 3)
+            .unwrap();
+        assert_eq!(&buffer, b"new");
+        assert!(fs.sb.used_blocks < used_blocks);
+
+        /* an empty directory is replaced, a non-empty one is kept */
+        fs.mkdir(&mut subvol, &mut device, "/d").unwrap();
+        fs.rename(&mut subvol, &mut device, "/d", "/a").unwrap();
+        assert!(!fs.is_dir(&mut subvol, &mut device, "/d"));
+        fs.create_file(&mut subvol, &mut device, "/b/f").unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/d").unwrap();
+        assert_eq!(
+            fs.rename(&mut subvol, &mut device, "/d", "/b")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::DirectoryNotEmpty
+        );
+        assert!(fs.is_dir(&mut subvol, &mut device, "/d"));
+        assert!(fs.is_file(&mut subvol, &mut device, "/b/f"));
+    }
+
+    #[test]
+    fn write_file_atomic() {
+        let (mut fs, mut device, mut subvol) = test_fs(200);
+        let mut fd = fs
+            .create_file(&mut subvol, &mut device, "/.f.0.tmp")
+            .unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"keep")
+            .unwrap();
+
+        fs.write_file_atomic(&mut subvol, &mut device, "/f", b"first")
+            .unwrap();
+        fs.write_file_atomic(&mut subvol, &mut device, "/f", b"second")
+            .unwrap();
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/f").unwrap();
+        let mut buffer = [0; 6];
+        fd.read(&mut fs, &mut subvol, &mut device, 0, &mut buffer, 6)
+            .unwrap();
+        assert_eq!(&buffer, b"second");
+
+        /* an existing file with the temporary name is left alone */
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/.f.0.tmp").unwrap();
+        let mut buffer = [0; 4];
+        fd.read(&mut fs, &mut subvol, &mut device, 0, &mut buffer, 4)
+            .unwrap();
+        assert_eq!(&buffer, b"keep");
+
+        /* the temporary file is removed if the rename fails */
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        let used_blocks = fs.sb.used_blocks;
+        assert_eq!(
+            fs.write_file_atomic(&mut subvol, &mut device, "/dir", &[1; 8192])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::PermissionDenied
+        );
+        let mut names = fs.list_dir(&mut subvol, &mut device, "/").unwrap();
+        names.sort();
+        assert_eq!(names, vec![".f.0.tmp", "dir", "f"]);
+        assert_eq!(fs.sb.used_blocks, used_blocks);
+
+        /* the temporary name must fit into a directory entry */
+        let name = format!("/{}", "n".repeat(dir::FILE_NAME_MAX - 6));
+        assert_eq!(
+            fs.write_file_atomic(&mut subvol, &mut device, &name, b"data")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+        let name = format!("/{}", "n".repeat(dir::FILE_NAME_MAX + 1));
+        assert_eq!(
+            fs.create_file(&mut subvol, &mut device, &name)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn sync_flushes() {
+        let (mut fs, device, mut subvol) = crate::utils::test_fs(2000);
+        let mut device = crate::utils::CountingDevice::new(device);
+
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"data")
+            .unwrap();
+        let flushes = device.flushes;
+        fd.flush(&mut device).unwrap();
+        assert_eq!(device.flushes, flushes + 1);
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        assert_eq!(device.flushes, flushes + 2);
+        fs.sync_meta_data(&mut device).unwrap();
+        assert_eq!(device.flushes, flushes + 3);
+        fs.sync_all(&mut device).unwrap();
+        assert_eq!(device.flushes, flushes + 4);
+
+        /* pending writes of a batch reach the device */
+        let mut device = CachedDevice::new(device.inner, DEFAULT_CACHE_BLOCKS);
+        device.begin_batch();
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/g").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, b"data")
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 4, b"more")
+            .unwrap();
+        assert!(device.dirty_blocks() > 0);
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.sync_all(&mut device).unwrap();
+        assert_eq!(device.dirty_blocks(), 0);
+
+        let mut device = device.into_inner();
+        let mut fs = Filesystem::load(&mut device).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        let mut buf = [0; 8];
+        fs.open_file(&mut subvol, &mut device, "/g")
+            .unwrap()
+            .read(&mut fs, &mut subvol, &mut device, 0, &mut buf, 8)
+            .unwrap();
+        assert_eq!(&buf, b"datamore");
+    }
+
+    #[test]
+    fn path_limits() {
+        let (mut fs, mut device, mut subvol) = crate::utils::test_fs(2000);
+
+        let name = format!("/{}", "n".repeat(FILE_NAME_MAX));
+        fs.create_file(&mut subvol, &mut device, &name).unwrap();
+        let name = format!("/{}", "n".repeat(FILE_NAME_MAX + 1));
+        assert_eq!(
+            fs.create_file(&mut subvol, &mut device, &name)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+
+        /* 16 components of the maximum length fill a path exactly */
+        let component = format!("/{}", "d".repeat(FILE_NAME_MAX));
+        let mut path = String::new();
+        for _ in 0..15 {
+            path.push_str(&component);
+            fs.mkdir(&mut subvol, &mut device, &path).unwrap();
+        }
+        path.push_str(&component);
+        assert_eq!(path.len(), PATH_MAX);
+        fs.create_file(&mut subvol, &mut device, &path).unwrap();
+        fs.open_file(&mut subvol, &mut device, &path).unwrap();
+
+        path.push_str("/f");
+        for result in [
+            fs.create_file(&mut subvol, &mut device, &path).map(|_| ()),
+            fs.open_file(&mut subvol, &mut device, &path).map(|_| ()),
+            fs.remove_file(&mut subvol, &mut device, &path),
+        ] {
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn mknod() {
+        let (mut fs, mut device, mut subvol) = test_fs(200);
+        fs.mknod(&mut subvol, &mut device, "/tty", FileType::CharDevice, 4, 1)
+            .unwrap();
+        let (_, _, inode) = fs
+            .resolve_nofollow(&mut subvol, &mut device, "/tty")
+            .unwrap();
+        let inode = subvol.get_inode(&mut device, inode).unwrap();
+        assert_eq!((inode.dev_major, inode.dev_minor), (4, 1));
+
+        assert_eq!(
+            fs.mknod(
+                &mut subvol,
+                &mut device,
+                "/file",
+                FileType::RegularFile,
+                0,
+                0
+            )
+            .unwrap_err()
+            .kind(),
+            ErrorKind::InvalidInput
+        );
+
+        /* inodes of failed creations are released, or a new inode group would be needed */
+        let used_blocks = fs.sb.used_blocks;
+        for _ in 0..INODE_PER_GROUP {
+            assert_eq!(
+                fs.mknod(
+                    &mut subvol,
+                    &mut device,
+                    "/tty",
+                    FileType::BlockDevice,
+                    8,
+                    0
+                )
+                .unwrap_err()
+                .kind(),
+                ErrorKind::AlreadyExists
+            );
+            assert_eq!(
+                fs.mknod(
+                    &mut subvol,
+                    &mut device,
+                    "/none/tty",
+                    FileType::BlockDevice,
+                    8,
+                    0
+                )
+                .unwrap_err()
+                .kind(),
+                ErrorKind::NotFound
+            );
+        }
+        assert_eq!(fs.sb.used_blocks, used_blocks);
+    }
+
+    #[test]
+    fn create_files() {
+        let (mut fs, mut device, mut subvol) = test_fs(4000);
+        fs.mkdir(&mut subvol, &mut device, "/d").unwrap();
+        let names: Vec<String> = (0..500).map(|i| format!("f{i}")).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let mut files = fs
+            .create_files(&mut subvol, &mut device, "/d", &names)
+            .unwrap();
+        assert_eq!(files.len(), 500);
+        assert_eq!(
+            fs.list_dir(&mut subvol, &mut device, "/d").unwrap().len(),
+            500
+        );
+        files[7]
+            .write(&mut fs, &mut subvol, &mut device, 0, b"data")
+            .unwrap();
+        assert_eq!(fs.read(&mut subvol, &mut device, "/d/f7").unwrap(), b"data");
+        assert!(fs.is_file(&mut subvol, &mut device, "/d/f499"));
+
+        /* a failed batch leaves neither entries nor inodes behind */
+        let inodes = subvol.inode_stats(&mut device).unwrap().0;
+        for (names, kind) in [
+            (["new", "f3"], ErrorKind::AlreadyExists),
+            (["new", "new"], ErrorKind::AlreadyExists),
+            (["new", "a/b"], ErrorKind::InvalidInput),
+        ] {
+            assert_eq!(
+                fs.create_files(&mut subvol, &mut device, "/d", &names)
+                    .unwrap_err()
+                    .kind(),
+                kind
+            );
+        }
+        assert_eq!(subvol.inode_stats(&mut device).unwrap().0, inodes);
+        assert!(!fs.exists(&mut subvol, &mut device, "/d/new"));
+    }
+
+    #[test]
+    fn resolve_nofollow() {
+        let (mut fs, mut device, mut subvol) = test_fs(200);
+        let root = subvol.entry.root_inode;
+        fs.mkdir(&mut subvol, &mut device, "/a").unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/a/b").unwrap();
+        fs.link(&mut subvol, &mut device, "/l", "/a/b").unwrap();
+        let (_, _, a) = fs.resolve_nofollow(&mut subvol, &mut device, "/a").unwrap();
+        let (_, _, b) = fs
+            .resolve_nofollow(&mut subvol, &mut device, "/a/b")
+            .unwrap();
+
+        assert_eq!(
+            fs.resolve_nofollow(&mut subvol, &mut device, "/").unwrap(),
+            (root, String::new(), root)
+        );
+        assert_eq!(
+            fs.resolve_nofollow(&mut subvol, &mut device, "/a/../a/./b")
+                .unwrap(),
+            (a, "b".to_string(), b)
+        );
+        /* `..` of given paths is resolved before symbol links are followed */
+        assert_eq!(
+            fs.resolve_nofollow(&mut subvol, &mut device, "/l/../a/b")
+                .unwrap(),
+            (a, "b".to_string(), b)
+        );
+        /* while `..` of link targets is taken after following them */
+        fs.link(&mut subvol, &mut device, "/a/b/up", "../../l/../b")
+            .unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/a/b/up/c").unwrap();
+        assert!(fs.is_dir(&mut subvol, &mut device, "/a/b/c"));
+        /* the last symbol link itself is returned, not its target */
+        let (parent, name, link) = fs.resolve_nofollow(&mut subvol, &mut device, "/l").unwrap();
+        assert_eq!((parent, name.as_str()), (root, "l"));
+        let entries = Directory::open(&mut fs, &mut subvol, &mut device, "/")
+            .unwrap()
+            .list_dir(&mut fs, &mut subvol, &mut device)
+            .unwrap();
+        assert_eq!(link, entries["l"]);
+        assert_ne!(link, b);
+        assert!(subvol.get_inode(&mut device, link).unwrap().is_symlink());
+        assert!(subvol.get_inode(&mut device, b).unwrap().is_dir());
+
+        assert_eq!(
+            fs.resolve_nofollow(&mut subvol, &mut device, "/a/..")
+                .unwrap(),
+            (root, String::new(), root)
+        );
+        assert_eq!(
+            fs.resolve_nofollow(&mut subvol, &mut device, "/a/b/..")
+                .unwrap(),
+            (root, "a".to_string(), a)
+        );
+    }
+
+    #[test]
+    fn normalize_paths() {
+        let (mut fs, mut device, mut subvol) = test_fs(200);
+        fs.mkdir(&mut subvol, &mut device, "/a/").unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/a/b").unwrap();
+        fs.create_file(&mut subvol, &mut device, "/a/./c").unwrap();
+        let (_, _, b) = fs
+            .resolve_nofollow(&mut subvol, &mut device, "/a/b")
+            .unwrap();
+        let c = fs
+            .open_file(&mut subvol, &mut device, "/a/c")
+            .unwrap()
+            .get_inode_count();
+
+        for path in ["/a/./b", "/a//b", "/a/b/", "a/b", "/../a/b", "/a/c/../b"] {
+            let (_, name, inode) = fs.resolve_nofollow(&mut subvol, &mut device, path).unwrap();
+            assert_eq!((name.as_str(), inode), ("b", b), "{path}");
+        }
+        for path in ["/a/b/../c", "/a/./c", "/a//c", "//a/c", "/../../a/c"] {
+            let fd = fs.open_file(&mut subvol, &mut device, path).unwrap();
+            assert_eq!(fd.get_inode_count(), c, "{path}");
+        }
+        assert!(fs.is_dir(&mut subvol, &mut device, "/a/b/./"));
+        assert!(fs.is_dir(&mut subvol, &mut device, "/.."));
+
+        fs.rename(&mut subvol, &mut device, "/a/b/../c", "/a//d/")
+            .unwrap();
+        let mut names = fs.list_dir(&mut subvol, &mut device, "/a/b/..").unwrap();
+        names.sort();
+        assert_eq!(names, ["b", "d"]);
+        fs.remove_file(&mut subvol, &mut device, "/a/./d").unwrap();
+        fs.rmdir(&mut subvol, &mut device, "/a/b/").unwrap();
+        assert!(fs
+            .list_dir(&mut subvol, &mut device, "/a")
+            .unwrap()
+            .is_empty());
+    }
 }
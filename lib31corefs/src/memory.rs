@@ -0,0 +1,202 @@
+use crate::block::BLOCK_SIZE;
+use crate::trim::Trim;
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result as IOResult};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/**
+ * A device keeping a filesystem image in memory
+ *
+ * Blocks are stored sparsely, those never written read as zero and take no memory, so a large
+ * image only costs the blocks the filesystem uses. Like a block device, the size is fixed and
+ * writes beyond the end fail.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct MemoryDevice {
+    blocks: HashMap<u64, Box<[u8; BLOCK_SIZE]>>,
+    size: u64,
+    position: u64,
+}
+
+impl MemoryDevice {
+    /** Create a device of `blocks` blocks filled with zero */
+    pub fn with_size(blocks: u64) -> Self {
+        Self {
+            size: blocks * BLOCK_SIZE as u64,
+            ..Default::default()
+        }
+    }
+    /** Create a device holding a copy of an image, whose size is rounded up to blocks */
+    pub fn from_bytes(image: &[u8]) -> Self {
+        let mut device = Self::with_size(image.len().div_ceil(BLOCK_SIZE) as u64);
+        device.write_all(image).unwrap();
+        device.position = 0;
+        device
+    }
+    /** Size of the device in bytes */
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+    /** Count of blocks taking memory */
+    pub fn stored_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+    /** Get the full image */
+    pub fn into_inner(self) -> Vec<u8> {
+        let mut image = vec![0; self.size as usize];
+        for (block_count, block) in self.blocks {
+            let start = block_count as usize * BLOCK_SIZE;
+            image[start..start + BLOCK_SIZE].copy_from_slice(&*block);
+        }
+        image
+    }
+}
+
+impl Read for MemoryDevice {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let len = std::cmp::min(buf.len() as u64, self.size.saturating_sub(self.position)) as usize;
+
+        let mut done = 0;
+        while done < len {
+            let block_count = self.position / BLOCK_SIZE as u64;
+            let offset = (self.position % BLOCK_SIZE as u64) as usize;
+            let block_len = std::cmp::min(len - done, BLOCK_SIZE - offset);
+
+            let dst = &mut buf[done..done + block_len];
+            match self.blocks.get(&block_count) {
+                Some(block) => dst.copy_from_slice(&block[offset..offset + block_len]),
+                None => dst.fill(0),
+            }
+            done += block_len;
+            self.position += block_len as u64;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryDevice {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        if !buf.is_empty() && self.position >= self.size {
+            return Err(Error::new(
+                ErrorKind::StorageFull,
+                format!("Write at {} beyond the end of the device.", self.position),
+            ));
+        }
+        let len = std::cmp::min(buf.len() as u64, self.size - self.position) as usize;
+
+        let mut done = 0;
+        while done < len {
+            let block_count = self.position / BLOCK_SIZE as u64;
+            let offset = (self.position % BLOCK_SIZE as u64) as usize;
+            let block_len = std::cmp::min(len - done, BLOCK_SIZE - offset);
+
+            let src = &buf[done..done + block_len];
+            match self.blocks.get_mut(&block_count) {
+                Some(block) => block[offset..offset + block_len].copy_from_slice(src),
+                /* zeros written to a block never written keep it sparse */
+                None if src.iter().all(|byte| *byte == 0) => {}
+                None => {
+                    let mut block = Box::new([0; BLOCK_SIZE]);
+                    block[offset..offset + block_len].copy_from_slice(src);
+                    self.blocks.insert(block_count, block);
+                }
+            }
+            done += block_len;
+            self.position += block_len as u64;
+        }
+        Ok(len)
+    }
+    fn flush(&mut self) -> IOResult<()> {
+        Ok(())
+    }
+}
+
+impl Trim for MemoryDevice {
+    /** Drop the blocks, which read as zero and take no memory afterwards */
+    fn discard(&mut self, block_count: u64, count: u64) -> IOResult<()> {
+        for block_count in block_count..block_count + count {
+            self.blocks.remove(&block_count);
+        }
+        Ok(())
+    }
+}
+
+impl Seek for MemoryDevice {
+    fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+        };
+        self.position = position
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid seek position."))?;
+
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Filesystem;
+
+    #[test]
+    fn format_and_reload() {
+        let mut device = MemoryDevice::with_size(100_000);
+        let mut fs = Filesystem::create(&mut device, 100_000).unwrap();
+        fs.sync_meta_data(&mut device).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/dir/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 5000, b"data")
+            .unwrap();
+        fd.sync_all(&mut fs, &mut subvol, &mut device).unwrap();
+        /* only used blocks take memory */
+        assert!(device.stored_blocks() < 1000);
+
+        let mut device = MemoryDevice::from_bytes(&device.into_inner());
+        let mut fs = Filesystem::load(&mut device).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        let mut buf = [0; 4];
+        fs.open_file(&mut subvol, &mut device, "/dir/f")
+            .unwrap()
+            .read(&mut fs, &mut subvol, &mut device, 5000, &mut buf, 4)
+            .unwrap();
+        assert_eq!(&buf, b"data");
+    }
+
+    #[test]
+    fn fixed_size() {
+        let mut device = MemoryDevice::with_size(2);
+        assert_eq!(device.len(), 2 * BLOCK_SIZE as u64);
+
+        device.seek(SeekFrom::Start(BLOCK_SIZE as u64 - 2)).unwrap();
+        device.write_all(&[1; 4]).unwrap();
+        device.seek(SeekFrom::End(-2)).unwrap();
+        assert_eq!(device.write(&[2; 4]).unwrap(), 2);
+        assert_eq!(
+            device.write(&[2; 4]).unwrap_err().kind(),
+            ErrorKind::StorageFull
+        );
+        assert!(device
+            .seek(SeekFrom::Current(-(3 * BLOCK_SIZE as i64)))
+            .is_err());
+
+        device.seek(SeekFrom::Start(BLOCK_SIZE as u64 - 4)).unwrap();
+        let mut buf = [0xff; 8];
+        device.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 1, 1, 1, 1, 0, 0]);
+        device.seek(SeekFrom::End(-1)).unwrap();
+        assert_eq!(device.read(&mut buf).unwrap(), 1);
+        assert_eq!(device.read(&mut buf).unwrap(), 0);
+
+        let image = device.into_inner();
+        assert_eq!(image.len(), 2 * BLOCK_SIZE);
+        assert_eq!(&image[BLOCK_SIZE - 2..BLOCK_SIZE + 2], &[1; 4]);
+        assert_eq!(&image[2 * BLOCK_SIZE - 2..], &[2; 2]);
+    }
+}
@@ -0,0 +1,436 @@
+use crate::send::{read_array, read_bytes, read_path, Command};
+use crate::subvol::Subvolume;
+use crate::utils::{get_sys_time, pin_time};
+use crate::Filesystem;
+
+use std::io::{Error, ErrorKind, Result as IOResult};
+use std::io::{Read, Seek, Write};
+
+const LOG_MAGIC_HEADER: [u8; 4] = *b"31CR";
+const LOG_VERSION: u8 = 1;
+
+const CMD_END: u8 = 0;
+const CMD_FORMAT: u8 = 1;
+const CMD_CREATE: u8 = 2;
+const CMD_MKDIR: u8 = 3;
+const CMD_WRITE: u8 = 4;
+const CMD_REMOVE: u8 = 5;
+const CMD_SNAPSHOT: u8 = 6;
+
+/**
+ * Record filesystem operations into a log, which `replay` applies to a fresh device
+ *
+ * Every operation runs with the clock pinned to the time it is recorded at and synchronizes
+ * meta data when done, so a replay reproduces the device byte for byte.
+ *
+ * # Log format
+ *
+ * A log starts with magic header `31CR` and a version byte, followed by commands encoded as
+ * in send streams. Each command is followed by the time it ran at, operations on files by the
+ * id of the subvolume. A command is logged before it runs, so a log cut off by a crash still
+ * replays up to the operation in progress.
+ *
+ * |Command |Fields                          |
+ * |--------|--------------------------------|
+ * |END     |                                |
+ * |FORMAT  |time, blocks, UUID              |
+ * |CREATE  |time, subvolume, path           |
+ * |MKDIR   |time, subvolume, path           |
+ * |WRITE   |time, subvolume, path, offset, data|
+ * |REMOVE  |time, subvolume, path           |
+ * |SNAPSHOT|time, subvolume                 |
+ */
+pub struct Recorder<W> {
+    log: W,
+}
+
+impl<W: Write> Recorder<W> {
+    /** Start a log */
+    pub fn new(mut log: W) -> IOResult<Self> {
+        log.write_all(&LOG_MAGIC_HEADER)?;
+        log.write_all(&[LOG_VERSION])?;
+        Ok(Self { log })
+    }
+    /** End the log and return the writer */
+    pub fn finish(mut self) -> IOResult<W> {
+        Command::new(CMD_END).send(&mut self.log)?;
+        self.log.flush()?;
+        Ok(self.log)
+    }
+    fn record(&mut self, command: Command) -> IOResult<()> {
+        command.send(&mut self.log)?;
+        self.log.flush()
+    }
+    /** Format a device with `blocks` blocks */
+    pub fn format<D>(&mut self, device: &mut D, blocks: usize) -> IOResult<Filesystem>
+    where
+        D: Read + Write + Seek,
+    {
+        let time = get_sys_time();
+        pinned(time, || {
+            let mut fs = Filesystem::create(device, blocks)?;
+            self.record(
+                Command::new(CMD_FORMAT)
+                    .u64(time)
+                    .u64(blocks as u64)
+                    .bytes(&fs.sb.uuid),
+            )?;
+            fs.sync_meta_data(device)?;
+            Ok(fs)
+        })
+    }
+    /** Create a regular file */
+    pub fn create_file<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: &str,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let time = self.record_path(CMD_CREATE, subvol, path)?;
+        pinned(time, || apply_create(fs, subvol, device, path))
+    }
+    /** Create a directory */
+    pub fn mkdir<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: &str,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let time = self.record_path(CMD_MKDIR, subvol, path)?;
+        pinned(time, || apply_mkdir(fs, subvol, device, path))
+    }
+    /** Write data into a file */
+    pub fn write<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: &str,
+        offset: u64,
+        data: &[u8],
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let time = get_sys_time();
+        self.record(
+            Command::new(CMD_WRITE)
+                .u64(time)
+                .u64(subvol.entry.id)
+                .bytes(path.as_bytes())
+                .u64(offset)
+                .bytes(data),
+        )?;
+        pinned(time, || apply_write(fs, subvol, device, path, offset, data))
+    }
+    /** Remove a regular file or a symbol link */
+    pub fn remove_file<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        subvol: &mut Subvolume,
+        device: &mut D,
+        path: &str,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let time = self.record_path(CMD_REMOVE, subvol, path)?;
+        pinned(time, || apply_remove(fs, subvol, device, path))
+    }
+    /** Create a snapshot of a subvolume, return id of the snapshot */
+    pub fn create_snapshot<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        device: &mut D,
+        id: u64,
+    ) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        let time = get_sys_time();
+        self.record(Command::new(CMD_SNAPSHOT).u64(time).u64(id))?;
+        pinned(time, || apply_snapshot(fs, device, id))
+    }
+    /** Log an operation on a path, return the time it runs at */
+    fn record_path(&mut self, cmd: u8, subvol: &Subvolume, path: &str) -> IOResult<u64> {
+        let time = get_sys_time();
+        self.record(
+            Command::new(cmd)
+                .u64(time)
+                .u64(subvol.entry.id)
+                .bytes(path.as_bytes()),
+        )?;
+        Ok(time)
+    }
+}
+
+/** Run an operation with the clock pinned to `time` */
+fn pinned<T, F>(time: u64, operation: F) -> IOResult<T>
+where
+    F: FnOnce() -> IOResult<T>,
+{
+    pin_time(Some(time));
+    let result = operation();
+    pin_time(None);
+    result
+}
+
+/** Synchronize meta data after an operation on files */
+fn sync<D>(fs: &mut Filesystem, subvol: &mut Subvolume, device: &mut D) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+{
+    subvol.sync_meta_data(fs, device)?;
+    fs.sync_meta_data(device)
+}
+
+fn apply_create<D>(
+    fs: &mut Filesystem,
+    subvol: &mut Subvolume,
+    device: &mut D,
+    path: &str,
+) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+{
+    fs.create_file(subvol, device, path)?;
+    sync(fs, subvol, device)
+}
+
+fn apply_mkdir<D>(
+    fs: &mut Filesystem,
+    subvol: &mut Subvolume,
+    device: &mut D,
+    path: &str,
+) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+{
+    fs.mkdir(subvol, device, path)?;
+    sync(fs, subvol, device)
+}
+
+fn apply_write<D>(
+    fs: &mut Filesystem,
+    subvol: &mut Subvolume,
+    device: &mut D,
+    path: &str,
+    offset: u64,
+    data: &[u8],
+) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+{
+    fs.open_file(subvol, device, path)?
+        .write(fs, subvol, device, offset, data)?;
+    sync(fs, subvol, device)
+}
+
+fn apply_remove<D>(
+    fs: &mut Filesystem,
+    subvol: &mut Subvolume,
+    device: &mut D,
+    path: &str,
+) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+{
+    fs.remove_file(subvol, device, path)?;
+    sync(fs, subvol, device)
+}
+
+fn apply_snapshot<D>(fs: &mut Filesystem, device: &mut D, id: u64) -> IOResult<u64>
+where
+    D: Read + Write + Seek,
+{
+    let snapshot = fs.create_snapshot(device, id)?;
+    fs.sync_meta_data(device)?;
+    Ok(snapshot)
+}
+
+/** Apply a log written by `Recorder` to a device, return the filesystem formatted on it
+ *
+ * Operations which fail are skipped, as they failed when recorded too. A log cut off between
+ * commands is applied up to where it ends.
+ */
+pub fn replay<D, R>(device: &mut D, log: &mut R) -> IOResult<Filesystem>
+where
+    D: Read + Write + Seek,
+    R: Read,
+{
+    if read_array::<R, 4>(log)? != LOG_MAGIC_HEADER {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Bad magic header of operation log.",
+        ));
+    }
+    let [version] = read_array(log)?;
+    if version != LOG_VERSION {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("Unsupported operation log version {version}."),
+        ));
+    }
+
+    let [cmd] = read_array(log)?;
+    if cmd != CMD_FORMAT {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Operation log does not start with a format.",
+        ));
+    }
+    let time = u64::from_be_bytes(read_array(log)?);
+    let blocks = u64::from_be_bytes(read_array(log)?) as usize;
+    let uuid = read_bytes(log)?
+        .try_into()
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid UUID in operation log."))?;
+    let mut fs = pinned(time, || {
+        let mut fs = Filesystem::create(device, blocks)?;
+        fs.sb.uuid = uuid;
+        fs.sync_meta_data(device)?;
+        Ok(fs)
+    })?;
+
+    loop {
+        let cmd = match read_array(log) {
+            Ok([cmd]) => cmd,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        };
+        if cmd == CMD_END {
+            break;
+        }
+        if !matches!(
+            cmd,
+            CMD_CREATE | CMD_MKDIR | CMD_WRITE | CMD_REMOVE | CMD_SNAPSHOT
+        ) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown command {cmd} in operation log."),
+            ));
+        }
+        let time = u64::from_be_bytes(read_array(log)?);
+        let id = u64::from_be_bytes(read_array(log)?);
+        if cmd == CMD_SNAPSHOT {
+            let _ = pinned(time, || apply_snapshot(&mut fs, device, id));
+            continue;
+        }
+
+        let path = read_path(log)?;
+        let (offset, data) = if cmd == CMD_WRITE {
+            (u64::from_be_bytes(read_array(log)?), read_bytes(log)?)
+        } else {
+            (0, Vec::new())
+        };
+        let _ = pinned(time, || {
+            let mut subvol = fs.get_subvolume(device, id)?;
+            match cmd {
+                CMD_CREATE => apply_create(&mut fs, &mut subvol, device, &path),
+                CMD_MKDIR => apply_mkdir(&mut fs, &mut subvol, device, &path),
+                CMD_WRITE => apply_write(&mut fs, &mut subvol, device, &path, offset, &data),
+                _ => apply_remove(&mut fs, &mut subvol, device, &path),
+            }
+        });
+    }
+
+    Ok(fs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::BLOCK_SIZE;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn record_and_replay() {
+        let blocks = 4000;
+        let mut device = Cursor::new(vec![0; blocks * BLOCK_SIZE]);
+        let mut recorder = Recorder::new(Vec::new()).unwrap();
+
+        let mut fs = recorder.format(&mut device, blocks).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        let data: Vec<u8> = (0..5 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        recorder
+            .create_file(&mut fs, &mut subvol, &mut device, "/a")
+            .unwrap();
+        recorder
+            .write(&mut fs, &mut subvol, &mut device, "/a", 100, &data)
+            .unwrap();
+        recorder
+            .mkdir(&mut fs, &mut subvol, &mut device, "/d")
+            .unwrap();
+        recorder
+            .create_file(&mut fs, &mut subvol, &mut device, "/d/b")
+            .unwrap();
+        recorder
+            .write(&mut fs, &mut subvol, &mut device, "/d/b", 0, b"hello")
+            .unwrap();
+        let snapshot = recorder
+            .create_snapshot(&mut fs, &mut device, subvol.entry.id)
+            .unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut device).unwrap();
+        recorder
+            .write(
+                &mut fs,
+                &mut subvol,
+                &mut device,
+                "/a",
+                0,
+                b"after snapshot",
+            )
+            .unwrap();
+        recorder
+            .remove_file(&mut fs, &mut subvol, &mut device, "/d/b")
+            .unwrap();
+        /* failed operations are recorded and replayed as well */
+        assert!(recorder
+            .create_file(&mut fs, &mut subvol, &mut device, "/a")
+            .is_err());
+        let mut snapshot = fs.get_subvolume(&mut device, snapshot).unwrap();
+        assert!(recorder
+            .create_file(&mut fs, &mut snapshot, &mut device, "/c")
+            .is_err());
+        let log = recorder.finish().unwrap();
+
+        let mut replayed = Cursor::new(vec![0; blocks * BLOCK_SIZE]);
+        let mut fs = replay(&mut replayed, &mut log.as_slice()).unwrap();
+        assert!(replayed.get_ref() == device.get_ref());
+        let mut snapshot = fs.get_subvolume(&mut replayed, snapshot.entry.id).unwrap();
+        assert!(fs.is_file(&mut snapshot, &mut replayed, "/d/b"));
+
+        /* a log cut off after a command replays the commands before it */
+        let mut partial = Cursor::new(vec![0; blocks * BLOCK_SIZE]);
+        let mut fs = replay(&mut partial, &mut &log[..log.len() - 1]).unwrap();
+        let mut subvol = fs.get_default_subvolume(&mut partial).unwrap();
+        assert!(fs.is_file(&mut subvol, &mut partial, "/a"));
+    }
+
+    #[test]
+    fn bad_log() {
+        let mut device = Cursor::new(vec![0; 4000 * BLOCK_SIZE]);
+        assert_eq!(
+            replay(&mut device, &mut &b"31CS\x01"[..])
+                .err()
+                .unwrap()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(
+            replay(&mut device, &mut &b"31CR\x02"[..])
+                .err()
+                .unwrap()
+                .kind(),
+            ErrorKind::Unsupported
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use crate::trim::Trim;
+
+use std::io::{Error, ErrorKind, Result as IOResult};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+/** How often and how long to wait before an operation failed by a transient error is retried */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /** Count of retries after the first attempt */
+    pub retries: u32,
+    /** Delay before the first retry, doubled for each following retry */
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /** Delay before retry `attempt`, counted from zero */
+    fn delay(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(1 << attempt.min(16))
+    }
+}
+
+/** Errors which may not happen again if the operation is retried */
+fn is_transient(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+    )
+}
+
+/**
+ * A device adapter retrying reads, writes and seeks failed by transient errors
+ *
+ * Before a retry, the inner device is moved back to where the failed operation started, so blocks
+ * are never read from or written to a wrong position. The position is only known after the first
+ * seek, which every access to a block begins with.
+ */
+#[derive(Debug)]
+pub struct RetryDevice<D> {
+    inner: D,
+    policy: RetryPolicy,
+    position: u64,
+}
+
+impl<D> RetryDevice<D>
+where
+    D: Read + Write + Seek,
+{
+    pub fn new(inner: D, policy: RetryPolicy) -> Self {
+        Self {
+            inner,
+            policy,
+            position: 0,
+        }
+    }
+    pub fn get_ref(&self) -> &D {
+        &self.inner
+    }
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+    pub fn set_policy(&mut self, policy: RetryPolicy) {
+        self.policy = policy;
+    }
+    fn retry<T>(&mut self, mut operation: impl FnMut(&mut D) -> IOResult<T>) -> IOResult<T> {
+        let mut attempt = 0;
+        loop {
+            let result = if attempt == 0 {
+                operation(&mut self.inner)
+            } else {
+                self.inner
+                    .seek(SeekFrom::Start(self.position))
+                    .and_then(|_| operation(&mut self.inner))
+            };
+
+            match result {
+                Err(err) if is_transient(&err) && attempt < self.policy.retries => {
+                    std::thread::sleep(self.policy.delay(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<D> Read for RetryDevice<D>
+where
+    D: Read + Write + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let len = self.retry(|inner| inner.read(buf))?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<D> Write for RetryDevice<D>
+where
+    D: Read + Write + Seek,
+{
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        let len = self.retry(|inner| inner.write(buf))?;
+        self.position += len as u64;
+        Ok(len)
+    }
+    fn flush(&mut self) -> IOResult<()> {
+        self.retry(|inner| inner.flush())
+    }
+}
+
+impl<D> Trim for RetryDevice<D>
+where
+    D: Read + Write + Seek + Trim,
+{
+    fn discard(&mut self, block_count: u64, count: u64) -> IOResult<()> {
+        self.retry(|inner| inner.discard(block_count, count))
+    }
+}
+
+impl<D> Seek for RetryDevice<D>
+where
+    D: Read + Write + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+        self.position = self.retry(|inner| inner.seek(pos))?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, BLOCK_SIZE};
+    use crate::btree::BtreeNode;
+    use crate::utils::test_fs;
+
+    use std::io::Cursor;
+
+    /** A device failing the first reads of a block */
+    struct FlakyDevice {
+        inner: Cursor<Vec<u8>>,
+        block: u64,
+        failures: usize,
+        kind: ErrorKind,
+    }
+
+    impl Read for FlakyDevice {
+        fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+            if self.inner.position() / BLOCK_SIZE as u64 == self.block && self.failures > 0 {
+                self.failures -= 1;
+                /* leave the device elsewhere, as a failed transfer may */
+                self.inner.set_position(0);
+                return Err(Error::from(self.kind));
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for FlakyDevice {
+        fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> IOResult<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl Seek for FlakyDevice {
+        fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    /** Read a file whose second block fails `failures` times */
+    fn read_flaky(failures: usize, kind: ErrorKind, retries: u32) -> IOResult<()> {
+        let (mut fs, device, mut subvol) = test_fs(2000);
+        let mut device = FlakyDevice {
+            inner: device,
+            block: 0,
+            failures: 0,
+            kind,
+        };
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect();
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &data)
+            .unwrap();
+
+        let root = BtreeNode::load_block(&mut device, fd.get_inode().btree_root).unwrap();
+        device.block = root.lookup(&mut device, 1).unwrap().value;
+        device.failures = failures;
+        let mut device = RetryDevice::new(
+            device,
+            RetryPolicy {
+                retries,
+                backoff: Duration::ZERO,
+            },
+        );
+
+        let mut buffer = vec![0; data.len()];
+        fd.read(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            0,
+            &mut buffer,
+            data.len() as u64,
+        )?;
+        assert_eq!(buffer, data);
+        Ok(())
+    }
+
+    #[test]
+    fn transient_errors() {
+        read_flaky(3, ErrorKind::WouldBlock, 3).unwrap();
+        read_flaky(2, ErrorKind::TimedOut, 3).unwrap();
+        assert_eq!(
+            read_flaky(4, ErrorKind::WouldBlock, 3).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        assert_eq!(
+            read_flaky(1, ErrorKind::WouldBlock, 0).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+
+        /* other errors are returned at once */
+        assert_eq!(
+            read_flaky(1, ErrorKind::UnexpectedEof, 3)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            retries: 3,
+            backoff: Duration::from_millis(5),
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(5));
+        assert_eq!(policy.delay(2), Duration::from_millis(20));
+        assert_eq!(
+            RetryPolicy {
+                retries: 100,
+                backoff: Duration::MAX,
+            }
+            .delay(99),
+            Duration::MAX
+        );
+    }
+}
@@ -0,0 +1,767 @@
+use crate::block::BLOCK_SIZE;
+use crate::btree::BtreeNode;
+use crate::dir::Directory;
+use crate::file::{compressed_len, read_cluster, File, CLUSTER_BLOCKS};
+use crate::inode::{FileType, INode, ACL_BLOCK, ACL_CHAR, ACL_WHITEOUT};
+use crate::subvol::{Subvolume, SubvolumeManager};
+use crate::symlink::read_link_from_inode;
+use crate::{xattr, Block, Filesystem};
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Result as IOResult};
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+const SEND_MAGIC_HEADER: [u8; 4] = *b"31CS";
+const SEND_VERSION: u8 = 1;
+
+/** Max length of a variable length field */
+const FIELD_MAX_LEN: usize = 16 * 1024 * 1024;
+
+const CMD_END: u8 = 0;
+const CMD_MKDIR: u8 = 1;
+const CMD_CREATE: u8 = 2;
+const CMD_SYMLINK: u8 = 3;
+const CMD_MKNOD: u8 = 4;
+const CMD_LINK: u8 = 5;
+const CMD_REMOVE: u8 = 6;
+const CMD_WRITE: u8 = 7;
+const CMD_TRUNCATE: u8 = 8;
+const CMD_SETATTR: u8 = 9;
+const CMD_SET_XATTR: u8 = 10;
+const CMD_REMOVE_XATTR: u8 = 11;
+
+/**
+ * # Stream format
+ *
+ * A stream starts with magic header `31CS` and a version byte, followed by commands. Each
+ * command starts with a command byte, integers are big endian and variable length fields
+ * are prefixed with a 4 bytes length. Files are referred by paths, as inode counts are not
+ * kept when received.
+ *
+ * |Command    |Fields                          |
+ * |-----------|--------------------------------|
+ * |END        |                                |
+ * |MKDIR      |path                            |
+ * |CREATE     |path                            |
+ * |SYMLINK    |path, target                    |
+ * |MKNOD      |path, file type, major, minor   |
+ * |LINK       |path, existing path             |
+ * |REMOVE     |path                            |
+ * |WRITE      |path, offset, data              |
+ * |TRUNCATE   |path, size                      |
+ * |SETATTR    |path, ACL, UID, GID, mtime      |
+ * |SET_XATTR  |path, name, value               |
+ * |REMOVE_XATTR|path, name                     |
+ */
+pub(crate) struct Command(Vec<u8>);
+
+impl Command {
+    pub(crate) fn new(cmd: u8) -> Self {
+        Self(vec![cmd])
+    }
+    pub(crate) fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.0.extend((bytes.len() as u32).to_be_bytes());
+        self.0.extend(bytes);
+        self
+    }
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend(value.to_be_bytes());
+        self
+    }
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend(value.to_be_bytes());
+        self
+    }
+    pub(crate) fn u64(mut self, value: u64) -> Self {
+        self.0.extend(value.to_be_bytes());
+        self
+    }
+    pub(crate) fn send<W: Write>(self, writer: &mut W) -> IOResult<()> {
+        writer.write_all(&self.0)
+    }
+}
+
+pub(crate) fn read_array<R: Read, const N: usize>(reader: &mut R) -> IOResult<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+pub(crate) fn read_bytes<R: Read>(reader: &mut R) -> IOResult<Vec<u8>> {
+    let len = u32::from_be_bytes(read_array(reader)?) as usize;
+    if len > FIELD_MAX_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Field of send stream is too long.",
+        ));
+    }
+
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+pub(crate) fn read_path<R: Read>(reader: &mut R) -> IOResult<String> {
+    String::from_utf8(read_bytes(reader)?)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid path in send stream."))
+}
+
+/** A file of a subvolume */
+struct Node {
+    path: String,
+    inode_count: u64,
+    inode: INode,
+}
+
+/** List all files of a subvolume, directories are listed before their children */
+fn walk<D>(fs: &mut Filesystem, subvol: &mut Subvolume, device: &mut D) -> IOResult<Vec<Node>>
+where
+    D: Read + Write + Seek,
+{
+    let root = subvol.entry.root_inode;
+    let mut nodes = vec![Node {
+        path: "/".to_string(),
+        inode_count: root,
+        inode: subvol.get_inode(device, root)?,
+    }];
+
+    let mut i = 0;
+    while i < nodes.len() {
+        if nodes[i].inode.is_dir() {
+            let dir_path = nodes[i].path.clone();
+            let mut entries = Directory::open(fs, subvol, device, &dir_path)?
+                .list_dir(fs, subvol, device)?
+                .into_iter()
+                .collect::<Vec<_>>();
+            entries.sort();
+
+            for (name, inode_count) in entries {
+                nodes.push(Node {
+                    path: Path::new(&dir_path)
+                        .join(name)
+                        .to_string_lossy()
+                        .to_string(),
+                    inode_count,
+                    inode: subvol.get_inode(device, inode_count)?,
+                });
+            }
+        }
+        i += 1;
+    }
+
+    Ok(nodes)
+}
+
+/** Get value of a data block, `None` if it is not allocated */
+fn lookup_block<D>(device: &mut D, root: &Option<BtreeNode>, count: u64) -> Option<u64>
+where
+    D: Read + Write + Seek,
+{
+    root.as_ref()
+        .and_then(|root| root.lookup(device, count).ok())
+        .map(|entry| entry.value)
+}
+
+fn content_root<D>(device: &mut D, inode: &INode) -> IOResult<Option<BtreeNode>>
+where
+    D: Read + Write + Seek,
+{
+    if inode.btree_root == 0 {
+        return Ok(None);
+    }
+
+    let mut node = BtreeNode::load_block(device, inode.btree_root)?;
+    node.block_count = inode.btree_root;
+    Ok(Some(node))
+}
+
+/** Send data blocks of a file which are not shared with the old version */
+fn send_content<D, W>(
+    device: &mut D,
+    writer: &mut W,
+    node: &Node,
+    old: Option<&INode>,
+) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+    W: Write,
+{
+    let root = content_root(device, &node.inode)?;
+    let old_root = match old {
+        Some(old) => content_root(device, old)?,
+        None => None,
+    };
+
+    let blocks = node.inode.size.div_ceil(BLOCK_SIZE as u64);
+    for cluster in 0..blocks.div_ceil(CLUSTER_BLOCKS) {
+        let first = cluster * CLUSTER_BLOCKS;
+        let counts = first..std::cmp::min(first + CLUSTER_BLOCKS, blocks);
+
+        let changed = counts
+            .clone()
+            .filter(|count| {
+                lookup_block(device, &root, *count) != lookup_block(device, &old_root, *count)
+            })
+            .collect::<Vec<u64>>();
+        if changed.is_empty() {
+            continue;
+        }
+
+        /* blocks of compressed clusters can not be compared one by one */
+        let is_compressed = |device: &mut D, root: &Option<BtreeNode>| {
+            root.as_ref()
+                .is_some_and(|root| compressed_len(device, root, cluster).is_some())
+        };
+        let changed = if is_compressed(device, &root) || is_compressed(device, &old_root) {
+            counts.collect()
+        } else {
+            changed
+        };
+
+        /* holes which were allocated in the old version are filled with zero */
+        let data = match &root {
+            Some(root) => read_cluster(device, root, cluster)?,
+            None => vec![0; CLUSTER_BLOCKS as usize * BLOCK_SIZE],
+        };
+        for count in changed {
+            let offset = count * BLOCK_SIZE as u64;
+            let start = ((count - first) as usize) * BLOCK_SIZE;
+            let len = std::cmp::min(BLOCK_SIZE as u64, node.inode.size - offset) as usize;
+            Command::new(CMD_WRITE)
+                .bytes(node.path.as_bytes())
+                .u64(offset)
+                .bytes(&data[start..start + len])
+                .send(writer)?;
+        }
+    }
+
+    if old.is_none_or(|old| old.size != node.inode.size) {
+        Command::new(CMD_TRUNCATE)
+            .bytes(node.path.as_bytes())
+            .u64(node.inode.size)
+            .send(writer)?;
+    }
+
+    Ok(())
+}
+
+/** Send extended attributes which are different from the old version */
+fn send_xattr<D, W>(
+    subvol: &Subvolume,
+    old_subvol: Option<&Subvolume>,
+    device: &mut D,
+    writer: &mut W,
+    node: &Node,
+    old: Option<u64>,
+) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+    W: Write,
+{
+    let attrs = xattr::load(subvol, device, node.inode_count)?;
+    let old_attrs = match (old_subvol, old) {
+        (Some(old_subvol), Some(old)) => xattr::load(old_subvol, device, old)?,
+        _ => Vec::new(),
+    };
+
+    for (name, _) in &old_attrs {
+        if !attrs.iter().any(|(attr_name, _)| attr_name == name) {
+            Command::new(CMD_REMOVE_XATTR)
+                .bytes(node.path.as_bytes())
+                .bytes(name)
+                .send(writer)?;
+        }
+    }
+    for attr in &attrs {
+        if !old_attrs.contains(attr) {
+            Command::new(CMD_SET_XATTR)
+                .bytes(node.path.as_bytes())
+                .bytes(&attr.0)
+                .bytes(&attr.1)
+                .send(writer)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn parent_path(path: &str) -> String {
+    Path::new(path)
+        .parent()
+        .map(|parent| parent.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/** IDs of a subvolume and the subvolumes it is snapshotted or cloned from */
+fn lineage<D>(fs: &Filesystem, device: &mut D, id: u64) -> IOResult<Vec<u64>>
+where
+    D: Read + Write + Seek,
+{
+    let mut ids = vec![id];
+    let mut entry = SubvolumeManager::get_subvolume(device, fs.sb.subvol_mgr, id)?.entry;
+    while entry.is_snapshot() && !ids.contains(&entry.parent_subvol) {
+        ids.push(entry.parent_subvol);
+        entry =
+            SubvolumeManager::get_subvolume(device, fs.sb.subvol_mgr, entry.parent_subvol)?.entry;
+    }
+
+    Ok(ids)
+}
+
+/** Send difference between two subvolumes, or all files if `parent_id` is `None` */
+pub(crate) fn send<D, W>(
+    fs: &mut Filesystem,
+    device: &mut D,
+    parent_id: Option<u64>,
+    child_id: u64,
+    writer: &mut W,
+) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+    W: Write,
+{
+    let mut child = fs.get_subvolume(device, child_id)?;
+    let nodes = walk(fs, &mut child, device)?;
+
+    let mut parent = match parent_id {
+        Some(parent_id) => {
+            /* inode counts are only comparable between subvolumes sharing an inode tree */
+            let child_lineage = lineage(fs, device, child_id)?;
+            if !lineage(fs, device, parent_id)?
+                .iter()
+                .any(|id| child_lineage.contains(id))
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Subvolume '{child_id}' is not related to '{parent_id}'."),
+                ));
+            }
+            Some(fs.get_subvolume(device, parent_id)?)
+        }
+        None => None,
+    };
+    let old_nodes = match &mut parent {
+        Some(parent) => walk(fs, parent, device)?,
+        None => Vec::new(),
+    };
+
+    let current: HashMap<&str, &Node> = nodes.iter().map(|n| (n.path.as_str(), n)).collect();
+
+    /* files replaced by other inodes are removed with all of their children */
+    let mut removed: HashSet<&str> = HashSet::new();
+    for old in &old_nodes {
+        let replaced = match current.get(old.path.as_str()) {
+            Some(node) => {
+                node.inode_count != old.inode_count
+                    || node.inode.file_type() != old.inode.file_type()
+            }
+            None => true,
+        };
+        if replaced || removed.contains(parent_path(&old.path).as_str()) {
+            removed.insert(&old.path);
+        }
+    }
+    let old: HashMap<&str, &Node> = old_nodes
+        .iter()
+        .filter(|n| !removed.contains(n.path.as_str()))
+        .map(|n| (n.path.as_str(), n))
+        .collect();
+
+    writer.write_all(&SEND_MAGIC_HEADER)?;
+    writer.write_all(&[SEND_VERSION])?;
+
+    /* directories whose entries are changed, their attributes are sent at last */
+    let mut touched_dirs = HashSet::new();
+
+    let mut removed_nodes = old_nodes
+        .iter()
+        .filter(|n| removed.contains(n.path.as_str()))
+        .collect::<Vec<_>>();
+    removed_nodes.sort_by_key(|n| std::cmp::Reverse(n.path.matches('/').count()));
+    for node in removed_nodes {
+        Command::new(CMD_REMOVE)
+            .bytes(node.path.as_bytes())
+            .send(writer)?;
+        touched_dirs.insert(parent_path(&node.path));
+    }
+
+    /* paths of inodes existing on receiver, used to create hard links */
+    let mut linked: HashMap<u64, &str> = old
+        .values()
+        .filter(|n| !n.inode.is_dir())
+        .map(|n| (n.inode_count, n.path.as_str()))
+        .collect();
+
+    /* inodes whose contents have been sent, hard links are only sent once */
+    let mut sent = HashSet::new();
+
+    for node in &nodes {
+        let old_node = old.get(node.path.as_str()).copied();
+        /* root directory always exists */
+        if old_node.is_none() && node.inode_count != child.entry.root_inode {
+            touched_dirs.insert(parent_path(&node.path));
+
+            if let Some(target) = linked.get(&node.inode_count) {
+                Command::new(CMD_LINK)
+                    .bytes(node.path.as_bytes())
+                    .bytes(target.as_bytes())
+                    .send(writer)?;
+                continue;
+            }
+
+            let path = node.path.as_bytes();
+            match node.inode.file_type() {
+                FileType::Directory => Command::new(CMD_MKDIR).bytes(path).send(writer)?,
+                FileType::RegularFile => Command::new(CMD_CREATE).bytes(path).send(writer)?,
+                FileType::Symlink => {
+                    let target = read_link_from_inode(&mut child, device, node.inode_count)?;
+                    Command::new(CMD_SYMLINK)
+                        .bytes(path)
+                        .bytes(target.to_string_lossy().as_bytes())
+                        .send(writer)?
+                }
+                FileType::CharDevice | FileType::BlockDevice | FileType::Whiteout => {
+                    Command::new(CMD_MKNOD)
+                        .bytes(path)
+                        .u16(node.inode.acl_type())
+                        .u32(node.inode.dev_major)
+                        .u32(node.inode.dev_minor)
+                        .send(writer)?
+                }
+                FileType::Unknown => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unknown file type of '{}'.", node.path),
+                    ))
+                }
+            }
+            if !node.inode.is_dir() {
+                linked.insert(node.inode_count, node.path.as_str());
+            }
+        }
+
+        if !sent.insert(node.inode_count) {
+            continue;
+        }
+        if node.inode.is_file() {
+            send_content(device, writer, node, old_node.map(|n| &n.inode))?;
+        }
+        send_xattr(
+            &child,
+            parent.as_ref(),
+            device,
+            writer,
+            node,
+            old_node.map(|n| n.inode_count),
+        )?;
+    }
+
+    /* attributes are sent after contents, so that mtime is kept */
+    for node in &nodes {
+        let changed = match old.get(node.path.as_str()) {
+            Some(old_node) => {
+                node.inode.acl != old_node.inode.acl
+                    || node.inode.uid != old_node.inode.uid
+                    || node.inode.gid != old_node.inode.gid
+                    || node.inode.mtime != old_node.inode.mtime
+            }
+            None => true,
+        };
+        if changed || touched_dirs.contains(&node.path) {
+            Command::new(CMD_SETATTR)
+                .bytes(node.path.as_bytes())
+                .u16(node.inode.acl)
+                .u16(node.inode.uid)
+                .u16(node.inode.gid)
+                .u64(node.inode.mtime)
+                .send(writer)?;
+        }
+    }
+
+    Command::new(CMD_END).send(writer)
+}
+
+/** Apply a stream onto a writable clone of `base_id`, or a new subvolume */
+pub(crate) fn receive<D, R>(
+    fs: &mut Filesystem,
+    device: &mut D,
+    base_id: Option<u64>,
+    reader: &mut R,
+) -> IOResult<u64>
+where
+    D: Read + Write + Seek,
+    R: Read,
+{
+    if read_array::<R, 4>(reader)? != SEND_MAGIC_HEADER
+        || read_array::<R, 1>(reader)?[0] != SEND_VERSION
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Invalid send stream header.",
+        ));
+    }
+
+    let id = match base_id {
+        Some(base_id) => SubvolumeManager::clone_subvolume(fs, device, base_id)?,
+        None => fs.new_subvolume(device, None)?,
+    };
+
+    let mut subvol = fs.get_subvolume(device, id)?;
+    let result = apply(fs, &mut subvol, device, reader);
+    subvol.sync_meta_data(fs, device)?;
+
+    /* a partially applied stream is not kept */
+    if let Err(err) = result {
+        fs.remove_subvolume(device, id)?;
+        return Err(err);
+    }
+    SubvolumeManager::set_subvolume_readonly(device, fs.sb.subvol_mgr, id, true)?;
+
+    Ok(id)
+}
+
+/** Apply commands of a stream onto a subvolume */
+fn apply<D, R>(
+    fs: &mut Filesystem,
+    subvol: &mut Subvolume,
+    device: &mut D,
+    reader: &mut R,
+) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+    R: Read,
+{
+    loop {
+        let [cmd] = read_array(reader)?;
+        if cmd == CMD_END {
+            break;
+        }
+
+        let path = read_path(reader)?;
+        match cmd {
+            CMD_MKDIR => {
+                fs.mkdir(subvol, device, &path)?;
+            }
+            CMD_CREATE => {
+                fs.create_file(subvol, device, &path)?;
+            }
+            CMD_SYMLINK => {
+                let target = read_path(reader)?;
+                fs.link(subvol, device, &path, &target)?;
+            }
+            CMD_MKNOD => {
+                let acl_type = u16::from_be_bytes(read_array(reader)?);
+                let major = u32::from_be_bytes(read_array(reader)?);
+                let minor = u32::from_be_bytes(read_array(reader)?);
+                let file_type = match acl_type {
+                    ACL_CHAR => FileType::CharDevice,
+                    ACL_BLOCK => FileType::BlockDevice,
+                    ACL_WHITEOUT => FileType::Whiteout,
+                    _ => FileType::Unknown,
+                };
+                fs.mknod(subvol, device, &path, file_type, major, minor)?;
+            }
+            CMD_LINK => {
+                let target = read_path(reader)?;
+                fs.hard_link(subvol, device, &target, &path)?;
+            }
+            CMD_REMOVE => {
+                let (_, _, inode_count) = fs.resolve_nofollow(subvol, device, &path)?;
+                if subvol.get_inode(device, inode_count)?.is_dir() {
+                    fs.rmdir(subvol, device, &path)?;
+                } else {
+                    File::remove(fs, subvol, device, &path)?;
+                }
+            }
+            CMD_WRITE => {
+                let offset = u64::from_be_bytes(read_array(reader)?);
+                let data = read_bytes(reader)?;
+                fs.open_file(subvol, device, &path)?
+                    .write(fs, subvol, device, offset, &data)?;
+            }
+            CMD_TRUNCATE => {
+                let size = u64::from_be_bytes(read_array(reader)?);
+                fs.open_file(subvol, device, &path)?
+                    .truncate(fs, subvol, device, size)?;
+            }
+            CMD_SETATTR => {
+                let acl = u16::from_be_bytes(read_array(reader)?);
+                let uid = u16::from_be_bytes(read_array(reader)?);
+                let gid = u16::from_be_bytes(read_array(reader)?);
+                let mtime = u64::from_be_bytes(read_array(reader)?);
+
+                let (_, _, inode_count) = fs.resolve_nofollow(subvol, device, &path)?;
+                let mut inode = subvol.get_inode(device, inode_count)?;
+                inode.acl = acl;
+                inode.uid = uid;
+                inode.gid = gid;
+                inode.mtime = mtime;
+                inode.update_ctime();
+                subvol.set_inode(fs, device, inode_count, inode)?;
+            }
+            CMD_SET_XATTR => {
+                let name = read_bytes(reader)?;
+                let value = read_bytes(reader)?;
+                fs.set_xattr(subvol, device, &path, &name, &value)?;
+            }
+            CMD_REMOVE_XATTR => {
+                let name = read_bytes(reader)?;
+                fs.remove_xattr(subvol, device, &path, &name)?;
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown command {cmd} in send stream."),
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_fs;
+
+    /** Path, file type, content or link target and extended attributes of each file */
+    type Tree = Vec<(String, FileType, Vec<u8>, Vec<(Vec<u8>, Vec<u8>)>)>;
+
+    fn tree<D>(fs: &mut Filesystem, device: &mut D, id: u64) -> Tree
+    where
+        D: Read + Write + Seek,
+    {
+        let mut subvol = fs.get_subvolume(device, id).unwrap();
+        walk(fs, &mut subvol, device)
+            .unwrap()
+            .into_iter()
+            .map(|node| {
+                let content = match node.inode.file_type() {
+                    FileType::RegularFile => {
+                        let mut buffer = vec![0; node.inode.size as usize];
+                        fs.open_file(&mut subvol, device, &node.path)
+                            .unwrap()
+                            .read(fs, &mut subvol, device, 0, &mut buffer, node.inode.size)
+                            .unwrap();
+                        buffer
+                    }
+                    FileType::Symlink => {
+                        read_link_from_inode(&mut subvol, device, node.inode_count)
+                            .unwrap()
+                            .to_string_lossy()
+                            .as_bytes()
+                            .to_vec()
+                    }
+                    _ => Vec::new(),
+                };
+                let attrs = xattr::load(&subvol, device, node.inode_count).unwrap();
+                (node.path, node.inode.file_type(), content, attrs)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn incremental_round_trip() {
+        let (mut fs, mut device, mut subvol) = test_fs(4000);
+        let id = subvol.entry.id;
+        fs.mkdir(&mut subvol, &mut device, "/dir").unwrap();
+        fs.mkdir(&mut subvol, &mut device, "/dir/sub").unwrap();
+        fs.create_file(&mut subvol, &mut device, "/dir/sub/gone")
+            .unwrap();
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/big").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[7; 5 * BLOCK_SIZE])
+            .unwrap();
+        fs.create_file(&mut subvol, &mut device, "/kept").unwrap();
+        fs.set_xattr(&mut subvol, &mut device, "/kept", b"user.a", b"1")
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        let old = fs.create_snapshot(&mut device, id).unwrap();
+
+        let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/big").unwrap();
+        fd.write(
+            &mut fs,
+            &mut subvol,
+            &mut device,
+            3 * BLOCK_SIZE as u64,
+            b"changed",
+        )
+        .unwrap();
+        fs.rmdir(&mut subvol, &mut device, "/dir/sub/gone")
+            .unwrap_err();
+        File::remove(&mut fs, &mut subvol, &mut device, "/dir/sub/gone").unwrap();
+        fs.rmdir(&mut subvol, &mut device, "/dir/sub").unwrap();
+        fs.rmdir(&mut subvol, &mut device, "/dir").unwrap();
+        fs.create_file(&mut subvol, &mut device, "/dir").unwrap();
+        fs.hard_link(&mut subvol, &mut device, "/big", "/big2")
+            .unwrap();
+        fs.link(&mut subvol, &mut device, "/link", "/kept").unwrap();
+        fs.remove_xattr(&mut subvol, &mut device, "/kept", b"user.a")
+            .unwrap();
+        fs.set_xattr(&mut subvol, &mut device, "/kept", b"user.b", b"2")
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        let new = fs.create_snapshot(&mut device, id).unwrap();
+
+        let mut full = Vec::new();
+        fs.send(&mut device, None, old, &mut full).unwrap();
+        let mut incremental = Vec::new();
+        fs.send(&mut device, Some(old), new, &mut incremental)
+            .unwrap();
+        /* only the changed block of `/big` is sent */
+        assert!(incremental.len() < 2 * BLOCK_SIZE);
+
+        let (mut dst_fs, mut dst_device, _) = test_fs(4000);
+        let received_old = dst_fs
+            .receive(&mut dst_device, None, &mut full.as_slice())
+            .unwrap();
+        let received_new = dst_fs
+            .receive(
+                &mut dst_device,
+                Some(received_old),
+                &mut incremental.as_slice(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            tree(&mut dst_fs, &mut dst_device, received_old),
+            tree(&mut fs, &mut device, old)
+        );
+        assert_eq!(
+            tree(&mut dst_fs, &mut dst_device, received_new),
+            tree(&mut fs, &mut device, new)
+        );
+    }
+
+    #[test]
+    fn unrelated_subvolumes() {
+        let (mut fs, mut device, subvol) = test_fs(2000);
+        let other = fs.new_subvolume(&mut device, None).unwrap();
+        assert_eq!(
+            fs.send(&mut device, Some(subvol.entry.id), other, &mut Vec::new())
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn failed_receive_removes_subvolume() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        fs.create_file(&mut subvol, &mut device, "/a").unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        let mut stream = Vec::new();
+        fs.send(&mut device, None, subvol.entry.id, &mut stream)
+            .unwrap();
+
+        let (mut dst_fs, mut dst_device, _) = test_fs(2000);
+        stream.truncate(stream.len() - 1);
+        dst_fs
+            .receive(&mut dst_device, None, &mut stream.as_slice())
+            .unwrap_err();
+        assert_eq!(dst_fs.subvolume_count(), 1);
+        assert_eq!(dst_fs.list_subvolumes(&mut dst_device).unwrap().len(), 1);
+    }
+}
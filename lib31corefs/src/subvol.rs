@@ -1,11 +1,12 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::{Error, ErrorKind, Result as IOResult};
 use std::io::{Read, Seek, Write};
 
-use crate::block::{BitmapBlock, BitmapIndexBlock, Block, INodeGroup, BLOCK_SIZE};
-use crate::btree::BtreeNode;
+use crate::block::{invalid_block, BitmapBlock, BitmapIndexBlock, Block, INodeGroup, BLOCK_SIZE};
+use crate::btree::{BtreeEntry, BtreeNode};
 use crate::inode::{INode, INODE_PER_GROUP};
 use crate::utils::get_sys_time;
-use crate::Filesystem;
+use crate::{Filesystem, FsError};
 
 const SUBVOLUMES: usize = BLOCK_SIZE / SUBVOLUME_ENTRY_SIZE - 1;
 const SUBVOLUME_ENTRY_SIZE: usize = 128;
@@ -13,6 +14,10 @@ const SUBVOLUME_ENTRY_SIZE: usize = 128;
 pub const SUBVOLUME_STATE_ALLOCATED: u8 = 1;
 pub const SUBVOLUME_STATE_REMOVED: u8 = 2;
 
+pub const SUBVOLUME_NAME_MAX: usize = 24;
+
+pub const SUBVOLUME_FLAG_RO: u8 = 1;
+
 fn new_bitmap<D>(fs: &mut Filesystem, device: &mut D, count: usize) -> IOResult<u64>
 where
     D: Write + Read + Seek,
@@ -87,6 +92,58 @@ where
     Ok(())
 }
 
+/** Check if a block is marked in a linked bitmap */
+fn bitmap_get_used<D>(device: &mut D, bitmap: u64, mut count: u64) -> IOResult<bool>
+where
+    D: Write + Read + Seek,
+{
+    let mut index = BitmapIndexBlock::load_block(device, bitmap)?;
+    loop {
+        if count < (index.bitmaps.len() * BLOCK_SIZE * 8) as u64 {
+            let bitmap =
+                BitmapBlock::load_block(device, index.bitmaps[count as usize / (8 * BLOCK_SIZE)])?;
+            return Ok(bitmap.get_used(count % (8 * BLOCK_SIZE as u64)));
+        } else if index.next != 0 {
+            count -= (index.bitmaps.len() * BLOCK_SIZE * 8) as u64;
+            index = BitmapIndexBlock::load_block(device, index.next)?;
+        } else {
+            return Ok(false);
+        }
+    }
+}
+
+/** Collect blocks marked in a linked bitmap */
+fn bitmap_used_blocks<D>(device: &mut D, bitmap: u64) -> IOResult<Vec<u64>>
+where
+    D: Write + Read + Seek,
+{
+    let mut blocks = Vec::new();
+    let mut index_block = BitmapIndexBlock::load_block(device, bitmap)?;
+    let mut start = 0;
+    loop {
+        for (bitmap_index, bitmap) in index_block.bitmaps.iter().enumerate() {
+            if *bitmap == 0 {
+                continue;
+            }
+            let bitmap = BitmapBlock::load_block(device, *bitmap)?;
+            let bitmap_start = start + (bitmap_index * BLOCK_SIZE * 8) as u64;
+            for count in 0..(BLOCK_SIZE * 8) as u64 {
+                if bitmap.get_used(count) {
+                    blocks.push(bitmap_start + count);
+                }
+            }
+        }
+        if index_block.next != 0 {
+            start += (index_block.bitmaps.len() * BLOCK_SIZE * 8) as u64;
+            index_block = BitmapIndexBlock::load_block(device, index_block.next)?;
+        } else {
+            break;
+        }
+    }
+
+    Ok(blocks)
+}
+
 const SUBVOL_TYPE_NORMAL: u8 = 1;
 const SUBVOL_TYPE_SNAP: u8 = 2;
 
@@ -108,6 +165,11 @@ const SUBVOL_TYPE_SNAP: u8 = 2;
  * |72   |80 |Snapshot count|
  * |80   |88 |Parent subvolume (for snapshot only)|
  * |88   |89 |Statement|
+ * |89   |90 |Type       |
+ * |90   |91 |Flags      |
+ * |92   |96 |Max inodes (0 for unlimited)|
+ * |96   |104|Quota (blocks, 0 for unlimited)|
+ * |104  |128|Name       |
  */
 pub struct SubvolumeEntry {
     pub id: u64,
@@ -123,9 +185,14 @@ pub struct SubvolumeEntry {
     pub parent_subvol: u64,
     pub state: u8,
     pub subvol_type: u8,
+    pub flags: u8,
+    pub max_inodes: u32,
+    pub quota: u64,
+    pub name: [u8; SUBVOLUME_NAME_MAX],
 }
 
 impl SubvolumeEntry {
+    /** Load an entry from its bytes, panics if `bytes` is shorter than an entry */
     pub fn load(bytes: &[u8]) -> Self {
         Self {
             id: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
@@ -141,6 +208,65 @@ impl SubvolumeEntry {
             parent_subvol: u64::from_be_bytes(bytes[80..88].try_into().unwrap()),
             state: bytes[88],
             subvol_type: bytes[89],
+            flags: bytes[90],
+            max_inodes: u32::from_be_bytes(bytes[92..96].try_into().unwrap()),
+            quota: u64::from_be_bytes(bytes[96..104].try_into().unwrap()),
+            name: bytes[104..128].try_into().unwrap(),
+        }
+    }
+    /** Load an entry, return an error if `bytes` is not the size of an entry */
+    pub fn try_load(bytes: &[u8]) -> IOResult<Self> {
+        if bytes.len() != SUBVOLUME_ENTRY_SIZE {
+            return Err(invalid_block(&format!(
+                "Subvolume entry of {} bytes, expected {SUBVOLUME_ENTRY_SIZE}.",
+                bytes.len()
+            )));
+        }
+        Ok(Self::load(bytes))
+    }
+    /** Get name of the subvolume, empty if not named */
+    pub fn get_name(&self) -> String {
+        let null_idx = self
+            .name
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(self.name.len());
+
+        String::from_utf8_lossy(&self.name[..null_idx]).to_string()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.name = [0; SUBVOLUME_NAME_MAX];
+        self.name[..name.len()].copy_from_slice(name.as_bytes());
+    }
+    /** Check if the subvolume is a snapshot */
+    pub fn is_snapshot(&self) -> bool {
+        self.subvol_type == SUBVOL_TYPE_SNAP
+    }
+    /** Get quota in blocks, `None` if unlimited */
+    pub fn get_quota(&self) -> Option<u64> {
+        if self.quota == 0 {
+            None
+        } else {
+            Some(self.quota)
+        }
+    }
+    /** Get max count of inodes, `None` if unlimited */
+    pub fn get_max_inodes(&self) -> Option<u32> {
+        if self.max_inodes == 0 {
+            None
+        } else {
+            Some(self.max_inodes)
+        }
+    }
+    /** Check if the subvolume is read-only */
+    pub fn is_readonly(&self) -> bool {
+        self.flags & SUBVOLUME_FLAG_RO != 0
+    }
+    pub fn set_readonly(&mut self, readonly: bool) {
+        if readonly {
+            self.flags |= SUBVOLUME_FLAG_RO;
+        } else {
+            self.flags &= !SUBVOLUME_FLAG_RO;
         }
     }
     pub fn dump(&self) -> [u8; SUBVOLUME_ENTRY_SIZE] {
@@ -159,11 +285,59 @@ impl SubvolumeEntry {
         bytes[80..88].copy_from_slice(&self.parent_subvol.to_be_bytes());
         bytes[88] = self.state;
         bytes[89] = self.subvol_type;
+        bytes[90] = self.flags;
+        bytes[92..96].copy_from_slice(&self.max_inodes.to_be_bytes());
+        bytes[96..104].copy_from_slice(&self.quota.to_be_bytes());
+        bytes[104..128].copy_from_slice(&self.name);
 
         bytes
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+/** Inconsistent sharing state of a block */
+pub enum SharingIssue {
+    /** The block is recorded by a subvolume but free in block groups */
+    Unallocated { block: u64, subvol: u64 },
+    /** The block is recorded more than once in bitmaps and shared bitmaps of subvolumes */
+    MultipleOwners { block: u64, subvols: Vec<u64> },
+}
+
+#[derive(Debug, Clone)]
+/** A subvolume with snapshots created from it */
+pub struct SubvolumeNode {
+    pub entry: SubvolumeEntry,
+    pub children: Vec<SubvolumeNode>,
+}
+
+impl SubvolumeNode {
+    /** Build trees of subvolumes from their snapshot relationships */
+    pub fn build_tree(entries: &[SubvolumeEntry]) -> Vec<Self> {
+        fn build(entries: &[SubvolumeEntry], entry: &SubvolumeEntry) -> SubvolumeNode {
+            SubvolumeNode {
+                entry: *entry,
+                children: entries
+                    .iter()
+                    .filter(|child| child.is_snapshot() && child.parent_subvol == entry.id)
+                    .map(|child| build(entries, child))
+                    .collect(),
+            }
+        }
+
+        entries
+            .iter()
+            .filter(|entry| {
+                !entry.is_snapshot() || !entries.iter().any(|e| e.id == entry.parent_subvol)
+            })
+            .map(|entry| build(entries, entry))
+            .collect()
+    }
+    /** Check if the subvolume has been removed but is still kept for its snapshots */
+    pub fn is_removed(&self) -> bool {
+        self.entry.state == SUBVOLUME_STATE_REMOVED
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 /**
  * # Data structure
@@ -186,15 +360,28 @@ impl Block for SubvolumeManager {
             ..Default::default()
         };
 
-        let entries_num = u64::from_be_bytes(bytes[8..16].try_into().unwrap()) as usize;
-        let entries_content = &bytes[SUBVOLUME_ENTRY_SIZE..];
-
-        for i in 0..entries_num {
-            let entry = SubvolumeEntry::load(&entries_content[SUBVOLUME_ENTRY_SIZE * i..]);
-            mgr.entries.push(entry);
-        }
+        /* a corrupted count must not read beyond the block */
+        let entries_num = std::cmp::min(
+            u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+            SUBVOLUMES as u64,
+        ) as usize;
+        mgr.entries = bytes[SUBVOLUME_ENTRY_SIZE..]
+            .chunks_exact(SUBVOLUME_ENTRY_SIZE)
+            .take(entries_num)
+            .map(SubvolumeEntry::load)
+            .collect();
         mgr
     }
+    fn try_load(bytes: [u8; BLOCK_SIZE]) -> IOResult<Self> {
+        let entries_num = u64::from_be_bytes(bytes[8..16].try_into().unwrap());
+        if entries_num > SUBVOLUMES as u64 {
+            Err(invalid_block(
+                "Count of subvolume entries exceeds manager capacity.",
+            ))
+        } else {
+            Ok(Self::load(bytes))
+        }
+    }
     fn dump(&self) -> [u8; BLOCK_SIZE] {
         let mut bytes = [0; BLOCK_SIZE];
 
@@ -245,6 +432,8 @@ impl SubvolumeManager {
                 return Ok(Subvolume {
                     entry: *entry,
                     igroup_mgt_btree,
+                    total_blocks: None,
+                    used_inodes: None,
                 });
             }
         }
@@ -253,6 +442,26 @@ impl SubvolumeManager {
             format!("No such subvolume '{id}'"),
         ))
     }
+    /** Get entry of a subvolume */
+    fn get_entry<D>(device: &mut D, mut mgr_block_count: u64, id: u64) -> IOResult<SubvolumeEntry>
+    where
+        D: Write + Read + Seek,
+    {
+        loop {
+            let mgr = Self::load_block(device, mgr_block_count)?;
+
+            if let Some(entry) = mgr.entries.iter().find(|entry| entry.id == id) {
+                return Ok(*entry);
+            } else if mgr.next != 0 {
+                mgr_block_count = mgr.next;
+            } else {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("No such subvolume '{id}'"),
+                ));
+            }
+        }
+    }
     /** Get a subvolume */
     pub fn get_subvolume<D>(
         device: &mut D,
@@ -277,6 +486,15 @@ impl SubvolumeManager {
             }
         }
     }
+    /** Get a subvolume, whose inode groups are checked to be within the filesystem */
+    pub fn open_subvolume<D>(fs: &Filesystem, device: &mut D, id: u64) -> IOResult<Subvolume>
+    where
+        D: Write + Read + Seek,
+    {
+        let mut subvol = Self::get_subvolume(device, fs.sb.subvol_mgr, id)?;
+        subvol.total_blocks = Some(fs.sb.total_blocks);
+        Ok(subvol)
+    }
     /** Set subvolume sntry */
     pub fn set_subvolume<D>(
         device: &mut D,
@@ -309,44 +527,101 @@ impl SubvolumeManager {
         }
     }
     /** Create a new subvolume */
-    pub fn new_subvolume<D>(fs: &mut Filesystem, device: &mut D) -> IOResult<u64>
+    pub fn new_subvolume<D>(
+        fs: &mut Filesystem,
+        device: &mut D,
+        name: Option<&str>,
+    ) -> IOResult<u64>
+    where
+        D: Write + Read + Seek,
+    {
+        if let Some(name) = name {
+            Self::check_name(device, fs.sb.subvol_mgr, None, name)?;
+        }
+
+        fs.rollback_on_error(|fs| Self::new_subvolume_internal(fs, device, name))
+    }
+    /** Append an entry to the last manager block, or a new one if it is full
+     *
+     * Return the last manager block before appending and whether a new block was linked to it.
+     */
+    fn append_entry<D>(
+        fs: &mut Filesystem,
+        device: &mut D,
+        entry: SubvolumeEntry,
+    ) -> IOResult<(u64, bool)>
     where
         D: Write + Read + Seek,
     {
         let mut mgr_block_count = fs.sb.subvol_mgr;
-        loop {
-            let mut mgr = Self::load_block(device, mgr_block_count)?;
-            if mgr.next == 0 {
-                if mgr.entries.len() < SUBVOLUMES {
-                    let entry = SubvolumeEntry {
-                        id: Self::generate_new_id(device, fs.sb.subvol_mgr)?,
-                        inode_tree_root: BtreeNode::allocate_on_block(fs, device)?,
-                        igroup_bitmap: IGroupBitmap::allocate_on_block(fs, device)?,
-                        bitmap: new_bitmap(fs, device, fs.groups.len())?,
-                        creation_date: get_sys_time(),
-                        state: SUBVOLUME_STATE_ALLOCATED,
-                        subvol_type: SUBVOL_TYPE_NORMAL,
-                        ..Default::default()
-                    };
-                    let subvol_id = entry.id;
-                    mgr.entries.push(entry);
-                    mgr.sync(device, mgr_block_count)?;
+        let mut mgr = Self::load_block(device, mgr_block_count)?;
+        while mgr.next != 0 {
+            mgr_block_count = mgr.next;
+            mgr = Self::load_block(device, mgr_block_count)?;
+        }
 
-                    let mut subvol = Self::get_subvolume(device, fs.sb.subvol_mgr, subvol_id)?;
-                    crate::dir::create(fs, &mut subvol, device)?;
-                    return Ok(subvol_id);
-                } else {
-                    let new_mgr_id = SubvolumeManager::allocate_on_block(fs, device)?;
-                    mgr.next = new_mgr_id;
-                    mgr.sync(device, mgr_block_count)?;
-                    mgr_block_count = new_mgr_id;
-                }
+        /* a new manager block is written before it is linked into the list */
+        let grow = mgr.entries.len() >= SUBVOLUMES;
+        if grow {
+            let new_mgr_block_count = fs.new_block()?;
+            Self::verify_allocation(fs, device, new_mgr_block_count)?;
+            Self {
+                next: 0,
+                entries: vec![entry],
+            }
+            .sync(device, new_mgr_block_count)?;
+            mgr.next = new_mgr_block_count;
+        } else {
+            mgr.entries.push(entry);
+        }
+        mgr.sync(device, mgr_block_count)?;
+
+        Ok((mgr_block_count, grow))
+    }
+    fn new_subvolume_internal<D>(
+        fs: &mut Filesystem,
+        device: &mut D,
+        name: Option<&str>,
+    ) -> IOResult<u64>
+    where
+        D: Write + Read + Seek,
+    {
+        let mut entry = SubvolumeEntry {
+            id: Self::generate_new_id(device, fs.sb.subvol_mgr)?,
+            inode_tree_root: BtreeNode::allocate_on_block(fs, device)?,
+            igroup_bitmap: IGroupBitmap::allocate_on_block(fs, device)?,
+            bitmap: new_bitmap(fs, device, fs.subvol_bitmap_size())?,
+            creation_date: get_sys_time(),
+            state: SUBVOLUME_STATE_ALLOCATED,
+            subvol_type: SUBVOL_TYPE_NORMAL,
+            ..Default::default()
+        };
+        entry.set_name(name.unwrap_or_default());
+        let subvol_id = entry.id;
+        let (mgr_block_count, grow) = Self::append_entry(fs, device, entry)?;
+
+        let mut subvol = Self::open_subvolume(fs, device, subvol_id)?;
+        if let Err(err) = crate::dir::create(fs, &mut subvol, device) {
+            /* unlink the subvolume */
+            let mut mgr = Self::load_block(device, mgr_block_count)?;
+            if grow {
+                mgr.next = 0;
             } else {
-                mgr_block_count = mgr.next;
+                mgr.entries.pop();
             }
+            mgr.sync(device, mgr_block_count)?;
+            return Err(err);
         }
+
+        fs.sb.subvols += 1;
+        Ok(subvol_id)
     }
-    /** Remove a subvolume */
+    /** Remove a subvolume
+     *
+     * A subvolume with snapshots is only marked removed and kept for them. `snaps` of the parent
+     * counts snapshots which are not dropped yet, so it is decreased when a snapshot is dropped
+     * rather than marked removed, and a removed parent is dropped with its last snapshot.
+     */
     pub fn remove_subvolume<D>(fs: &mut Filesystem, device: &mut D, id: u64) -> IOResult<()>
     where
         D: Write + Read + Seek,
@@ -357,50 +632,44 @@ impl SubvolumeManager {
 
             for (i, subvol) in mgr.entries.iter_mut().enumerate() {
                 if subvol.id == id {
-                    IGroupBitmap::destroy_blocks(fs, device, subvol.igroup_bitmap)?;
-
-                    if subvol.snaps == 0 && subvol.state == SUBVOLUME_STATE_REMOVED {
-                        subvol.bitmap = subvol.shared_bitmap;
+                    /* already dropped when the subvolume was marked removed */
+                    if subvol.state != SUBVOLUME_STATE_REMOVED {
+                        IGroupBitmap::destroy_blocks(fs, device, subvol.igroup_bitmap)?;
                     }
 
-                    let mut index_block = BitmapIndexBlock::load_block(device, subvol.bitmap)?;
+                    /* own blocks of a removed subvolume are already released, shared blocks are
+                     * released once no snapshot refers to them */
+                    let mut bitmaps = Vec::new();
+                    if subvol.state != SUBVOLUME_STATE_REMOVED {
+                        bitmaps.push(subvol.bitmap);
+                    }
+                    if subvol.snaps == 0 && subvol.shared_bitmap != 0 {
+                        bitmaps.push(subvol.shared_bitmap);
+                    }
 
-                    let mut bitmap_index = 0;
                     /* unmark blocks from global bitmap */
-                    for group in 0..fs.groups.len() {
-                        let bitmap = BitmapBlock::load_block(
-                            device,
-                            index_block.bitmaps[bitmap_index % index_block.bitmaps.len()],
-                        )?;
-                        for byte in 0..BLOCK_SIZE {
-                            fs.groups[group].block_map.bytes[byte] &= !bitmap.bytes[byte];
-                        }
-                        bitmap_index += 1;
-                        if bitmap_index % index_block.bitmaps.len() == 0 {
-                            index_block = BitmapIndexBlock::load_block(device, index_block.next)?;
+                    for bitmap in bitmaps {
+                        for block in bitmap_used_blocks(device, bitmap)? {
+                            fs.unmark_block(block);
                         }
                     }
 
                     if subvol.state != SUBVOLUME_STATE_REMOVED {
                         fs.sb.used_blocks -= subvol.used_blocks;
+                        fs.sb.subvols = fs.sb.subvols.checked_sub(1).ok_or_else(|| {
+                            Error::new(ErrorKind::InvalidData, "Subvolume count underflow.")
+                        })?;
                     }
 
-                    if subvol.subvol_type == SUBVOL_TYPE_SNAP {
-                        let mut parent =
-                            Self::get_subvolume(device, fs.sb.subvol_mgr, subvol.parent_subvol)?;
-                        parent.entry.snaps -= 1;
-                        Self::set_subvolume(
-                            device,
-                            fs.sb.subvol_mgr,
-                            subvol.parent_subvol,
-                            parent.entry,
-                        )?;
-                        if parent.entry.snaps == 0 && parent.entry.state == SUBVOLUME_STATE_REMOVED
-                        {
-                            SubvolumeManager::remove_subvolume(fs, device, parent.entry.id)?;
-                        }
-                    }
+                    /* snapshot still refers to blocks of its parent until it is dropped */
+                    let parent_id = if subvol.subvol_type == SUBVOL_TYPE_SNAP && subvol.snaps == 0 {
+                        Some(subvol.parent_subvol)
+                    } else {
+                        None
+                    };
                     if subvol.snaps > 0 {
+                        /* blocks of its own are released, only shared ones are kept */
+                        clean_bitmap(device, subvol.bitmap)?;
                         subvol.state = SUBVOLUME_STATE_REMOVED;
                     } else {
                         if subvol.state != SUBVOLUME_STATE_REMOVED {
@@ -410,6 +679,17 @@ impl SubvolumeManager {
                     }
 
                     mgr.sync(device, mgr_block_count)?;
+
+                    /* parent may be stored in the same manager block */
+                    if let Some(parent_id) = parent_id {
+                        /* inode tree of a removed parent may be released, only use the entry */
+                        let mut parent = Self::get_entry(device, fs.sb.subvol_mgr, parent_id)?;
+                        parent.snaps -= 1;
+                        Self::set_subvolume(device, fs.sb.subvol_mgr, parent_id, parent)?;
+                        if parent.snaps == 0 && parent.state == SUBVOLUME_STATE_REMOVED {
+                            SubvolumeManager::remove_subvolume(fs, device, parent_id)?;
+                        }
+                    }
                     return Ok(());
                 }
             }
@@ -424,27 +704,53 @@ impl SubvolumeManager {
             }
         }
     }
-    /** Create a snapshot */
+    /** Create a read-only snapshot */
     pub fn create_snapshot<D>(fs: &mut Filesystem, device: &mut D, id: u64) -> IOResult<u64>
     where
         D: Read + Write + Seek,
     {
-        let snap_id = Self::new_subvolume(fs, device)?;
-        let mut snap = Self::get_subvolume(device, fs.sb.subvol_mgr, id)?;
-        let mut origin_subvol = Self::get_subvolume(device, fs.sb.subvol_mgr, id)?;
-
-        snap.entry.id = snap_id;
-        snap.entry.shared_bitmap = new_bitmap(fs, device, fs.groups.len())?;
-        snap.entry.creation_date = get_sys_time();
-        snap.entry.parent_subvol = id;
-        snap.entry.subvol_type = SUBVOL_TYPE_SNAP;
-        snap.entry.used_blocks = origin_subvol.entry.used_blocks;
-        Self::set_subvolume(device, fs.sb.subvol_mgr, snap_id, snap.entry)?;
+        fs.rollback_on_error(|fs| Self::snapshot_internal(fs, device, id, SUBVOLUME_FLAG_RO))
+    }
+    /** Create a writable clone, which shares blocks with its origin like a snapshot */
+    pub fn clone_subvolume<D>(fs: &mut Filesystem, device: &mut D, id: u64) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        fs.rollback_on_error(|fs| Self::snapshot_internal(fs, device, id, 0))
+    }
+    fn snapshot_internal<D>(
+        fs: &mut Filesystem,
+        device: &mut D,
+        id: u64,
+        flags: u8,
+    ) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut origin_subvol = Self::open_subvolume(fs, device, id)?;
+
+        /* the snapshot shares the inode tree of its origin, only bitmaps are its own */
+        let snap = SubvolumeEntry {
+            id: Self::generate_new_id(device, fs.sb.subvol_mgr)?,
+            bitmap: new_bitmap(fs, device, fs.subvol_bitmap_size())?,
+            shared_bitmap: new_bitmap(fs, device, fs.subvol_bitmap_size())?,
+            real_used_blocks: 0,
+            creation_date: get_sys_time(),
+            snaps: 0,
+            parent_subvol: id,
+            subvol_type: SUBVOL_TYPE_SNAP,
+            flags,
+            name: [0; SUBVOLUME_NAME_MAX],
+            ..origin_subvol.entry
+        };
+        let snap_id = snap.id;
+        Self::append_entry(fs, device, snap)?;
+        fs.sb.subvols += 1;
 
         origin_subvol.entry.snaps += 1;
         /* allocate shared bitmap if empty */
         if origin_subvol.entry.shared_bitmap == 0 {
-            origin_subvol.entry.shared_bitmap = new_bitmap(fs, device, fs.groups.len())?;
+            origin_subvol.entry.shared_bitmap = new_bitmap(fs, device, fs.subvol_bitmap_size())?;
         }
         merge_to_shared_bitmap(
             device,
@@ -460,23 +766,273 @@ impl SubvolumeManager {
         fs.sb.used_blocks += origin_subvol.entry.used_blocks;
         Ok(snap_id)
     }
-    /** List submolumes */
-    pub fn list_subvols<D>(
+    /** Check if a name is valid and not used by other subvolumes */
+    fn check_name<D>(
         device: &mut D,
-        mut mgr_block_count: u64,
-    ) -> IOResult<Vec<SubvolumeEntry>>
+        mgr_block_count: u64,
+        id: Option<u64>,
+        name: &str,
+    ) -> IOResult<()>
     where
         D: Read + Write + Seek,
     {
-        let mut ids = Vec::new();
-        loop {
-            let mgr = Self::load_block(device, mgr_block_count)?;
+        if name.is_empty() || name.len() > SUBVOLUME_NAME_MAX || name.contains('\0') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid subvolume name '{name}'"),
+            ));
+        }
+        if Self::list_subvols(device, mgr_block_count)?
+            .iter()
+            .any(|entry| Some(entry.id) != id && entry.get_name() == name)
+        {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Subvolume '{name}' already exists"),
+            ));
+        }
+
+        Ok(())
+    }
+    /** Set name of a subvolume */
+    pub fn set_subvolume_name<D>(
+        device: &mut D,
+        mgr_block_count: u64,
+        id: u64,
+        name: &str,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        Self::check_name(device, mgr_block_count, Some(id), name)?;
+
+        let mut subvol = Self::get_subvolume(device, mgr_block_count, id)?;
+        subvol.entry.set_name(name);
+        Self::set_subvolume(device, mgr_block_count, id, subvol.entry)
+    }
+    /** Set or clear read-only flag of a subvolume */
+    pub fn set_subvolume_readonly<D>(
+        device: &mut D,
+        mgr_block_count: u64,
+        id: u64,
+        readonly: bool,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut subvol = Self::get_subvolume(device, mgr_block_count, id)?;
+        subvol.entry.set_readonly(readonly);
+        Self::set_subvolume(device, mgr_block_count, id, subvol.entry)
+    }
+    /** Set quota of a subvolume in blocks, `None` for unlimited */
+    pub fn set_quota<D>(
+        device: &mut D,
+        mgr_block_count: u64,
+        id: u64,
+        quota: Option<u64>,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut subvol = Self::get_subvolume(device, mgr_block_count, id)?;
+        subvol.entry.quota = quota.unwrap_or(0);
+        Self::set_subvolume(device, mgr_block_count, id, subvol.entry)
+    }
+    /** Set max count of inodes of a subvolume, `None` for unlimited */
+    pub fn set_max_inodes<D>(
+        device: &mut D,
+        mgr_block_count: u64,
+        id: u64,
+        max_inodes: Option<u32>,
+    ) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut subvol = Self::get_subvolume(device, mgr_block_count, id)?;
+        subvol.entry.max_inodes = max_inodes.unwrap_or(0);
+        Self::set_subvolume(device, mgr_block_count, id, subvol.entry)
+    }
+    /** Get a subvolume by name */
+    pub fn get_subvolume_by_name<D>(
+        device: &mut D,
+        mgr_block_count: u64,
+        name: &str,
+    ) -> IOResult<Subvolume>
+    where
+        D: Read + Write + Seek,
+    {
+        match Self::list_subvols(device, mgr_block_count)?
+            .iter()
+            .find(|entry| entry.get_name() == name)
+        {
+            Some(entry) => Self::get_subvolume(device, mgr_block_count, entry.id),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No such subvolume '{name}'"),
+            )),
+        }
+    }
+    /** Cross-reference bitmaps and shared bitmaps of all subvolumes
+     *
+     * Every block should be recorded by only one bitmap and allocated in block groups. If
+     * `repair` is set, unallocated blocks are marked allocated again.
+     */
+    pub fn verify_sharing<D>(
+        fs: &mut Filesystem,
+        device: &mut D,
+        repair: bool,
+    ) -> IOResult<Vec<SharingIssue>>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut owners: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        for entry in Self::list_all(device, fs.sb.subvol_mgr)? {
+            for bitmap in [entry.bitmap, entry.shared_bitmap] {
+                if bitmap == 0 {
+                    continue;
+                }
+                for block in bitmap_used_blocks(device, bitmap)? {
+                    owners.entry(block).or_default().push(entry.id);
+                }
+            }
+        }
 
-            for this_entry in &mgr.entries {
-                if this_entry.state != SUBVOLUME_STATE_REMOVED {
-                    ids.push(*this_entry);
+        let mut issues = Vec::new();
+        for (block, subvols) in owners {
+            if !fs.is_block_allocated(block) {
+                let mut unallocated = subvols.clone();
+                unallocated.dedup();
+                for subvol in unallocated {
+                    issues.push(SharingIssue::Unallocated { block, subvol });
+                }
+                if repair {
+                    fs.mark_block_allocated(block);
                 }
             }
+            if subvols.len() > 1 {
+                issues.push(SharingIssue::MultipleOwners { block, subvols });
+            }
+        }
+
+        Ok(issues)
+    }
+    /** Count blocks recorded by shared bitmaps of all subvolumes */
+    pub fn shared_blocks<D>(fs: &Filesystem, device: &mut D) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        if fs.sb.subvol_mgr == 0 {
+            return Ok(0);
+        }
+
+        let mut shared = BTreeSet::new();
+        for entry in Self::list_all(device, fs.sb.subvol_mgr)? {
+            if entry.shared_bitmap != 0 {
+                shared.extend(bitmap_used_blocks(device, entry.shared_bitmap)?);
+            }
+        }
+        Ok(shared.len() as u64)
+    }
+    /** Count blocks which removing a subvolume releases
+     *
+     * Its own blocks are always released, while blocks of its shared bitmap are only released
+     * if no snapshot refers to them.
+     */
+    pub(crate) fn exclusive_blocks<D>(device: &mut D, entry: &SubvolumeEntry) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut blocks: BTreeSet<u64> = bitmap_used_blocks(device, entry.bitmap)?
+            .into_iter()
+            .collect();
+        if entry.snaps == 0 && entry.shared_bitmap != 0 {
+            blocks.extend(bitmap_used_blocks(device, entry.shared_bitmap)?);
+        }
+        Ok(blocks.len() as u64)
+    }
+    /** Check if used blocks of the superblock match those of subvolumes
+     *
+     * Allocated blocks recorded by no subvolume bitmap are meta data of the filesystem, the
+     * rest of used blocks is the sum of used blocks of subvolumes which are not removed.
+     */
+    pub fn check_used_blocks<D>(fs: &Filesystem, device: &mut D) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut owned = BTreeSet::new();
+        let mut subvol_used_blocks = 0;
+        for entry in Self::list_all(device, fs.sb.subvol_mgr)? {
+            for bitmap in [entry.bitmap, entry.shared_bitmap] {
+                if bitmap != 0 {
+                    owned.extend(bitmap_used_blocks(device, bitmap)?);
+                }
+            }
+            if entry.state != SUBVOLUME_STATE_REMOVED {
+                subvol_used_blocks += entry.used_blocks;
+            }
+        }
+        let meta_data_blocks = fs
+            .groups
+            .iter()
+            .flat_map(|group| group.used_blocks())
+            .filter(|block| fs.is_data_block(*block) && !owned.contains(block))
+            .count() as u64;
+
+        if fs.sb.used_blocks != subvol_used_blocks + meta_data_blocks {
+            return Err(invalid_block(&format!(
+                "{} blocks are used, while subvolumes use {subvol_used_blocks} and meta data uses {meta_data_blocks}.",
+                fs.sb.used_blocks
+            )));
+        }
+        Ok(())
+    }
+    /** List submolumes */
+    pub fn list_subvols<D>(device: &mut D, mgr_block_count: u64) -> IOResult<Vec<SubvolumeEntry>>
+    where
+        D: Read + Write + Seek,
+    {
+        Ok(Self::list_all(device, mgr_block_count)?
+            .into_iter()
+            .filter(|entry| entry.state != SUBVOLUME_STATE_REMOVED)
+            .collect())
+    }
+    /** Make sure a newly allocated block is not marked by any subvolume
+     *
+     * Only checked if enabled by `Filesystem::set_verify_allocation`.
+     */
+    pub(crate) fn verify_allocation<D>(fs: &Filesystem, device: &mut D, block: u64) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        if !fs.verify_allocation || fs.sb.subvol_mgr == 0 {
+            return Ok(());
+        }
+
+        for entry in Self::list_all(device, fs.sb.subvol_mgr)? {
+            for bitmap in [entry.bitmap, entry.shared_bitmap] {
+                if bitmap != 0 && bitmap_get_used(device, bitmap, block)? {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "Block {block} is allocated while it is used by subvolume {}.",
+                            entry.id
+                        ),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+    /** List all subvolumes, including removed ones which are still kept for their snapshots */
+    pub fn list_all<D>(device: &mut D, mut mgr_block_count: u64) -> IOResult<Vec<SubvolumeEntry>>
+    where
+        D: Read + Write + Seek,
+    {
+        let mut entries = Vec::new();
+        loop {
+            let mgr = Self::load_block(device, mgr_block_count)?;
+            entries.extend_from_slice(&mgr.entries);
 
             if mgr.next != 0 {
                 mgr_block_count = mgr.next;
@@ -485,7 +1041,7 @@ impl SubvolumeManager {
             }
         }
 
-        Ok(ids)
+        Ok(entries)
     }
 }
 
@@ -551,10 +1107,7 @@ impl IGroupBitmap {
                 byte -= allocator.bitmap_data.len();
                 allocator_count = allocator.next;
             } else {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "Unexpected end of linked list.",
-                ));
+                return Err(invalid_block("Unexpected end of linked list."));
             }
         }
     }
@@ -581,12 +1134,16 @@ impl IGroupBitmap {
                 allocator.sync(device, allocator_count)?;
                 allocator_count = subvol.new_block(fs, device)?;
                 allocator.rc = 0;
+                subvol.drop_shared_block(fs);
 
                 if let Some(last_allocator_count) = last_allocator_count {
                     let mut last_allocator =
                         IGroupBitmap::load_block(device, last_allocator_count)?;
                     last_allocator.next = allocator_count;
                     last_allocator.sync(device, last_allocator_count)?;
+                } else {
+                    subvol.entry.igroup_bitmap = allocator_count;
+                    subvol.write_entry(fs, device)?;
                 }
             }
 
@@ -600,10 +1157,7 @@ impl IGroupBitmap {
                 last_allocator_count = Some(allocator_count);
                 allocator_count = allocator.next;
             } else {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "Unexpected end of linked list.",
-                ));
+                return Err(invalid_block("Unexpected end of linked list."));
             }
         }
     }
@@ -630,12 +1184,16 @@ impl IGroupBitmap {
                 allocator.sync(device, allocator_count)?;
                 allocator_count = subvol.new_block(fs, device)?;
                 allocator.rc = 0;
+                subvol.drop_shared_block(fs);
 
                 if let Some(last_allocator_count) = last_allocator_count {
                     let mut last_allocator =
                         IGroupBitmap::load_block(device, last_allocator_count)?;
                     last_allocator.next = allocator_count;
                     last_allocator.sync(device, last_allocator_count)?;
+                } else {
+                    subvol.entry.igroup_bitmap = allocator_count;
+                    subvol.write_entry(fs, device)?;
                 }
             }
 
@@ -649,10 +1207,7 @@ impl IGroupBitmap {
                 last_allocator_count = Some(allocator_count);
                 allocator_count = allocator.next;
             } else {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "Unexpected end of linked list.",
-                ));
+                return Err(invalid_block("Unexpected end of linked list."));
             }
         }
     }
@@ -677,10 +1232,7 @@ impl IGroupBitmap {
             if allocator.next != 0 {
                 allocator_count = allocator.next;
             } else {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "Unexpected end of linked list.",
-                ));
+                return Err(invalid_block("Unexpected end of linked list."));
             }
         }
     }
@@ -718,7 +1270,7 @@ impl IGroupBitmap {
                 allocator.rc -= 1;
                 allocator.sync(device, allocator_count)?;
             } else {
-                fs.release_block(allocator_count);
+                fs.release_block(allocator_count)?;
             }
 
             if allocator.next == 0 {
@@ -734,15 +1286,95 @@ impl IGroupBitmap {
 pub struct Subvolume {
     pub entry: SubvolumeEntry,
     pub igroup_mgt_btree: BtreeNode,
+    /** Blocks of the filesystem, if known when the subvolume was opened */
+    total_blocks: Option<u64>,
+    /** Count of allocated inodes, counted when an inode is first allocated under a limit
+     *
+     * Inodes allocated through other handles of the subvolume are not seen, as blocks used by
+     * them are not.
+     */
+    used_inodes: Option<u64>,
 }
 
 impl Subvolume {
+    /** Return `FsError::ReadOnly` if the subvolume is read-only, or mark the filesystem dirty
+     * before it is changed */
+    pub(crate) fn check_writable<D>(&mut self, fs: &mut Filesystem, device: &mut D) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.refresh_entry(fs, device)?;
+        if self.entry.is_readonly() {
+            return Err(FsError::ReadOnly(self.entry.id).into());
+        }
+
+        fs.mark_dirty(device)
+    }
+    /** Modify the inode group B-Tree, whose nodes are allocated through the subvolume */
+    pub(crate) fn modify_igroup_btree<T, F>(&mut self, operation: F) -> IOResult<T>
+    where
+        F: FnOnce(&mut BtreeNode, &mut Self) -> IOResult<T>,
+    {
+        let mut btree = std::mem::take(&mut self.igroup_mgt_btree);
+        let result = operation(&mut btree, self);
+        self.entry.inode_tree_root = btree.block_count;
+        self.igroup_mgt_btree = btree;
+        result
+    }
+    /** Count blocks which become referenced once more by a copy in the same subvolume */
+    pub(crate) fn add_shared_blocks(&mut self, fs: &mut Filesystem, count: u64) {
+        self.entry.used_blocks += count;
+        fs.sb.used_blocks += count;
+    }
+    /** Stop referring to a block shared with other subvolumes, which is kept for them */
+    pub(crate) fn drop_shared_block(&mut self, fs: &mut Filesystem) {
+        self.entry.used_blocks = self.entry.used_blocks.saturating_sub(1);
+        fs.sb.used_blocks = fs.sb.used_blocks.saturating_sub(1);
+    }
+    /** Reload fields of the entry which are changed through `SubvolumeManager`
+     *
+     * Flags, quota, inode limit, name and snapshot state may be changed after the subvolume was
+     * opened, while the inode tree and block counters are only changed through the subvolume
+     * itself.
+     */
+    fn refresh_entry<D>(&mut self, fs: &Filesystem, device: &mut D) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.entry = SubvolumeEntry {
+            inode_tree_root: self.entry.inode_tree_root,
+            used_blocks: self.entry.used_blocks,
+            real_used_blocks: self.entry.real_used_blocks,
+            ..SubvolumeManager::get_entry(device, fs.sb.subvol_mgr, self.entry.id)?
+        };
+
+        Ok(())
+    }
+    /** Write the entry back, keeping fields changed through `SubvolumeManager` */
+    pub(crate) fn write_entry<D>(&mut self, fs: &Filesystem, device: &mut D) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        self.refresh_entry(fs, device)?;
+        SubvolumeManager::set_subvolume(device, fs.sb.subvol_mgr, self.entry.id, self.entry)
+    }
+    /** Allocate an inode, return `FsError::NoInodes` if the max count of inodes is reached */
     pub fn new_inode<D>(&mut self, fs: &mut Filesystem, device: &mut D) -> IOResult<u64>
     where
         D: Write + Read + Seek,
     {
+        if let Some(max_inodes) = self.entry.get_max_inodes() {
+            let used_inodes = match self.used_inodes {
+                Some(used_inodes) => used_inodes,
+                None => self.inode_stats(device)?.0,
+            };
+            self.used_inodes = Some(used_inodes);
+            if used_inodes >= max_inodes as u64 {
+                return Err(FsError::NoInodes(self.entry.id).into());
+            }
+        }
         if let Ok(inode_group) = IGroupBitmap::find_available(device, self.entry.igroup_bitmap) {
-            let inode_block_count = self.igroup_mgt_btree.lookup(device, inode_group)?.value;
+            let inode_block_count = self.igroup_entry(device, inode_group)?.value;
             let group = INodeGroup::load_block(device, inode_block_count)?;
 
             let mut inode_count = 0;
@@ -755,18 +1387,12 @@ impl Subvolume {
 
             Ok(inode_count)
         } else {
-            let inode_group_block = INodeGroup::allocate_on_block(fs, device)?;
+            let inode_group_block = INodeGroup::allocate_on_block_subvol(fs, self, device)?;
             let inode_group_count = self.igroup_mgt_btree.find_unused(device)?;
-            self.igroup_mgt_btree.insert(
-                fs,
-                &mut self.clone(),
-                device,
-                inode_group_count,
-                inode_group_block,
-            )?;
-            self.entry.inode_tree_root = self.igroup_mgt_btree.block_count;
-
-            SubvolumeManager::set_subvolume(device, fs.sb.subvol_mgr, self.entry.id, self.entry)?;
+            self.modify_igroup_btree(|btree, subvol| {
+                btree.insert(fs, subvol, device, inode_group_count, inode_group_block)
+            })?;
+            self.write_entry(fs, device)?;
 
             IGroupBitmap::set_available(fs, self, device, inode_group_count)?;
 
@@ -779,14 +1405,91 @@ impl Subvolume {
     {
         let inode_group_count = inode / INODE_PER_GROUP as u64;
         let inode_num = inode as usize % INODE_PER_GROUP;
-        let inode_group_block = self
-            .igroup_mgt_btree
-            .lookup(device, inode_group_count)?
-            .value;
 
-        let inode_group = INodeGroup::load_block(device, inode_group_block)?;
+        let inode_group = self.get_inode_group(device, inode_group_count)?;
         Ok(inode_group.inodes[inode_num])
     }
+    /** Load an inode group by its group count */
+    pub(crate) fn get_inode_group<D>(
+        &self,
+        device: &mut D,
+        inode_group_count: u64,
+    ) -> IOResult<INodeGroup>
+    where
+        D: Read + Write + Seek,
+    {
+        let inode_group_block = self.igroup_entry(device, inode_group_count)?.value;
+
+        INodeGroup::load_block(device, inode_group_block)
+    }
+    /** Look up the block of an inode group
+     *
+     * Blocks out of the filesystem, including the superblock, are rejected, so a corrupted inode
+     * tree can not make the superblock read or written as an inode group.
+     */
+    pub(crate) fn igroup_entry<D>(
+        &self,
+        device: &mut D,
+        inode_group_count: u64,
+    ) -> IOResult<BtreeEntry>
+    where
+        D: Read + Write + Seek,
+    {
+        let entry = self.igroup_mgt_btree.lookup(device, inode_group_count)?;
+        self.check_igroup_entry(entry)
+    }
+    fn check_igroup_entry(&self, entry: BtreeEntry) -> IOResult<BtreeEntry> {
+        if entry.value == 0 || self.total_blocks.is_some_and(|total| entry.value >= total) {
+            return Err(invalid_block(&format!(
+                "Inode group {} is stored at invalid block {}.",
+                entry.key, entry.value
+            )));
+        }
+        Ok(entry)
+    }
+    /** Get count of allocated inodes and count of inodes in allocated inode groups
+     *
+     * Full inode groups are known from the inode group bitmap and are not loaded.
+     */
+    pub fn inode_stats<D>(&self, device: &mut D) -> IOResult<(u64, u64)>
+    where
+        D: Read + Write + Seek,
+    {
+        let groups = self.igroup_mgt_btree.range(device, 0, u64::MAX)?;
+        let mut used = 0;
+        for entry in &groups {
+            used += if IGroupBitmap::get_available(device, self.entry.igroup_bitmap, entry.key)? {
+                let entry = self.check_igroup_entry(*entry)?;
+                INodeGroup::load_block(device, entry.value)?
+                    .inodes
+                    .iter()
+                    .filter(|inode| !inode.is_empty_inode())
+                    .count() as u64
+            } else {
+                INODE_PER_GROUP as u64
+            };
+        }
+        Ok((used, groups.len() as u64 * INODE_PER_GROUP as u64))
+    }
+    /** Iterate allocated inodes with their counts, in order of counts
+     *
+     * Inodes are found through inode groups rather than directories, so inodes not linked into
+     * any directory are included.
+     */
+    pub fn iter_inodes<'a, D>(
+        &'a self,
+        device: &'a mut D,
+    ) -> impl Iterator<Item = IOResult<(u64, INode)>> + 'a
+    where
+        D: Read + Write + Seek,
+    {
+        INodes {
+            subvol: self,
+            device,
+            groups: None,
+            inodes: std::collections::VecDeque::new(),
+        }
+    }
     pub fn set_inode<D>(
         &mut self,
         fs: &mut Filesystem,
@@ -800,10 +1503,11 @@ impl Subvolume {
         let igroup_count = inode_count / INODE_PER_GROUP as u64;
         let igroup_offset = inode_count as usize % INODE_PER_GROUP;
 
-        let btree_query_result = self.igroup_mgt_btree.lookup(device, igroup_count)?;
+        let btree_query_result = self.igroup_entry(device, igroup_count)?;
         let inode_group_block = btree_query_result.value;
 
         let mut inode_group = INodeGroup::load_block(device, inode_group_block)?;
+        let was_empty = inode_group.inodes[igroup_offset].is_empty_inode();
         inode_group.inodes[igroup_offset] = inode;
 
         if inode_group.is_full() {
@@ -811,30 +1515,34 @@ impl Subvolume {
         }
 
         if btree_query_result.rc > 0 {
-            let new_inode_group_block = self.new_block(fs, device)?;
-            self.igroup_mgt_btree.modify(
-                fs,
-                &mut self.clone(),
-                device,
-                igroup_count,
-                new_inode_group_block,
-            )?;
-            self.entry.inode_tree_root = self.igroup_mgt_btree.block_count;
-            SubvolumeManager::set_subvolume(device, fs.sb.subvol_mgr, self.entry.id, self.entry)?;
-
-            inode_group.sync(device, new_inode_group_block)?;
-            for (i, inode) in inode_group.inodes.iter().enumerate() {
+            for inode in inode_group.inodes.iter_mut() {
                 if !inode.is_empty_inode() {
-                    crate::file::clone_by_inode(
-                        self,
-                        device,
-                        igroup_count * INODE_PER_GROUP as u64 + i as u64,
-                    )?;
+                    crate::file::clone_by_inode(device, inode)?;
                 }
             }
+
+            let new_inode_group_block = self.new_block(fs, device)?;
+            self.modify_igroup_btree(|btree, subvol| {
+                btree.modify(fs, subvol, device, igroup_count, new_inode_group_block)
+            })?;
+            self.drop_shared_block(fs);
+            self.write_entry(fs, device)?;
+
+            inode_group.sync(device, new_inode_group_block)?;
         } else {
             inode_group.sync(device, inode_group_block)?;
         }
+
+        if let Some(used_inodes) = &mut self.used_inodes {
+            match (
+                was_empty,
+                inode_group.inodes[igroup_offset].is_empty_inode(),
+            ) {
+                (true, false) => *used_inodes += 1,
+                (false, true) => *used_inodes = used_inodes.saturating_sub(1),
+                _ => {}
+            }
+        }
         Ok(())
     }
     /** Release an inode */
@@ -848,28 +1556,114 @@ impl Subvolume {
         D: Read + Write + Seek,
     {
         let inode_group_count = inode / INODE_PER_GROUP as u64;
-        let btree_query_result = self.igroup_mgt_btree.lookup(device, inode_group_count)?;
-        let inode_group_block = btree_query_result.value;
         self.set_inode(fs, device, inode, INode::empty())?;
 
+        /* the inode group may have been copied out by set_inode */
+        let inode_group_block = self.igroup_entry(device, inode_group_count)?.value;
         let inode_group = INodeGroup::load_block(device, inode_group_block)?;
 
         /* release inode group */
         if inode_group.is_empty() {
-            IGroupBitmap::set_unavailable(fs, self, device, inode_group_block)?;
-            self.igroup_mgt_btree
-                .remove(fs, &mut self.clone(), device, inode_group_count)?;
-            fs.release_block(inode_group_block);
+            IGroupBitmap::set_unavailable(fs, self, device, inode_group_count)?;
+            self.modify_igroup_btree(|btree, subvol| {
+                btree.remove(fs, subvol, device, inode_group_count)
+            })?;
+            self.release_block(fs, device, inode_group_block)?;
+            self.write_entry(fs, device)?;
             fs.sync_meta_data(device)?;
+        } else {
+            IGroupBitmap::set_available(fs, self, device, inode_group_count)?;
         }
         Ok(())
     }
-    /** Allocate a data block */
+    /** Allocate a block, which may take blocks reserved for metadata */
     pub fn new_block<D>(&mut self, fs: &mut Filesystem, device: &mut D) -> IOResult<u64>
     where
         D: Read + Write + Seek,
     {
-        let count_orig = fs.new_block()?;
+        self.allocate_block(fs, device, false)
+    }
+    /** Allocate a block for file data, blocks reserved for metadata are not used */
+    pub(crate) fn new_data_block<D>(&mut self, fs: &mut Filesystem, device: &mut D) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        self.allocate_block(fs, device, true)
+    }
+    fn allocate_block<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        device: &mut D,
+        data: bool,
+    ) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        if self.entry.quota != 0 && self.entry.used_blocks >= self.entry.quota {
+            return Err(FsError::QuotaExceeded(self.entry.id).into());
+        }
+
+        let count = if data {
+            fs.new_data_block()?
+        } else {
+            fs.new_block()?
+        };
+        self.record_block(fs, device, count)?;
+        Ok(count)
+    }
+    /** Allocate `count` contiguous blocks for file data and return the first one */
+    pub(crate) fn new_data_run<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        device: &mut D,
+        count: u64,
+    ) -> IOResult<u64>
+    where
+        D: Read + Write + Seek,
+    {
+        if self.entry.quota != 0 && self.entry.used_blocks + count > self.entry.quota {
+            return Err(FsError::QuotaExceeded(self.entry.id).into());
+        }
+
+        let first = fs.new_data_run(count)?;
+        for block in first..first + count {
+            self.record_block(fs, device, block)?;
+        }
+        Ok(first)
+    }
+    /** Allocate up to `count` contiguous blocks for file data
+     *
+     * Return the first block and count of blocks allocated, which is less than `count` if no
+     * run is as long or the quota is reached.
+     */
+    pub(crate) fn new_data_blocks<D>(
+        &mut self,
+        fs: &mut Filesystem,
+        device: &mut D,
+        mut count: u64,
+    ) -> IOResult<(u64, u64)>
+    where
+        D: Read + Write + Seek,
+    {
+        if self.entry.quota != 0 {
+            if self.entry.used_blocks >= self.entry.quota {
+                return Err(FsError::QuotaExceeded(self.entry.id).into());
+            }
+            count = std::cmp::min(count, self.entry.quota - self.entry.used_blocks);
+        }
+
+        let (first, len) = fs.new_data_blocks(count)?;
+        for block in first..first + len {
+            self.record_block(fs, device, block)?;
+        }
+        Ok((first, len))
+    }
+    /** Count an allocated block as used by the subvolume and mark it in the bitmap */
+    fn record_block<D>(&mut self, fs: &Filesystem, device: &mut D, count_orig: u64) -> IOResult<()>
+    where
+        D: Read + Write + Seek,
+    {
+        SubvolumeManager::verify_allocation(fs, device, count_orig)?;
         self.entry.used_blocks += 1;
         self.entry.real_used_blocks += 1;
         let mut count = count_orig;
@@ -888,20 +1682,35 @@ impl Subvolume {
                 count -= (index.bitmaps.len() * BLOCK_SIZE * 8) as u64;
                 index = BitmapIndexBlock::load_block(device, index.next)?;
             } else {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "Unexpected end of linked list.",
-                ));
+                return Err(invalid_block("Unexpected end of linked list."));
             }
         }
 
-        Ok(count_orig)
+        Ok(())
+    }
+    /** Check if a block is recorded by the bitmap of the subvolume instead of being shared */
+    pub(crate) fn owns_block<D>(&self, device: &mut D, count: u64) -> IOResult<bool>
+    where
+        D: Read + Write + Seek,
+    {
+        bitmap_get_used(device, self.entry.bitmap, count)
     }
     /** Release a data block from shared_bitmap */
-    fn release_shared_block<D>(&mut self, device: &mut D, mut count: u64) -> IOResult<()>
+    fn release_shared_block<D>(
+        &mut self,
+        fs: &Filesystem,
+        device: &mut D,
+        block: u64,
+    ) -> IOResult<()>
     where
         D: Read + Write + Seek,
     {
+        /* blocks allocated outside of subvolume bitmaps by older versions */
+        if self.entry.shared_bitmap == 0 {
+            return Ok(());
+        }
+
+        let mut count = block;
         let mut index = BitmapIndexBlock::load_block(device, self.entry.shared_bitmap)?;
         loop {
             if count < (index.bitmaps.len() * BLOCK_SIZE * 8) as u64 {
@@ -913,9 +1722,18 @@ impl Subvolume {
                     bitmap.set_unused(count % (8 * BLOCK_SIZE as u64));
                     bitmap.sync(device, index.bitmaps[count as usize / (8 * BLOCK_SIZE)])?;
                     self.entry.real_used_blocks -= 1;
-                } else {
-                    SubvolumeManager::get_subvolume(device, 0, self.entry.parent_subvol)?
-                        .release_shared_block(device, count)?;
+                } else if self.entry.subvol_type == SUBVOL_TYPE_SNAP {
+                    /* inode tree of a removed parent may be released, only use the entry */
+                    let entry = SubvolumeManager::get_entry(
+                        device,
+                        fs.sb.subvol_mgr,
+                        self.entry.parent_subvol,
+                    )?;
+                    Subvolume {
+                        entry,
+                        ..Default::default()
+                    }
+                    .release_shared_block(fs, device, block)?;
                 }
 
                 break;
@@ -923,10 +1741,7 @@ impl Subvolume {
                 count -= (index.bitmaps.len() * BLOCK_SIZE * 8) as u64;
                 index = BitmapIndexBlock::load_block(device, index.next)?;
             } else {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "Unexpected end of linked list.",
-                ));
+                return Err(invalid_block("Unexpected end of linked list."));
             }
         }
 
@@ -937,11 +1752,12 @@ impl Subvolume {
         &mut self,
         fs: &mut Filesystem,
         device: &mut D,
-        mut count: u64,
+        block: u64,
     ) -> IOResult<()>
     where
         D: Read + Write + Seek,
     {
+        let mut count = block;
         let mut index = BitmapIndexBlock::load_block(device, self.entry.bitmap)?;
         loop {
             if count < (index.bitmaps.len() * BLOCK_SIZE * 8) as u64 {
@@ -955,7 +1771,7 @@ impl Subvolume {
 
                     self.entry.real_used_blocks -= 1;
                 } else {
-                    self.release_shared_block(device, count)?;
+                    self.release_shared_block(fs, device, block)?;
                 }
                 self.entry.used_blocks -= 1;
 
@@ -964,23 +1780,672 @@ impl Subvolume {
                 count -= (index.bitmaps.len() * BLOCK_SIZE * 8) as u64;
                 index = BitmapIndexBlock::load_block(device, index.next)?;
             } else {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "Unexpected end of linked list.",
-                ));
+                return Err(invalid_block("Unexpected end of linked list."));
             }
         }
 
-        fs.release_block(count);
-        Ok(())
+        fs.release_block(block)
     }
-    /** Synchronize subvolume entry to disk */
+    /** Synchronize subvolume entry to disk and flush the device */
     pub fn sync_meta_data<D>(&mut self, fs: &mut Filesystem, device: &mut D) -> IOResult<()>
     where
         D: Read + Write + Seek,
     {
-        SubvolumeManager::set_subvolume(device, fs.sb.subvol_mgr, self.entry.id, self.entry)?;
+        if fs.is_readonly() {
+            return Ok(());
+        }
+        self.write_entry(fs, device)?;
+        device.flush()
+    }
+}
 
-        Ok(())
+/** Iterator of `Subvolume::iter_inodes`, loading one inode group at a time */
+struct INodes<'a, D> {
+    subvol: &'a Subvolume,
+    device: &'a mut D,
+    /** Inode groups not loaded yet, listed on the first call */
+    groups: Option<std::vec::IntoIter<BtreeEntry>>,
+    /** Allocated inodes of the last loaded group */
+    inodes: std::collections::VecDeque<(u64, INode)>,
+}
+
+impl<D> INodes<'_, D>
+where
+    D: Read + Write + Seek,
+{
+    /** Load the next inode group, return false if there is none */
+    fn load_group(&mut self) -> IOResult<bool> {
+        let groups = match &mut self.groups {
+            Some(groups) => groups,
+            None => self.groups.insert(
+                self.subvol
+                    .igroup_mgt_btree
+                    .range(self.device, 0, u64::MAX)?
+                    .into_iter(),
+            ),
+        };
+        let Some(entry) = groups.next() else {
+            return Ok(false);
+        };
+
+        let entry = self.subvol.check_igroup_entry(entry)?;
+        let group = INodeGroup::load_block(self.device, entry.value)?;
+        let first = entry.key * INODE_PER_GROUP as u64;
+        self.inodes.extend(
+            group
+                .inodes
+                .iter()
+                .enumerate()
+                .filter(|(_, inode)| !inode.is_empty_inode())
+                .map(|(i, inode)| (first + i as u64, *inode)),
+        );
+        Ok(true)
+    }
+}
+
+impl<D> Iterator for INodes<'_, D>
+where
+    D: Read + Write + Seek,
+{
+    type Item = IOResult<(u64, INode)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(inode) = self.inodes.pop_front() {
+                return Some(Ok(inode));
+            }
+            match self.load_group() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(err) => {
+                    /* stop after an error rather than returning it again */
+                    self.groups = Some(Vec::new().into_iter());
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{load_block, save_block};
+    use crate::utils::test_fs;
+
+    #[test]
+    fn inode_stats() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        let per_group = INODE_PER_GROUP as u64;
+        /* the root directory */
+        assert_eq!(subvol.inode_stats(&mut device).unwrap(), (1, per_group));
+
+        for i in 1..per_group {
+            fs.create_file(&mut subvol, &mut device, format!("/f{i}"))
+                .unwrap();
+        }
+        /* the first group is full */
+        assert_eq!(
+            subvol.inode_stats(&mut device).unwrap(),
+            (per_group, per_group)
+        );
+
+        fs.create_file(&mut subvol, &mut device, "/next").unwrap();
+        assert_eq!(
+            subvol.inode_stats(&mut device).unwrap(),
+            (per_group + 1, 2 * per_group)
+        );
+
+        fs.remove_file(&mut subvol, &mut device, "/f1").unwrap();
+        fs.remove_file(&mut subvol, &mut device, "/f2").unwrap();
+        assert_eq!(
+            subvol.inode_stats(&mut device).unwrap(),
+            (per_group - 1, 2 * per_group)
+        );
+
+        /* the second group is released with its last inode */
+        fs.remove_file(&mut subvol, &mut device, "/next").unwrap();
+        assert_eq!(
+            subvol.inode_stats(&mut device).unwrap(),
+            (per_group - 2, per_group)
+        );
+    }
+
+    #[test]
+    fn iter_inodes() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        let mut expected = vec![subvol.entry.root_inode];
+        /* enough files for several inode groups */
+        for i in 0..2 * INODE_PER_GROUP {
+            let fd = fs
+                .create_file(&mut subvol, &mut device, format!("/f{i}"))
+                .unwrap();
+            expected.push(fd.get_inode_count());
+        }
+        fs.remove_file(&mut subvol, &mut device, "/f3").unwrap();
+        expected.remove(4);
+        /* an inode not linked into any directory */
+        expected.push(crate::file::create(&mut fs, &mut subvol, &mut device).unwrap());
+        expected.sort();
+
+        let inodes: Vec<(u64, INode)> = subvol
+            .iter_inodes(&mut device)
+            .collect::<IOResult<_>>()
+            .unwrap();
+        let counts: Vec<u64> = inodes.iter().map(|(count, _)| *count).collect();
+        assert_eq!(counts, expected);
+        for (count, inode) in inodes {
+            assert_eq!(inode, subvol.get_inode(&mut device, count).unwrap());
+        }
+
+        /* a corrupted inode group ends the iteration with an error */
+        subvol
+            .modify_igroup_btree(|btree, subvol| btree.modify(&mut fs, subvol, &mut device, 1, 0))
+            .unwrap();
+        let results: Vec<IOResult<(u64, INode)>> = subvol.iter_inodes(&mut device).collect();
+        assert_eq!(
+            results.last().unwrap().as_ref().unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+    }
+
+    #[test]
+    fn invalid_inode_group_block() {
+        let (mut fs, mut device, mut subvol) = test_fs(200);
+        let root = subvol.entry.root_inode;
+        /* only the first change after synchronization writes the superblock */
+        fs.mark_dirty(&mut device).unwrap();
+        let superblock = load_block(&mut device, 0).unwrap();
+
+        for block in [0, fs.sb.total_blocks, u64::MAX] {
+            subvol
+                .modify_igroup_btree(|btree, subvol| {
+                    btree.modify(&mut fs, subvol, &mut device, 0, block)
+                })
+                .unwrap();
+            assert_eq!(
+                subvol.get_inode(&mut device, root).unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+            assert_eq!(
+                subvol
+                    .set_inode(&mut fs, &mut device, root, INode::default())
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::InvalidData
+            );
+            assert_eq!(
+                fs.create_file(&mut subvol, &mut device, "/f")
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::InvalidData
+            );
+        }
+        assert_eq!(load_block(&mut device, 0).unwrap(), superblock);
+    }
+
+    #[test]
+    fn entry_size_checked() {
+        let (fs, mut device, subvol) = test_fs(200);
+        let bytes = subvol.entry.dump();
+        assert_eq!(
+            SubvolumeEntry::try_load(&bytes).unwrap().dump(),
+            subvol.entry.dump()
+        );
+        for len in [0, SUBVOLUME_ENTRY_SIZE - 1, SUBVOLUME_ENTRY_SIZE + 1] {
+            let mut bytes = bytes.to_vec();
+            bytes.resize(len, 0);
+            assert_eq!(
+                SubvolumeEntry::try_load(&bytes).unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+        }
+
+        /* a full manager block is loaded */
+        let mut bytes = load_block(&mut device, fs.sb.subvol_mgr).unwrap();
+        bytes[8..16].copy_from_slice(&(SUBVOLUMES as u64).to_be_bytes());
+        assert_eq!(
+            SubvolumeManager::try_load(bytes).unwrap().entries.len(),
+            SUBVOLUMES
+        );
+    }
+
+    #[test]
+    fn manager_entry_count_out_of_range() {
+        let (mut fs, mut device, _) = test_fs(200);
+        fs.new_subvolume(&mut device, None).unwrap();
+
+        let mut bytes = load_block(&mut device, fs.sb.subvol_mgr).unwrap();
+        bytes[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+
+        assert_eq!(SubvolumeManager::load(bytes).entries.len(), SUBVOLUMES);
+        for count in [SUBVOLUMES as u64 + 1, u32::MAX as u64] {
+            bytes[8..16].copy_from_slice(&count.to_be_bytes());
+            assert_eq!(
+                SubvolumeManager::try_load(bytes).unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+        }
+        bytes[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(
+            SubvolumeManager::try_load(bytes).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        save_block(&mut device, fs.sb.subvol_mgr, bytes).unwrap();
+        assert_eq!(
+            fs.list_subvolumes(&mut device).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn release_emptied_inode_group() {
+        let (mut fs, mut device, _) = test_fs(1024);
+        let id = fs.new_subvolume(&mut device, None).unwrap();
+        let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+
+        let fs_used = fs.sb.used_blocks;
+        let subvol_used = subvol.entry.used_blocks;
+
+        /* the second inode group is allocated and emptied again */
+        let count = INODE_PER_GROUP + 8;
+        for i in 0..count {
+            let mut file = fs
+                .create_file(&mut subvol, &mut device, format!("/{}", i))
+                .unwrap();
+            file.write(&mut fs, &mut subvol, &mut device, 0, b"data")
+                .unwrap();
+        }
+        for i in 0..count {
+            fs.remove_file(&mut subvol, &mut device, format!("/{}", i))
+                .unwrap();
+        }
+
+        assert_eq!(subvol.entry.used_blocks, subvol_used);
+        assert_eq!(fs.sb.used_blocks, fs_used);
+        assert!(fs.verify_sharing(&mut device, false).unwrap().is_empty());
+
+        /* the entry records used blocks the removal releases */
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.remove_subvolume(&mut device, id).unwrap();
+        assert!(fs.sb.used_blocks < fs_used);
+        fs.check_used_blocks(&mut device).unwrap();
+        assert!(fs.verify_sharing(&mut device, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_creation_releases_blocks() {
+        let (mut fs, mut device, _) = test_fs(100);
+
+        /* fill the disk with subvolumes, the last one fails part way */
+        let err = loop {
+            let used_blocks = fs.sb.used_blocks;
+            let subvols = fs.list_subvolumes(&mut device).unwrap().len();
+            match fs.new_subvolume(&mut device, None) {
+                Ok(_) => {}
+                Err(err) => {
+                    assert_eq!(fs.sb.used_blocks, used_blocks);
+                    assert_eq!(fs.list_subvolumes(&mut device).unwrap().len(), subvols);
+                    break err;
+                }
+            }
+        };
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert_eq!(
+            fs.groups
+                .iter()
+                .map(|g| g.meta_data.free_blocks)
+                .sum::<u64>(),
+            fs.sb.total_blocks - fs.sb.groups * (1 + fs.sb.block_map_size) - 2 - fs.sb.used_blocks
+        );
+        assert!(fs.verify_sharing(&mut device, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn open_handle_keeps_changed_entry() {
+        let (mut fs, mut device, _) = test_fs(1000);
+        let id = fs.new_subvolume(&mut device, None).unwrap();
+        let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+
+        fs.set_subvolume_name(&mut device, id, "name").unwrap();
+        fs.set_quota(&mut device, id, Some(500)).unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 8192])
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+
+        fs.set_subvolume_readonly(&mut device, id, true).unwrap();
+        assert_eq!(
+            fd.write(&mut fs, &mut subvol, &mut device, 0, b"data")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::PermissionDenied
+        );
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+
+        let entry = fs.get_subvolume(&mut device, id).unwrap().entry;
+        assert!(entry.is_readonly());
+        assert_eq!(entry.quota, 500);
+        assert_eq!(entry.get_name(), "name");
+        assert_eq!(entry.used_blocks, subvol.entry.used_blocks);
+    }
+
+    #[test]
+    fn clone_shares_blocks() {
+        let (mut fs, mut device, _) = test_fs(1000);
+        let id = fs.new_subvolume(&mut device, None).unwrap();
+        let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 8192])
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+
+        /* only bitmaps of the clone and the shared bitmap of its origin are allocated */
+        let real_used_blocks = fs.sb.real_used_blocks;
+        let clone_id = fs.clone_subvolume(&mut device, id).unwrap();
+        let bitmap_blocks = fs.subvol_bitmap_size() as u64 + 1;
+        assert_eq!(fs.sb.real_used_blocks, real_used_blocks + 3 * bitmap_blocks);
+        assert_eq!(fs.subvolume_count(), 3);
+
+        let mut clone = fs.get_subvolume(&mut device, clone_id).unwrap();
+        assert!(!clone.entry.is_readonly());
+        let mut fd = fs.open_file(&mut clone, &mut device, "/f").unwrap();
+        fd.write(&mut fs, &mut clone, &mut device, 0, &[2; 4096])
+            .unwrap();
+        fs.create_file(&mut clone, &mut device, "/g").unwrap();
+
+        let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+        let mut buffer = [0; 8192];
+        let mut fd = fs.open_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.read(&mut fs, &mut subvol, &mut device, 0, &mut buffer, 8192)
+            .unwrap();
+        assert_eq!(buffer, [1; 8192]);
+        assert!(!fs.is_file(&mut subvol, &mut device, "/g"));
+
+        let mut fd = fs.open_file(&mut clone, &mut device, "/f").unwrap();
+        fd.read(&mut fs, &mut clone, &mut device, 0, &mut buffer, 8192)
+            .unwrap();
+        assert_eq!(buffer[..4096], [2; 4096]);
+        assert_eq!(buffer[4096..], [1; 4096]);
+
+        fs.remove_subvolume(&mut device, clone_id).unwrap();
+        assert_eq!(fs.subvolume_count(), 2);
+        assert!(fs.verify_sharing(&mut device, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn snapshot_counts() {
+        let (mut fs, mut device, _) = test_fs(2000);
+        let id = fs.new_subvolume(&mut device, None).unwrap();
+        let snap = fs.create_snapshot(&mut device, id).unwrap();
+        let nested = fs.create_snapshot(&mut device, snap).unwrap();
+        let mgr = fs.sb.subvol_mgr;
+        let snaps = |device: &mut std::io::Cursor<Vec<u8>>, id| {
+            SubvolumeManager::get_entry(device, mgr, id).unwrap().snaps
+        };
+        assert_eq!((snaps(&mut device, id), snaps(&mut device, snap)), (1, 1));
+        assert_eq!(snaps(&mut device, nested), 0);
+
+        /* a snapshot marked removed still counts for its parent */
+        fs.remove_subvolume(&mut device, snap).unwrap();
+        assert_eq!(snaps(&mut device, id), 1);
+        fs.remove_subvolume(&mut device, id).unwrap();
+        let entry = SubvolumeManager::get_entry(&mut device, fs.sb.subvol_mgr, id).unwrap();
+        assert_eq!((entry.state, entry.snaps), (SUBVOLUME_STATE_REMOVED, 1));
+
+        /* dropping the last snapshot drops its removed parents in turn */
+        fs.remove_subvolume(&mut device, nested).unwrap();
+        let ids: Vec<u64> = SubvolumeManager::list_all(&mut device, fs.sb.subvol_mgr)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.id)
+            .collect();
+        assert!(!ids.contains(&id) && !ids.contains(&snap) && !ids.contains(&nested));
+        assert_eq!(fs.subvolume_count(), 1);
+    }
+
+    /** Record a block in the first bitmap block of a subvolume bitmap */
+    fn record_block(device: &mut std::io::Cursor<Vec<u8>>, bitmap: u64, block: u64) {
+        let index = BitmapIndexBlock::load_block(device, bitmap).unwrap();
+        let mut bitmap = BitmapBlock::load_block(device, index.bitmaps[0]).unwrap();
+        bitmap.set_used(block);
+        bitmap.sync(device, index.bitmaps[0]).unwrap();
+    }
+
+    #[test]
+    fn verify_sharing() {
+        let (mut fs, mut device, mut subvol) = test_fs(1000);
+        let id = subvol.entry.id;
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 8192])
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        let snap_id = fs.create_snapshot(&mut device, id).unwrap();
+        assert!(fs.verify_sharing(&mut device, false).unwrap().is_empty());
+
+        /* a free block recorded by a subvolume */
+        let free_block = (0..1000)
+            .rev()
+            .find(|c| !fs.is_block_allocated(*c))
+            .unwrap();
+        let snap = fs.get_subvolume(&mut device, snap_id).unwrap();
+        record_block(&mut device, snap.entry.bitmap, free_block);
+        assert_eq!(
+            fs.verify_sharing(&mut device, false).unwrap(),
+            vec![SharingIssue::Unallocated {
+                block: free_block,
+                subvol: snap_id
+            }]
+        );
+        assert!(!fs.is_block_allocated(free_block));
+
+        fs.verify_sharing(&mut device, true).unwrap();
+        assert!(fs.is_block_allocated(free_block));
+        assert!(fs.verify_sharing(&mut device, false).unwrap().is_empty());
+
+        /* a block recorded by both subvolumes */
+        let subvol = fs.get_subvolume(&mut device, id).unwrap();
+        record_block(&mut device, subvol.entry.bitmap, free_block);
+        assert_eq!(
+            fs.verify_sharing(&mut device, false).unwrap(),
+            vec![SharingIssue::MultipleOwners {
+                block: free_block,
+                subvols: vec![id, snap_id]
+            }]
+        );
+    }
+
+    #[test]
+    fn quota() {
+        let (mut fs, mut device, _) = test_fs(2000);
+        let id = fs.new_subvolume(&mut device, None).unwrap();
+        let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+        let mut fd = fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fd.write(&mut fs, &mut subvol, &mut device, 0, &[1; 40960])
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+
+        let quota = subvol.entry.used_blocks + 20;
+        fs.set_quota(&mut device, id, Some(quota)).unwrap();
+        assert_eq!(
+            fd.write(&mut fs, &mut subvol, &mut device, 40960, &[1; 40960 * 3])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::Other
+        );
+        /* the failed write is rolled back */
+        assert_eq!(subvol.entry.used_blocks, quota - 20);
+        assert_eq!(fd.get_inode().size, 40960);
+        fd.truncate(&mut fs, &mut subvol, &mut device, 40960)
+            .unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        let used_blocks = subvol.entry.used_blocks;
+
+        /* blocks shared with the origin are counted once for the clone */
+        let clone_id = fs.clone_subvolume(&mut device, id).unwrap();
+        let mut clone = fs.get_subvolume(&mut device, clone_id).unwrap();
+        assert_eq!(clone.entry.used_blocks, used_blocks);
+        assert_eq!(clone.entry.quota, quota);
+        let fs_used_blocks = fs.sb.used_blocks;
+        let mut fd = fs.open_file(&mut clone, &mut device, "/f").unwrap();
+        for i in 0..10 {
+            fd.write(&mut fs, &mut clone, &mut device, 0, &[i; 40960])
+                .unwrap();
+        }
+        assert_eq!(clone.entry.used_blocks, used_blocks);
+        clone.sync_meta_data(&mut fs, &mut device).unwrap();
+
+        let subvol = fs.get_subvolume(&mut device, id).unwrap();
+        assert_eq!(subvol.entry.used_blocks, used_blocks);
+        assert_eq!(fs.sb.used_blocks, fs_used_blocks);
+    }
+
+    #[test]
+    fn max_inodes() {
+        let (mut fs, mut device, _) = test_fs(2000);
+        let id = fs.new_subvolume(&mut device, None).unwrap();
+        fs.set_max_inodes(&mut device, id, Some(4)).unwrap();
+        let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+        assert_eq!(subvol.entry.get_max_inodes(), Some(4));
+
+        /* the root directory takes the first inode */
+        for i in 1..4 {
+            fs.create_file(&mut subvol, &mut device, format!("/f{i}"))
+                .unwrap();
+        }
+        let used_blocks = subvol.entry.used_blocks;
+        let err = fs.create_file(&mut subvol, &mut device, "/f4").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert_eq!(FsError::from_io(&err), Some(&FsError::NoInodes(id)));
+        assert_eq!(subvol.entry.used_blocks, used_blocks);
+        assert!(fs.open_file(&mut subvol, &mut device, "/f4").is_err());
+        /* inodes are counted once, rather than on each allocation */
+        assert_eq!(subvol.used_inodes, Some(4));
+
+        /* a released inode can be allocated again */
+        fs.remove_file(&mut subvol, &mut device, "/f1").unwrap();
+        assert_eq!(subvol.used_inodes, Some(3));
+        fs.create_file(&mut subvol, &mut device, "/f4").unwrap();
+
+        fs.set_max_inodes(&mut device, id, None).unwrap();
+        fs.create_file(&mut subvol, &mut device, "/f5").unwrap();
+        assert_eq!(subvol.inode_stats(&mut device).unwrap().0, 5);
+    }
+
+    #[test]
+    fn verify_allocation() {
+        let (mut fs, mut device, _) = test_fs(8000);
+        fs.set_verify_allocation(true);
+        let id = fs.new_subvolume(&mut device, None).unwrap();
+
+        let mut snaps = Vec::new();
+        for round in 0..8u8 {
+            let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+            for i in 0..20 {
+                let path = format!("/{}", (round as usize * 7 + i) % 30);
+                if fs.is_file(&mut subvol, &mut device, &path) && i % 3 == 0 {
+                    fs.remove_file(&mut subvol, &mut device, &path).unwrap();
+                    continue;
+                }
+                let mut fd = match fs.open_file(&mut subvol, &mut device, &path) {
+                    Ok(fd) => fd,
+                    Err(_) => fs.create_file(&mut subvol, &mut device, &path).unwrap(),
+                };
+                fd.write(
+                    &mut fs,
+                    &mut subvol,
+                    &mut device,
+                    (i * 1000) as u64,
+                    &[round; 6000],
+                )
+                .unwrap();
+            }
+            subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+
+            snaps.push(fs.create_snapshot(&mut device, id).unwrap());
+            if round % 2 == 1 {
+                fs.remove_subvolume(&mut device, snaps.remove(0)).unwrap();
+            }
+            if round % 3 == 2 {
+                let clone = fs.clone_subvolume(&mut device, id).unwrap();
+                let mut clone_subvol = fs.get_subvolume(&mut device, clone).unwrap();
+                fs.create_file(&mut clone_subvol, &mut device, "/clone")
+                    .unwrap()
+                    .write(&mut fs, &mut clone_subvol, &mut device, 0, &[round; 9000])
+                    .unwrap();
+                clone_subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+                fs.remove_subvolume(&mut device, clone).unwrap();
+            }
+        }
+
+        fs.remove_subvolume(&mut device, id).unwrap();
+        for snap in snaps {
+            fs.remove_subvolume(&mut device, snap).unwrap();
+        }
+        let id = fs.new_subvolume(&mut device, None).unwrap();
+
+        /* a block which is free in the group bitmap but marked by a subvolume */
+        let subvol = fs.get_subvolume(&mut device, id).unwrap();
+        let next = fs.clone().new_block().unwrap();
+        record_block(&mut device, subvol.entry.bitmap, next);
+        assert_eq!(
+            fs.new_subvolume(&mut device, None).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn used_blocks_invariant() {
+        let (mut fs, mut device, mut subvol) = test_fs(8000);
+        fs.set_verify_allocation(true);
+        fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+        fs.check_used_blocks(&mut device).unwrap();
+
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        let mut random = |range: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % range
+        };
+        for step in 0..120 {
+            let subvols = fs.list_subvolumes(&mut device).unwrap();
+            let entry = &subvols[random(subvols.len() as u64) as usize];
+            let id = entry.id;
+            match random(5) {
+                0 => {
+                    let id = fs.new_subvolume(&mut device, None).unwrap();
+                    let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+                    fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+                    subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+                }
+                1 => {
+                    fs.create_snapshot(&mut device, id).unwrap();
+                }
+                2 => {
+                    fs.clone_subvolume(&mut device, id).unwrap();
+                }
+                3 if entry.flags & SUBVOLUME_FLAG_RO == 0 => {
+                    let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+                    let offset = random(16) * BLOCK_SIZE as u64;
+                    let data = vec![step as u8; (1 + random(4)) as usize * BLOCK_SIZE];
+                    fs.open_file(&mut subvol, &mut device, "/f")
+                        .unwrap()
+                        .write(&mut fs, &mut subvol, &mut device, offset, &data)
+                        .unwrap();
+                    subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+                }
+                _ if id != fs.sb.default_subvol => {
+                    fs.remove_subvolume(&mut device, id).unwrap();
+                }
+                _ => continue,
+            }
+            if let Err(err) = fs.check_used_blocks(&mut device) {
+                panic!("step {step}: {err}");
+            }
+        }
     }
 }
@@ -1,55 +1,59 @@
-use crate::block::LinkedContentTable;
+use crate::block::{invalid_block, LinkedContentTable, BLOCK_SIZE};
 use crate::dir::Directory;
 use crate::inode::{INode, ACL_SYMBOLLINK, PERMISSION_BITS};
 use crate::subvol::Subvolume;
-use crate::utils::{base_name, dir_path};
-use crate::{Block, Filesystem};
+use crate::utils::{base_name, dir_path, normalize};
+use crate::{Block, Filesystem, FsError};
 
-use std::io::Result as IOResult;
+use std::io::{Error, ErrorKind, Result as IOResult};
 use std::io::{Read, Seek, Write};
 use std::path::{Path, PathBuf};
 
+/** Longest target of a symbol link, which also bounds reading a corrupted chain of tables */
+pub(crate) const TARGET_MAX: usize = 4 * (BLOCK_SIZE - 8);
+
 /** Create a symbol link */
 pub fn create<D, P>(
     fs: &mut Filesystem,
     subvol: &mut Subvolume,
     device: &mut D,
     path: P,
-    mut point_to: &str,
+    point_to: &str,
 ) -> IOResult<u64>
 where
     D: Read + Write + Seek,
     P: AsRef<Path>,
 {
+    let path = normalize(path.as_ref())?;
+    subvol.check_writable(fs, device)?;
+    if point_to.len() > TARGET_MAX {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Target of symbol link '{}' is too long.", path.display()),
+        ));
+    }
+
+    let (parent, name) = (dir_path(&path)?, base_name(&path)?);
     let inode_count = subvol.new_inode(fs, device)?;
 
-    let mut content_ptr = LinkedContentTable::allocate_on_block_subvol(fs, subvol, device)?;
-    let inode = INode {
+    let content_ptr = LinkedContentTable::write_chain(fs, subvol, device, point_to.as_bytes())?;
+    let mut inode = INode {
         acl: ACL_SYMBOLLINK << PERMISSION_BITS,
         btree_root: content_ptr,
+        size: point_to.len() as u64,
         ..Default::default()
     };
-
-    loop {
-        let mut lct = LinkedContentTable::default();
-        let size = std::cmp::min(point_to.len(), lct.content.len());
-        lct.content[..size].copy_from_slice(point_to[..size].as_bytes());
-        point_to = &point_to[size..];
-
-        if point_to.is_empty() {
-            lct.sync(device, content_ptr)?;
-            break;
-        } else {
-            content_ptr = subvol.new_block(fs, device)?;
-            lct.next = content_ptr;
-            lct.sync(device, content_ptr)?;
-        }
-    }
+    inode.update_atime();
+    inode.update_mtime();
 
     subvol.set_inode(fs, device, inode_count, inode)?;
 
-    let mut dir = Directory::open(fs, subvol, device, dir_path(path.as_ref()))?;
-    dir.add_file(fs, subvol, device, base_name(path.as_ref()), inode_count)?;
+    if let Err(err) = Directory::open(fs, subvol, device, parent)
+        .and_then(|mut dir| dir.add_file(fs, subvol, device, name, inode_count))
+    {
+        crate::file::remove_by_inode(fs, subvol, device, inode_count)?;
+        return Err(err);
+    }
 
     Ok(inode_count)
 }
@@ -65,12 +69,18 @@ where
     D: Read + Write + Seek,
     P: AsRef<Path>,
 {
-    let inode_count = Directory::open(fs, subvol, device, dir_path(path.as_ref()))?
-        .find_inode_by_name(fs, subvol, device, base_name(path.as_ref()))?;
+    let path = normalize(path.as_ref())?;
+    let inode_count = Directory::open(fs, subvol, device, dir_path(path.as_ref())?)?
+        .find_inode_by_name(fs, subvol, device, base_name(path.as_ref())?)?;
 
     read_link_from_inode(subvol, device, inode_count)
 }
 
+/** Error of a path following more than `SYMLINK_MAX_FOLLOW` symbol links, like `ELOOP` */
+pub(crate) fn too_many_links() -> Error {
+    FsError::TooManyLinks.into()
+}
+
 /** Read symbol link by inode count */
 pub(crate) fn read_link_from_inode<D>(
     subvol: &mut Subvolume,
@@ -82,21 +92,58 @@ where
 {
     let inode = subvol.get_inode(device, inode_count)?;
 
-    let mut point_to = String::new();
+    /* links created before their length was stored end at the first zero byte */
+    let len = match inode.size {
+        0 => None,
+        size if size > TARGET_MAX as u64 => {
+            return Err(invalid_block(&format!(
+                "Target of symbol link {inode_count} is too long."
+            )))
+        }
+        size => Some(size as usize),
+    };
+
+    let mut target = Vec::new();
     let mut content_ptr = inode.btree_root;
-    'main: loop {
+    while content_ptr != 0 && len.is_none_or(|len| target.len() < len) {
+        if target.len() >= TARGET_MAX {
+            return Err(invalid_block(&format!(
+                "Target of symbol link {inode_count} is too long."
+            )));
+        }
         let lct = LinkedContentTable::load_block(device, content_ptr)?;
-
-        for byte in lct.content {
-            if byte == 0 {
-                break 'main;
-            } else {
-                point_to.push(byte as char);
-            }
+        target.extend_from_slice(&lct.content);
+        if len.is_none() && lct.content.contains(&0) {
+            break;
         }
-
         content_ptr = lct.next;
     }
 
-    Ok(point_to.into())
+    let len = match len {
+        Some(len) if len > target.len() => {
+            return Err(invalid_block(&format!(
+                "Target of symbol link {inode_count} is shorter than its size."
+            )))
+        }
+        Some(len) => len,
+        None => target
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(target.len()),
+    };
+
+    Ok(String::from_utf8_lossy(&target[..len]).into_owned().into())
+}
+
+/** Release content blocks of a symbol link */
+pub(crate) fn release_content<D>(
+    fs: &mut Filesystem,
+    subvol: &mut Subvolume,
+    device: &mut D,
+    inode: &INode,
+) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+{
+    LinkedContentTable::release_chain(fs, subvol, device, inode.btree_root)
 }
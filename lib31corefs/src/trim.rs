@@ -0,0 +1,35 @@
+use crate::block::BLOCK_SIZE;
+
+use std::io::{Cursor, Result as IOResult};
+
+/**
+ * A device able to discard blocks, e.g. by TRIM on an SSD or punching holes in a sparse file
+ *
+ * Discarded blocks hold undefined data afterwards, the filesystem only discards free blocks
+ * (see `Filesystem::discard_released` and `Filesystem::trim_free_space`). A file device may
+ * implement it with `fallocate(FALLOC_FL_PUNCH_HOLE)`.
+ */
+pub trait Trim {
+    /** Discard `count` blocks from `block_count` */
+    fn discard(&mut self, block_count: u64, count: u64) -> IOResult<()>;
+}
+
+impl Trim for Cursor<Vec<u8>> {
+    /** Zero the blocks, as memory of a vector can't be returned */
+    fn discard(&mut self, block_count: u64, count: u64) -> IOResult<()> {
+        let image = self.get_mut();
+        let start = std::cmp::min(block_count as usize * BLOCK_SIZE, image.len());
+        let end = std::cmp::min(start + count as usize * BLOCK_SIZE, image.len());
+        image[start..end].fill(0);
+        Ok(())
+    }
+}
+
+impl<T> Trim for &mut T
+where
+    T: Trim + ?Sized,
+{
+    fn discard(&mut self, block_count: u64, count: u64) -> IOResult<()> {
+        (**self).discard(block_count, count)
+    }
+}
@@ -1,22 +1,219 @@
+use crate::{FILE_NAME_MAX, PATH_MAX};
+
 use std::{
-    path::Path,
+    io::{Error, ErrorKind, Result as IOResult},
+    path::{Component, Path, PathBuf},
     time::{SystemTime, UNIX_EPOCH},
 };
 
-#[inline]
-pub fn base_name(path: &Path) -> &str {
-    path.file_name().unwrap().to_str().unwrap()
+/** Get name of the last component of a path */
+pub fn base_name(path: &Path) -> IOResult<&str> {
+    path.file_name()
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("'{}' has no file name.", path.to_string_lossy()),
+            )
+        })?
+        .to_str()
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("'{}' is not valid UTF-8.", path.to_string_lossy()),
+            )
+        })
 }
 
-#[inline]
-pub fn dir_path(path: &Path) -> &Path {
-    path.parent().unwrap()
+/** Get the directory containing the last component of a path */
+pub fn dir_path(path: &Path) -> IOResult<&Path> {
+    path.parent().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("'{}' has no parent directory.", path.to_string_lossy()),
+        )
+    })
+}
+
+/** Resolve `.`, `..` and redundant separators of a path
+ *
+ * The result is always absolute, as paths are taken from the root directory of a subvolume.
+ * `..` is resolved without looking at the filesystem and stays at the root directory.
+ *
+ * Paths longer than `PATH_MAX` or with a component longer than `FILE_NAME_MAX` are rejected.
+ */
+pub fn normalize(path: &Path) -> IOResult<PathBuf> {
+    if path.as_os_str().len() > PATH_MAX {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Path '{}' is too long.", path.to_string_lossy()),
+        ));
+    }
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) if name.len() > FILE_NAME_MAX => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("File name '{}' is too long.", name.to_string_lossy()),
+                ));
+            }
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    Ok(normalized)
+}
+
+#[cfg(any(feature = "replay", test))]
+thread_local! {
+    /** Time returned by `get_sys_time` while an operation is recorded or replayed */
+    static PINNED_TIME: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
 }
 
 #[inline]
 pub fn get_sys_time() -> u64 {
+    #[cfg(any(feature = "replay", test))]
+    if let Some(time) = PINNED_TIME.with(|time| time.get()) {
+        return time;
+    }
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_nanos() as u64
 }
+
+/** Make `get_sys_time` return `time` on this thread, `None` restores the system clock */
+#[cfg(any(feature = "replay", test))]
+pub(crate) fn pin_time(time: Option<u64>) {
+    PINNED_TIME.with(|pinned| pinned.set(time));
+}
+
+/** Format an in-memory device with `blocks` blocks and open its default subvolume */
+#[cfg(test)]
+pub(crate) fn test_fs(
+    blocks: usize,
+) -> (
+    crate::Filesystem,
+    std::io::Cursor<Vec<u8>>,
+    crate::Subvolume,
+) {
+    let mut device = std::io::Cursor::new(vec![0; blocks * crate::block::BLOCK_SIZE]);
+    let mut fs = crate::Filesystem::create(&mut device, blocks).unwrap();
+    fs.sync_meta_data(&mut device).unwrap();
+    let subvol = fs.get_default_subvolume(&mut device).unwrap();
+
+    (fs, device, subvol)
+}
+
+/** A device counting reads, writes and flushes issued to it */
+#[cfg(test)]
+pub(crate) struct CountingDevice {
+    pub inner: std::io::Cursor<Vec<u8>>,
+    pub reads: usize,
+    /** Block each read starts at */
+    pub read_blocks: Vec<u64>,
+    pub writes: usize,
+    pub flushes: usize,
+}
+
+#[cfg(test)]
+impl CountingDevice {
+    pub fn new(inner: std::io::Cursor<Vec<u8>>) -> Self {
+        Self {
+            inner,
+            reads: 0,
+            read_blocks: Vec::new(),
+            writes: 0,
+            flushes: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+impl std::io::Read for CountingDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads += 1;
+        self.read_blocks
+            .push(self.inner.position() / crate::block::BLOCK_SIZE as u64);
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl std::io::Write for CountingDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writes += 1;
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flushes += 1;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+impl std::io::Seek for CountingDevice {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/** A device recording ranges discarded on it */
+#[cfg(test)]
+pub(crate) struct TrimDevice {
+    pub inner: std::io::Cursor<Vec<u8>>,
+    /** First block and count of blocks of each discard */
+    pub discarded: Vec<(u64, u64)>,
+}
+
+#[cfg(test)]
+impl TrimDevice {
+    pub fn new(inner: std::io::Cursor<Vec<u8>>) -> Self {
+        Self {
+            inner,
+            discarded: Vec::new(),
+        }
+    }
+    /** Every block discarded */
+    pub fn discarded_blocks(&self) -> Vec<u64> {
+        self.discarded
+            .iter()
+            .flat_map(|(start, count)| *start..*start + *count)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl std::io::Read for TrimDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl std::io::Write for TrimDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+impl std::io::Seek for TrimDevice {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+impl crate::Trim for TrimDevice {
+    fn discard(&mut self, block_count: u64, count: u64) -> std::io::Result<()> {
+        self.discarded.push((block_count, count));
+        self.inner.discard(block_count, count)
+    }
+}
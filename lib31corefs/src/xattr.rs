@@ -0,0 +1,315 @@
+use crate::block::LinkedContentTable;
+use crate::file::handle_rc_inode;
+use crate::subvol::Subvolume;
+use crate::Filesystem;
+
+use std::io::{Error, ErrorKind, Result as IOResult};
+use std::io::{Read, Seek, Write};
+
+/** Max length of an attribute name */
+pub const XATTR_NAME_MAX: usize = 255;
+/** Max size of all attributes of an inode */
+pub const XATTR_SIZE_MAX: usize = 64 * 1024;
+
+/**
+ * # Data structure
+ *
+ * Extended attributes of an inode are stored in a chain of `LinkedContentTable`, which is
+ * referenced by the `xattr` field of inode. Entries are stored one by one:
+ *
+ * |Start          |End                       |Description |
+ * |---------------|--------------------------|------------|
+ * |0              |2                         |Name length |
+ * |2              |6                         |Value length|
+ * |6              |6 + name length           |Name        |
+ * |6 + name length|6 + name + value length   |Value       |
+ *
+ * A zero name length marks the end of entries.
+ */
+pub(crate) type Attributes = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn parse(data: &[u8]) -> Attributes {
+    let mut attrs = Vec::new();
+    let mut offset = 0;
+
+    while offset + 6 <= data.len() {
+        let name_len = u16::from_be_bytes(data[offset..offset + 2].try_into().unwrap()) as usize;
+        let value_len =
+            u32::from_be_bytes(data[offset + 2..offset + 6].try_into().unwrap()) as usize;
+        offset += 6;
+
+        if name_len == 0 || offset + name_len + value_len > data.len() {
+            break;
+        }
+
+        let name = data[offset..offset + name_len].to_vec();
+        offset += name_len;
+        let value = data[offset..offset + value_len].to_vec();
+        offset += value_len;
+
+        attrs.push((name, value));
+    }
+
+    attrs
+}
+
+fn dump(attrs: &Attributes) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (name, value) in attrs {
+        data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        data.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        data.extend_from_slice(name);
+        data.extend_from_slice(value);
+    }
+
+    data
+}
+
+/** Load extended attributes of an inode */
+pub(crate) fn load<D>(subvol: &Subvolume, device: &mut D, inode_count: u64) -> IOResult<Attributes>
+where
+    D: Read + Write + Seek,
+{
+    let inode = subvol.get_inode(device, inode_count)?;
+    Ok(parse(&LinkedContentTable::read_chain(device, inode.xattr)?))
+}
+
+/** Replace extended attributes of an inode */
+fn store<D>(
+    fs: &mut Filesystem,
+    subvol: &mut Subvolume,
+    device: &mut D,
+    inode_count: u64,
+    attrs: &Attributes,
+) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+{
+    subvol.check_writable(fs, device)?;
+
+    let data = dump(attrs);
+    if data.len() > XATTR_SIZE_MAX {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Extended attributes are too large.",
+        ));
+    }
+
+    handle_rc_inode(fs, subvol, device, inode_count)?;
+
+    let mut inode = subvol.get_inode(device, inode_count)?;
+    LinkedContentTable::release_chain(fs, subvol, device, inode.xattr)?;
+    inode.xattr = LinkedContentTable::write_chain(fs, subvol, device, &data)?;
+    inode.update_ctime();
+    subvol.set_inode(fs, device, inode_count, inode)
+}
+
+/** Get value of an extended attribute */
+pub(crate) fn get<D>(
+    subvol: &Subvolume,
+    device: &mut D,
+    inode_count: u64,
+    name: &[u8],
+) -> IOResult<Option<Vec<u8>>>
+where
+    D: Read + Write + Seek,
+{
+    Ok(load(subvol, device, inode_count)?
+        .into_iter()
+        .find(|(attr_name, _)| attr_name == name)
+        .map(|(_, value)| value))
+}
+
+/** List names of extended attributes */
+pub(crate) fn list<D>(
+    subvol: &Subvolume,
+    device: &mut D,
+    inode_count: u64,
+) -> IOResult<Vec<Vec<u8>>>
+where
+    D: Read + Write + Seek,
+{
+    Ok(load(subvol, device, inode_count)?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect())
+}
+
+/** Create or overwrite an extended attribute */
+pub(crate) fn set<D>(
+    fs: &mut Filesystem,
+    subvol: &mut Subvolume,
+    device: &mut D,
+    inode_count: u64,
+    name: &[u8],
+    value: &[u8],
+) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+{
+    if name.is_empty() || name.len() > XATTR_NAME_MAX {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Invalid extended attribute name.",
+        ));
+    }
+
+    let mut attrs = load(subvol, device, inode_count)?;
+    match attrs.iter_mut().find(|(attr_name, _)| attr_name == name) {
+        Some((_, attr_value)) => *attr_value = value.to_vec(),
+        None => attrs.push((name.to_vec(), value.to_vec())),
+    }
+
+    store(fs, subvol, device, inode_count, &attrs)
+}
+
+/** Remove an extended attribute */
+pub(crate) fn remove<D>(
+    fs: &mut Filesystem,
+    subvol: &mut Subvolume,
+    device: &mut D,
+    inode_count: u64,
+    name: &[u8],
+) -> IOResult<()>
+where
+    D: Read + Write + Seek,
+{
+    let mut attrs = load(subvol, device, inode_count)?;
+    let attrs_count = attrs.len();
+    attrs.retain(|(attr_name, _)| attr_name != name);
+
+    if attrs.len() == attrs_count {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No such attribute '{}'", String::from_utf8_lossy(name)),
+        ));
+    }
+
+    store(fs, subvol, device, inode_count, &attrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_fs;
+
+    #[test]
+    fn set_get_list_remove() {
+        let (mut fs, mut device, mut subvol) = test_fs(200);
+        fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+
+        fs.set_xattr(&mut subvol, &mut device, "/f", b"user.a", b"1")
+            .unwrap();
+        fs.set_xattr(&mut subvol, &mut device, "/f", b"user.b", b"2")
+            .unwrap();
+        fs.set_xattr(&mut subvol, &mut device, "/f", b"user.a", b"3")
+            .unwrap();
+        assert_eq!(
+            fs.get_xattr(&mut subvol, &mut device, "/f", b"user.a")
+                .unwrap(),
+            Some(b"3".to_vec())
+        );
+        assert_eq!(
+            fs.list_xattr(&mut subvol, &mut device, "/f").unwrap(),
+            vec![b"user.a".to_vec(), b"user.b".to_vec()]
+        );
+
+        fs.remove_xattr(&mut subvol, &mut device, "/f", b"user.a")
+            .unwrap();
+        assert_eq!(
+            fs.get_xattr(&mut subvol, &mut device, "/f", b"user.a")
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            fs.remove_xattr(&mut subvol, &mut device, "/f", b"user.a")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let (mut fs, mut device, mut subvol) = test_fs(200);
+        fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+
+        let name = vec![b'n'; XATTR_NAME_MAX];
+        fs.set_xattr(&mut subvol, &mut device, "/f", &name, b"")
+            .unwrap();
+        for name in [vec![b'n'; XATTR_NAME_MAX + 1], Vec::new()] {
+            assert_eq!(
+                fs.set_xattr(&mut subvol, &mut device, "/f", &name, b"")
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+        fs.remove_xattr(&mut subvol, &mut device, "/f", &name)
+            .unwrap();
+
+        /* each entry has a header of 6 bytes */
+        let value = vec![1; XATTR_SIZE_MAX - 7];
+        fs.set_xattr(&mut subvol, &mut device, "/f", b"v", &value)
+            .unwrap();
+        assert_eq!(
+            fs.get_xattr(&mut subvol, &mut device, "/f", b"v").unwrap(),
+            Some(value)
+        );
+        assert_eq!(
+            fs.set_xattr(
+                &mut subvol,
+                &mut device,
+                "/f",
+                b"v",
+                &[1; XATTR_SIZE_MAX - 6]
+            )
+            .unwrap_err()
+            .kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn shared_with_snapshot() {
+        let (mut fs, mut device, mut subvol) = test_fs(2000);
+        let id = subvol.entry.id;
+        fs.create_file(&mut subvol, &mut device, "/f").unwrap();
+        fs.set_xattr(&mut subvol, &mut device, "/f", b"user.a", &[1; 5000])
+            .unwrap();
+        fs.link(&mut subvol, &mut device, "/l", "/f").unwrap();
+        subvol.sync_meta_data(&mut fs, &mut device).unwrap();
+
+        let snap_id = fs.create_snapshot(&mut device, id).unwrap();
+        let mut subvol = fs.get_subvolume(&mut device, id).unwrap();
+        let mut snap = fs.get_subvolume(&mut device, snap_id).unwrap();
+
+        /* chains are not copied when the inode group is cloned */
+        fs.create_file(&mut subvol, &mut device, "/g").unwrap();
+        let (_, _, inode) = fs.resolve_nofollow(&mut subvol, &mut device, "/f").unwrap();
+        assert_eq!(
+            subvol.get_inode(&mut device, inode).unwrap().xattr,
+            snap.get_inode(&mut device, inode).unwrap().xattr
+        );
+
+        fs.set_xattr(&mut subvol, &mut device, "/f", b"user.a", b"new")
+            .unwrap();
+        fs.remove_file(&mut subvol, &mut device, "/l").unwrap();
+
+        assert_eq!(
+            fs.get_xattr(&mut subvol, &mut device, "/f", b"user.a")
+                .unwrap(),
+            Some(b"new".to_vec())
+        );
+        assert_eq!(
+            fs.get_xattr(&mut snap, &mut device, "/f", b"user.a")
+                .unwrap(),
+            Some(vec![1; 5000])
+        );
+        assert_eq!(
+            fs.read_link(&mut snap, &mut device, "/l").unwrap(),
+            std::path::PathBuf::from("/f")
+        );
+        assert!(fs.verify_sharing(&mut device, false).unwrap().is_empty());
+    }
+}
@@ -1,10 +1,14 @@
 use clap::Parser;
-use lib31corefs::Filesystem;
+use lib31corefs::{DirHash, Filesystem, NO_DEFAULT_SUBVOLUME};
 
 #[derive(Parser, Debug)]
 struct Args {
     /// Path to device
     device: String,
+
+    /// Print layout of block groups and subvolumes
+    #[arg(short, long)]
+    verbose: bool,
 }
 
 fn main() -> std::io::Result<()> {
@@ -14,20 +18,81 @@ fn main() -> std::io::Result<()> {
         .write(true)
         .read(true)
         .open(args.device)?;
-    let fs = Filesystem::load(&mut device)?;
+    let fs = Filesystem::load_readonly(&mut device)?;
 
     println!("Label: {}", fs.sb.get_label());
     println!("UUID: {}", uuid::Uuid::from_bytes(fs.sb.uuid));
     println!(
         "Creation time: {}",
-        chrono::DateTime::from_timestamp(fs.sb.creation_time as i64, 0)
-            .unwrap()
+        chrono::DateTime::from_timestamp_nanos(fs.sb.creation_time as i64)
             .format("%Y-%m-%d %H:%M:%S")
     );
-    println!("Dufault subvolume: {}", fs.sb.default_subvol);
+    if fs.sb.default_subvol == NO_DEFAULT_SUBVOLUME {
+        println!("Dufault subvolume: none");
+    } else {
+        println!("Dufault subvolume: {}", fs.sb.default_subvol);
+    }
     println!("Total blocks: {}", fs.sb.total_blocks);
     println!("Used blocks: {}", fs.sb.used_blocks);
     println!("Real used blocks: {}", fs.sb.real_used_blocks);
+    println!("Block groups: {}", fs.sb.groups);
+    println!("Bitmap blocks per group: {}", fs.sb.block_map_size);
+    println!("Features: {}", fs.features().names().join(" "));
+    println!("State: {}", if fs.was_dirty() { "dirty" } else { "clean" });
+    if fs.loaded_from_backup() {
+        println!("Superblock: loaded from backup");
+    }
+    if let Some(dir_hash) = DirHash::from_id(fs.sb.dir_hash) {
+        println!("Directory hash: {}", dir_hash.name());
+    }
+    if let Some(heat_map) = fs.heat_map() {
+        println!("Block group writes:");
+        for (id, writes) in heat_map.iter().enumerate() {
+            println!("  {}: {}", id, writes);
+        }
+    }
+
+    if args.verbose {
+        let description = fs.describe(&mut device)?;
+        println!("Block groups:");
+        for group in description.groups {
+            println!(
+                "  {}: start block {}, capacity {}, free blocks {}",
+                group.id, group.start_block, group.capacity, group.free_blocks
+            );
+        }
+        println!("Subvolumes:");
+        for subvol in description.subvolumes {
+            let entry = subvol.entry;
+            println!("  {}:", entry.id);
+            println!("    Name: {}", entry.get_name());
+            println!("    State: {}", entry.state);
+            println!("    Type: {}", entry.subvol_type);
+            println!("    Flags: {:#x}", entry.flags);
+            println!(
+                "    Creation time: {}",
+                chrono::DateTime::from_timestamp_nanos(entry.creation_date as i64)
+                    .format("%Y-%m-%d %H:%M:%S")
+            );
+            println!("    Parent: {}", entry.parent_subvol);
+            println!("    Snapshots: {}", entry.snaps);
+            println!("    Used blocks: {}", entry.used_blocks);
+            println!("    Real used blocks: {}", entry.real_used_blocks);
+            println!("    Quota: {}", entry.quota);
+            println!("    Max inodes: {}", entry.max_inodes);
+            println!("    Inode tree root: {}", entry.inode_tree_root);
+            println!("    Bitmap: {}", entry.bitmap);
+            println!("    Shared bitmap: {}", entry.shared_bitmap);
+            println!("    Inode group bitmap: {}", entry.igroup_bitmap);
+            match subvol.root_inode {
+                Some(inode) => println!(
+                    "    Root inode: {} (acl {:#o}, size {}, links {}, block tree {})",
+                    entry.root_inode, inode.acl, inode.size, inode.hlinks, inode.btree_root
+                ),
+                None => println!("    Root inode: {} (removed)", entry.root_inode),
+            }
+        }
+    }
 
     Ok(())
 }
@@ -1,7 +1,7 @@
 use clap::Parser;
-use lib31corefs::block::BLOCK_SIZE;
-use lib31corefs::Filesystem;
-use std::io::{Result as IOResult, Seek};
+use lib31corefs::block::{BLOCK_SIZE, DEFAULT_BLOCK_MAP_SIZE};
+use lib31corefs::{DirHash, Filesystem, FormatOptions};
+use std::io::{Error, ErrorKind, Result as IOResult, Seek};
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -11,6 +11,30 @@ struct Args {
     /// Filesystem label;
     #[arg(short = 'L', long, default_value_t = String::from(""))]
     label: String,
+
+    /// Do not create a default subvolume
+    #[arg(long)]
+    no_default_subvolume: bool,
+
+    /// Count writes of each block group
+    #[arg(long)]
+    heat_map: bool,
+
+    /// Allow compression of file data
+    #[arg(long)]
+    compression: bool,
+
+    /// Count of bitmap blocks in each block group
+    #[arg(long, default_value_t = DEFAULT_BLOCK_MAP_SIZE)]
+    group_bitmap_blocks: u64,
+
+    /// Count of blocks reserved for metadata
+    #[arg(long, default_value_t = 0)]
+    metadata_reserve: u64,
+
+    /// Hash of names in directory indexes, `fnv1a` or `siphash`
+    #[arg(long, default_value_t = String::from("fnv1a"))]
+    dir_hash: String,
 }
 
 fn get_size(fd: &mut std::fs::File) -> IOResult<u64> {
@@ -19,13 +43,27 @@ fn get_size(fd: &mut std::fs::File) -> IOResult<u64> {
 
 fn main() -> IOResult<()> {
     let args = Args::parse();
+    let dir_hash = DirHash::from_name(&args.dir_hash).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Unknown directory hash '{}'.", args.dir_hash),
+        )
+    })?;
 
     let mut device = std::fs::OpenOptions::new()
         .write(true)
         .read(true)
         .open(args.device)?;
     let size = get_size(&mut device)? as usize / BLOCK_SIZE;
-    let mut fs = Filesystem::create(&mut device, size)?;
+    let options = FormatOptions {
+        default_subvolume: !args.no_default_subvolume,
+        heat_map: args.heat_map,
+        compression: args.compression,
+        block_map_size: args.group_bitmap_blocks,
+        metadata_reserve: args.metadata_reserve,
+        dir_hash,
+    };
+    let mut fs = Filesystem::create_with_options(&mut device, size, &options)?;
 
     fs.sb.set_label(&args.label);
 
@@ -1,5 +1,5 @@
 use clap::{Parser, Subcommand};
-use lib31corefs::{block::BLOCK_SIZE, Filesystem};
+use lib31corefs::{block::BLOCK_SIZE, Filesystem, SubvolumeNode};
 
 #[derive(Parser)]
 struct Args {
@@ -15,13 +15,27 @@ enum Commands {
     /// List subvolumes
     List,
     /// Create a subvolume
-    Create,
+    Create {
+        /// Name of the subvolume
+        #[arg(short, long)]
+        name: Option<String>,
+    },
+    /// Rename a subvolume
+    Rename { id: u64, name: String },
     /// Create a snapshot
     Snap { id: u64 },
+    /// Create a writable clone
+    Clone { id: u64 },
     /// Remove a subvolume
     Remove { id: u64 },
     /// Set default subvolume
     SetDefault { id: u64 },
+    /// Set quota of a subvolume in blocks, unlimited if omitted
+    SetQuota { id: u64, blocks: Option<u64> },
+    /// Set max count of inodes of a subvolume, unlimited if omitted
+    SetMaxInodes { id: u64, inodes: Option<u32> },
+    /// Mark a subvolume read-only or writable
+    SetReadonly { id: u64, readonly: bool },
 }
 
 fn to_size_str(size: usize) -> String {
@@ -42,6 +56,18 @@ fn to_size_str(size: usize) -> String {
     }
 }
 
+/** Flatten subvolume trees in depth-first order, along with depth of each node */
+fn flatten_tree<'a>(
+    nodes: &'a [SubvolumeNode],
+    depth: usize,
+    list: &mut Vec<(usize, &'a SubvolumeNode)>,
+) {
+    for node in nodes {
+        list.push((depth, node));
+        flatten_tree(&node.children, depth + 1, list);
+    }
+}
+
 fn main() -> std::io::Result<()> {
     let args = Args::parse();
     let mut device = std::fs::OpenOptions::new()
@@ -56,47 +82,114 @@ fn main() -> std::io::Result<()> {
             println!("Created snapshot '{}' of subvolume '{}'.", snap_id, id);
             fs.sync_meta_data(&mut device)?;
         }
-        Commands::Create => {
-            let id = fs.new_subvolume(&mut device)?;
+        Commands::Clone { id } => {
+            let clone_id = fs.clone_subvolume(&mut device, id)?;
+            println!("Created clone '{}' of subvolume '{}'.", clone_id, id);
+            fs.sync_meta_data(&mut device)?;
+        }
+        Commands::Create { name } => {
+            let id = fs.new_subvolume(&mut device, name.as_deref())?;
             println!("Created subvolume '{}'.", id);
             fs.sync_meta_data(&mut device)?;
         }
+        Commands::Rename { id, name } => {
+            fs.set_subvolume_name(&mut device, id, &name)?;
+            println!("Renamed subvolume '{}' to '{}'.", id, name);
+        }
         Commands::Remove { id } => {
             fs.remove_subvolume(&mut device, id)?;
             println!("Removed submovume '{}'.", id);
             fs.sync_meta_data(&mut device)?;
         }
         Commands::List => {
-            let list = fs.list_subvolumes(&mut device)?;
+            let tree = fs.subvolume_tree(&mut device)?;
+            let mut list = Vec::new();
+            flatten_tree(&tree, 0, &mut list);
 
-            println!("+{}+{}+{}+", "-".repeat(7), "-".repeat(20), "-".repeat(8));
-            println!("|{:7}|{:20}|{:8}|", "ID", "Creation Date", "Size");
-            println!("+{}+{}+{}+", "-".repeat(7), "-".repeat(20), "-".repeat(8));
+            let separator = format!(
+                "+{}+{}+{}+{}+{}+{}+{}+{}+",
+                "-".repeat(12),
+                "-".repeat(24),
+                "-".repeat(20),
+                "-".repeat(8),
+                "-".repeat(8),
+                "-".repeat(8),
+                "-".repeat(7),
+                "-".repeat(8)
+            );
+            println!("{}", separator);
+            println!(
+                "|{:12}|{:24}|{:20}|{:8}|{:8}|{:8}|{:7}|{:8}|",
+                "ID", "Name", "Creation Date", "Size", "Quota", "Type", "Origin", "State"
+            );
+            println!("{}", separator);
 
-            for entry in list {
-                let id_str = if fs.sb.default_subvol == entry.id {
-                    format!("{} *", entry.id)
+            for (depth, node) in list {
+                let mut id_str = if depth > 0 {
+                    format!("{}└ {}", "  ".repeat(depth - 1), node.entry.id)
+                } else {
+                    format!("{}", node.entry.id)
+                };
+                if fs.sb.default_subvol == node.entry.id {
+                    id_str.push_str(" *");
+                }
+                let (type_str, origin_str) = if node.entry.is_snapshot() {
+                    ("snapshot", format!("{}", node.entry.parent_subvol))
                 } else {
-                    format!("{}", entry.id)
+                    ("normal", "-".to_string())
+                };
+                let quota_str = match node.entry.get_quota() {
+                    Some(quota) => to_size_str(quota as usize * BLOCK_SIZE),
+                    None => "-".to_string(),
+                };
+                let state_str = if node.is_removed() {
+                    "removed"
+                } else if node.entry.is_readonly() {
+                    "readonly"
+                } else {
+                    "active"
                 };
                 println!(
-                    "|{:7}|{:20}|{:8}|",
+                    "|{:12}|{:24}|{:20}|{:8}|{:8}|{:8}|{:7}|{:8}|",
                     id_str,
-                    chrono::DateTime::from_timestamp(entry.creation_date as i64, 0)
-                        .unwrap()
+                    node.entry.get_name(),
+                    chrono::DateTime::from_timestamp_nanos(node.entry.creation_date as i64)
                         .format("%Y-%m-%d %H:%M:%S"),
-                    to_size_str(entry.real_used_blocks as usize * BLOCK_SIZE),
+                    to_size_str(node.entry.real_used_blocks as usize * BLOCK_SIZE),
+                    quota_str,
+                    type_str,
+                    origin_str,
+                    state_str,
                 );
-                println!("+{}+{}+{}+", "-".repeat(7), "-".repeat(20), "-".repeat(8));
+                println!("{}", separator);
             }
         }
         Commands::SetDefault { id } => {
-            if fs.get_subvolume(&mut device, id).is_err() {
-                panic!("No such subvolume {}", id);
-            }
-            fs.sb.default_subvol = id;
+            fs.set_default_subvolume(&mut device, id)?;
             fs.sync_meta_data(&mut device)?;
         }
+        Commands::SetQuota { id, blocks } => {
+            fs.set_quota(&mut device, id, blocks)?;
+            match blocks {
+                Some(blocks) => println!("Set quota of subvolume '{}' to {} blocks.", id, blocks),
+                None => println!("Removed quota of subvolume '{}'.", id),
+            }
+        }
+        Commands::SetMaxInodes { id, inodes } => {
+            fs.set_max_inodes(&mut device, id, inodes)?;
+            match inodes {
+                Some(inodes) => println!("Set max inodes of subvolume '{}' to {}.", id, inodes),
+                None => println!("Removed inode limit of subvolume '{}'.", id),
+            }
+        }
+        Commands::SetReadonly { id, readonly } => {
+            fs.set_subvolume_readonly(&mut device, id, readonly)?;
+            println!(
+                "Subvolume '{}' is now {}.",
+                id,
+                if readonly { "read-only" } else { "writable" }
+            );
+        }
     }
 
     Ok(())